@@ -0,0 +1,362 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use ipnet::IpNet;
+use regex::Regex;
+use serde::Deserialize;
+
+/// The decision applied to addresses that match neither the allow nor the deny list.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum IpPolicy {
+    #[default]
+    Allow,
+    Deny,
+}
+
+/// Decides whether a client address may attempt to authenticate.
+///
+/// # Remarks
+///
+/// - Addresses in the deny list are always rejected, even if they are also in the allow list.
+/// - Addresses in the allow list are accepted.
+/// - Every other address is handled according to the default policy.
+#[derive(Clone, Debug)]
+pub struct IpFilter {
+    allow: Vec<IpNet>,
+    deny: Vec<IpNet>,
+    default_policy: IpPolicy,
+}
+
+/// Parses a CIDR block, accepting a bare IPv4 or IPv6 address as a single host network.
+///
+/// # Arguments
+///
+/// * `value` - The CIDR block, e.g. `10.0.0.0/8`, `2001:db8::/32` or `192.168.1.10`.
+///
+/// # Returns
+///
+/// The parsed network, or an error message naming the invalid value.
+fn parse_network(value: &str) -> Result<IpNet, String> {
+    let value = value.trim();
+    value
+        .parse::<IpNet>()
+        .or_else(|_| value.parse::<IpAddr>().map(IpNet::from))
+        .map_err(|_| format!("Invalid IP address or CIDR block '{}'", value))
+}
+
+impl IpFilter {
+    /// Creates a filter from the configured lists.
+    ///
+    /// # Arguments
+    ///
+    /// * `allow` - The CIDR blocks that are allowed to connect.
+    /// * `deny` - The CIDR blocks that are never allowed to connect.
+    /// * `default_policy` - The decision for addresses matching neither list.
+    ///
+    /// # Returns
+    ///
+    /// The `IpFilter`, or an error message if any of the blocks can't be parsed.
+    pub fn new(
+        allow: &[String],
+        deny: &[String],
+        default_policy: IpPolicy,
+    ) -> Result<IpFilter, String> {
+        Ok(IpFilter {
+            allow: allow
+                .iter()
+                .map(|value| parse_network(value))
+                .collect::<Result<Vec<IpNet>, String>>()?,
+            deny: deny
+                .iter()
+                .map(|value| parse_network(value))
+                .collect::<Result<Vec<IpNet>, String>>()?,
+            default_policy,
+        })
+    }
+
+    /// Checks whether an address may connect.
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - The source address of the client.
+    ///
+    /// # Returns
+    ///
+    /// `true` if the address is allowed, `false` otherwise.
+    pub fn is_allowed(&self, addr: IpAddr) -> bool {
+        // IPv4 clients on a dual stack listener show up as IPv4-mapped IPv6 addresses
+        let addr = match addr {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(addr, IpAddr::V4),
+            v4 => v4,
+        };
+        if self.deny.iter().any(|net| net.contains(&addr)) {
+            return false;
+        }
+        if self.allow.iter().any(|net| net.contains(&addr)) {
+            return true;
+        }
+        self.default_policy == IpPolicy::Allow
+    }
+}
+
+/// Decides whether a client may run the command it requested.
+///
+/// # Remarks
+///
+/// - Every pattern is a regular expression that has to match either the program name or the whole
+///   command line, e.g. `ls` or `git (pull|status)( .*)?`.
+/// - An empty list allows every command.
+/// - Requests without a command open an interactive shell, which is only governed by `allow_shell`.
+#[derive(Clone, Debug)]
+pub struct CommandFilter {
+    patterns: Vec<Regex>,
+    allow_shell: bool,
+}
+
+impl CommandFilter {
+    /// Creates a filter from the configured allowlist.
+    ///
+    /// # Arguments
+    ///
+    /// * `patterns` - The permitted command names or regular expressions.
+    /// * `allow_shell` - Whether requests without a command may open an interactive shell.
+    ///
+    /// # Returns
+    ///
+    /// The `CommandFilter`, or an error message if any of the patterns can't be compiled.
+    pub fn new(patterns: &[String], allow_shell: bool) -> Result<CommandFilter, String> {
+        Ok(CommandFilter {
+            patterns: patterns
+                .iter()
+                .map(|pattern| {
+                    Regex::new(&format!("^(?:{})$", pattern))
+                        .map_err(|e| format!("Invalid command pattern '{}': {}", pattern, e))
+                })
+                .collect::<Result<Vec<Regex>, String>>()?,
+            allow_shell,
+        })
+    }
+
+    /// Checks whether a command may run.
+    ///
+    /// # Arguments
+    ///
+    /// * `command` - The command requested by the client, empty for an interactive shell.
+    ///
+    /// # Returns
+    ///
+    /// `true` if the command is allowed, `false` otherwise.
+    pub fn is_allowed(&self, command: &[String]) -> bool {
+        let program = match command.first() {
+            Some(program) => program,
+            None => return self.allow_shell,
+        };
+        if self.patterns.is_empty() {
+            return true;
+        }
+        let line = command.join(" ");
+        self.patterns
+            .iter()
+            .any(|pattern| pattern.is_match(program) || pattern.is_match(&line))
+    }
+}
+
+/// The delay added to the first failed authentication of an address, doubled for every further failure.
+const AUTH_PENALTY_INITIAL: Duration = Duration::from_secs(1);
+const AUTH_PENALTY_MAX: Duration = Duration::from_secs(30);
+
+/// The failed authentications of a single source address.
+///
+/// # Fields
+///
+/// * `count`: The number of consecutive failures.
+/// * `banned_until`: When the ban of the address ends, if it is banned.
+#[derive(Clone, Copy, Debug, Default)]
+struct AuthFailure {
+    count: u32,
+    banned_until: Option<Instant>,
+}
+
+/// Tracks failed authentications per source address to slow down and ban clients hammering auth.
+///
+/// # Remarks
+///
+/// - Every failure delays the rejection by a penalty that doubles with each consecutive failure.
+/// - Once the threshold is reached the address is banned for the ban duration, a threshold of `0`
+///   disables bans.
+/// - A successful authentication resets the address.
+#[derive(Debug)]
+pub struct AuthFailures {
+    threshold: u32,
+    ban_duration: Duration,
+    failures: Mutex<HashMap<IpAddr, AuthFailure>>,
+}
+
+impl AuthFailures {
+    /// Creates an empty tracker.
+    ///
+    /// # Arguments
+    ///
+    /// * `threshold` - The number of consecutive failures after which an address is banned.
+    /// * `ban_duration` - How long a ban lasts.
+    pub fn new(threshold: u32, ban_duration: Duration) -> AuthFailures {
+        AuthFailures {
+            threshold,
+            ban_duration,
+            failures: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<IpAddr, AuthFailure>> {
+        match self.failures.lock() {
+            Ok(failures) => failures,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    /// Checks whether an address is currently banned.
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - The source address of the client.
+    ///
+    /// # Returns
+    ///
+    /// `true` while the ban of the address lasts. An expired ban resets the address.
+    pub fn is_banned(&self, addr: IpAddr) -> bool {
+        let mut failures = self.lock();
+        match failures.get(&addr).and_then(|failure| failure.banned_until) {
+            Some(until) if until > Instant::now() => true,
+            Some(_) => {
+                failures.remove(&addr);
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Records a failed authentication.
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - The source address of the client.
+    ///
+    /// # Returns
+    ///
+    /// The penalty to wait before rejecting the client.
+    pub fn record_failure(&self, addr: IpAddr) -> Duration {
+        let mut failures = self.lock();
+        let failure = failures.entry(addr).or_default();
+        failure.count = failure.count.saturating_add(1);
+        if self.threshold > 0 && failure.count >= self.threshold {
+            failure.banned_until = Some(Instant::now() + self.ban_duration);
+        }
+        auth_penalty(failure.count)
+    }
+
+    /// Forgets the failures of an address after it authenticated successfully.
+    pub fn record_success(&self, addr: IpAddr) {
+        self.lock().remove(&addr);
+    }
+}
+
+/// Computes the penalty of a failed authentication.
+///
+/// # Arguments
+///
+/// * `count` - The number of consecutive failures, starting at 1.
+///
+/// # Returns
+///
+/// The initial penalty doubled for every previous failure, capped at the maximum penalty.
+pub fn auth_penalty(count: u32) -> Duration {
+    let factor = 2u32.saturating_pow(count.saturating_sub(1));
+    AUTH_PENALTY_INITIAL
+        .saturating_mul(factor)
+        .min(AUTH_PENALTY_MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|value| value.to_string()).collect()
+    }
+
+    #[test]
+    fn deny_list_wins_over_allow_list() {
+        let filter = IpFilter::new(
+            &strings(&["10.0.0.0/8"]),
+            &strings(&["10.0.66.0/24"]),
+            IpPolicy::Deny,
+        )
+        .unwrap();
+        assert!(filter.is_allowed("10.1.2.3".parse().unwrap()));
+        assert!(!filter.is_allowed("10.0.66.1".parse().unwrap()));
+        assert!(!filter.is_allowed("192.168.1.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn matches_ipv4_mapped_addresses_and_bare_hosts() {
+        let filter = IpFilter::new(&strings(&["192.168.1.10"]), &[], IpPolicy::Deny).unwrap();
+        assert!(filter.is_allowed("::ffff:192.168.1.10".parse().unwrap()));
+        assert!(!filter.is_allowed("192.168.1.11".parse().unwrap()));
+    }
+
+    #[test]
+    fn rejects_invalid_networks() {
+        assert!(IpFilter::new(&strings(&["not-an-ip"]), &[], IpPolicy::Allow).is_err());
+    }
+
+    #[test]
+    fn filters_commands_by_program_or_line() {
+        let filter =
+            CommandFilter::new(&strings(&["ls", "git (pull|status)( .*)?"]), false).unwrap();
+        assert!(filter.is_allowed(&strings(&["ls", "-la"])));
+        assert!(filter.is_allowed(&strings(&["git", "status", "--short"])));
+        assert!(!filter.is_allowed(&strings(&["git", "push"])));
+        assert!(!filter.is_allowed(&strings(&["lsof"])));
+        assert!(!filter.is_allowed(&[]));
+    }
+
+    #[test]
+    fn empty_command_filter_allows_everything() {
+        let filter = CommandFilter::new(&[], true).unwrap();
+        assert!(filter.is_allowed(&strings(&["rm", "-rf", "/tmp/x"])));
+        assert!(filter.is_allowed(&[]));
+    }
+
+    #[test]
+    fn bans_after_threshold_and_resets_on_success() {
+        let failures = AuthFailures::new(2, Duration::from_secs(60));
+        let addr: IpAddr = "10.0.0.1".parse().unwrap();
+        assert_eq!(failures.record_failure(addr), Duration::from_secs(1));
+        assert!(!failures.is_banned(addr));
+        assert_eq!(failures.record_failure(addr), Duration::from_secs(2));
+        assert!(failures.is_banned(addr));
+        failures.record_success(addr);
+        assert!(!failures.is_banned(addr));
+    }
+
+    #[test]
+    fn zero_threshold_never_bans() {
+        let failures = AuthFailures::new(0, Duration::from_secs(60));
+        let addr: IpAddr = "10.0.0.1".parse().unwrap();
+        for _ in 0..10 {
+            failures.record_failure(addr);
+        }
+        assert!(!failures.is_banned(addr));
+    }
+
+    #[test]
+    fn penalty_doubles_up_to_the_cap() {
+        assert_eq!(auth_penalty(1), Duration::from_secs(1));
+        assert_eq!(auth_penalty(3), Duration::from_secs(4));
+        assert_eq!(auth_penalty(10), AUTH_PENALTY_MAX);
+        assert_eq!(auth_penalty(u32::MAX), AUTH_PENALTY_MAX);
+    }
+}
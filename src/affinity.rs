@@ -0,0 +1,84 @@
+use std::collections::HashMap;
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+/// Remembers which container served a user's target, so reconnecting clients land on the same
+/// replica.
+///
+/// # Fields
+///
+/// * `entries`: The container ID and Docker host index served last, and when, keyed by the login
+///   name and the target.
+#[derive(Default)]
+pub struct Affinity {
+    entries: Mutex<HashMap<(String, String), (Instant, String, usize)>>,
+}
+
+impl Affinity {
+    /// Returns the container that last served a user's target.
+    ///
+    /// # Arguments
+    ///
+    /// * `user` - The login name of the client.
+    /// * `target` - The requested target.
+    /// * `window` - How long a served container is remembered.
+    ///
+    /// # Returns
+    ///
+    /// The container ID and the index of its Docker host, or `None` if the target wasn't served to
+    /// the user within the window.
+    pub fn preferred(&self, user: &str, target: &str, window: Duration) -> Option<(String, usize)> {
+        let entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+        match entries.get(&(user.to_string(), target.to_string())) {
+            Some((served_at, container_id, host)) if served_at.elapsed() < window => {
+                Some((container_id.clone(), *host))
+            }
+            _ => None,
+        }
+    }
+
+    /// Records the container that served a user's target.
+    ///
+    /// # Arguments
+    ///
+    /// * `user` - The login name of the client.
+    /// * `target` - The requested target.
+    /// * `container_id` - The ID of the container.
+    /// * `host` - The index of the Docker host the container runs on.
+    /// * `window` - How long a served container is remembered, older entries are dropped.
+    pub fn remember(
+        &self,
+        user: &str,
+        target: &str,
+        container_id: &str,
+        host: usize,
+        window: Duration,
+    ) {
+        let mut entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+        entries.retain(|_, (served_at, _, _)| served_at.elapsed() < window);
+        entries.insert(
+            (user.to_string(), target.to_string()),
+            (Instant::now(), container_id.to_string(), host),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn remembers_containers_within_the_window() {
+        let affinity = Affinity::default();
+        let window = Duration::from_secs(60);
+        affinity.remember("alice", "app", "abc", 1, window);
+        assert_eq!(
+            affinity.preferred("alice", "app", window),
+            Some((String::from("abc"), 1))
+        );
+        // Keyed on both the user and the target
+        assert_eq!(affinity.preferred("bob", "app", window), None);
+        assert_eq!(affinity.preferred("alice", "db", window), None);
+        assert_eq!(affinity.preferred("alice", "app", Duration::ZERO), None);
+    }
+}
@@ -0,0 +1,198 @@
+use russh::{compression, Preferred};
+
+use crate::config::ServerConfig;
+
+/// Compression algorithms offered when compression is enabled, `none` first so clients that don't
+/// ask for compression, e.g. `ssh` without `-C`, don't get it.
+const COMPRESSION_ENABLED: &[compression::Name] = &[
+    compression::NONE,
+    compression::ZLIB,
+    compression::ZLIB_LEGACY,
+];
+/// Compression algorithms offered when compression is disabled.
+const COMPRESSION_DISABLED: &[compression::Name] = &[compression::NONE];
+/// Prefixes of the pseudo key exchanges announcing protocol extensions, like strict key exchange.
+/// They are kept when the key exchanges are restricted, since they aren't algorithms.
+const KEX_EXTENSION_PREFIXES: [&str; 2] = ["ext-info-", "kex-strict-"];
+
+/// Picks the configured algorithms of one kind out of the ones russh supports.
+///
+/// # Arguments
+///
+/// * `kind` - What the algorithms are, used in errors.
+/// * `configured` - The configured algorithm names, in order of preference.
+/// * `supported` - The algorithms russh supports by default.
+///
+/// # Returns
+///
+/// The configured algorithms, or `supported` if none are configured. An error message naming the
+/// supported algorithms if one of them is unknown.
+///
+/// # Remarks
+///
+/// The list lives for the whole lifetime of the server, as russh expects.
+fn select<N>(
+    kind: &str,
+    configured: &[String],
+    supported: &'static [N],
+) -> Result<&'static [N], String>
+where
+    N: AsRef<str> + Copy,
+{
+    if configured.is_empty() {
+        return Ok(supported);
+    }
+    let selected = configured
+        .iter()
+        .map(|name| {
+            supported
+                .iter()
+                .find(|algorithm| algorithm.as_ref() == name)
+                .copied()
+                .ok_or_else(|| {
+                    let names: Vec<&str> = supported.iter().map(AsRef::as_ref).collect();
+                    format!(
+                        "Unknown {} '{}', supported are {}",
+                        kind,
+                        name,
+                        names.join(", ")
+                    )
+                })
+        })
+        .collect::<Result<Vec<N>, String>>()?;
+    Ok(Box::leak(selected.into_boxed_slice()))
+}
+
+/// Builds the algorithm preferences of the SSH server.
+///
+/// # Arguments
+///
+/// * `config` - The server configuration.
+///
+/// # Returns
+///
+/// russh's defaults restricted to the configured ciphers, key exchanges and MACs, with `zlib` and
+/// `zlib@openssh.com` compression offered only if enabled. An error message if an algorithm isn't
+/// supported.
+pub fn preferred(config: &ServerConfig) -> Result<Preferred, String> {
+    let defaults = Preferred::default();
+    let kex_algorithms: Vec<String> = match config.kex_algorithms.is_empty() {
+        true => Vec::new(),
+        false => config
+            .kex_algorithms
+            .iter()
+            .cloned()
+            .chain(
+                defaults
+                    .kex
+                    .iter()
+                    .map(|kex| kex.as_ref())
+                    .filter(|kex| {
+                        KEX_EXTENSION_PREFIXES
+                            .iter()
+                            .any(|prefix| kex.starts_with(prefix))
+                    })
+                    .map(String::from),
+            )
+            .collect(),
+    };
+    Ok(Preferred {
+        kex: select("key exchange", &kex_algorithms, defaults.kex)?,
+        cipher: select("cipher", &config.ciphers, defaults.cipher)?,
+        mac: select("MAC", &config.macs, defaults.mac)?,
+        compression: match config.compression {
+            true => COMPRESSION_ENABLED,
+            false => COMPRESSION_DISABLED,
+        },
+        ..defaults
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn offers_compression_when_enabled() {
+        let mut config = ServerConfig::default();
+        let offered = preferred(&config).unwrap().compression;
+        assert!(offered.contains(&compression::ZLIB));
+        assert!(offered.contains(&compression::ZLIB_LEGACY));
+        // Available, but not forced on clients that don't ask for it
+        assert_eq!(offered[0], compression::NONE);
+
+        config.compression = false;
+        assert_eq!(
+            preferred(&config).unwrap().compression,
+            &[compression::NONE]
+        );
+    }
+
+    #[test]
+    fn applies_restricted_algorithms() {
+        let config = ServerConfig {
+            ciphers: vec![String::from("aes256-gcm@openssh.com")],
+            kex_algorithms: vec![String::from("curve25519-sha256")],
+            macs: vec![String::from("hmac-sha2-256")],
+            ..Default::default()
+        };
+        let server_config = russh::server::Config {
+            preferred: preferred(&config).unwrap(),
+            ..Default::default()
+        };
+        let names = |names: Vec<&str>| names.join(",");
+        assert_eq!(
+            names(
+                server_config
+                    .preferred
+                    .cipher
+                    .iter()
+                    .map(AsRef::as_ref)
+                    .collect()
+            ),
+            "aes256-gcm@openssh.com"
+        );
+        assert_eq!(
+            names(
+                server_config
+                    .preferred
+                    .mac
+                    .iter()
+                    .map(AsRef::as_ref)
+                    .collect()
+            ),
+            "hmac-sha2-256"
+        );
+        let kex: Vec<&str> = server_config
+            .preferred
+            .kex
+            .iter()
+            .map(AsRef::as_ref)
+            .collect();
+        assert_eq!(kex[0], "curve25519-sha256");
+        // Strict key exchange is still announced
+        assert!(kex.iter().all(|kex| *kex == "curve25519-sha256"
+            || KEX_EXTENSION_PREFIXES
+                .iter()
+                .any(|prefix| kex.starts_with(prefix))));
+    }
+
+    #[test]
+    fn keeps_the_defaults_unless_configured() {
+        let defaults = Preferred::default();
+        let preferred = preferred(&ServerConfig::default()).unwrap();
+        assert_eq!(preferred.cipher, defaults.cipher);
+        assert_eq!(preferred.kex, defaults.kex);
+        assert_eq!(preferred.mac, defaults.mac);
+    }
+
+    #[test]
+    fn rejects_unknown_algorithms() {
+        let config = ServerConfig {
+            ciphers: vec![String::from("3des-cbc")],
+            ..Default::default()
+        };
+        let error = preferred(&config).unwrap_err();
+        assert!(error.starts_with("Unknown cipher '3des-cbc', supported are "));
+    }
+}
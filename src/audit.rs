@@ -0,0 +1,107 @@
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+
+use log::error;
+use serde::Serialize;
+
+const AUDIT_STDOUT: &str = "stdout";
+const AUDIT_STDERR: &str = "stderr";
+
+/// Represents an event recorded in the audit log.
+///
+/// # Remarks
+///
+/// Every event is serialized as a single JSON line, with the variant name stored in the
+/// `event` field next to the fields shared by all events.
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum AuditEvent<'a> {
+    SessionOpened,
+    Exec {
+        container_id: &'a str,
+        container_name: Option<&'a str>,
+        command: &'a str,
+    },
+    SessionClosed {
+        container_id: Option<&'a str>,
+        duration_secs: f64,
+        exit_code: Option<i64>,
+        bytes_received: u64,
+        bytes_sent: u64,
+    },
+}
+
+/// A single line of the audit log.
+#[derive(Serialize)]
+struct AuditRecord<'a> {
+    timestamp: String,
+    client_ip: Option<String>,
+    username: Option<&'a str>,
+    #[serde(flatten)]
+    event: AuditEvent<'a>,
+}
+
+/// Writes structured JSON audit records to the configured destination.
+///
+/// # Remarks
+///
+/// - The destination is either `stdout`, `stderr` or the path of a file that records are
+///   appended to.
+/// - Failing to write a record is logged but never interrupts the session being audited.
+pub struct AuditLog {
+    writer: Mutex<Box<dyn Write + Send>>,
+}
+
+impl AuditLog {
+    /// Opens the audit log destination.
+    ///
+    /// # Arguments
+    ///
+    /// * `destination` - `stdout`, `stderr` or the path of the file to append records to.
+    ///
+    /// # Returns
+    ///
+    /// Returns the `AuditLog`, or the error encountered while opening the destination file.
+    pub fn open(destination: &str) -> Result<AuditLog, std::io::Error> {
+        let writer: Box<dyn Write + Send> = match destination {
+            AUDIT_STDOUT => Box::new(std::io::stdout()),
+            AUDIT_STDERR => Box::new(std::io::stderr()),
+            path => Box::new(OpenOptions::new().create(true).append(true).open(path)?),
+        };
+        Ok(AuditLog {
+            writer: Mutex::new(writer),
+        })
+    }
+
+    /// Records an event for a client connection.
+    ///
+    /// # Arguments
+    ///
+    /// * `peer_addr` - The source address of the client, if known.
+    /// * `username` - The SSH user name the client authenticated with, if known.
+    /// * `event` - The event to record.
+    pub fn record(&self, peer_addr: Option<SocketAddr>, username: Option<&str>, event: AuditEvent) {
+        let record = AuditRecord {
+            timestamp: chrono::Utc::now().to_rfc3339(),
+            client_ip: peer_addr.map(|addr| addr.ip().to_string()),
+            username,
+            event,
+        };
+        let line = match serde_json::to_string(&record) {
+            Ok(line) => line,
+            Err(e) => {
+                error!("Failed to serialize audit record: {}", e);
+                return;
+            }
+        };
+        let mut writer = match self.writer.lock() {
+            Ok(writer) => writer,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        if let Err(e) = writeln!(writer, "{}", line).and_then(|_| writer.flush()) {
+            error!("Failed to write audit record: {}", e);
+        }
+    }
+}
@@ -0,0 +1,399 @@
+use std::process::Stdio;
+
+use log::{error, warn};
+use russh::MethodSet;
+use russh_keys::key::PublicKey;
+use serde::Deserialize;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+use crate::cli::is_valid_name;
+use crate::config::ServerConfig;
+
+/// Environment variable holding the login name, passed to the keyboard-interactive command.
+const KEYBOARD_INTERACTIVE_USER_ENV: &str = "TUNNYD_USER";
+/// Placeholder of the `authorized_keys_file` replaced by the user logging in, like `sshd`'s `%u`.
+pub const AUTHORIZED_KEYS_USER_TOKEN: &str = "%u";
+
+/// An authentication method the server can offer.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum AuthMethod {
+    None,
+    Publickey,
+    KeyboardInteractive,
+}
+
+/// How the `none` and `publickey` methods decide whether a client is who it claims to be.
+///
+/// # Remarks
+///
+/// Every mode still applies the IP allow/deny lists, the login bans and the container access
+/// checks. Keyboard-interactive authentication is always verified.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AuthPolicy {
+    /// Public keys have to be listed in the `authorized_keys_file` and `none` is refused.
+    Enforce,
+    /// Any public key and `none` are accepted. Only meant for local development, it is enabled with
+    /// `serve --insecure-allow-all`.
+    InsecureAllowAll,
+}
+
+impl AuthPolicy {
+    /// Picks the policy for the `--insecure-allow-all` flag.
+    pub fn from_flag(insecure_allow_all: bool) -> AuthPolicy {
+        if insecure_allow_all {
+            AuthPolicy::InsecureAllowAll
+        } else {
+            AuthPolicy::Enforce
+        }
+    }
+
+    /// Checks whether a client may authenticate without credentials.
+    pub fn accepts_none(self) -> bool {
+        self == AuthPolicy::InsecureAllowAll
+    }
+
+    /// Checks whether a client may authenticate with a public key.
+    ///
+    /// # Arguments
+    ///
+    /// * `authorized` - The keys of the `authorized_keys_file`.
+    /// * `key` - The key the client offered.
+    pub fn accepts_key(self, authorized: &[PublicKey], key: &PublicKey) -> bool {
+        self == AuthPolicy::InsecureAllowAll || authorized.contains(key)
+    }
+}
+
+/// Parses keys in the format of OpenSSH's `authorized_keys`.
+///
+/// # Arguments
+///
+/// * `contents` - One key per line, as `type base64 [comment]`. Empty lines and lines starting
+///   with `#` are skipped.
+///
+/// # Returns
+///
+/// The keys that could be parsed, invalid lines are logged and skipped.
+///
+/// # Remarks
+///
+/// Key options like `from=`, `command=` or `no-pty` aren't supported. Lines carrying options are
+/// skipped rather than accepting a restricted key with full access.
+pub fn parse_authorized_keys(contents: &str) -> Vec<PublicKey> {
+    let mut keys = Vec::new();
+    for (index, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let key = match fields.as_slice() {
+            [key_type, key, ..] if is_key_type(key_type) => {
+                russh_keys::parse_public_key_base64(key).ok()
+            }
+            _ => None,
+        };
+        match key {
+            Some(key) => keys.push(key),
+            // Options come before the key type
+            None if fields.iter().skip(1).any(|field| is_key_type(field)) => warn!(
+                "Skipping authorized key on line {}: key options aren't supported",
+                index + 1
+            ),
+            None => warn!("Skipping invalid authorized key on line {}", index + 1),
+        }
+    }
+    keys
+}
+
+/// Checks whether a field of an `authorized_keys` line names a key type, e.g. `ssh-ed25519`.
+fn is_key_type(field: &str) -> bool {
+    ["ssh-", "ecdsa-sha2-", "sk-", "rsa-sha2-"]
+        .iter()
+        .any(|prefix| field.starts_with(prefix))
+}
+
+/// Returns the `authorized_keys` file holding the keys of a user.
+///
+/// # Arguments
+///
+/// * `template` - The configured `authorized_keys_file`.
+/// * `user` - The user logging in.
+///
+/// # Returns
+///
+/// The path with every `%u` replaced by the user, or `None` if the user isn't a plain name and
+/// could point the path elsewhere. A path without `%u` is shared by every user.
+pub fn authorized_keys_path(template: &str, user: &str) -> Option<String> {
+    if !template.contains(AUTHORIZED_KEYS_USER_TOKEN) {
+        return Some(template.to_string());
+    }
+    is_valid_name(user).then(|| template.replace(AUTHORIZED_KEYS_USER_TOKEN, user))
+}
+
+/// Reads the keys allowed to authenticate as a user.
+///
+/// # Arguments
+///
+/// * `config` - The server configuration.
+/// * `user` - The user logging in, the part of the login name before any `@`.
+///
+/// # Returns
+///
+/// The keys of the user's `authorized_keys_file`, none if it isn't configured or can't be read.
+///
+/// # Remarks
+///
+/// The file is read on every attempt like `sshd` does, so keys can be added and revoked without
+/// reloading the configuration.
+pub async fn load_authorized_keys(config: &ServerConfig, user: &str) -> Vec<PublicKey> {
+    let Some(template) = &config.authorized_keys_file else {
+        return Vec::new();
+    };
+    let Some(path) = authorized_keys_path(template, user) else {
+        warn!("Refusing keys for login {}: not a plain user name", user);
+        return Vec::new();
+    };
+    match tokio::fs::read_to_string(&path).await {
+        Ok(contents) => parse_authorized_keys(&contents),
+        Err(e) => {
+            error!("Failed to read authorized keys from {}: {}", path, e);
+            Vec::new()
+        }
+    }
+}
+
+/// Builds the set of methods announced to clients.
+///
+/// # Arguments
+///
+/// * `methods` - The configured authentication methods.
+///
+/// # Returns
+///
+/// The `MethodSet` containing every configured method.
+pub fn method_set(methods: &[AuthMethod]) -> MethodSet {
+    methods
+        .iter()
+        .fold(MethodSet::empty(), |set, method| match method {
+            AuthMethod::None => set | MethodSet::NONE,
+            AuthMethod::Publickey => set | MethodSet::PUBLICKEY,
+            AuthMethod::KeyboardInteractive => set | MethodSet::KEYBOARD_INTERACTIVE,
+        })
+}
+
+/// Checks whether keyboard-interactive authentication has a way to verify responses.
+///
+/// # Arguments
+///
+/// * `config` - The server configuration.
+///
+/// # Returns
+///
+/// `true` if a verification command or at least one token is configured.
+pub fn keyboard_interactive_enabled(config: &ServerConfig) -> bool {
+    config.keyboard_interactive_command.is_some() || !config.keyboard_interactive_tokens.is_empty()
+}
+
+/// Verifies the responses a client gave to the keyboard-interactive prompts.
+///
+/// # Arguments
+///
+/// * `config` - The server configuration.
+/// * `user` - The login name of the client.
+/// * `responses` - The responses, one per configured prompt.
+///
+/// # Returns
+///
+/// `true` if the responses are accepted.
+///
+/// # Remarks
+///
+/// - With a `keyboard_interactive_command`, the command is run with the login name in
+///   `TUNNYD_USER` and the responses on stdin, one per line. Exiting with status `0` accepts.
+/// - Otherwise the first response has to equal the token configured for the user in
+///   `keyboard_interactive_tokens`.
+pub async fn verify_keyboard_interactive(
+    config: &ServerConfig,
+    user: &str,
+    responses: &[String],
+) -> bool {
+    match &config.keyboard_interactive_command {
+        Some(command) => run_verification_command(command, user, responses).await,
+        None => match (
+            config.keyboard_interactive_tokens.get(user),
+            responses.first(),
+        ) {
+            (Some(token), Some(response)) => {
+                constant_time_eq(token.as_bytes(), response.as_bytes())
+            }
+            _ => false,
+        },
+    }
+}
+
+/// Compares two byte strings in a time that only depends on their lengths, so the time a
+/// rejection takes doesn't tell how much of a token was guessed right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Runs the external verification command.
+async fn run_verification_command(command: &[String], user: &str, responses: &[String]) -> bool {
+    let (program, args) = match command.split_first() {
+        Some(split) => split,
+        None => {
+            error!("keyboard_interactive_command is empty");
+            return false;
+        }
+    };
+    let mut child = match Command::new(program)
+        .args(args)
+        .env(KEYBOARD_INTERACTIVE_USER_ENV, user)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .kill_on_drop(true)
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(e) => {
+            error!(
+                "Failed to run keyboard-interactive command {}: {}",
+                program, e
+            );
+            return false;
+        }
+    };
+    if let Some(mut stdin) = child.stdin.take() {
+        let input = responses.iter().fold(String::new(), |mut input, response| {
+            input.push_str(response);
+            input.push('\n');
+            input
+        });
+        if let Err(e) = stdin.write_all(input.as_bytes()).await {
+            warn!("Failed to pass responses to {}: {}", program, e);
+        }
+    }
+    match child.wait().await {
+        Ok(status) => status.success(),
+        Err(e) => {
+            error!("Keyboard-interactive command {} failed: {}", program, e);
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builds_method_sets() {
+        assert_eq!(method_set(&[AuthMethod::Publickey]), MethodSet::PUBLICKEY);
+        assert_eq!(
+            method_set(&[AuthMethod::None, AuthMethod::KeyboardInteractive]),
+            MethodSet::NONE | MethodSet::KEYBOARD_INTERACTIVE
+        );
+        assert!(method_set(&[]).is_empty());
+    }
+
+    #[test]
+    fn keyboard_interactive_needs_a_verifier() {
+        let mut config = ServerConfig::default();
+        assert!(!keyboard_interactive_enabled(&config));
+        config
+            .keyboard_interactive_tokens
+            .insert(String::from("alice"), String::from("s3cret"));
+        assert!(keyboard_interactive_enabled(&config));
+    }
+
+    #[tokio::test]
+    async fn rejects_wrong_tokens() {
+        let mut config = ServerConfig::default();
+        config
+            .keyboard_interactive_tokens
+            .insert(String::from("alice"), String::from("s3cret"));
+        let answer = |response: &str| vec![response.to_string()];
+        assert!(verify_keyboard_interactive(&config, "alice", &answer("s3cret")).await);
+        assert!(!verify_keyboard_interactive(&config, "alice", &answer("s3cres")).await);
+        assert!(!verify_keyboard_interactive(&config, "alice", &answer("s3cret ")).await);
+        assert!(!verify_keyboard_interactive(&config, "alice", &answer("")).await);
+        assert!(!verify_keyboard_interactive(&config, "bob", &answer("s3cret")).await);
+        assert!(!verify_keyboard_interactive(&config, "alice", &[]).await);
+    }
+
+    #[test]
+    fn enforces_authorized_keys() {
+        use russh_keys::key::KeyPair;
+        use russh_keys::PublicKeyBase64;
+
+        let public_key = |pair: KeyPair| pair.clone_public_key().unwrap();
+        let authorized = public_key(KeyPair::generate_ed25519().unwrap());
+        let stranger = public_key(KeyPair::generate_ed25519().unwrap());
+        let contents = format!(
+            "# deploy keys\n\nssh-ed25519 {} alice@laptop\nssh-ed25519 garbage\n",
+            authorized.public_key_base64()
+        );
+        let keys = parse_authorized_keys(&contents);
+        assert_eq!(keys.len(), 1);
+        assert!(keys[0] == authorized);
+        // A restricted key would get full access, so it isn't accepted at all
+        let restricted = format!(
+            "no-pty,from=\"10.0.0.0/8\" ssh-ed25519 {} alice@laptop\n",
+            authorized.public_key_base64()
+        );
+        assert!(parse_authorized_keys(&restricted).is_empty());
+
+        assert!(AuthPolicy::Enforce.accepts_key(&keys, &authorized));
+        assert!(!AuthPolicy::Enforce.accepts_key(&keys, &stranger));
+        assert!(!AuthPolicy::Enforce.accepts_key(&[], &authorized));
+        assert!(!AuthPolicy::Enforce.accepts_none());
+        assert_eq!(AuthPolicy::from_flag(false), AuthPolicy::Enforce);
+    }
+
+    #[test]
+    fn insecure_mode_accepts_everyone() {
+        let key = russh_keys::key::KeyPair::generate_ed25519()
+            .unwrap()
+            .clone_public_key()
+            .unwrap();
+        let policy = AuthPolicy::from_flag(true);
+        assert_eq!(policy, AuthPolicy::InsecureAllowAll);
+        assert!(policy.accepts_key(&[], &key));
+        assert!(policy.accepts_none());
+    }
+
+    #[tokio::test]
+    async fn reads_the_keys_of_the_user_logging_in() {
+        use russh_keys::PublicKeyBase64;
+
+        assert_eq!(
+            authorized_keys_path("/etc/tunnyd/keys/%u", "alice").as_deref(),
+            Some("/etc/tunnyd/keys/alice")
+        );
+        assert_eq!(authorized_keys_path("/etc/tunnyd/keys/%u", "../root"), None);
+        assert_eq!(authorized_keys_path("/etc/tunnyd/keys/%u", ""), None);
+        assert_eq!(
+            authorized_keys_path("/etc/tunnyd/authorized_keys", "alice").as_deref(),
+            Some("/etc/tunnyd/authorized_keys")
+        );
+
+        let dir = tempfile::tempdir().unwrap();
+        let key = russh_keys::key::KeyPair::generate_ed25519()
+            .unwrap()
+            .clone_public_key()
+            .unwrap();
+        std::fs::write(
+            dir.path().join("alice"),
+            format!("ssh-ed25519 {}\n", key.public_key_base64()),
+        )
+        .unwrap();
+        let config = ServerConfig {
+            authorized_keys_file: Some(format!("{}/%u", dir.path().display())),
+            ..Default::default()
+        };
+        assert!(load_authorized_keys(&config, "alice").await == vec![key]);
+        assert!(load_authorized_keys(&config, "bob").await.is_empty());
+    }
+}
@@ -1,6 +1,304 @@
-use clap::{Arg, Command};
+use clap::{value_parser, Arg, ArgAction, ArgMatches, Command};
 use shlex::Shlex;
 
+use crate::error::TunnydError;
+
+const DEFAULT_BIND: &str = "0.0.0.0";
+const DEFAULT_PORT: &str = "2222";
+
+/// Builds the command line of the daemon itself.
+///
+/// # Remarks
+///
+/// This is unrelated to the parser of the SSH exec requests built by `cli()`, which parses the
+/// commands clients send and not the arguments tunnyd is started with.
+fn daemon_cli() -> Command {
+    Command::new("tunnyd")
+        .about("SSH gateway into Docker containers")
+        .version(env!("CARGO_PKG_VERSION"))
+        .arg(
+            Arg::new("verbose")
+                .short('v')
+                .long("verbose")
+                .action(ArgAction::Count)
+                .global(true)
+                .help("Log more, can be repeated"),
+        )
+        .arg(
+            Arg::new("quiet")
+                .short('q')
+                .long("quiet")
+                .action(ArgAction::Count)
+                .global(true)
+                .help("Log less, can be repeated"),
+        )
+        .subcommand(
+            Command::new("serve")
+                .about("Run the SSH server (the default when no subcommand is given)")
+                .arg(
+                    Arg::new("bind")
+                        .short('b')
+                        .long("bind")
+                        .value_name("ADDRESS")
+                        .default_value(DEFAULT_BIND)
+                        .help("The address the SSH server listens on"),
+                )
+                .arg(
+                    Arg::new("port")
+                        .short('p')
+                        .long("port")
+                        .value_name("PORT")
+                        .default_value(DEFAULT_PORT)
+                        .value_parser(value_parser!(u16))
+                        .help("The port the SSH server listens on"),
+                )
+                .arg(
+                    Arg::new("listen")
+                        .short('l')
+                        .long("listen")
+                        .value_name("ENDPOINT")
+                        .action(ArgAction::Append)
+                        .conflicts_with_all(["bind", "port"])
+                        .help("An endpoint to listen on, host:port or unix:PATH. Can be repeated"),
+                )
+                .arg(
+                    Arg::new("unix_socket")
+                        .long("unix-socket")
+                        .value_name("PATH")
+                        .conflicts_with_all(["bind", "port"])
+                        .help("Listen on a Unix socket instead of TCP"),
+                )
+                .arg(
+                    Arg::new("docker_context")
+                        .long("docker-context")
+                        .value_name("NAME")
+                        .help("The Docker CLI context to connect to, overrides docker_context"),
+                )
+                .arg(
+                    Arg::new("config")
+                        .short('c')
+                        .long("config")
+                        .value_name("PATH")
+                        .help("The TOML configuration file, overrides TUNNYD_CONFIG"),
+                )
+                .arg(
+                    Arg::new("test_config")
+                        .short('t')
+                        .long("test-config")
+                        .action(ArgAction::SetTrue)
+                        .help("Check the configuration and exit without starting the server"),
+                )
+                .arg(
+                    Arg::new("check_docker")
+                        .long("check-docker")
+                        .action(ArgAction::SetTrue)
+                        .requires("test_config")
+                        .help("Also check that Docker is reachable with --test-config"),
+                )
+                .arg(
+                    Arg::new("insecure_allow_all")
+                        .long("insecure-allow-all")
+                        .action(ArgAction::SetTrue)
+                        .help(
+                            "Accept any public key and the none method, for local development only",
+                        ),
+                ),
+        )
+        .subcommand(
+            Command::new("list-targets")
+                .about("List the containers exposing a tunnyd hostname")
+                .arg(
+                    Arg::new("json")
+                        .long("json")
+                        .action(ArgAction::SetTrue)
+                        .help("Print the targets as JSON"),
+                )
+                .arg(
+                    Arg::new("docker_context")
+                        .long("docker-context")
+                        .value_name("NAME")
+                        .help("The Docker CLI context to connect to, overrides docker_context"),
+                )
+                .arg(
+                    Arg::new("config")
+                        .short('c')
+                        .long("config")
+                        .value_name("PATH")
+                        .help("The TOML configuration file, overrides TUNNYD_CONFIG"),
+                ),
+        )
+        .subcommand(
+            Command::new("resolve")
+                .about("Show which container a target resolves to, and why the others don't match")
+                .arg(
+                    Arg::new("target")
+                        .short('t')
+                        .long("target")
+                        .required(true)
+                        .value_name("TARGET")
+                        .help("The target to resolve, as matched against tunnyD.hostname"),
+                )
+                .arg(
+                    Arg::new("user")
+                        .short('u')
+                        .long("user")
+                        .value_name("USER")
+                        .help("The user to check access for"),
+                )
+                .arg(
+                    Arg::new("docker_context")
+                        .long("docker-context")
+                        .value_name("NAME")
+                        .help("The Docker CLI context to connect to, overrides docker_context"),
+                )
+                .arg(
+                    Arg::new("config")
+                        .short('c')
+                        .long("config")
+                        .value_name("PATH")
+                        .help("The TOML configuration file, overrides TUNNYD_CONFIG"),
+                ),
+        )
+}
+
+/// Represents the arguments of the `serve` subcommand.
+///
+/// # Fields
+///
+/// * `bind`: The address the SSH server listens on.
+/// * `port`: The port the SSH server listens on.
+/// * `listen`: The endpoints given with `--listen`, replacing `bind` and `port`.
+/// * `unix_socket`: The Unix socket the SSH server listens on instead of TCP, if any.
+/// * `docker_context`: The Docker context given on the command line, if any.
+/// * `config`: The configuration file given on the command line, if any.
+/// * `test_config`: Whether the configuration is only checked instead of starting the server.
+/// * `check_docker`: Whether checking the configuration also connects to Docker.
+/// * `insecure_allow_all`: Whether `publickey` and `none` accept every client instead of enforcing
+///   the `authorized_keys_file`, for local development only.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ServeArgs {
+    pub bind: String,
+    pub port: u16,
+    pub listen: Vec<String>,
+    pub unix_socket: Option<String>,
+    pub docker_context: Option<String>,
+    pub config: Option<String>,
+    pub test_config: bool,
+    pub check_docker: bool,
+    pub insecure_allow_all: bool,
+}
+
+/// Represents the arguments of the `resolve` subcommand.
+///
+/// # Fields
+///
+/// * `target`: The target to resolve.
+/// * `user`: The user to check access for, if any.
+/// * `docker_context`: The Docker context given on the command line, if any.
+/// * `config`: The configuration file given on the command line, if any.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ResolveArgs {
+    pub target: String,
+    pub user: Option<String>,
+    pub docker_context: Option<String>,
+    pub config: Option<String>,
+}
+
+/// Represents the arguments of the `list-targets` subcommand.
+///
+/// # Fields
+///
+/// * `json`: Whether the targets are printed as JSON instead of a table.
+/// * `docker_context`: The Docker context given on the command line, if any.
+/// * `config`: The configuration file given on the command line, if any.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ListTargetsArgs {
+    pub json: bool,
+    pub docker_context: Option<String>,
+    pub config: Option<String>,
+}
+
+/// Represents the action the daemon was started for.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DaemonCommand {
+    Serve(ServeArgs),
+    Resolve(ResolveArgs),
+    ListTargets(ListTargetsArgs),
+}
+
+/// Represents the command line the daemon was started with.
+///
+/// # Fields
+///
+/// * `verbosity`: How many levels the log level is raised (`-v`) or lowered (`-q`).
+/// * `command`: The action to run.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DaemonArgs {
+    pub verbosity: i8,
+    pub command: DaemonCommand,
+}
+
+/// Reads the arguments of the `serve` subcommand.
+fn serve_args(matches: &ArgMatches) -> ServeArgs {
+    ServeArgs {
+        bind: matches
+            .get_one::<String>("bind")
+            .expect("has a default")
+            .clone(),
+        port: *matches.get_one::<u16>("port").expect("has a default"),
+        listen: matches
+            .get_many::<String>("listen")
+            .map(|values| values.cloned().collect())
+            .unwrap_or_default(),
+        unix_socket: matches.get_one::<String>("unix_socket").cloned(),
+        docker_context: matches.get_one::<String>("docker_context").cloned(),
+        config: matches.get_one::<String>("config").cloned(),
+        test_config: matches.get_flag("test_config"),
+        check_docker: matches.get_flag("check_docker"),
+        insecure_allow_all: matches.get_flag("insecure_allow_all"),
+    }
+}
+
+/// Parses the command line the daemon was started with.
+///
+/// # Arguments
+///
+/// * `args` - The command line, including the binary name.
+///
+/// # Returns
+///
+/// The `DaemonArgs` to run, or the clap error for invalid arguments, `--help` and `--version`.
+/// Without a subcommand the daemon serves with the default settings.
+pub fn parse_daemon_args<I, T>(args: I) -> Result<DaemonArgs, clap::Error>
+where
+    I: IntoIterator<Item = T>,
+    T: Into<std::ffi::OsString> + Clone,
+{
+    let matches = daemon_cli().try_get_matches_from(args)?;
+    // Global flags are propagated to the subcommand, which sees them wherever they were given
+    let flags = matches.subcommand().map_or(&matches, |(_, sub)| sub);
+    let verbosity = flags.get_count("verbose") as i8 - flags.get_count("quiet") as i8;
+    let command = match matches.subcommand() {
+        Some(("serve", serve)) => DaemonCommand::Serve(serve_args(serve)),
+        Some(("list-targets", list)) => DaemonCommand::ListTargets(ListTargetsArgs {
+            json: list.get_flag("json"),
+            docker_context: list.get_one::<String>("docker_context").cloned(),
+            config: list.get_one::<String>("config").cloned(),
+        }),
+        Some(("resolve", resolve)) => DaemonCommand::Resolve(ResolveArgs {
+            target: resolve
+                .get_one::<String>("target")
+                .expect("required")
+                .clone(),
+            user: resolve.get_one::<String>("user").cloned(),
+            docker_context: resolve.get_one::<String>("docker_context").cloned(),
+            config: resolve.get_one::<String>("config").cloned(),
+        }),
+        _ => parse_daemon_args(["tunnyd", "serve"])?.command,
+    };
+    Ok(DaemonArgs { verbosity, command })
+}
+
 fn cli() -> Command {
     Command::new("tunnyD")
         .about("Tunnel into a Docker Container")
@@ -18,20 +316,73 @@ fn cli() -> Command {
                 .long("user")
                 .required(false)
                 .value_name("USER")
-                .help("The user to use to login to the docker container"),
+                .help("The user to use to login to the docker container, optionally as user:group"),
+        )
+        .arg(
+            Arg::new("by_id")
+                .long("by-id")
+                .action(ArgAction::SetTrue)
+                .conflicts_with("by_name")
+                .help("Treat the target as a container id instead of a hostname label"),
+        )
+        .arg(
+            Arg::new("by_name")
+                .long("by-name")
+                .action(ArgAction::SetTrue)
+                .help("Treat the target as a container name instead of a hostname label"),
+        )
+        .arg(
+            Arg::new("share")
+                .long("share")
+                .value_name("NAME")
+                .help("Attach to the shell shared under this name, starting it if needed"),
+        )
+        .arg(
+            Arg::new("attach")
+                .long("attach")
+                .action(ArgAction::SetTrue)
+                .help("Attach to the main process of the container instead of starting a shell"),
+        )
+        .arg(
+            Arg::new("command")
+                .required(false)
+                .num_args(0..)
+                .trailing_var_arg(true)
+                .value_name("COMMAND")
+                .help("The command to run instead of an interactive shell"),
         )
 }
 
+/// Describes how the target of a request is matched against the containers.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TargetSelector {
+    /// The target is matched against the hostname label.
+    #[default]
+    Hostname,
+    /// The target is the id of the container, or a prefix of at least 12 characters.
+    Id,
+    /// The target is the name of the container.
+    Name,
+}
+
 /// Represents the arguments for creating a container.
 ///
 /// # Fields
 ///
 /// * `user`: An optional string representing the user for the container.
 /// * `target`: A string representing the target for the container.
+/// * `selector`: How the target is matched against the containers.
+/// * `command`: The command to run, an empty vector requests an interactive shell.
+/// * `share`: The name of the shared session to attach to, if any.
+/// * `attach`: Whether to attach to the main process of the container instead of starting an exec.
 #[derive(Clone)]
 pub struct ContainerArgs {
     pub user: Option<String>,
     pub target: String,
+    pub selector: TargetSelector,
+    pub command: Vec<String>,
+    pub share: Option<String>,
+    pub attach: bool,
 }
 
 /// Parses the given data and matches the arguments.
@@ -39,36 +390,360 @@ pub struct ContainerArgs {
 /// # Arguments
 ///
 /// * `data` - A byte slice containing the data to be parsed and matched.
+/// * `max_bytes` - The longest payload that is parsed, `0` means unlimited.
+/// * `max_words` - The most words the payload may split into, `0` means unlimited.
 ///
 /// # Returns
 ///
-/// The matched arguments wrapped in a `ContainerArgs` object.
+/// The matched arguments wrapped in a `ContainerArgs` object, or a `TunnydError::InvalidEncoding`
+/// if the data isn't valid UTF-8. Docker only takes UTF-8 arguments, so rather than replacing the
+/// invalid bytes and running a different command than the one sent, the request is refused.
+/// Payloads over `max_bytes` are refused with `TunnydError::CommandTooLong` before anything is
+/// parsed, and splitting stops once it exceeds `max_words` with `TunnydError::TooManyWords`.
+/// Arguments that don't match, and `--help`, are returned as `TunnydError::InvalidArguments` with
+/// the message clap would have printed.
 ///
 /// # Example
 ///
-/// ```
-/// use my_crate::parse_and_match_args;
+/// ```ignore
+/// use crate::cli::parse_and_match_args;
 ///
-/// let data = b"--user john --target server";
-/// let args = parse_and_match_args(data);
+/// let data = b"tunnyd --target server --user john";
+/// let args = parse_and_match_args(data, 65536, 4096)?;
+/// assert_eq!(args.target, "server");
 /// ```
-///
-/// # Panics
-///
-/// This function panics if the required argument "target" is not found.
-pub fn parse_and_match_args(data: &[u8]) -> ContainerArgs {
-    let data_str = String::from_utf8_lossy(data).into_owned();
-    let input = Shlex::new(&data_str);
-    let matches = cli().get_matches_from(input);
-    // Get the value of user and target
-    let (user, target) = (
+pub fn parse_and_match_args(
+    data: &[u8],
+    max_bytes: usize,
+    max_words: usize,
+) -> Result<ContainerArgs, TunnydError> {
+    if max_bytes != 0 && data.len() > max_bytes {
+        return Err(TunnydError::CommandTooLong {
+            length: data.len(),
+            limit: max_bytes,
+        });
+    }
+    let data_str = std::str::from_utf8(data).map_err(|e| TunnydError::InvalidEncoding {
+        offset: e.valid_up_to(),
+    })?;
+    let input: Vec<String> = match max_words {
+        0 => Shlex::new(data_str).collect(),
+        limit => Shlex::new(data_str).take(limit + 1).collect(),
+    };
+    if max_words != 0 && input.len() > max_words {
+        return Err(TunnydError::TooManyWords { limit: max_words });
+    }
+    // clap exits the process on errors and --help unless asked to return them, a client must not
+    // be able to stop the daemon
+    let matches = cli()
+        .try_get_matches_from(input)
+        .map_err(TunnydError::InvalidArguments)?;
+    // Get the value of user, target and command
+    let (user, target, command) = (
         matches.get_one::<String>("user").map(|s| s.clone()),
         matches
             .get_one::<String>("target")
             .expect("required")
             .clone(),
+        matches
+            .get_many::<String>("command")
+            .map(|values| values.cloned().collect())
+            .unwrap_or_default(),
     );
 
+    let selector = match (matches.get_flag("by_id"), matches.get_flag("by_name")) {
+        (true, _) => TargetSelector::Id,
+        (_, true) => TargetSelector::Name,
+        _ => TargetSelector::Hostname,
+    };
+
     // Return as Args object
-    ContainerArgs { user, target }
+    Ok(ContainerArgs {
+        user,
+        target,
+        selector,
+        command,
+        share: matches.get_one::<String>("share").cloned(),
+        attach: matches.get_flag("attach"),
+    })
+}
+
+/// Builds the container arguments from the SSH login name.
+///
+/// The login name is either just the target (`ssh my-name.my-docker@gateway`) or the container
+/// user and the target separated by an `@` (`ssh root@my-name.my-docker@gateway`).
+///
+/// # Arguments
+///
+/// * `login` - The user name the client authenticated with.
+///
+/// # Returns
+///
+/// The resulting arguments wrapped in a `ContainerArgs` object.
+pub fn args_from_login(login: &str) -> ContainerArgs {
+    match login.split_once('@') {
+        Some((user, target)) if !user.is_empty() => ContainerArgs {
+            user: Some(user.to_string()),
+            target: target.to_string(),
+            selector: TargetSelector::Hostname,
+            command: Vec::new(),
+            share: None,
+            attach: false,
+        },
+        _ => ContainerArgs {
+            user: None,
+            target: login.trim_start_matches('@').to_string(),
+            selector: TargetSelector::Hostname,
+            command: Vec::new(),
+            share: None,
+            attach: false,
+        },
+    }
+}
+
+/// Returns the user a login name authenticates as.
+///
+/// # Arguments
+///
+/// * `login` - The user name the client authenticated with.
+///
+/// # Returns
+///
+/// The part of the login name before the first `@`, the whole login name if it has none. Access
+/// to containers is checked against this user, whichever user the session runs as.
+pub fn login_identity(login: &str) -> &str {
+    login.split('@').next().unwrap_or_default()
+}
+
+/// Checks that a single user or group name is a plain name or a numeric id.
+pub fn is_valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(first) if first.is_ascii_alphanumeric() || first == '_' => {
+            chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.')
+        }
+        _ => false,
+    }
+}
+
+/// Validates the user an exec runs as.
+///
+/// # Arguments
+///
+/// * `spec` - The user, either `user` or `user:group`. Both parts may be names or numeric ids.
+///
+/// # Returns
+///
+/// `true` if the value can be passed to Docker as the exec user.
+pub fn is_valid_user_spec(spec: &str) -> bool {
+    match spec.split_once(':') {
+        Some((user, group)) => is_valid_name(user) && is_valid_name(group),
+        None => is_valid_name(spec),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(data: &[u8]) -> Result<ContainerArgs, TunnydError> {
+        parse_and_match_args(data, 0, 0)
+    }
+
+    #[test]
+    fn parses_target_user_and_command() {
+        let args = parse(b"tunnyd --target app.my-docker --user root ls -la").unwrap();
+        assert_eq!(args.target, "app.my-docker");
+        assert_eq!(args.user.as_deref(), Some("root"));
+        assert_eq!(args.command, vec!["ls", "-la"]);
+        assert_eq!(args.selector, TargetSelector::Hostname);
+        assert_eq!(args.share, None);
+    }
+
+    #[test]
+    fn parses_shared_session_name() {
+        let args = parse(b"tunnyd -t app.my-docker --share debugging").unwrap();
+        assert_eq!(args.share.as_deref(), Some("debugging"));
+        assert!(args.command.is_empty());
+    }
+
+    #[test]
+    fn parses_attach_flag() {
+        let args = parse(b"tunnyd -t app.my-docker --attach").unwrap();
+        assert!(args.attach);
+        assert!(args.command.is_empty());
+        let args = parse(b"tunnyd -t app.my-docker").unwrap();
+        assert!(!args.attach);
+    }
+
+    #[test]
+    fn parses_direct_addressing_flags() {
+        let args = parse(b"tunnyd --by-id -t 0123456789ab ls").unwrap();
+        assert_eq!(args.selector, TargetSelector::Id);
+        assert_eq!(args.command, vec!["ls"]);
+        let args = parse(b"tunnyd --by-name -t example").unwrap();
+        assert_eq!(args.selector, TargetSelector::Name);
+        assert_eq!(args.target, "example");
+    }
+
+    #[test]
+    fn parses_without_user_or_command() {
+        let args = parse(b"tunnyd -t app.my-docker").unwrap();
+        assert_eq!(args.target, "app.my-docker");
+        assert_eq!(args.user, None);
+        assert!(args.command.is_empty());
+    }
+
+    #[test]
+    fn keeps_quoted_arguments_together() {
+        let args = parse(b"tunnyd -t app sh -c 'echo hello world'").unwrap();
+        assert_eq!(args.command, vec!["sh", "-c", "echo hello world"]);
+    }
+
+    #[test]
+    fn rejects_commands_that_are_not_utf8() {
+        let result = parse(b"tunnyd -t app cat caf\xe9.txt");
+        assert!(matches!(
+            result,
+            Err(TunnydError::InvalidEncoding { offset: 21 })
+        ));
+    }
+
+    #[test]
+    fn rejects_oversized_commands_before_parsing() {
+        // Neither valid UTF-8 nor naming a target, so only a parse that never started can return
+        let mut payload = b"tunnyd ".to_vec();
+        payload.extend(std::iter::repeat(0xff).take(1024 * 1024));
+        assert!(matches!(
+            parse_and_match_args(&payload, 65536, 0),
+            Err(TunnydError::CommandTooLong {
+                length: 1048583,
+                limit: 65536
+            })
+        ));
+
+        let words = format!("tunnyd -t app echo {}", "a ".repeat(100));
+        assert!(matches!(
+            parse_and_match_args(words.as_bytes(), 0, 50),
+            Err(TunnydError::TooManyWords { limit: 50 })
+        ));
+        let args = parse_and_match_args(b"tunnyd -t app ls -la", 20, 5).unwrap();
+        assert_eq!(args.command, ["ls", "-la"]);
+    }
+
+    #[test]
+    fn returns_argument_errors_instead_of_exiting() {
+        // clap would print these and exit the daemon
+        for data in [
+            &b"tunnyd ls"[..],
+            b"tunnyd -t app --unknown ls",
+            b"tunnyd --help",
+        ] {
+            assert!(matches!(parse(data), Err(TunnydError::InvalidArguments(_))));
+        }
+    }
+
+    #[test]
+    fn splits_login_into_user_and_target() {
+        let args = args_from_login("root@app.my-docker");
+        assert_eq!(args.user.as_deref(), Some("root"));
+        assert_eq!(args.target, "app.my-docker");
+
+        let args = args_from_login("app.my-docker");
+        assert_eq!(args.user, None);
+        assert_eq!(args.target, "app.my-docker");
+
+        let args = args_from_login("@app.my-docker");
+        assert_eq!(args.user, None);
+        assert_eq!(args.target, "app.my-docker");
+    }
+
+    #[test]
+    fn validates_user_specs() {
+        assert!(is_valid_user_spec("root"));
+        assert!(is_valid_user_spec("1000:1000"));
+        assert!(is_valid_user_spec("www-data:www-data"));
+        assert!(!is_valid_user_spec(""));
+        assert!(!is_valid_user_spec("root:"));
+        assert!(!is_valid_user_spec("-root"));
+        assert!(!is_valid_user_spec("root; rm -rf /"));
+    }
+
+    #[test]
+    fn defaults_to_serve_without_subcommand() {
+        let args = parse_daemon_args(["tunnyd"]).unwrap();
+        assert_eq!(args.verbosity, 0);
+        match args.command {
+            DaemonCommand::Serve(serve) => {
+                assert_eq!(serve.bind, DEFAULT_BIND);
+                assert_eq!(serve.port, 2222);
+            }
+            command => panic!("unexpected command {:?}", command),
+        }
+    }
+
+    #[test]
+    fn parses_serve_arguments_and_verbosity() {
+        let args = parse_daemon_args(["tunnyd", "-vv", "serve", "-q", "--listen", "127.0.0.1:22"])
+            .unwrap();
+        assert_eq!(args.verbosity, 1);
+        match args.command {
+            DaemonCommand::Serve(serve) => assert_eq!(serve.listen, vec!["127.0.0.1:22"]),
+            command => panic!("unexpected command {:?}", command),
+        }
+    }
+
+    #[test]
+    fn parses_test_config_flags() {
+        let args = parse_daemon_args(["tunnyd", "serve", "-t", "--check-docker"]).unwrap();
+        match args.command {
+            DaemonCommand::Serve(serve) => assert!(serve.test_config && serve.check_docker),
+            command => panic!("unexpected command {:?}", command),
+        }
+        assert!(parse_daemon_args(["tunnyd", "serve", "--check-docker"]).is_err());
+    }
+
+    #[test]
+    fn enforces_authentication_unless_asked_not_to() {
+        let insecure =
+            |args: &[&str]| match parse_daemon_args(args.iter().copied()).unwrap().command {
+                DaemonCommand::Serve(serve) => serve.insecure_allow_all,
+                command => panic!("unexpected command {:?}", command),
+            };
+        assert!(!insecure(&["tunnyd", "serve"]));
+        assert!(insecure(&["tunnyd", "serve", "--insecure-allow-all"]));
+    }
+
+    #[test]
+    fn parses_resolve_arguments() {
+        let args = parse_daemon_args(["tunnyd", "resolve", "-t", "app", "-u", "root"]).unwrap();
+        assert_eq!(
+            args.command,
+            DaemonCommand::Resolve(ResolveArgs {
+                target: String::from("app"),
+                user: Some(String::from("root")),
+                docker_context: None,
+                config: None,
+            })
+        );
+        assert!(parse_daemon_args(["tunnyd", "resolve"]).is_err());
+    }
+
+    #[test]
+    fn parses_list_targets_arguments() {
+        let args = parse_daemon_args(["tunnyd", "list-targets", "--json"]).unwrap();
+        assert_eq!(
+            args.command,
+            DaemonCommand::ListTargets(ListTargetsArgs {
+                json: true,
+                docker_context: None,
+                config: None,
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_listen_together_with_port() {
+        assert!(parse_daemon_args(["tunnyd", "serve", "--listen", "a:1", "--port", "2"]).is_err());
+    }
 }
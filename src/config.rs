@@ -0,0 +1,552 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use log::{info, warn};
+use serde::Deserialize;
+
+use crate::acl::IpPolicy;
+use crate::auth::AuthMethod;
+
+/// Environment variable holding the path of the TOML configuration file.
+const CONFIG_PATH_ENV: &str = "TUNNYD_CONFIG";
+
+/// Represents the template of the Docker exec started for every session.
+///
+/// # Fields
+///
+/// * `cmd`: The command run when the client doesn't request one, the interactive shell by default.
+/// * `tty`: Whether the exec is allocated a TTY.
+/// * `attach_stdin`: Whether the client's input is attached to the exec.
+/// * `attach_stdout`: Whether the exec's standard output is sent to the client.
+/// * `attach_stderr`: Whether the exec's standard error is sent to the client.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct ExecTemplate {
+    pub cmd: Vec<String>,
+    pub tty: bool,
+    pub attach_stdin: bool,
+    pub attach_stdout: bool,
+    pub attach_stderr: bool,
+}
+
+impl Default for ExecTemplate {
+    fn default() -> Self {
+        ExecTemplate {
+            cmd: vec![String::from("bash")],
+            tty: true,
+            attach_stdin: true,
+            attach_stdout: true,
+            attach_stderr: true,
+        }
+    }
+}
+
+impl ExecTemplate {
+    /// Checks that the template describes an exec that can be started.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(())` if the template is valid, otherwise a message describing the conflicting options.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.cmd.is_empty() {
+            return Err(String::from("exec.cmd must not be empty"));
+        }
+        if self.tty && !self.attach_stdin {
+            return Err(String::from(
+                "exec.tty requires exec.attach_stdin, a TTY without input can't be used",
+            ));
+        }
+        if !self.attach_stdout && !self.attach_stderr {
+            return Err(String::from(
+                "at least one of exec.attach_stdout and exec.attach_stderr must be set",
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Represents the runtime configuration of the tunnyd server.
+///
+/// # Fields
+///
+/// * `log_level`: The log level used when `RUST_LOG` is not set, `off`, `error`, `warn`, `info`,
+///   `debug` or `trace`.
+/// * `docker_context`: The Docker CLI context to connect to, the local defaults when unset.
+/// * `docker_hosts`: Additional Docker daemons searched for containers alongside the primary one,
+///   as `tcp://`, `http://` or `unix://` URLs. Port forwarding and signals only reach containers on
+///   the primary daemon.
+/// * `docker_api_version`: The highest Docker API version to use, e.g. `1.41`, for older daemons.
+///   `DOCKER_API_VERSION` is used when unset.
+/// * `docker_startup_timeout_secs`: Seconds to wait at startup for the Docker daemon to become
+///   reachable before exiting, `0` gives up after the first attempt.
+/// * `max_sessions`: The maximum number of concurrent client sessions, `0` means unlimited.
+/// * `max_sessions_per_user`: The maximum number of concurrent sessions of one login name, `0`
+///   means unlimited.
+/// * `max_sessions_per_ip`: The maximum number of concurrent sessions from one source address, `0`
+///   means unlimited.
+/// * `max_channels_per_connection`: The maximum number of session channels one SSH connection may
+///   have open at once, `0` means unlimited.
+/// * `max_command_bytes`: The longest exec request that is parsed, `0` means unlimited.
+/// * `max_command_words`: The most words an exec request may split into, `0` means unlimited.
+/// * `max_output_chunk_bytes`: The largest piece of output sent to a session channel in one write,
+///   longer output (e.g. a huge line without a newline) is split. Must be greater than `0`.
+/// * `max_output_bytes_per_sec`: The most output a session channel is sent per second, the
+///   container's output stream is read no faster. `0` means unlimited.
+/// * `audit_log`: Where the JSON audit log is written, `stdout`, `stderr` or a file path.
+/// * `allow_ips`: IPv4/IPv6 CIDR blocks that may connect.
+/// * `deny_ips`: IPv4/IPv6 CIDR blocks that may never connect, taking precedence over `allow_ips`.
+/// * `default_ip_policy`: Whether addresses in neither list are allowed (`allow`) or not (`deny`).
+/// * `proxy_protocol`: Whether connections from `trusted_proxies` start with a PROXY protocol v1 or
+///   v2 header, whose client address then replaces the address of the proxy.
+/// * `trusted_proxies`: IPv4/IPv6 CIDR blocks of the load balancers allowed to send PROXY headers.
+/// * `recording_path`: Path template of asciinema recordings of interactive sessions, recording is
+///   disabled when unset. Supports the `{session}`, `{user}` and `{timestamp}` placeholders.
+/// * `accept_env`: Names of the environment variables clients may pass to the exec, a trailing `*`
+///   matches any suffix.
+/// * `banner`: Text shown to clients before authentication.
+/// * `banner_file`: File whose contents are shown before authentication, takes precedence over `banner`.
+/// * `container_cache_ttl_ms`: How long the container list is cached in milliseconds, `0` disables the cache.
+/// * `listen`: Endpoints the SSH server listens on, `host:port` or `unix:/path`, used when none are
+///   given on the command line.
+/// * `health_listen`: Address of the health check endpoint, e.g. `0.0.0.0:8080`, disabled when unset.
+/// * `allow_remote_forwarding`: Whether clients may request remote port forwards (`ssh -R`).
+/// * `forward_to_networks`: Whether local port forwards (`ssh -L`) may reach other addresses on the
+///   container's networks, like other containers. Otherwise only the container itself can be
+///   forwarded to. The gateways, i.e. the Docker host, are refused either way.
+/// * `idle_timeout_secs`: Seconds without input or output after which a session is closed, `0` disables it.
+/// * `max_session_duration_secs`: Seconds after which a session is closed regardless of activity,
+///   `0` means unlimited.
+/// * `drain_timeout_secs`: Seconds open sessions get to end on their own after a shutdown signal,
+///   new sessions are refused meanwhile. `0` exits right away.
+/// * `keepalive_interval_secs`: Seconds between keepalive requests sent to clients, keeping idle
+///   connections open through NAT and firewalls. `0` disables them.
+/// * `auth_timeout_secs`: Seconds a connection gets to authenticate before it is dropped, like
+///   OpenSSH's `LoginGraceTime`. `0` waits forever.
+/// * `session_affinity_secs`: Seconds a user's hostname target keeps landing on the container that
+///   served it last, while that container still matches. `0` disables the affinity.
+/// * `exec_timeout_secs`: Seconds Docker gets to create and to start an exec, `0` waits forever.
+/// * `forced_command`: Command run for every session instead of the shell or the requested command,
+///   like OpenSSH's `ForceCommand`.
+/// * `exec_wrapper`: Command every exec runs through, its arguments are placed before the shell or
+///   the requested command, e.g. `["env", "-i"]`. Must not be empty when set.
+/// * `allowed_commands`: Command names or regular expressions clients may run, every command is
+///   allowed when empty. Doesn't apply to `forced_command`.
+/// * `allow_interactive_shell`: Whether clients may open an interactive shell by not requesting a command.
+/// * `require_tty`: Whether exec and shell requests are rejected unless the client requested a
+///   pseudo-terminal first, so only interactive sessions are possible.
+/// * `allow_direct_addressing`: Whether clients may address containers by id or name with `--by-id`
+///   and `--by-name` instead of the hostname label. The enable and allowed users labels still apply.
+/// * `allowed_images`: Images containers must run to be reachable, in addition to their labels. A
+///   trailing `*` matches any suffix and a name without a tag matches every tag. Empty allows every
+///   image.
+/// * `tolerated_write_failures`: Consecutive failed writes to a client's channel tolerated before
+///   the session is torn down and its exec hung up, `0` gives up on the first failure.
+/// * `exit_banner`: The message shown to sessions with a TTY once their process exited, empty to
+///   show none. Sessions without a TTY never get it.
+/// * `welcome_message`: The message shown to sessions with a TTY before any output, empty to show
+///   none. `{target}` and `{user}` are replaced by the target and the container user.
+/// * `usage_motd`: Whether sessions with a TTY are shown the CPU and memory usage and the uptime of
+///   their container before any output.
+/// * `denied_message`: The message sent to clients whose session request is refused. `{target}`,
+///   `{user}` and `{reason}` are replaced by the requested target, the requested container user
+///   and why the request was refused.
+/// * `scrollback_lines`: The number of the container's most recent log lines sent to a session before
+///   the output of its exec, `0` sends none.
+/// * `allow_shared_sessions`: Whether clients may share one exec with `--share NAME`, attaching to
+///   the shell another client of the same container user started under that name.
+/// * `allow_attach`: Whether clients may attach to the main process of a container with `--attach`
+///   instead of starting an exec.
+/// * `default_user_from_login`: Whether sessions run as the login user when neither `--user` nor
+///   the container's `tunnyD.default.user` label pick one, instead of the image's default user.
+/// * `user_groups`: The groups of each user, granting access to containers listing any of them in
+///   their `tunnyD.allowed.groups` label.
+/// * `auth_failure_threshold`: Consecutive failed authentications after which a source address is
+///   banned, `0` disables bans. Every failure is also delayed by a growing penalty.
+/// * `auth_ban_secs`: How long a banned address is rejected in seconds.
+/// * `auth_methods`: The authentication methods offered to clients, `none`, `publickey` and
+///   `keyboard-interactive`.
+/// * `authorized_keys_file`: The keys allowed to authenticate with `publickey`, in the format of
+///   OpenSSH's `authorized_keys` without key options. `%u` is replaced by the user logging in, so
+///   each user has its own file; a path without `%u` lets every key log in as any user. Without it
+///   every key is refused, unless the server runs with `--insecure-allow-all`.
+/// * `keyboard_interactive_prompts`: The prompts of keyboard-interactive authentication, each shown
+///   in its own round.
+/// * `keyboard_interactive_command`: Command verifying keyboard-interactive responses, it gets the
+///   login name in `TUNNYD_USER` and the responses on stdin and accepts by exiting with `0`.
+/// * `keyboard_interactive_tokens`: Static tokens per user, checked against the first response when
+///   no command is configured.
+/// * `connect_hook_command`: Command asked before every session is opened, it gets the session
+///   request as JSON on stdin and allows it by exiting with `0`.
+/// * `connect_hook_url`: `http://` URL the session request is POSTed to as JSON before every session
+///   is opened, a `2xx` status allows it.
+/// * `connect_hook_timeout_secs`: How long the connect hooks may take to decide, sessions are
+///   denied once it passes.
+/// * `buffer_partial_utf8`: Whether an incomplete UTF-8 sequence at the end of an output chunk is
+///   held back until the next chunk completes it, for clients decoding the output as text.
+/// * `tty_translate_newlines`: Whether bare line feeds in the output of sessions with a pseudo-terminal
+///   are translated to `\r\n`, for programs whose output would otherwise render staircased.
+/// * `ciphers`: The ciphers offered to clients in order of preference, russh's defaults when empty.
+/// * `kex_algorithms`: The key exchange algorithms offered to clients in order of preference,
+///   russh's defaults when empty.
+/// * `macs`: The MACs offered to clients in order of preference, russh's defaults when empty.
+/// * `compression`: Whether `zlib` compression is offered to clients. It is only used if the client
+///   asks for it, e.g. with `ssh -C`.
+/// * `exec`: The template of the Docker exec started for every session.
+///
+/// # Remarks
+///
+/// Every field has a default so a configuration file only needs to contain the values it
+/// wants to change.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct ServerConfig {
+    pub log_level: String,
+    pub docker_context: Option<String>,
+    pub docker_hosts: Vec<String>,
+    pub docker_api_version: Option<String>,
+    pub docker_startup_timeout_secs: u64,
+    pub max_sessions: usize,
+    pub max_sessions_per_user: usize,
+    pub max_sessions_per_ip: usize,
+    pub max_channels_per_connection: usize,
+    pub max_command_bytes: usize,
+    pub max_command_words: usize,
+    pub max_output_chunk_bytes: usize,
+    pub max_output_bytes_per_sec: u64,
+    pub audit_log: String,
+    pub allow_ips: Vec<String>,
+    pub deny_ips: Vec<String>,
+    pub default_ip_policy: IpPolicy,
+    pub proxy_protocol: bool,
+    pub trusted_proxies: Vec<String>,
+    pub recording_path: Option<String>,
+    pub accept_env: Vec<String>,
+    pub banner: Option<String>,
+    pub banner_file: Option<String>,
+    pub container_cache_ttl_ms: u64,
+    pub listen: Vec<String>,
+    pub health_listen: Option<String>,
+    pub allow_remote_forwarding: bool,
+    pub forward_to_networks: bool,
+    pub idle_timeout_secs: u64,
+    pub max_session_duration_secs: u64,
+    pub drain_timeout_secs: u64,
+    pub keepalive_interval_secs: u64,
+    pub auth_timeout_secs: u64,
+    pub session_affinity_secs: u64,
+    pub exec_timeout_secs: u64,
+    pub forced_command: Option<Vec<String>>,
+    pub exec_wrapper: Option<Vec<String>>,
+    pub allowed_commands: Vec<String>,
+    pub allow_interactive_shell: bool,
+    pub require_tty: bool,
+    pub allow_direct_addressing: bool,
+    pub allow_shared_sessions: bool,
+    pub allow_attach: bool,
+    pub default_user_from_login: bool,
+    pub tolerated_write_failures: u32,
+    pub scrollback_lines: u64,
+    pub exit_banner: String,
+    pub welcome_message: String,
+    pub usage_motd: bool,
+    pub denied_message: String,
+    pub allowed_images: Vec<String>,
+    pub user_groups: HashMap<String, Vec<String>>,
+    pub auth_failure_threshold: u32,
+    pub auth_ban_secs: u64,
+    pub auth_methods: Vec<AuthMethod>,
+    pub authorized_keys_file: Option<String>,
+    pub keyboard_interactive_prompts: Vec<String>,
+    pub keyboard_interactive_command: Option<Vec<String>>,
+    pub keyboard_interactive_tokens: HashMap<String, String>,
+    pub connect_hook_command: Option<Vec<String>>,
+    pub connect_hook_url: Option<String>,
+    pub connect_hook_timeout_secs: u64,
+    pub buffer_partial_utf8: bool,
+    pub tty_translate_newlines: bool,
+    pub ciphers: Vec<String>,
+    pub kex_algorithms: Vec<String>,
+    pub macs: Vec<String>,
+    pub compression: bool,
+    pub exec: ExecTemplate,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            log_level: String::from("info"),
+            docker_context: None,
+            docker_hosts: Vec::new(),
+            docker_api_version: None,
+            docker_startup_timeout_secs: 60,
+            max_sessions: 0,
+            max_sessions_per_user: 0,
+            max_sessions_per_ip: 0,
+            max_channels_per_connection: 0,
+            max_command_bytes: 64 * 1024,
+            max_command_words: 4096,
+            max_output_chunk_bytes: 32 * 1024,
+            max_output_bytes_per_sec: 0,
+            audit_log: String::from("stderr"),
+            allow_ips: Vec::new(),
+            deny_ips: Vec::new(),
+            default_ip_policy: IpPolicy::Allow,
+            proxy_protocol: false,
+            trusted_proxies: Vec::new(),
+            recording_path: None,
+            accept_env: vec![
+                String::from("LANG"),
+                String::from("LC_*"),
+                String::from("TERM"),
+            ],
+            banner: None,
+            banner_file: None,
+            container_cache_ttl_ms: 2000,
+            listen: Vec::new(),
+            health_listen: None,
+            allow_remote_forwarding: true,
+            forward_to_networks: false,
+            idle_timeout_secs: 0,
+            max_session_duration_secs: 0,
+            drain_timeout_secs: 0,
+            keepalive_interval_secs: 0,
+            auth_timeout_secs: 0,
+            session_affinity_secs: 0,
+            exec_timeout_secs: 30,
+            forced_command: None,
+            exec_wrapper: None,
+            allowed_commands: Vec::new(),
+            allow_interactive_shell: true,
+            require_tty: false,
+            allow_direct_addressing: true,
+            allow_shared_sessions: false,
+            allow_attach: false,
+            default_user_from_login: true,
+            tolerated_write_failures: 0,
+            scrollback_lines: 0,
+            exit_banner: String::from("Container process exited"),
+            welcome_message: String::new(),
+            usage_motd: false,
+            denied_message: String::from("{reason}"),
+            allowed_images: Vec::new(),
+            user_groups: HashMap::new(),
+            auth_failure_threshold: 5,
+            auth_ban_secs: 300,
+            auth_methods: vec![AuthMethod::Publickey],
+            authorized_keys_file: None,
+            keyboard_interactive_prompts: vec![String::from("Token: ")],
+            keyboard_interactive_command: None,
+            keyboard_interactive_tokens: HashMap::new(),
+            connect_hook_command: None,
+            connect_hook_url: None,
+            connect_hook_timeout_secs: 5,
+            buffer_partial_utf8: false,
+            tty_translate_newlines: false,
+            ciphers: Vec::new(),
+            kex_algorithms: Vec::new(),
+            macs: Vec::new(),
+            compression: true,
+            exec: ExecTemplate::default(),
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Loads the configuration from the given file, or the file referenced by the `TUNNYD_CONFIG`
+    /// environment variable.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The configuration file given on the command line, taking precedence over `TUNNYD_CONFIG`.
+    ///
+    /// # Returns
+    ///
+    /// Returns the parsed `ServerConfig`, or the default configuration when no file is given.
+    /// If the file can't be read or parsed, the error is wrapped in a `Box<dyn std::error::Error>`.
+    pub fn load(path: Option<&str>) -> Result<ServerConfig, Box<dyn std::error::Error>> {
+        match path
+            .map(str::to_string)
+            .or_else(|| std::env::var(CONFIG_PATH_ENV).ok())
+        {
+            Some(path) => ServerConfig::from_file(path),
+            None => Ok(ServerConfig::default()),
+        }
+    }
+
+    /// Checks that the exec wrapper, if set, names a command.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(())` if the wrapper is unset or valid, otherwise a message describing the problem.
+    pub fn validate_exec_wrapper(&self) -> Result<(), String> {
+        match &self.exec_wrapper {
+            Some(wrapper) if wrapper.is_empty() => {
+                Err(String::from("exec_wrapper must not be empty when set"))
+            }
+            Some(wrapper) if wrapper.iter().any(|arg| arg.is_empty()) => Err(String::from(
+                "exec_wrapper must not contain empty arguments",
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks whether a client may pass an environment variable to the exec.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the environment variable.
+    ///
+    /// # Returns
+    ///
+    /// `true` if the name matches one of the `accept_env` patterns.
+    pub fn accepts_env(&self, name: &str) -> bool {
+        self.accept_env
+            .iter()
+            .any(|pattern| match pattern.strip_suffix('*') {
+                Some(prefix) => name.starts_with(prefix),
+                None => name == pattern,
+            })
+    }
+
+    /// Looks up the groups a user is a member of.
+    ///
+    /// # Arguments
+    ///
+    /// * `user` - The user name, compared case-insensitively. For `user:group` only the user is used.
+    ///
+    /// # Returns
+    ///
+    /// The groups configured for the user in `user_groups`, empty if the user has none.
+    pub fn groups_of(&self, user: &str) -> &[String] {
+        let user = user.split(':').next().unwrap_or_default().trim();
+        self.user_groups
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(user))
+            .map(|(_, groups)| groups.as_slice())
+            .unwrap_or_default()
+    }
+
+    /// Loads the pre-authentication banner.
+    ///
+    /// # Returns
+    ///
+    /// The contents of `banner_file` if it is set, otherwise the inline `banner`. A banner file that
+    /// can't be read is logged and skipped.
+    pub fn load_banner(&self) -> Option<String> {
+        match &self.banner_file {
+            Some(path) => match std::fs::read_to_string(path) {
+                Ok(banner) => Some(banner),
+                Err(e) => {
+                    warn!("Skipping banner, failed to read {}: {}", path, e);
+                    None
+                }
+            },
+            None => self.banner.clone(),
+        }
+    }
+
+    /// Reads and parses a TOML configuration file.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path of the configuration file.
+    ///
+    /// # Returns
+    ///
+    /// Returns the parsed `ServerConfig` or the error encountered while reading, parsing or
+    /// validating the file.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<ServerConfig, Box<dyn std::error::Error>> {
+        let contents = std::fs::read_to_string(path.as_ref())?;
+        let config: ServerConfig = toml::from_str(&contents)?;
+        config.exec.validate()?;
+        config.validate_exec_wrapper()?;
+        if config.max_output_chunk_bytes == 0 {
+            return Err("max_output_chunk_bytes must be greater than 0".into());
+        }
+        config
+            .log_level
+            .parse::<log::LevelFilter>()
+            .map_err(|_| format!("Invalid log_level '{}'", config.log_level))?;
+        info!("Loaded configuration from {}", path.as_ref().display());
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_partial_configuration() {
+        let config: ServerConfig = toml::from_str(
+            r#"
+            max_sessions = 4
+            auth_methods = ["publickey", "keyboard-interactive"]
+
+            [user_groups]
+            Alice = ["developers"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.max_sessions, 4);
+        assert_eq!(
+            config.auth_methods,
+            vec![AuthMethod::Publickey, AuthMethod::KeyboardInteractive]
+        );
+        assert_eq!(config.exec_timeout_secs, 30);
+        assert_eq!(config.groups_of("alice:staff"), ["developers"]);
+        assert!(config.groups_of("bob").is_empty());
+    }
+
+    #[test]
+    fn matches_accepted_environment_variables() {
+        let config = ServerConfig::default();
+        assert!(config.accepts_env("LANG"));
+        assert!(config.accepts_env("LC_ALL"));
+        assert!(!config.accepts_env("LANGUAGE"));
+        assert!(!config.accepts_env("PATH"));
+    }
+
+    #[test]
+    fn validates_exec_template() {
+        assert!(ExecTemplate::default().validate().is_ok());
+        let no_stdin = ExecTemplate {
+            attach_stdin: false,
+            ..Default::default()
+        };
+        assert!(no_stdin.validate().is_err());
+        let no_output = ExecTemplate {
+            attach_stdout: false,
+            attach_stderr: false,
+            ..Default::default()
+        };
+        assert!(no_output.validate().is_err());
+        let no_cmd = ExecTemplate {
+            cmd: Vec::new(),
+            ..Default::default()
+        };
+        assert!(no_cmd.validate().is_err());
+    }
+
+    #[test]
+    fn validates_exec_wrapper() {
+        let wrapper = |wrapper: Option<&[&str]>| ServerConfig {
+            exec_wrapper: wrapper.map(|args| args.iter().map(|arg| arg.to_string()).collect()),
+            ..Default::default()
+        };
+        assert!(wrapper(None).validate_exec_wrapper().is_ok());
+        assert!(wrapper(Some(&["env", "-i"]))
+            .validate_exec_wrapper()
+            .is_ok());
+        assert!(wrapper(Some(&[])).validate_exec_wrapper().is_err());
+        assert!(wrapper(Some(&["env", ""])).validate_exec_wrapper().is_err());
+    }
+
+    #[test]
+    fn rejects_empty_output_chunks() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(file.path(), "max_output_chunk_bytes = 0\n").unwrap();
+        let error = ServerConfig::from_file(file.path()).unwrap_err();
+        assert!(error.to_string().contains("max_output_chunk_bytes"));
+    }
+}
@@ -0,0 +1,276 @@
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+/// Environment variable overriding the Docker CLI configuration directory.
+const DOCKER_CONFIG_ENV: &str = "DOCKER_CONFIG";
+/// The context that stands for the local defaults rather than a stored context.
+pub const DEFAULT_CONTEXT: &str = "default";
+const DOCKER_HOST_ENV: &str = "DOCKER_HOST";
+const DOCKER_TLS_VERIFY_ENV: &str = "DOCKER_TLS_VERIFY";
+const DOCKER_CERT_PATH_ENV: &str = "DOCKER_CERT_PATH";
+const TLS_CA_FILE: &str = "ca.pem";
+const TLS_CERT_FILE: &str = "cert.pem";
+const TLS_KEY_FILE: &str = "key.pem";
+
+/// The verified TLS connection to a remote Docker daemon requested through the environment.
+///
+/// # Fields
+///
+/// * `host`: The TCP host of the daemon, from `DOCKER_HOST`.
+/// * `ca`: The CA certificate the daemon's certificate is verified against.
+/// * `cert`: The client certificate.
+/// * `key`: The key of the client certificate.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DockerTls {
+    pub host: String,
+    pub ca: PathBuf,
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+/// The metadata the Docker CLI stores for a context in `contexts/meta/<hash>/meta.json`.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ContextMeta {
+    name: String,
+    endpoints: ContextEndpoints,
+}
+
+#[derive(Debug, Deserialize)]
+struct ContextEndpoints {
+    docker: Option<ContextEndpoint>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ContextEndpoint {
+    host: String,
+}
+
+/// Returns the configuration directory of the Docker CLI.
+///
+/// # Returns
+///
+/// `$DOCKER_CONFIG` if set, otherwise `~/.docker`.
+pub fn docker_config_dir() -> PathBuf {
+    match std::env::var_os(DOCKER_CONFIG_ENV) {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(std::env::var_os("HOME").unwrap_or_default()).join(".docker"),
+    }
+}
+
+/// Reads the TLS settings of the Docker CLI from the environment.
+///
+/// # Arguments
+///
+/// * `var` - Looks up an environment variable.
+/// * `config_dir` - The configuration directory of the Docker CLI, holding the certificates when
+///   `DOCKER_CERT_PATH` isn't set.
+///
+/// # Returns
+///
+/// The `DockerTls` settings if `DOCKER_TLS_VERIFY` is set and `DOCKER_HOST` is a TCP host, `None`
+/// if TLS isn't requested, or an error naming the missing certificate file.
+///
+/// # Remarks
+///
+/// Like the Docker CLI, any non-empty value of `DOCKER_TLS_VERIFY` enables verification, and
+/// `ca.pem`, `cert.pem` and `key.pem` are read from `DOCKER_CERT_PATH`.
+pub fn docker_tls<F>(var: F, config_dir: &Path) -> Result<Option<DockerTls>, String>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    if var(DOCKER_TLS_VERIFY_ENV).map_or(true, |verify| verify.is_empty()) {
+        return Ok(None);
+    }
+    let host = match var(DOCKER_HOST_ENV) {
+        Some(host) if host.starts_with("tcp://") => host,
+        _ => return Ok(None),
+    };
+    let cert_dir = var(DOCKER_CERT_PATH_ENV)
+        .filter(|path| !path.is_empty())
+        .map_or_else(|| config_dir.to_path_buf(), PathBuf::from);
+    let file = |name: &str| {
+        let path = cert_dir.join(name);
+        match path.is_file() {
+            true => Ok(path),
+            false => Err(format!(
+                "{} is set but the TLS file {} is missing",
+                DOCKER_TLS_VERIFY_ENV,
+                path.display()
+            )),
+        }
+    };
+    Ok(Some(DockerTls {
+        host,
+        ca: file(TLS_CA_FILE)?,
+        cert: file(TLS_CERT_FILE)?,
+        key: file(TLS_KEY_FILE)?,
+    }))
+}
+
+/// Resolves the Docker endpoint of a named context.
+///
+/// # Arguments
+///
+/// * `config_dir` - The configuration directory of the Docker CLI.
+/// * `name` - The name of the context, as shown by `docker context ls`.
+///
+/// # Returns
+///
+/// The host of the context's Docker endpoint, e.g. `unix:///var/run/docker.sock` or
+/// `tcp://10.0.0.2:2375`, or an error message if the context doesn't exist.
+///
+/// # Remarks
+///
+/// The CLI names the metadata directories after a hash of the context name, the directories are
+/// scanned instead so no hashing is needed.
+pub fn resolve_context_host(config_dir: &Path, name: &str) -> Result<String, String> {
+    let meta_dir = config_dir.join("contexts").join("meta");
+    let entries = std::fs::read_dir(&meta_dir).map_err(|e| {
+        format!(
+            "Docker context '{}' not found, can't read {}: {}",
+            name,
+            meta_dir.display(),
+            e
+        )
+    })?;
+    for entry in entries.flatten() {
+        let contents = match std::fs::read_to_string(entry.path().join("meta.json")) {
+            Ok(contents) => contents,
+            Err(_) => continue,
+        };
+        let meta = match serde_json::from_str::<ContextMeta>(&contents) {
+            Ok(meta) if meta.name == name => meta,
+            _ => continue,
+        };
+        return meta
+            .endpoints
+            .docker
+            .map(|endpoint| endpoint.host)
+            .ok_or_else(|| format!("Docker context '{}' has no docker endpoint", name));
+    }
+    Err(format!("Docker context '{}' not found", name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_context(config_dir: &Path, dir: &str, meta: &str) {
+        let dir = config_dir.join("contexts").join("meta").join(dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("meta.json"), meta).unwrap();
+    }
+
+    fn write_certs(dir: &Path, names: &[&str]) {
+        for name in names {
+            std::fs::write(dir.join(name), "").unwrap();
+        }
+    }
+
+    fn env<'a>(vars: &'a [(&'a str, &'a str)]) -> impl Fn(&str) -> Option<String> + 'a {
+        move |name| {
+            vars.iter()
+                .find(|(key, _)| *key == name)
+                .map(|(_, value)| value.to_string())
+        }
+    }
+
+    #[test]
+    fn reads_certificates_from_cert_path() {
+        let cert_dir = tempfile::tempdir().unwrap();
+        write_certs(cert_dir.path(), &[TLS_CA_FILE, TLS_CERT_FILE, TLS_KEY_FILE]);
+        let cert_path = cert_dir.path().to_str().unwrap();
+        let vars = [
+            (DOCKER_TLS_VERIFY_ENV, "1"),
+            (DOCKER_HOST_ENV, "tcp://10.0.0.2:2376"),
+            (DOCKER_CERT_PATH_ENV, cert_path),
+        ];
+        let tls = docker_tls(env(&vars), Path::new("/nonexistent"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(tls.host, "tcp://10.0.0.2:2376");
+        assert_eq!(tls.ca, cert_dir.path().join(TLS_CA_FILE));
+        assert_eq!(tls.key, cert_dir.path().join(TLS_KEY_FILE));
+    }
+
+    #[test]
+    fn defaults_to_config_dir_for_certificates() {
+        let config_dir = tempfile::tempdir().unwrap();
+        write_certs(
+            config_dir.path(),
+            &[TLS_CA_FILE, TLS_CERT_FILE, TLS_KEY_FILE],
+        );
+        let vars = [
+            (DOCKER_TLS_VERIFY_ENV, "1"),
+            (DOCKER_HOST_ENV, "tcp://docker:2376"),
+        ];
+        let tls = docker_tls(env(&vars), config_dir.path()).unwrap().unwrap();
+        assert_eq!(tls.cert, config_dir.path().join(TLS_CERT_FILE));
+    }
+
+    #[test]
+    fn skips_tls_unless_verifying_a_tcp_host() {
+        let dir = Path::new("/nonexistent");
+        assert_eq!(
+            docker_tls(env(&[(DOCKER_HOST_ENV, "tcp://docker:2376")]), dir),
+            Ok(None)
+        );
+        let unix = [
+            (DOCKER_TLS_VERIFY_ENV, "1"),
+            (DOCKER_HOST_ENV, "unix:///var/run/docker.sock"),
+        ];
+        assert_eq!(docker_tls(env(&unix), dir), Ok(None));
+        let empty = [
+            (DOCKER_TLS_VERIFY_ENV, ""),
+            (DOCKER_HOST_ENV, "tcp://docker:2376"),
+        ];
+        assert_eq!(docker_tls(env(&empty), dir), Ok(None));
+    }
+
+    #[test]
+    fn names_the_missing_certificate() {
+        let cert_dir = tempfile::tempdir().unwrap();
+        write_certs(cert_dir.path(), &[TLS_CA_FILE, TLS_KEY_FILE]);
+        let vars = [
+            (DOCKER_TLS_VERIFY_ENV, "1"),
+            (DOCKER_HOST_ENV, "tcp://docker:2376"),
+        ];
+        let error = docker_tls(env(&vars), cert_dir.path()).unwrap_err();
+        assert!(error.contains(TLS_CERT_FILE), "{}", error);
+    }
+
+    #[test]
+    fn resolves_context_by_name() {
+        let config_dir = tempfile::tempdir().unwrap();
+        write_context(
+            config_dir.path(),
+            "0123",
+            r#"{"Name":"remote","Metadata":{},"Endpoints":{"docker":{"Host":"tcp://10.0.0.2:2375","SkipTLSVerify":false}}}"#,
+        );
+        write_context(config_dir.path(), "4567", "not json");
+        assert_eq!(
+            resolve_context_host(config_dir.path(), "remote").as_deref(),
+            Ok("tcp://10.0.0.2:2375")
+        );
+    }
+
+    #[test]
+    fn reports_unknown_contexts() {
+        let config_dir = tempfile::tempdir().unwrap();
+        assert!(resolve_context_host(config_dir.path(), "missing").is_err());
+        write_context(
+            config_dir.path(),
+            "0123",
+            r#"{"Name":"remote","Endpoints":{}}"#,
+        );
+        assert!(resolve_context_host(config_dir.path(), "missing")
+            .unwrap_err()
+            .contains("not found"));
+        assert!(resolve_context_host(config_dir.path(), "remote")
+            .unwrap_err()
+            .contains("no docker endpoint"));
+    }
+}
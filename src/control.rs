@@ -0,0 +1,230 @@
+use bollard::container::LogOutput;
+use serde::{Deserialize, Serialize};
+
+use crate::docker::Target;
+
+/// The subsystem speaking the JSON line protocol, for programmatic clients.
+pub const CONTROL_SUBSYSTEM: &str = "tunnyd-ctl";
+/// The longest request line accepted, longer lines are discarded up to the next line feed.
+const MAX_LINE_LENGTH: usize = 1024 * 1024;
+
+/// A request sent by a client of the control subsystem, one JSON object per line.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ControlRequest {
+    /// Lists the targets the login user may open sessions on.
+    ListTargets,
+    /// Opens an exec in a target, the configured exec command runs if `command` is empty.
+    Open {
+        target: String,
+        #[serde(default)]
+        user: Option<String>,
+        #[serde(default)]
+        command: Vec<String>,
+        #[serde(default)]
+        tty: bool,
+    },
+    /// Writes to the stdin of the open exec.
+    Input { data: String },
+    /// Resizes the TTY of the open exec.
+    Resize { cols: u16, rows: u16 },
+}
+
+/// The stream a chunk of output was written to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputStream {
+    Stdout,
+    Stderr,
+}
+
+/// A response sent to a client of the control subsystem, one JSON object per line.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ControlResponse {
+    /// The targets the login user may open sessions on.
+    Targets { targets: Vec<Target> },
+    /// The exec was started.
+    Opened {
+        container_id: String,
+        exec_id: String,
+    },
+    /// A chunk of output of the exec, invalid UTF-8 is replaced.
+    Output { stream: OutputStream, data: String },
+    /// The exec exited, with its exit code if Docker reported one.
+    Exit { code: Option<i64> },
+    /// A request failed.
+    Error { message: String },
+}
+
+impl ControlResponse {
+    /// Wraps a chunk of output of an exec.
+    ///
+    /// # Arguments
+    ///
+    /// * `output` - The output read from Docker.
+    ///
+    /// # Returns
+    ///
+    /// The `Output` response, or `None` for the echoed stdin of the exec.
+    pub fn from_output(output: LogOutput) -> Option<ControlResponse> {
+        let (stream, message) = match output {
+            LogOutput::StdOut { message } | LogOutput::Console { message } => {
+                (OutputStream::Stdout, message)
+            }
+            LogOutput::StdErr { message } => (OutputStream::Stderr, message),
+            LogOutput::StdIn { .. } => return None,
+        };
+        Some(ControlResponse::Output {
+            stream,
+            data: String::from_utf8_lossy(&message).into_owned(),
+        })
+    }
+
+    /// Serializes the response to a line of the protocol, terminated by a line feed.
+    pub fn to_line(&self) -> Vec<u8> {
+        let mut line = serde_json::to_vec(self).unwrap_or_else(|e| {
+            format!(r#"{{"type":"error","message":"Failed to encode response: {e}"}}"#).into_bytes()
+        });
+        line.push(b'\n');
+        line
+    }
+}
+
+/// Splits the data received on a control channel into requests.
+///
+/// # Remarks
+///
+/// Data can end mid-line, the incomplete line is kept until the rest of it arrives.
+#[derive(Debug, Default)]
+pub struct LineBuffer {
+    pending: Vec<u8>,
+    discarding: bool,
+}
+
+impl LineBuffer {
+    /// Appends data received from the client.
+    ///
+    /// # Arguments
+    ///
+    /// * `data` - The bytes received on the channel.
+    ///
+    /// # Returns
+    ///
+    /// The requests completed by the data, or why a line couldn't be parsed. Blank lines are
+    /// skipped.
+    pub fn push(&mut self, data: &[u8]) -> Vec<Result<ControlRequest, String>> {
+        let mut requests = Vec::new();
+        for chunk in data.split_inclusive(|byte| *byte == b'\n') {
+            let complete = chunk.ends_with(b"\n");
+            if !self.discarding {
+                self.pending.extend_from_slice(chunk);
+            }
+            if !self.discarding && self.pending.len() > MAX_LINE_LENGTH {
+                self.pending.clear();
+                self.discarding = true;
+                requests.push(Err(format!(
+                    "Request exceeds the maximum of {} bytes",
+                    MAX_LINE_LENGTH
+                )));
+            }
+            if !complete {
+                continue;
+            }
+            if self.discarding {
+                self.discarding = false;
+                continue;
+            }
+            let line = std::mem::take(&mut self.pending);
+            if line.iter().all(u8::is_ascii_whitespace) {
+                continue;
+            }
+            requests
+                .push(serde_json::from_slice(&line).map_err(|e| format!("Invalid request: {}", e)));
+        }
+        requests
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_requests_split_across_chunks() {
+        let mut buffer = LineBuffer::default();
+        assert!(buffer.push(br#"{"type":"open","tar"#).is_empty());
+        let requests = buffer.push(b"get\":\"app\",\"tty\":true}\n\n{\"type\":\"list_targets\"}\n");
+        assert_eq!(
+            requests,
+            vec![
+                Ok(ControlRequest::Open {
+                    target: String::from("app"),
+                    user: None,
+                    command: Vec::new(),
+                    tty: true,
+                }),
+                Ok(ControlRequest::ListTargets),
+            ]
+        );
+    }
+
+    #[test]
+    fn round_trips_requests_and_responses() {
+        let pairs = [
+            (
+                ControlRequest::Resize { cols: 80, rows: 24 },
+                ControlResponse::Error {
+                    message: String::from("No exec is open"),
+                },
+            ),
+            (
+                ControlRequest::Input {
+                    data: String::from("ls\n"),
+                },
+                ControlResponse::Output {
+                    stream: OutputStream::Stdout,
+                    data: String::from("bin\n"),
+                },
+            ),
+        ];
+        for (request, response) in pairs {
+            let mut line = serde_json::to_vec(&request).unwrap();
+            line.push(b'\n');
+            assert_eq!(LineBuffer::default().push(&line), vec![Ok(request)]);
+            let line = response.to_line();
+            assert_eq!(line.last(), Some(&b'\n'));
+            let decoded: ControlResponse = serde_json::from_slice(&line).unwrap();
+            assert_eq!(decoded, response);
+        }
+        assert_eq!(
+            String::from_utf8(ControlResponse::Exit { code: Some(0) }.to_line()).unwrap(),
+            "{\"type\":\"exit\",\"code\":0}\n"
+        );
+    }
+
+    #[test]
+    fn reports_invalid_and_oversized_lines() {
+        let mut buffer = LineBuffer::default();
+        let requests = buffer.push(b"{\"type\":\"reboot\"}\n");
+        assert!(matches!(requests.as_slice(), [Err(_)]));
+        let requests = buffer.push(&vec![b'x'; MAX_LINE_LENGTH + 1]);
+        assert!(matches!(requests.as_slice(), [Err(_)]));
+        let requests = buffer.push(b"xx\n{\"type\":\"list_targets\"}\n");
+        assert_eq!(requests, vec![Ok(ControlRequest::ListTargets)]);
+    }
+
+    #[test]
+    fn wraps_output_lossily() {
+        let response = ControlResponse::from_output(LogOutput::StdErr {
+            message: vec![b'o', b'k', 0xff].into(),
+        });
+        assert_eq!(
+            response,
+            Some(ControlResponse::Output {
+                stream: OutputStream::Stderr,
+                data: String::from("ok\u{fffd}"),
+            })
+        );
+    }
+}
@@ -1,175 +1,1837 @@
-use bollard::container::ListContainersOptions;
+use bollard::container::{InspectContainerOptions, ListContainersOptions};
 use bollard::errors::Error;
 use bollard::models::ContainerSummary;
-use bollard::Docker;
-use log::info;
-use std::collections::HashMap;
+use bollard::{ClientVersion, Docker, API_DEFAULT_VERSION};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::future::Future;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
 
-use crate::cli::ContainerArgs;
+use crate::cli::{ContainerArgs, TargetSelector};
+use crate::context::{docker_config_dir, docker_tls, resolve_context_host, DEFAULT_CONTEXT};
+use crate::error::TunnydError;
+use crate::limits::{parse_cpu_secs, parse_memory, ResourceLimits};
 
 const LIST_ALL_CONTAINERS: bool = true;
 const SSH_ENABLE_LABEL_KEY: &str = "tunnyD.enable";
 const SSH_HOSTNAME_LABEL_KEY: &str = "tunnyD.hostname";
 const SSH_ALLOWED_USERS_LABEL_KEY: &str = "tunnyD.allowed.users";
-const EXEC_DOCKER: &str = "docker";
-const SSH_COMMAND_ENV: &str = "SSH_ORIGINAL_COMMAND=${}";
-const COMMAND_SHELL: &str = "sh";
+const SSH_ALLOWED_GROUPS_LABEL_KEY: &str = "tunnyD.allowed.groups";
+const SSH_MOTD_LABEL_KEY: &str = "tunnyD.motd";
+const SSH_DEFAULT_USER_LABEL_KEY: &str = "tunnyD.default.user";
+const SSH_WORKDIR_LABEL_KEY: &str = "tunnyD.workdir";
+const SSH_IDLE_TIMEOUT_LABEL_KEY: &str = "tunnyD.idle.timeout";
+const SSH_READONLY_LABEL_KEY: &str = "tunnyD.readonly";
+const SSH_LIMITS_CPU_LABEL_KEY: &str = "tunnyD.limits.cpu";
+const SSH_LIMITS_MEMORY_LABEL_KEY: &str = "tunnyD.limits.memory";
+const SSH_NETWORK_LABEL_KEY: &str = "tunnyD.network";
+const COMPOSE_PROJECT_LABEL_KEY: &str = "com.docker.compose.project";
+const COMPOSE_SERVICE_LABEL_KEY: &str = "com.docker.compose.service";
+const COMPOSE_REPLICA_LABEL_KEY: &str = "com.docker.compose.container-number";
+/// Length of the shortest id prefix a container can be addressed by, the short id `docker ps` shows.
+const SHORT_ID_LENGTH: usize = 12;
+/// Prefix shared by every label tunnyd reads.
+const LABEL_PREFIX: &str = "tunnyD.";
+/// Prefix of an allowed users entry naming a file on the tunnyd host that lists more users.
+const USERS_FILE_PREFIX: &str = "file:";
+/// How long the contents of a users file are reused before the file is read again.
+const USERS_FILE_TTL: Duration = Duration::from_secs(10);
+const CONTAINER_STATE_RUNNING: &str = "running";
+/// The suffix Docker appends to the status of a container whose health check is failing.
+const CONTAINER_STATUS_UNHEALTHY: &str = "(unhealthy)";
+const RECONNECT_ATTEMPTS: u32 = 3;
+const RECONNECT_INITIAL_BACKOFF: Duration = Duration::from_millis(250);
+const STARTUP_INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+const STARTUP_MAX_BACKOFF: Duration = Duration::from_secs(5);
+/// Request timeout of Docker clients built from a context, the same as bollard's local defaults.
+const DOCKER_TIMEOUT_SECS: u64 = 120;
+/// Environment variable the Docker CLI reads a pinned API version from.
+const DOCKER_API_VERSION_ENV: &str = "DOCKER_API_VERSION";
+/// The host the local defaults connect to when `DOCKER_HOST` isn't set.
+const DEFAULT_DOCKER_HOST: &str = "unix:///var/run/docker.sock";
+/// The oldest API version tunnyd works with, the minimum current daemons still support.
+const MIN_API_VERSION: ClientVersion = ClientVersion {
+    major_version: 1,
+    minor_version: 24,
+};
+
+/// Describes how to reach the Docker daemon.
+///
+/// # Fields
+///
+/// * `context`: The Docker CLI context to connect to, the local defaults when `None`.
+/// * `api_version`: The highest API version to use, e.g. `1.41`, the newest bollard supports when
+///   `None`. The version negotiated with the daemon never exceeds it.
+/// * `host`: The URL of the daemon, e.g. `tcp://node-2:2375`, taking precedence over the context.
+#[derive(Clone, Debug, Default)]
+pub struct DockerSettings {
+    pub context: Option<String>,
+    pub api_version: Option<String>,
+    pub host: Option<String>,
+}
+
+impl DockerSettings {
+    /// Builds the settings of the Docker connection.
+    ///
+    /// # Arguments
+    ///
+    /// * `context` - The Docker CLI context to connect to, the local defaults when `None`.
+    /// * `api_version` - The configured API version, `DOCKER_API_VERSION` is used when `None`.
+    pub fn new(context: Option<String>, api_version: Option<String>) -> DockerSettings {
+        DockerSettings {
+            context,
+            api_version: api_version.or_else(|| {
+                std::env::var(DOCKER_API_VERSION_ENV)
+                    .ok()
+                    .filter(|version| !version.is_empty())
+            }),
+            host: None,
+        }
+    }
+
+    /// Builds the settings of an additional Docker host, sharing the API version of these.
+    ///
+    /// # Arguments
+    ///
+    /// * `host` - The URL of the daemon.
+    pub fn with_host(&self, host: &str) -> DockerSettings {
+        DockerSettings {
+            context: None,
+            api_version: self.api_version.clone(),
+            host: Some(host.to_string()),
+        }
+    }
+
+    /// Returns the API version clients are built with.
+    ///
+    /// # Returns
+    ///
+    /// The configured version, or the newest version bollard supports. An error message if the
+    /// configured version can't be parsed or is not supported.
+    pub fn client_version(&self) -> Result<ClientVersion, String> {
+        match self.api_version.as_deref() {
+            Some(version) => parse_api_version(version),
+            None => Ok(*API_DEFAULT_VERSION),
+        }
+    }
+}
+
+/// Parses a Docker API version.
+///
+/// # Arguments
+///
+/// * `version` - The version, e.g. `1.41`.
+///
+/// # Returns
+///
+/// The version, or an error message if it isn't a version or lies outside the versions tunnyd
+/// supports.
+fn parse_api_version(version: &str) -> Result<ClientVersion, String> {
+    let parsed = version
+        .trim()
+        .trim_start_matches('v')
+        .split_once('.')
+        .and_then(|(major, minor)| Some((major.parse().ok()?, minor.parse().ok()?)));
+    let Some((major_version, minor_version)) = parsed else {
+        return Err(format!(
+            "Invalid Docker API version '{}', expected e.g. 1.41",
+            version
+        ));
+    };
+    let parsed = ClientVersion {
+        major_version,
+        minor_version,
+    };
+    if parsed < MIN_API_VERSION || parsed > *API_DEFAULT_VERSION {
+        return Err(format!(
+            "Unsupported Docker API version {}, supported are {} to {}",
+            parsed, MIN_API_VERSION, API_DEFAULT_VERSION
+        ));
+    }
+    Ok(parsed)
+}
+
+/// A Docker daemon containers are searched on.
+///
+/// # Fields
+///
+/// * `name`: The name of the host used in the logs, its URL or `default` for the primary daemon.
+/// * `docker`: The client of the daemon.
+/// * `cache`: The container list cache of the daemon, shared by every connection.
+pub struct DockerHost {
+    pub name: String,
+    pub docker: DockerClient,
+    pub cache: Arc<Mutex<ContainerCache>>,
+}
+
+/// A Docker client shared by every connection that is rebuilt when the daemon goes away.
+///
+/// # Remarks
+///
+/// Cloning a `DockerClient` is cheap and every clone sees the client rebuilt by any other clone,
+/// so a daemon restart only has to be noticed once.
+#[derive(Clone)]
+pub struct DockerClient {
+    inner: Arc<RwLock<Docker>>,
+    settings: DockerSettings,
+}
+
+/// Checks whether an error means the Docker daemon couldn't be reached, as opposed to the daemon
+/// answering with an error.
+pub fn is_connection_error(error: &Error) -> bool {
+    matches!(
+        error,
+        Error::IOError { .. } | Error::HyperResponseError { .. } | Error::RequestTimeoutError
+    )
+}
+
+/// Checks whether a request failed before it reached the Docker daemon, so it is safe to send
+/// again even if it isn't idempotent.
+///
+/// # Remarks
+///
+/// Timeouts and errors on an established connection don't qualify, the daemon may already have
+/// acted on the request, e.g. created an exec.
+fn is_connect_failure(error: &Error) -> bool {
+    matches!(error, Error::HyperResponseError { err } if err.is_connect())
+}
+
+impl DockerClient {
+    /// Wraps a connected Docker client.
+    ///
+    /// # Arguments
+    ///
+    /// * `docker` - The connected client.
+    /// * `settings` - The settings the client was connected with, reused to reconnect.
+    pub fn new(docker: Docker, settings: DockerSettings) -> DockerClient {
+        DockerClient {
+            inner: Arc::new(RwLock::new(docker)),
+            settings,
+        }
+    }
+
+    /// Returns the current Docker client.
+    pub fn get(&self) -> Docker {
+        match self.inner.read() {
+            Ok(docker) => docker.clone(),
+            Err(poisoned) => poisoned.into_inner().clone(),
+        }
+    }
+
+    /// Rebuilds the Docker client, retrying with an exponential backoff until the daemon answers a ping.
+    ///
+    /// # Returns
+    ///
+    /// The new Docker client, or `None` if the daemon is still unreachable after the last attempt.
+    async fn reconnect(&self) -> Option<Docker> {
+        let mut backoff = RECONNECT_INITIAL_BACKOFF;
+        for attempt in 1..=RECONNECT_ATTEMPTS {
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+            let docker = match connect_to_docker(&self.settings).await {
+                Ok(docker) => docker,
+                Err(e) => {
+                    warn!("Docker reconnect attempt {} failed: {}", attempt, e);
+                    continue;
+                }
+            };
+            match docker.ping().await {
+                Ok(_) => {
+                    info!("Reconnected to Docker after {} attempt(s)", attempt);
+                    match self.inner.write() {
+                        Ok(mut inner) => *inner = docker.clone(),
+                        Err(poisoned) => *poisoned.into_inner() = docker.clone(),
+                    }
+                    return Some(docker);
+                }
+                Err(e) => warn!("Docker reconnect attempt {} failed: {}", attempt, e),
+            }
+        }
+        None
+    }
+
+    /// Runs a Docker operation, reconnecting and retrying it once if the daemon couldn't be reached.
+    /// Operations that may have reached the daemon aren't retried, see `is_connect_failure`.
+    ///
+    /// # Arguments
+    ///
+    /// * `operation` - The operation to run, called with the Docker client to use.
+    ///
+    /// # Returns
+    ///
+    /// The result of the operation. If reconnecting fails, the original connection error is returned.
+    pub async fn with_retry<T, F, Fut>(&self, operation: F) -> Result<T, Error>
+    where
+        F: Fn(Docker) -> Fut,
+        Fut: Future<Output = Result<T, Error>>,
+    {
+        match operation(self.get()).await {
+            Err(e) if is_connect_failure(&e) => {
+                warn!("Lost connection to Docker ({}), reconnecting", e);
+                match self.reconnect().await {
+                    Some(docker) => operation(docker).await,
+                    None => Err(e),
+                }
+            }
+            result => result,
+        }
+    }
+}
+
+/// Caches the container list for a short time to spare the Docker API under load.
+///
+/// # Fields
+///
+/// * `ttl`: How long a fetched container list is served before it is refreshed.
+/// * `entry`: The cached container list and the instant it was fetched at.
+/// * `users_files`: The users files referenced by the allowed users labels of the containers.
+pub struct ContainerCache {
+    ttl: Duration,
+    entry: Option<(Instant, Vec<ContainerSummary>)>,
+    users_files: UsersFiles,
+}
+
+impl ContainerCache {
+    /// Creates an empty cache.
+    ///
+    /// # Arguments
+    ///
+    /// * `ttl` - How long a fetched container list is served before it is refreshed.
+    pub fn new(ttl: Duration) -> ContainerCache {
+        ContainerCache {
+            ttl,
+            entry: None,
+            users_files: UsersFiles::default(),
+        }
+    }
+
+    /// Creates a cache holding a container list that doesn't expire within a test.
+    ///
+    /// # Arguments
+    ///
+    /// * `containers` - The containers served by the cache.
+    #[cfg(test)]
+    pub fn preloaded(containers: Vec<ContainerSummary>) -> ContainerCache {
+        let mut cache = ContainerCache::new(Duration::from_secs(3600));
+        cache.entry = Some((Instant::now(), containers));
+        cache
+    }
+
+    /// Returns the cached container list if it hasn't expired yet.
+    fn cached(&self) -> Option<&Vec<ContainerSummary>> {
+        match &self.entry {
+            Some((fetched_at, containers)) if fetched_at.elapsed() < self.ttl => Some(containers),
+            _ => None,
+        }
+    }
+
+    /// Fetches the container list from Docker and stores it in the cache.
+    async fn refresh(&mut self, docker: &DockerClient) -> Result<&Vec<ContainerSummary>, Error> {
+        let mut containers = docker
+            .with_retry(|docker| async move {
+                let options = ListContainersOptions::<String> {
+                    all: LIST_ALL_CONTAINERS,
+                    ..Default::default()
+                };
+                docker.list_containers(Some(options)).await
+            })
+            .await?;
+        for container in &mut containers {
+            interpolate_container_labels(docker, container).await;
+            resolve_users_files(&mut self.users_files, container).await;
+        }
+        let (_, containers) = self.entry.insert((Instant::now(), containers));
+        Ok(containers)
+    }
+
+    /// Drops the cached container list so the next lookup fetches it from Docker.
+    pub fn invalidate(&mut self) {
+        self.entry = None;
+    }
+}
+
+/// Resolves `${VAR}` references in the tunnyd labels of a container against its environment.
+///
+/// # Arguments
+///
+/// * `docker` - The Docker client the container is inspected with.
+/// * `container` - The container, its labels are rewritten in place.
+///
+/// # Remarks
+///
+/// Containers are only inspected if one of their tunnyd labels references a variable. If the
+/// inspection fails the labels are left as written.
+async fn interpolate_container_labels(docker: &DockerClient, container: &mut ContainerSummary) {
+    let Some(labels) = container.labels.as_mut() else {
+        return;
+    };
+    let Some(id) = container.id.as_deref() else {
+        return;
+    };
+    if !labels
+        .iter()
+        .any(|(key, value)| key.starts_with(LABEL_PREFIX) && value.contains("${"))
+    {
+        return;
+    }
+    let config = match docker
+        .get()
+        .inspect_container(id, None::<InspectContainerOptions>)
+        .await
+    {
+        Ok(inspect) => inspect.config.unwrap_or_default(),
+        Err(e) => {
+            warn!("Failed to inspect container {} for its labels: {}", id, e);
+            return;
+        }
+    };
+    let mut env: HashMap<String, String> = config
+        .env
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|variable| variable.split_once('='))
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect();
+    // Docker sets HOSTNAME when the container starts, it isn't part of the configured environment
+    if let Some(hostname) = config.hostname {
+        env.entry(String::from("HOSTNAME")).or_insert(hostname);
+    }
+    interpolate_labels(labels, &env, id);
+}
+
+/// Resolves `${VAR}` references in tunnyd labels.
+///
+/// # Arguments
+///
+/// * `labels` - The labels of a container, rewritten in place. Labels without the tunnyd prefix
+///   are left alone.
+/// * `env` - The environment of the container.
+/// * `container` - The ID of the container, used in warnings.
+///
+/// # Remarks
+///
+/// A label referencing a variable the container doesn't set is left as written, so it can't match
+/// by accident, e.g. an empty hostname.
+fn interpolate_labels(
+    labels: &mut HashMap<String, String>,
+    env: &HashMap<String, String>,
+    container: &str,
+) {
+    for (key, value) in labels
+        .iter_mut()
+        .filter(|(key, _)| key.starts_with(LABEL_PREFIX))
+    {
+        match interpolate(value, env) {
+            Ok(interpolated) => *value = interpolated,
+            Err(missing) => warn!(
+                "Label {} of container {} references ${{{}}}, which isn't set, using it as written",
+                key, container, missing
+            ),
+        }
+    }
+}
+
+/// Resolves the `${VAR}` references in a label value.
+///
+/// # Arguments
+///
+/// * `value` - The label value.
+/// * `env` - The variables references are resolved against.
+///
+/// # Returns
+///
+/// The value with every reference replaced, or the name of the first variable that isn't set. A
+/// `${` without a closing brace is kept literally.
+fn interpolate(value: &str, env: &HashMap<String, String>) -> Result<String, String> {
+    let mut interpolated = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(start) = rest.find("${") {
+        let Some(length) = rest[start + 2..].find('}') else {
+            break;
+        };
+        let name = &rest[start + 2..start + 2 + length];
+        let resolved = env.get(name).ok_or_else(|| name.to_string())?;
+        interpolated.push_str(&rest[..start]);
+        interpolated.push_str(resolved);
+        rest = &rest[start + 2 + length + 1..];
+    }
+    interpolated.push_str(rest);
+    Ok(interpolated)
+}
+
+/// Caches the users files referenced by `file:` entries of the allowed users label.
+///
+/// # Fields
+///
+/// * `entries`: The users listed in each file, `None` if it couldn't be read, and when it was read.
+#[derive(Default)]
+struct UsersFiles {
+    entries: HashMap<String, (Instant, Option<Vec<String>>)>,
+}
+
+impl UsersFiles {
+    /// Returns the users listed in a file, reading it again once the cached contents expired.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path of the file on the tunnyd host.
+    ///
+    /// # Returns
+    ///
+    /// The users, or `None` if the file can't be read.
+    async fn users(&mut self, path: &str) -> Option<Vec<String>> {
+        if let Some((read_at, users)) = self.entries.get(path) {
+            if read_at.elapsed() < USERS_FILE_TTL {
+                return users.clone();
+            }
+        }
+        let users = match tokio::fs::read_to_string(path).await {
+            Ok(contents) => Some(parse_users_file(&contents)),
+            Err(e) => {
+                warn!("Failed to read users file {}: {}", path, e);
+                None
+            }
+        };
+        self.entries
+            .insert(path.to_string(), (Instant::now(), users.clone()));
+        users
+    }
+}
+
+/// Splits a users file into user names.
+///
+/// # Arguments
+///
+/// * `contents` - The file, with one or more comma separated users per line. Blank lines and lines
+///   starting with `#` are skipped.
+fn parse_users_file(contents: &str) -> Vec<String> {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.starts_with('#'))
+        .flat_map(parse_allowed_users)
+        .collect()
+}
+
+/// Replaces the `file:` entries of a container's allowed users label with the users the files list.
+///
+/// # Arguments
+///
+/// * `files` - The cache the files are read through.
+/// * `container` - The container, its allowed users label is rewritten in place.
+///
+/// # Remarks
+///
+/// An entry whose file can't be read, or lists nobody, is left as written. It can't match a user
+/// then, so a missing file denies access instead of lifting the restriction.
+async fn resolve_users_files(files: &mut UsersFiles, container: &mut ContainerSummary) {
+    let Some(value) = container
+        .labels
+        .as_mut()
+        .and_then(|labels| labels.get_mut(SSH_ALLOWED_USERS_LABEL_KEY))
+    else {
+        return;
+    };
+    if !value.contains(USERS_FILE_PREFIX) {
+        return;
+    }
+    let mut users = Vec::new();
+    for entry in parse_allowed_users(value) {
+        match entry.strip_prefix(USERS_FILE_PREFIX) {
+            Some(path) => match files.users(path.trim()).await {
+                Some(listed) if !listed.is_empty() => users.extend(listed),
+                _ => users.push(entry),
+            },
+            None => users.push(entry),
+        }
+    }
+    *value = users.join(",");
+}
+
+/// Describes why a container doesn't match a request.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Rejection {
+    /// The enable label is missing or not `true`.
+    NotEnabled,
+    /// The hostname label is missing or empty.
+    EmptyHostname,
+    /// The request has an empty target.
+    EmptyTarget,
+    /// The hostname label names another target.
+    HostnameMismatch { hostname: String },
+    /// The target is a compose service the container doesn't belong to, `service` is the
+    /// `project/service/replica` of the container if it was started by Docker Compose.
+    ComposeMismatch { service: Option<String> },
+    /// Neither the user nor any of its groups is allowed.
+    UserNotAllowed { user: String },
+    /// The image of the container is not in the allowed images.
+    ImageNotAllowed { image: String },
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rejection::NotEnabled => write!(f, "{} is not 'true'", SSH_ENABLE_LABEL_KEY),
+            Rejection::EmptyHostname => write!(f, "{} is missing or empty", SSH_HOSTNAME_LABEL_KEY),
+            Rejection::EmptyTarget => write!(f, "the target is empty"),
+            Rejection::HostnameMismatch { hostname } => {
+                write!(
+                    f,
+                    "{} '{}' doesn't match the target",
+                    SSH_HOSTNAME_LABEL_KEY, hostname
+                )
+            }
+            Rejection::ComposeMismatch { service: None } => write!(
+                f,
+                "{} and {} are missing",
+                COMPOSE_PROJECT_LABEL_KEY, COMPOSE_SERVICE_LABEL_KEY
+            ),
+            Rejection::ComposeMismatch {
+                service: Some(service),
+            } => write!(f, "compose service '{}' doesn't match the target", service),
+            Rejection::UserNotAllowed { user } => write!(
+                f,
+                "user '{}' is not listed in {} and none of its groups is listed in {}",
+                user, SSH_ALLOWED_USERS_LABEL_KEY, SSH_ALLOWED_GROUPS_LABEL_KEY
+            ),
+            Rejection::ImageNotAllowed { image } => {
+                write!(f, "image '{}' is not in allowed_images", image)
+            }
+        }
+    }
+}
+
+/// A target addressing a Docker Compose service rather than a hostname label.
+///
+/// # Fields
+///
+/// * `project`: The compose project, matched against `com.docker.compose.project`.
+/// * `service`: The service of the project, matched against `com.docker.compose.service`.
+/// * `replica`: The replica of a scaled service, matched against
+///   `com.docker.compose.container-number`. Any replica matches without it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct ComposeTarget<'a> {
+    project: &'a str,
+    service: &'a str,
+    replica: Option<&'a str>,
+}
+
+impl ComposeTarget<'_> {
+    /// Reads a target of the form `project/service` or `project/service/replica`.
+    ///
+    /// # Returns
+    ///
+    /// The compose target, or `None` if the target has no `/` or any of its parts is empty.
+    fn parse(target: &str) -> Option<ComposeTarget<'_>> {
+        let mut parts = target.trim().split('/');
+        let project = parts.next()?;
+        let service = parts.next()?;
+        let replica = parts.next();
+        if parts.next().is_some()
+            || project.is_empty()
+            || service.is_empty()
+            || replica.is_some_and(str::is_empty)
+        {
+            return None;
+        }
+        Some(ComposeTarget {
+            project,
+            service,
+            replica,
+        })
+    }
+
+    /// Checks whether a container with the given labels belongs to the service.
+    fn matches(&self, labels: &HashMap<String, String>) -> bool {
+        let label = |key: &str| labels.get(key).map(String::as_str);
+        label(COMPOSE_PROJECT_LABEL_KEY) == Some(self.project)
+            && label(COMPOSE_SERVICE_LABEL_KEY) == Some(self.service)
+            && self.replica.map_or(true, |replica| {
+                label(COMPOSE_REPLICA_LABEL_KEY) == Some(replica)
+            })
+    }
+}
+
+/// Describes the compose service a container belongs to.
+///
+/// # Returns
+///
+/// `project/service/replica`, without the replica if Compose didn't number the container, or
+/// `None` if the container wasn't started by Docker Compose.
+fn compose_service(labels: &HashMap<String, String>) -> Option<String> {
+    let project = labels.get(COMPOSE_PROJECT_LABEL_KEY)?;
+    let service = labels.get(COMPOSE_SERVICE_LABEL_KEY)?;
+    Some(match labels.get(COMPOSE_REPLICA_LABEL_KEY) {
+        Some(replica) => format!("{}/{}/{}", project, service, replica),
+        None => format!("{}/{}", project, service),
+    })
+}
+
+/// Returns the replica number Docker Compose gave a container, if any.
+fn compose_replica(container: &ContainerSummary) -> Option<u32> {
+    container
+        .labels
+        .as_ref()?
+        .get(COMPOSE_REPLICA_LABEL_KEY)?
+        .parse()
+        .ok()
+}
 
 /// Checks the validity of a container based on its labels, target, and user.
 ///
+/// User names are compared case-insensitively and whitespace around the entries of the
+/// allowed users label is ignored. For a `user:group` value only the user is compared.
+/// A container with an empty hostname label never matches, and neither does an empty target.
+/// Targets of the form `project/service[/replica]` are matched against the Docker Compose labels
+/// instead of the hostname label, the container still has to be enabled.
+///
+/// Access is granted if the user is listed in the allowed users label or is a member of any group
+/// listed in the allowed groups label. A container with neither label is open to every user.
+///
+/// # Arguments
+///
+/// * `labels` - A HashMap of labels associated with the container.
+/// * `target` - The target label value to match against the SSH hostname label.
+/// * `user` - The user label value to match against the allowed users label.
+/// * `groups` - The groups the user is a member of, matched against the allowed groups label.
+///
+/// # Returns
+///
+/// Returns a boolean indicating whether the container is valid or not.
+///
+/// # Examples
+///
+/// ```ignore
+/// use std::collections::HashMap;
+///
+/// let labels = {
+///     let mut hashmap = HashMap::new();
+///     hashmap.insert(String::from("tunnyD.enable"), String::from("true"));
+///     hashmap.insert(String::from("tunnyD.hostname"), String::from("myhost"));
+///     hashmap.insert(String::from("tunnyD.allowed.users"), String::from("user1,user2"));
+///     hashmap
+/// };
+///
+/// assert_eq!(true, check_container_validity(&labels, "myhost", "user1", &[]));
+/// assert_eq!(false, check_container_validity(&labels, "otherhost", "user3", &[]));
+/// ```
+fn check_container_validity(
+    labels: &HashMap<String, String>,
+    target: &str,
+    user: &str,
+    groups: &[String],
+) -> bool {
+    container_rejection(labels, target, user, groups).is_none()
+}
+
+/// Explains why a container doesn't match a request.
+///
+/// # Arguments
+///
+/// * `labels` - A HashMap of labels associated with the container.
+/// * `target` - The target label value to match against the SSH hostname label.
+/// * `user` - The user label value to match against the allowed users label.
+/// * `groups` - The groups the user is a member of, matched against the allowed groups label.
+///
+/// # Returns
+///
+/// The first check the container fails, or `None` if it matches.
+fn container_rejection(
+    labels: &HashMap<String, String>,
+    target: &str,
+    user: &str,
+    groups: &[String],
+) -> Option<Rejection> {
+    if labels.get(SSH_ENABLE_LABEL_KEY).map(String::as_str) != Some("true") {
+        return Some(Rejection::NotEnabled);
+    }
+    if let Some(compose) = ComposeTarget::parse(target) {
+        if !compose.matches(labels) {
+            return Some(Rejection::ComposeMismatch {
+                service: compose_service(labels),
+            });
+        }
+        return match is_user_allowed(labels, user, groups) {
+            true => None,
+            false => Some(Rejection::UserNotAllowed {
+                user: user.to_string(),
+            }),
+        };
+    }
+    // An empty hostname label or target must never match, or a misconfigured container
+    // would be exposed to any client sending an empty target
+    let hostname = labels
+        .get(SSH_HOSTNAME_LABEL_KEY)
+        .map(|hostname| hostname.trim())
+        .unwrap_or_default();
+    if hostname.is_empty() {
+        return Some(Rejection::EmptyHostname);
+    }
+    if target.trim().is_empty() {
+        return Some(Rejection::EmptyTarget);
+    }
+    if hostname != target {
+        return Some(Rejection::HostnameMismatch {
+            hostname: hostname.to_string(),
+        });
+    }
+    if !is_user_allowed(labels, user, groups) {
+        return Some(Rejection::UserNotAllowed {
+            user: user.to_string(),
+        });
+    }
+    None
+}
+
+/// Describes how a container fared against a request, as reported by the `resolve` subcommand.
+///
+/// # Fields
+///
+/// * `name`: The name of the container, or its id if it has no name.
+/// * `state`: The state Docker reports for the container, e.g. `running` or `exited`.
+/// * `labels`: The tunnyd labels of the container.
+/// * `rejection`: Why the container doesn't match, `None` if it does.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Candidate {
+    pub name: String,
+    pub state: Option<String>,
+    pub labels: BTreeMap<String, String>,
+    pub rejection: Option<Rejection>,
+}
+
+/// Matches every container carrying tunnyd labels against a request.
+///
+/// # Arguments
+///
+/// * `containers` - The containers to check.
+/// * `args` - The requested target.
+/// * `user` - The user the connection authenticated as, checked against the allowed users.
+/// * `groups` - The groups the user is a member of.
+/// * `images` - The allowed images, empty allows every image.
+///
+/// # Returns
+///
+/// One `Candidate` per container with at least one tunnyd label, in the order Docker listed them.
+pub fn diagnose_candidates(
+    containers: &[ContainerSummary],
+    args: &ContainerArgs,
+    user: &str,
+    groups: &[String],
+    images: &[String],
+) -> Vec<Candidate> {
+    containers
+        .iter()
+        .filter_map(|container| {
+            let labels = container.labels.as_ref()?;
+            let tunnyd_labels: BTreeMap<String, String> = labels
+                .iter()
+                .filter(|(key, _)| key.starts_with(LABEL_PREFIX))
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect();
+            if tunnyd_labels.is_empty() {
+                return None;
+            }
+            Some(Candidate {
+                name: container_name(container)
+                    .or(container.id.as_deref())
+                    .unwrap_or_default()
+                    .to_string(),
+                state: container.state.clone(),
+                labels: tunnyd_labels,
+                rejection: container_rejection(labels, &args.target, user, groups).or_else(|| {
+                    match is_image_allowed(container.image.as_deref(), images) {
+                        true => None,
+                        false => Some(Rejection::ImageNotAllowed {
+                            image: container.image.clone().unwrap_or_default(),
+                        }),
+                    }
+                }),
+            })
+        })
+        .collect()
+}
+
+/// Describes a container exposing a tunnyd hostname, as listed by the `list-targets` subcommand.
+///
+/// # Fields
+///
+/// * `hostname`: The value of the hostname label.
+/// * `allowed_users`: The users listed in the allowed users label.
+/// * `state`: The state Docker reports for the container, e.g. `running` or `exited`.
+/// * `id`: The id of the container.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Target {
+    pub hostname: String,
+    pub allowed_users: Vec<String>,
+    pub state: Option<String>,
+    pub id: Option<String>,
+}
+
+/// Collects the containers with the enable label set to `true`.
+///
+/// # Arguments
+///
+/// * `containers` - The containers to check.
+///
+/// # Returns
+///
+/// One `Target` per enabled container, in the order Docker listed them.
+pub fn enabled_targets(containers: &[ContainerSummary]) -> Vec<Target> {
+    containers
+        .iter()
+        .filter_map(|container| {
+            let labels = container.labels.as_ref()?;
+            if labels.get(SSH_ENABLE_LABEL_KEY).map(String::as_str) != Some("true") {
+                return None;
+            }
+            Some(Target {
+                hostname: labels
+                    .get(SSH_HOSTNAME_LABEL_KEY)
+                    .map(|hostname| hostname.trim().to_string())
+                    .unwrap_or_default(),
+                allowed_users: labels
+                    .get(SSH_ALLOWED_USERS_LABEL_KEY)
+                    .map_or(Vec::new(), |users| parse_allowed_users(users)),
+                state: container.state.clone(),
+                id: container.id.clone(),
+            })
+        })
+        .collect()
+}
+
+/// Lists the containers exposing a tunnyd hostname.
+///
+/// # Arguments
+///
+/// * `docker` - The Docker client used to list the containers.
+///
+/// # Returns
+///
+/// The enabled containers, or the Docker error encountered while listing them.
+pub async fn list_targets(docker: &DockerClient) -> Result<Vec<Target>, TunnydError> {
+    let mut cache = ContainerCache::new(Duration::ZERO);
+    let containers = cache.refresh(docker).await?;
+    Ok(enabled_targets(containers))
+}
+
+/// Collects the enabled containers a user may open sessions on.
+///
+/// # Arguments
+///
+/// * `containers` - The containers to check.
+/// * `user` - The requested user.
+/// * `groups` - The groups the requested user is a member of.
+/// * `images` - The allowed images, empty allows every image.
+///
+/// # Returns
+///
+/// One `Target` per enabled container the user is allowed on, in the order Docker listed them.
+pub fn allowed_targets(
+    containers: &[ContainerSummary],
+    user: &str,
+    groups: &[String],
+    images: &[String],
+) -> Vec<Target> {
+    let allowed: Vec<ContainerSummary> = containers
+        .iter()
+        .filter(|container| is_image_allowed(container.image.as_deref(), images))
+        .filter(|container| {
+            container
+                .labels
+                .as_ref()
+                .is_some_and(|labels| is_user_allowed(labels, user, groups))
+        })
+        .cloned()
+        .collect();
+    enabled_targets(&allowed)
+}
+
+/// Lists the targets a user may open sessions on, refreshing the container caches of the hosts.
+///
+/// # Arguments
+///
+/// * `hosts` - The Docker hosts the containers are listed on.
+/// * `user` - The requested user.
+/// * `groups` - The groups the requested user is a member of.
+/// * `images` - The allowed images, empty allows every image.
+///
+/// # Returns
+///
+/// The targets of every host, or the Docker error encountered if no host could be listed. Hosts
+/// that can't be reached are skipped.
+pub async fn targets_for_user(
+    hosts: &[DockerHost],
+    user: &str,
+    groups: &[String],
+    images: &[String],
+) -> Result<Vec<Target>, TunnydError> {
+    let results = futures::future::join_all(hosts.iter().map(|host| async move {
+        let mut cache = host.cache.lock().await;
+        let containers = cache.refresh(&host.docker).await?;
+        Ok::<_, TunnydError>(allowed_targets(containers, user, groups, images))
+    }))
+    .await;
+    let mut targets = Vec::new();
+    let mut failure = None;
+    let mut listed = false;
+    for (host, result) in hosts.iter().zip(results) {
+        match result {
+            Ok(host_targets) => {
+                listed = true;
+                targets.extend(host_targets);
+            }
+            Err(e) => {
+                warn!("Skipping Docker host {}: {}", host.name, e);
+                failure.get_or_insert(e);
+            }
+        }
+    }
+    match (listed, failure) {
+        (false, Some(e)) => Err(e),
+        _ => Ok(targets),
+    }
+}
+
+/// Lists the containers carrying tunnyd labels and how each of them fares against a request.
+///
+/// # Arguments
+///
+/// * `docker` - The Docker client used to list the containers.
+/// * `args` - The requested target.
+/// * `user` - The user the connection authenticated as, checked against the allowed users.
+/// * `groups` - The groups the user is a member of.
+/// * `images` - The allowed images, empty allows every image.
+///
+/// # Returns
+///
+/// The candidates, or the Docker error encountered while listing the containers.
+pub async fn list_candidates(
+    docker: &DockerClient,
+    args: &ContainerArgs,
+    user: &str,
+    groups: &[String],
+    images: &[String],
+) -> Result<Vec<Candidate>, TunnydError> {
+    let mut cache = ContainerCache::new(Duration::ZERO);
+    let containers = cache.refresh(docker).await?;
+    Ok(diagnose_candidates(containers, args, user, groups, images))
+}
+
+/// Checks whether a container runs an allowed image.
+///
+/// # Arguments
+///
+/// * `image` - The image of the container, as Docker reports it.
+/// * `allowed` - The allowed images. A trailing `*` matches any suffix, a name without a tag or
+///   digest matches every tag and digest of it.
+///
+/// # Returns
+///
+/// `true` if no images are configured or the image matches one of them.
+fn is_image_allowed(image: Option<&str>, allowed: &[String]) -> bool {
+    if allowed.is_empty() {
+        return true;
+    }
+    let Some(image) = image else {
+        return false;
+    };
+    allowed
+        .iter()
+        .any(|pattern| match pattern.strip_suffix('*') {
+            Some(prefix) => image.starts_with(prefix),
+            None => image
+                .strip_prefix(pattern.as_str())
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with([':', '@'])),
+        })
+}
+
+/// Checks whether a user may access a container, regardless of its hostname.
+///
+/// # Arguments
+///
+/// * `labels` - A HashMap of labels associated with the container.
+/// * `user` - The user, for a `user:group` value only the user is compared.
+/// * `groups` - The groups the user is a member of.
+///
+/// # Returns
+///
+/// `true` if the container lists neither allowed users nor allowed groups, or if the user or one
+/// of its groups is listed.
+fn is_user_allowed(labels: &HashMap<String, String>, user: &str, groups: &[String]) -> bool {
+    // Assuming values for SSH_ALLOWED_USERS_LABEL_KEY and SSH_ALLOWED_GROUPS_LABEL_KEY are comma separated
+    let allow_users = labels
+        .get(SSH_ALLOWED_USERS_LABEL_KEY)
+        .map_or(Vec::new(), |users| parse_allowed_users(users));
+    let allow_groups = labels
+        .get(SSH_ALLOWED_GROUPS_LABEL_KEY)
+        .map_or(Vec::new(), |groups| parse_allowed_users(groups));
+    let user = user.split(':').next().unwrap_or_default().trim();
+    (allow_users.is_empty() && allow_groups.is_empty())
+        || (!user.is_empty()
+            && allow_users
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(user)))
+        || groups.iter().any(|group| {
+            allow_groups
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(group))
+        })
+}
+
+/// Splits the value of the allowed users or groups label into individual names.
+///
+/// Each entry is trimmed of surrounding whitespace and empty entries (e.g. from a trailing
+/// comma) are skipped, so `" alice, bob,"` yields `["alice", "bob"]`.
+///
+/// # Arguments
+///
+/// * `users` - The raw, comma separated value of the allowed users label.
+///
+/// # Returns
+///
+/// A vector of the non-empty user names in the order they appear in the label.
+fn parse_allowed_users(users: &str) -> Vec<String> {
+    users
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(|s| s.to_string())
+        .collect::<Vec<String>>()
+}
+
+/// Returns the name of a container without the leading `/` Docker reports it with.
+///
+/// # Arguments
+///
+/// * `container` - The container summary to read the name from.
+///
+/// # Returns
+///
+/// The first name of the container, or `None` if the container has no name.
+pub fn container_name(container: &ContainerSummary) -> Option<&str> {
+    container
+        .names
+        .as_ref()
+        .and_then(|names| names.first())
+        .map(|name| name.trim_start_matches('/'))
+}
+
+/// Returns the message of the day configured on a container, ready to be written to a terminal.
+///
+/// # Arguments
+///
+/// * `container` - The container summary to read the `tunnyD.motd` label from.
+///
+/// # Returns
+///
+/// The label value with every line ending translated to `\r\n`, or `None` if the label is absent
+/// or empty.
+pub fn container_motd(container: &ContainerSummary) -> Option<String> {
+    let motd = container.labels.as_ref()?.get(SSH_MOTD_LABEL_KEY)?;
+    if motd.is_empty() {
+        return None;
+    }
+    let mut motd = motd.replace("\r\n", "\n").replace('\n', "\r\n");
+    if !motd.ends_with("\r\n") {
+        motd.push_str("\r\n");
+    }
+    Some(motd)
+}
+
+/// Returns the user shells land as when the client doesn't pick one.
+///
+/// # Arguments
+///
+/// * `container` - The container summary to read the `tunnyD.default.user` label from.
+///
+/// # Returns
+///
+/// The label value, either `user` or `user:group`, or `None` if the label is absent or empty.
+pub fn container_default_user(container: &ContainerSummary) -> Option<String> {
+    container
+        .labels
+        .as_ref()?
+        .get(SSH_DEFAULT_USER_LABEL_KEY)
+        .map(|user| user.trim().to_string())
+        .filter(|user| !user.is_empty())
+}
+
+/// Returns the working directory shells start in.
+///
+/// # Arguments
+///
+/// * `container` - The container summary to read the `tunnyD.workdir` label from.
+///
+/// # Returns
+///
+/// The label value, or `None` if the label is absent or empty.
+pub fn container_workdir(container: &ContainerSummary) -> Option<String> {
+    container
+        .labels
+        .as_ref()?
+        .get(SSH_WORKDIR_LABEL_KEY)
+        .map(|workdir| workdir.trim().to_string())
+        .filter(|workdir| !workdir.is_empty())
+}
+
+/// Checks whether a container only allows read-only sessions.
+///
+/// # Arguments
+///
+/// * `container` - The container summary to read the `tunnyD.readonly` label from.
+///
+/// # Returns
+///
+/// `true` if the label is `true`, sessions then get no stdin.
+pub fn container_readonly(container: &ContainerSummary) -> bool {
+    container
+        .labels
+        .as_ref()
+        .and_then(|labels| labels.get(SSH_READONLY_LABEL_KEY))
+        .is_some_and(|readonly| readonly.trim() == "true")
+}
+
+/// Returns the Docker network port forwards of a container default to.
+///
+/// # Arguments
+///
+/// * `container` - The container summary to read the `tunnyD.network` label from.
+///
+/// # Returns
+///
+/// The network name, `None` if the label is absent or empty, or a `TunnydError::UnknownNetwork`
+/// if the container isn't attached to the network.
+pub fn container_network(container: &ContainerSummary) -> Result<Option<String>, TunnydError> {
+    let Some(network) = container
+        .labels
+        .as_ref()
+        .and_then(|labels| labels.get(SSH_NETWORK_LABEL_KEY))
+        .map(|network| network.trim())
+        .filter(|network| !network.is_empty())
+    else {
+        return Ok(None);
+    };
+    let attached = container
+        .network_settings
+        .as_ref()
+        .and_then(|settings| settings.networks.as_ref())
+        .is_some_and(|networks| networks.contains_key(network));
+    match attached {
+        true => Ok(Some(network.to_string())),
+        false => Err(TunnydError::UnknownNetwork {
+            network: network.to_string(),
+        }),
+    }
+}
+
+/// Returns the resource limits applied to the sessions of a container.
+///
+/// # Arguments
+///
+/// * `container` - The container summary to read the `tunnyD.limits.cpu` and `tunnyD.limits.memory`
+///   labels from.
+///
+/// # Returns
+///
+/// The limits, unset for absent or empty labels, or a `TunnydError::InvalidLimit` if a label
+/// can't be parsed. Sessions are refused then rather than run without the intended limit.
+pub fn container_limits(container: &ContainerSummary) -> Result<ResourceLimits, TunnydError> {
+    let label = |key: &str| {
+        container
+            .labels
+            .as_ref()
+            .and_then(|labels| labels.get(key))
+            .map(|value| value.trim())
+            .filter(|value| !value.is_empty())
+    };
+    let cpu_secs = match label(SSH_LIMITS_CPU_LABEL_KEY) {
+        Some(value) => Some(parse_cpu_secs(value).ok_or(TunnydError::InvalidLimit {
+            label: SSH_LIMITS_CPU_LABEL_KEY,
+            value: value.to_string(),
+            expected: "a positive number of CPU seconds",
+        })?),
+        None => None,
+    };
+    let memory_bytes = match label(SSH_LIMITS_MEMORY_LABEL_KEY) {
+        Some(value) => Some(parse_memory(value).ok_or(TunnydError::InvalidLimit {
+            label: SSH_LIMITS_MEMORY_LABEL_KEY,
+            value: value.to_string(),
+            expected: "a size like 512m",
+        })?),
+        None => None,
+    };
+    Ok(ResourceLimits {
+        cpu_secs,
+        memory_bytes,
+    })
+}
+
+/// Returns the idle timeout configured on a container.
+///
+/// # Arguments
+///
+/// * `container` - The container summary to read the `tunnyD.idle.timeout` label from.
+///
+/// # Returns
+///
+/// The timeout in seconds, `0` disabling it, or `None` if the label is absent or not a number of
+/// seconds. Invalid values are logged.
+pub fn container_idle_timeout(container: &ContainerSummary) -> Option<u64> {
+    let value = container
+        .labels
+        .as_ref()?
+        .get(SSH_IDLE_TIMEOUT_LABEL_KEY)?
+        .trim();
+    match value.parse() {
+        Ok(secs) => Some(secs),
+        Err(_) => {
+            warn!(
+                "Ignoring invalid {} label {:?} of container {:?}",
+                SSH_IDLE_TIMEOUT_LABEL_KEY, value, container.id
+            );
+            None
+        }
+    }
+}
+
+/// Finds an SSH-enabled container based on the provided arguments.
+///
+/// # Arguments
+///
+/// * `docker` - The Docker client used to list the containers.
+/// * `args` - The arguments used to filter the containers.
+/// * `user` - The user the connection authenticated as, checked against the allowed users.
+/// * `groups` - The groups the user is a member of.
+/// * `images` - The allowed images, empty allows every image.
+/// * `cache` - The container list cache shared by the server.
+///
+/// # Returns
+///
+/// * `Result<ContainerSummary, TunnydError>` - The container summary if a match is found, otherwise
+///   `TunnydError::ContainerNotFound` or the Docker error encountered while listing the containers.
+///
+/// # Remarks
+///
+/// The container list is served from `cache` while it is fresh. If the cached match is no longer
+/// running, the cache is invalidated and the lookup is repeated against Docker.
+///
+/// # Examples
+///
+/// ```ignore
+/// use crate::cli::ContainerArgs;
+///
+/// let args = ContainerArgs {
+///     target: String::from("name_matching_docker_label_tunnyD.hostname"),
+///     user: Some(String::from("root")),
+///     selector: TargetSelector::Hostname,
+///     command: Vec::new(),
+///     share: None,
+///     attach: false,
+/// };
+///
+/// let result = find_ssh_enabled_container(&docker, &args, "root", &[], &[], &cache).await;
+/// ```
+pub async fn find_ssh_enabled_container(
+    docker: &DockerClient,
+    args: &ContainerArgs,
+    user: &str,
+    groups: &[String],
+    images: &[String],
+    cache: &Mutex<ContainerCache>,
+) -> Result<ContainerSummary, TunnydError> {
+    let mut cache = cache.lock().await;
+    if let Some(containers) = cache.cached() {
+        match select_container(containers, args, user, groups, images) {
+            Some(container) if container.state.as_deref() == Some(CONTAINER_STATE_RUNNING) => {
+                return Ok(container.clone());
+            }
+            Some(_) => {
+                info!(
+                    "Cached container for {} is not running, refreshing",
+                    args.target
+                );
+                cache.invalidate();
+            }
+            None => {
+                return Err(no_matching_container(
+                    containers, args, user, groups, images,
+                ))
+            }
+        }
+    }
+    let containers = cache.refresh(docker).await?;
+    select_container(containers, args, user, groups, images)
+        .cloned()
+        .ok_or_else(|| no_matching_container(containers, args, user, groups, images))
+}
+
+/// Searches every Docker host for the container matching the provided arguments.
+///
+/// # Arguments
+///
+/// * `hosts` - The Docker hosts, in order of preference.
+/// * `args` - The arguments used to filter the containers.
+/// * `user` - The user the connection authenticated as, checked against the allowed users.
+/// * `groups` - The groups the user is a member of.
+/// * `images` - The allowed images, empty allows every image.
+///
+/// # Returns
+///
+/// The matching container and the index of the host it runs on, or a `TunnydError` if no host
+/// has a matching container.
+///
+/// # Remarks
+///
+/// The hosts are searched in parallel. If several hosts have a match, the first host in the
+/// configured order wins. Hosts that can't be reached are skipped, their error is only returned if
+/// no host could be searched at all.
+pub async fn find_ssh_enabled_container_on_hosts(
+    hosts: &[DockerHost],
+    args: &ContainerArgs,
+    user: &str,
+    groups: &[String],
+    images: &[String],
+) -> Result<(ContainerSummary, usize), TunnydError> {
+    let results = futures::future::join_all(hosts.iter().map(|host| {
+        find_ssh_enabled_container(&host.docker, args, user, groups, images, &host.cache)
+    }))
+    .await;
+    let names: Vec<&str> = hosts.iter().map(|host| host.name.as_str()).collect();
+    first_match(&names, results, || TunnydError::ContainerNotFound {
+        target: args.target.clone(),
+    })
+}
+
+/// Looks up the container that served a target before, if it still matches.
+///
 /// # Arguments
 ///
-/// * `labels` - A HashMap of labels associated with the container.
-/// * `target` - The target label value to match against the SSH hostname label.
-/// * `user` - The user label value to match against the allowed users label.
+/// * `host` - The Docker host the container ran on.
+/// * `args` - The arguments used to filter the containers.
+/// * `user` - The user the connection authenticated as, checked against the allowed users.
+/// * `groups` - The groups the user is a member of.
+/// * `images` - The allowed images, empty allows every image.
+/// * `container_id` - The ID of the container that served the target.
 ///
 /// # Returns
 ///
-/// Returns a boolean indicating whether the container is valid or not.
+/// The container, `None` if it is gone, stopped, unhealthy or no longer matches the target, or the
+/// Docker error encountered while listing the containers.
+pub async fn find_preferred_container(
+    host: &DockerHost,
+    args: &ContainerArgs,
+    user: &str,
+    groups: &[String],
+    images: &[String],
+    container_id: &str,
+) -> Result<Option<ContainerSummary>, TunnydError> {
+    let mut cache = host.cache.lock().await;
+    match cache.cached() {
+        Some(containers) => {
+            Ok(select_preferred(containers, args, user, groups, images, container_id).cloned())
+        }
+        None => Ok(select_preferred(
+            cache.refresh(&host.docker).await?,
+            args,
+            user,
+            groups,
+            images,
+            container_id,
+        )
+        .cloned()),
+    }
+}
+
+/// Selects the container with the given ID if it is running, healthy and matches the arguments.
 ///
-/// # Examples
+/// # Arguments
 ///
-/// ```
-/// use std::collections::HashMap;
+/// * `containers` - The containers to select from.
+/// * `args` - The arguments used to filter the containers.
+/// * `user` - The user the connection authenticated as, checked against the allowed users.
+/// * `groups` - The groups the user is a member of.
+/// * `images` - The allowed images, empty allows every image.
+/// * `container_id` - The ID of the container.
+fn select_preferred<'a>(
+    containers: &'a [ContainerSummary],
+    args: &ContainerArgs,
+    user: &str,
+    groups: &[String],
+    images: &[String],
+    container_id: &str,
+) -> Option<&'a ContainerSummary> {
+    containers.iter().find(|container| {
+        container.id.as_deref() == Some(container_id)
+            && container.state.as_deref() == Some(CONTAINER_STATE_RUNNING)
+            && !is_unhealthy(container)
+            && matches_target(container, args, user, groups, images)
+    })
+}
+
+/// Picks the first match among the results of searching several Docker hosts.
 ///
-/// let labels = {
-///     let mut hashmap = HashMap::new();
-///     hashmap.insert(String::from("ssh-enable"), String::from("true"));
-///     hashmap.insert(String::from("ssh-hostname"), String::from("myhost"));
-///     hashmap.insert(String::from("ssh-allowed-users"), String::from("user1,user2"));
-///     hashmap
-/// };
+/// # Arguments
 ///
-/// assert_eq!(true, check_container_validity(&labels, "myhost", "user1"));
-/// assert_eq!(false, check_container_validity(&labels, "otherhost", "user3"));
-/// ```
-fn check_container_validity(labels: &HashMap<String, String>, target: &str, user: &str) -> bool {
-    if let Some(value) = labels.get(SSH_ENABLE_LABEL_KEY) {
-        // Assuming value for SSH_ALLOWED_USERS_LABEL_KEY is comma separated
-        let allow_users = labels
-            .get(SSH_ALLOWED_USERS_LABEL_KEY)
-            .map_or(Vec::new(), |users| {
-                users
-                    .split(',')
-                    .map(|s| s.to_string())
-                    .collect::<Vec<String>>()
-            });
-        value == "true"
-            && labels
-                .get(SSH_HOSTNAME_LABEL_KEY)
-                .unwrap_or(&String::from(""))
-                == target
-            && (allow_users.is_empty()
-                || (!user.is_empty() && allow_users.contains(&user.to_string())))
-    } else {
-        false
+/// * `names` - The names of the hosts, in the order of the results.
+/// * `results` - The result of searching each host.
+/// * `not_found` - Builds the error returned if no host was searched at all.
+///
+/// # Returns
+///
+/// The match and the index of its host. Without a match, the error of a host that was searched
+/// wins over the Docker error of a host that couldn't be.
+fn first_match<T>(
+    names: &[&str],
+    results: Vec<Result<T, TunnydError>>,
+    not_found: impl FnOnce() -> TunnydError,
+) -> Result<(T, usize), TunnydError> {
+    let mut found = None;
+    let mut searched_error = None;
+    let mut docker_error = None;
+    for (index, result) in results.into_iter().enumerate() {
+        let name = names.get(index).copied().unwrap_or_default();
+        match result {
+            Ok(matched) if found.is_none() => found = Some((matched, index)),
+            Ok(_) => warn!(
+                "Docker host {} has another matching container, keeping the first match",
+                name
+            ),
+            Err(TunnydError::Docker(e)) => {
+                warn!("Skipping Docker host {}: {}", name, e);
+                docker_error.get_or_insert(TunnydError::Docker(e));
+            }
+            Err(e) => {
+                searched_error.get_or_insert(e);
+            }
+        }
+    }
+    match (found, searched_error, docker_error) {
+        (Some(found), _, _) => Ok(found),
+        (None, Some(e), _) | (None, None, Some(e)) => Err(e),
+        (None, None, None) => Err(not_found()),
     }
 }
 
-/// Finds an SSH-enabled container based on the provided arguments.
+/// Checks whether a user may access at least one SSH-enabled container on any Docker host.
 ///
 /// # Arguments
 ///
-/// * `args` - The arguments used to filter the containers.
+/// * `hosts` - The Docker hosts the containers are listed on.
+/// * `user` - The user to check.
+/// * `groups` - The groups the user is a member of.
 ///
 /// # Returns
 ///
-/// * `Result<ContainerSummary, Error>` - The container summary if a match is found, otherwise an error.
+/// `true` if any SSH-enabled container allows the user, or the Docker error encountered if no
+/// host could be listed. Hosts that can't be reached are skipped.
+pub async fn any_container_allows_on_hosts(
+    hosts: &[DockerHost],
+    user: &str,
+    groups: &[String],
+) -> Result<bool, TunnydError> {
+    let mut failure = None;
+    let mut listed = false;
+    for host in hosts {
+        match any_container_allows(&host.docker, user, groups, &host.cache).await {
+            Ok(true) => return Ok(true),
+            Ok(false) => listed = true,
+            Err(e) => {
+                warn!("Skipping Docker host {}: {}", host.name, e);
+                failure.get_or_insert(e);
+            }
+        }
+    }
+    match (listed, failure) {
+        (false, Some(e)) => Err(e),
+        _ => Ok(false),
+    }
+}
+
+/// Checks whether a user may access at least one SSH-enabled container.
 ///
-/// # Examples
+/// # Arguments
 ///
-/// ```rust
-/// use my_crate::ContainerArgs;
-/// use futures::executor::block_on;
+/// * `docker` - The Docker client used to list the containers.
+/// * `user` - The user to check.
+/// * `groups` - The groups the user is a member of.
+/// * `cache` - The container list cache shared by the server.
 ///
-/// let args = ContainerArgs {
-///     target: "name_matching_docker_label_tunnyD.hostname",
-///     user: "root",
-/// };
+/// # Returns
 ///
-/// let result = find_ssh_enabled_container(&args).await;
-/// ```
-pub async fn find_ssh_enabled_container(args: &ContainerArgs) -> Result<ContainerSummary, Error> {
-    let docker = connect_to_docker().await.expect("get docker");
-    let options = ListContainersOptions::<String> {
-        all: LIST_ALL_CONTAINERS,
-        ..Default::default()
+/// `true` if any SSH-enabled container allows the user, or the Docker error encountered while
+/// listing the containers.
+async fn any_container_allows(
+    docker: &DockerClient,
+    user: &str,
+    groups: &[String],
+    cache: &Mutex<ContainerCache>,
+) -> Result<bool, TunnydError> {
+    let allows = |containers: &Vec<ContainerSummary>| {
+        containers.iter().any(|container| {
+            container.labels.as_ref().is_some_and(|labels| {
+                labels.get(SSH_ENABLE_LABEL_KEY).map(String::as_str) == Some("true")
+                    && is_user_allowed(labels, user, groups)
+            })
+        })
     };
-    let containers = docker.list_containers(Some(options)).await?;
-    for container in containers {
-        match &container.labels {
-            None => continue,
-            Some(labels) => {
-                if check_container_validity(
-                    &labels,
-                    &args.target,
-                    &args.user.clone().unwrap_or_default(),
-                ) {
-                    return Ok(container);
-                }
+    let mut cache = cache.lock().await;
+    match cache.cached() {
+        Some(containers) => Ok(allows(containers)),
+        None => Ok(allows(cache.refresh(docker).await?)),
+    }
+}
+
+/// Selects the first container of a list whose labels match the provided arguments.
+///
+/// # Arguments
+///
+/// * `containers` - The containers to select from.
+/// * `args` - The arguments used to filter the containers.
+/// * `user` - The user the connection authenticated as, checked against the allowed users.
+/// * `groups` - The groups the user is a member of.
+/// * `images` - The allowed images, empty allows every image.
+///
+/// # Returns
+///
+/// The matching container, or `None` if no container matches. Containers running an image that
+/// isn't allowed are skipped even if their labels match, and containers whose health check fails
+/// are only selected if no healthy container matches. Containers without an ID can't be reached
+/// and are skipped as well. The replicas of a compose service are tried in the order Compose
+/// numbered them, so `project/service` keeps landing on the same replica.
+fn select_container<'a>(
+    containers: &'a [ContainerSummary],
+    args: &ContainerArgs,
+    user: &str,
+    groups: &[String],
+    images: &[String],
+) -> Option<&'a ContainerSummary> {
+    let mut matching: Vec<&ContainerSummary> = containers
+        .iter()
+        .filter(|container| matches_target(container, args, user, groups, images))
+        .filter(|container| {
+            if container.id.is_none() {
+                warn!(
+                    "Skipping container {} for {}, Docker reported no id for it",
+                    container_name(container).unwrap_or_default(),
+                    args.target
+                );
             }
+            container.id.is_some()
+        })
+        .collect();
+    if args.selector == TargetSelector::Hostname && ComposeTarget::parse(&args.target).is_some() {
+        matching.sort_by_key(|container| compose_replica(container).unwrap_or(u32::MAX));
+    }
+    let mut matching = matching.into_iter();
+    let first = matching.next()?;
+    if !is_unhealthy(first) {
+        return Some(first);
+    }
+    match matching.find(|container| !is_unhealthy(container)) {
+        Some(healthy) => {
+            info!(
+                "Skipping unhealthy container {} for {}",
+                container_name(first)
+                    .or(first.id.as_deref())
+                    .unwrap_or_default(),
+                args.target
+            );
+            Some(healthy)
+        }
+        None => Some(first),
+    }
+}
+
+/// Checks whether the health check of a container is failing.
+///
+/// # Arguments
+///
+/// * `container` - The container to check.
+///
+/// # Returns
+///
+/// `true` if Docker reports the container as unhealthy. Containers without a health check or whose
+/// health check is still starting aren't.
+fn is_unhealthy(container: &ContainerSummary) -> bool {
+    container
+        .status
+        .as_deref()
+        .is_some_and(|status| status.ends_with(CONTAINER_STATUS_UNHEALTHY))
+}
+
+/// Checks whether a container is the one a request addresses by id or name.
+///
+/// # Arguments
+///
+/// * `container` - The container to check.
+/// * `args` - The request, its target is an id or a name depending on the selector.
+///
+/// # Returns
+///
+/// `true` if the container's id starts with the target (which has to be at least as long as a
+/// short id) or its name equals the target. Always `false` for hostname requests.
+fn is_addressed_directly(container: &ContainerSummary, args: &ContainerArgs) -> bool {
+    match args.selector {
+        TargetSelector::Hostname => false,
+        TargetSelector::Id => {
+            args.target.len() >= SHORT_ID_LENGTH
+                && container
+                    .id
+                    .as_deref()
+                    .is_some_and(|id| id.starts_with(&args.target))
         }
+        TargetSelector::Name => container_name(container) == Some(args.target.as_str()),
+    }
+}
+
+/// Checks whether the labels and the image of a container match the provided arguments.
+///
+/// # Arguments
+///
+/// * `container` - The container to check.
+/// * `args` - The arguments used to filter the containers.
+/// * `user` - The user the connection authenticated as, checked against the allowed users.
+/// * `groups` - The groups the user is a member of.
+/// * `images` - The allowed images, empty allows every image.
+fn matches_target(
+    container: &ContainerSummary,
+    args: &ContainerArgs,
+    user: &str,
+    groups: &[String],
+    images: &[String],
+) -> bool {
+    match &container.labels {
+        _ if !is_image_allowed(container.image.as_deref(), images) => false,
+        None => false,
+        Some(labels) => match args.selector {
+            TargetSelector::Hostname => {
+                check_container_validity(labels, &args.target, user, groups)
+            }
+            TargetSelector::Id | TargetSelector::Name => {
+                is_addressed_directly(container, args)
+                    && labels.get(SSH_ENABLE_LABEL_KEY).map(String::as_str) == Some("true")
+                    && is_user_allowed(labels, user, groups)
+            }
+        },
+    }
+}
 
-        // let container_id = &container.id.expect("Missing Container Id");
+/// The error returned when no container can be selected for the requested target.
+///
+/// # Returns
+///
+/// `TunnydError::MissingContainerId` if only containers without an ID match, so the client learns
+/// the target exists, otherwise `TunnydError::ContainerNotFound`.
+fn no_matching_container(
+    containers: &[ContainerSummary],
+    args: &ContainerArgs,
+    user: &str,
+    groups: &[String],
+    images: &[String],
+) -> TunnydError {
+    let target = args.target.clone();
+    match containers
+        .iter()
+        .any(|container| matches_target(container, args, user, groups, images))
+    {
+        true => TunnydError::MissingContainerId { target },
+        false => TunnydError::ContainerNotFound { target },
+    }
+}
 
-        // exec_into_container(&args, &container_id);
+/// Retries an operation with an exponential backoff until it succeeds or the time is up.
+///
+/// # Arguments
+///
+/// * `max_wait` - How long to keep retrying. The last attempt starts before this has passed.
+/// * `initial_backoff` - The delay before the second attempt, doubled for every further attempt.
+/// * `attempt` - Runs one attempt.
+///
+/// # Returns
+///
+/// The result of the first successful attempt, or the error of the last one.
+async fn retry_until<T, E, F, Fut>(
+    max_wait: Duration,
+    initial_backoff: Duration,
+    mut attempt: F,
+) -> Result<T, E>
+where
+    E: fmt::Display,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    let started = Instant::now();
+    let mut backoff = initial_backoff;
+    for count in 1.. {
+        match attempt().await {
+            Ok(value) => return Ok(value),
+            Err(e) if started.elapsed() + backoff > max_wait => return Err(e),
+            Err(e) => {
+                warn!(
+                    "Docker is not reachable yet (attempt {}): {}, retrying in {:?}",
+                    count, e, backoff
+                );
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(STARTUP_MAX_BACKOFF);
+            }
+        }
     }
-    Err(Error::DockerContainerWaitError {
-        error: "No Available Container matches".to_string(),
-        code: 0,
+    unreachable!("the attempts are unbounded")
+}
+
+/// Connects to Docker at startup, waiting for the daemon to answer a ping.
+///
+/// # Arguments
+///
+/// * `settings` - How to reach the Docker daemon.
+/// * `max_wait` - How long to wait for the daemon, `0` gives up after the first attempt.
+///
+/// # Returns
+///
+/// The connected Docker client, or the error of the last attempt once `max_wait` has passed.
+///
+/// # Remarks
+///
+/// During boot the Docker daemon may start after tunnyd, so an unreachable daemon is retried with
+/// an exponential backoff instead of failing right away.
+pub async fn wait_for_docker(
+    settings: &DockerSettings,
+    max_wait: Duration,
+) -> Result<Docker, Box<dyn std::error::Error>> {
+    retry_until(max_wait, STARTUP_INITIAL_BACKOFF, || async {
+        let docker = connect_to_docker(settings).await?;
+        docker.ping().await?;
+        Ok::<Docker, Box<dyn std::error::Error>>(docker)
     })
+    .await
 }
-//
-// fn exec_into_container(args: &ContainerArgs, container_id: &&String) {
-//     let ssh_original_command = format!(
-//         "SSH_ORIGINAL_COMMAND={}",
-//         std::env::var("SSH_ORIGINAL_COMMAND").unwrap_or("\"\"".parse().unwrap())
-//     );
-//
-//     let mut docker_args = vec![
-//         "exec",
-//         "-i",
-//         "--env",
-//         &ssh_original_command,
-//         &container_id.as_str(),
-//         COMMAND_SHELL,
-//     ];
-//
-//     if let Some(user) = &args.user {
-//         // only add args.user if it is not None
-//         if !user.is_empty() {
-//             // only add user if it is not an empty string
-//             docker_args.insert(2, "-u");
-//             docker_args.insert(3, user.as_str());
-//         }
-//     }
-//     let args_to_pass: Vec<String> = env::args().skip(1).collect();
-//     docker_args.extend(args_to_pass.iter().map(|s| s.as_str()));
-//     let docker_args_str = docker_args.join(" ");
-//
-//     // Print or log the full command
-//     println!("Full command: {} {}", EXEC_DOCKER, docker_args_str);
-//     Command::new(EXEC_DOCKER)
-//         .args(&docker_args)
-//         .spawn()
-//         .expect("Failed to execute command");
-// }
-
-/// Connects to Docker using the local defaults.
+
+/// Connects to Docker, either through a named context or using the local defaults.
 ///
 /// # Returns
 ///
 /// Returns a `Result` containing a `Docker` instance if the connection is successful.
 /// If there is an error during the connection, the error is wrapped in a `Box<dyn std::error::Error>`.
 ///
+/// # Remarks
+///
+/// Without a context, `DOCKER_TLS_VERIFY` together with a TCP `DOCKER_HOST` connects over verified
+/// TLS using the certificates in `DOCKER_CERT_PATH`, like the Docker CLI does.
+///
+/// The API version is negotiated with the daemon, capped at the configured version.
+///
 /// # Examples
 ///
-/// ```rust
-/// use docker::Docker;
+/// ```ignore
+/// use crate::docker::{connect_to_docker, DockerSettings};
 ///
 /// #[tokio::main]
 /// async fn main() {
-///     match connect_to_docker().await {
+///     match connect_to_docker(&DockerSettings::default()).await {
 ///         Ok(docker) => {
 ///             println!("Connected to Docker successfully!");
 ///             // Use the Docker instance here
@@ -180,12 +1842,949 @@ pub async fn find_ssh_enabled_container(args: &ContainerArgs) -> Result<Containe
 ///     }
 /// }
 /// ```
-pub async fn connect_to_docker() -> Result<Docker, Box<dyn std::error::Error>> {
-    return match Docker::connect_with_local_defaults() {
-        Ok(docker) => {
-            info!("Successfully connected to Docker");
-            Ok(docker)
+pub async fn connect_to_docker(
+    settings: &DockerSettings,
+) -> Result<Docker, Box<dyn std::error::Error>> {
+    let version = settings.client_version()?;
+    let docker = match (settings.host.as_deref(), settings.context.as_deref()) {
+        (Some(host), _) => {
+            info!("Using Docker host {}", host);
+            connect_to_host(host, &version)?
+        }
+        (None, None | Some(DEFAULT_CONTEXT)) => {
+            match docker_tls(|name| std::env::var(name).ok(), &docker_config_dir())? {
+                Some(tls) => {
+                    info!("Using TLS for Docker at {}", tls.host);
+                    Docker::connect_with_ssl(
+                        &tls.host,
+                        &tls.key,
+                        &tls.cert,
+                        &tls.ca,
+                        DOCKER_TIMEOUT_SECS,
+                        &version,
+                    )?
+                }
+                None => {
+                    let host = std::env::var("DOCKER_HOST")
+                        .unwrap_or_else(|_| DEFAULT_DOCKER_HOST.to_string());
+                    connect_to_host(&host, &version)?
+                }
+            }
+        }
+        (None, Some(context)) => {
+            let host = resolve_context_host(&docker_config_dir(), context)?;
+            info!("Using Docker context {} at {}", context, host);
+            connect_to_host(&host, &version)?
         }
-        Err(e) => Err(Box::new(e)),
     };
+    let docker = docker.negotiate_version().await?;
+    info!(
+        "Successfully connected to Docker, using API version {}",
+        docker.client_version()
+    );
+    Ok(docker)
+}
+
+/// Connects to an additional Docker host, which may be down while the server starts.
+///
+/// # Arguments
+///
+/// * `settings` - The settings of the host, see `DockerSettings::with_host`.
+///
+/// # Returns
+///
+/// The Docker client, or an error if the host URL is invalid.
+///
+/// # Remarks
+///
+/// A host that can't be reached gets a client that isn't connected yet. It is reconnected like the
+/// primary daemon once a search reaches it, until then it is skipped.
+pub async fn connect_to_docker_host(
+    settings: &DockerSettings,
+) -> Result<Docker, Box<dyn std::error::Error>> {
+    match connect_to_docker(settings).await {
+        Ok(docker) => Ok(docker),
+        Err(e) => {
+            let host = settings.host.as_deref().unwrap_or_default();
+            warn!("Docker host {} is unreachable: {}", host, e);
+            connect_to_host(host, &settings.client_version()?)
+        }
+    }
+}
+
+/// Connects to a Docker daemon by its host URL.
+///
+/// # Arguments
+///
+/// * `host` - The host, `unix://` followed by a socket path or `tcp://` / `http://` followed by an address.
+/// * `version` - The API version the client starts with.
+///
+/// # Returns
+///
+/// The Docker client, or an error for unsupported schemes.
+fn connect_to_host(
+    host: &str,
+    version: &ClientVersion,
+) -> Result<Docker, Box<dyn std::error::Error>> {
+    match host.split_once("://") {
+        Some(("unix", path)) => Ok(Docker::connect_with_unix(
+            path,
+            DOCKER_TIMEOUT_SECS,
+            version,
+        )?),
+        Some(("tcp" | "http", _)) => Ok(Docker::connect_with_http(
+            host,
+            DOCKER_TIMEOUT_SECS,
+            version,
+        )?),
+        _ => Err(format!("Unsupported Docker host '{}'", host).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{mock_host, MockDocker, Reply, UNREACHABLE_DOCKER};
+
+    fn labels(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    fn container(pairs: &[(&str, &str)]) -> ContainerSummary {
+        ContainerSummary {
+            id: Some(String::from("0123456789abcdef")),
+            labels: Some(labels(pairs)),
+            ..Default::default()
+        }
+    }
+
+    fn args(target: &str) -> ContainerArgs {
+        ContainerArgs {
+            user: None,
+            target: target.to_string(),
+            selector: TargetSelector::Hostname,
+            command: Vec::new(),
+            share: None,
+            attach: false,
+        }
+    }
+
+    fn direct_containers() -> Vec<ContainerSummary> {
+        let mut containers = vec![
+            container(&[
+                (SSH_ENABLE_LABEL_KEY, "true"),
+                (SSH_HOSTNAME_LABEL_KEY, "app"),
+            ]),
+            container(&[
+                (SSH_ENABLE_LABEL_KEY, "true"),
+                (SSH_ALLOWED_USERS_LABEL_KEY, "git"),
+            ]),
+            container(&[(SSH_ENABLE_LABEL_KEY, "false")]),
+        ];
+        for (index, container) in containers.iter_mut().enumerate() {
+            container.id = Some(format!("{}123456789abcdef", index));
+            container.names = Some(vec![format!("/example-{}", index)]);
+        }
+        containers
+    }
+
+    #[test]
+    fn matches_enabled_container_by_hostname_and_user() {
+        let labels = labels(&[
+            (SSH_ENABLE_LABEL_KEY, "true"),
+            (SSH_HOSTNAME_LABEL_KEY, "app"),
+            (SSH_ALLOWED_USERS_LABEL_KEY, "git, Root"),
+        ]);
+        assert!(check_container_validity(&labels, "app", "root", &[]));
+        assert!(check_container_validity(&labels, "app", "git:git", &[]));
+        assert!(!check_container_validity(&labels, "other", "root", &[]));
+        assert!(!check_container_validity(&labels, "app", "alice", &[]));
+    }
+
+    #[test]
+    fn rejects_disabled_or_unlabeled_containers() {
+        let disabled = labels(&[
+            (SSH_ENABLE_LABEL_KEY, "false"),
+            (SSH_HOSTNAME_LABEL_KEY, "app"),
+        ]);
+        assert!(!check_container_validity(&disabled, "app", "root", &[]));
+        let unlabeled = labels(&[(SSH_HOSTNAME_LABEL_KEY, "app")]);
+        assert!(!check_container_validity(&unlabeled, "app", "root", &[]));
+    }
+
+    #[test]
+    fn never_matches_empty_hostnames() {
+        let labels = labels(&[
+            (SSH_ENABLE_LABEL_KEY, "true"),
+            (SSH_HOSTNAME_LABEL_KEY, " "),
+        ]);
+        assert!(!check_container_validity(&labels, "", "root", &[]));
+        assert!(!check_container_validity(&labels, " ", "root", &[]));
+    }
+
+    #[test]
+    fn grants_access_through_groups() {
+        let labels = labels(&[
+            (SSH_ALLOWED_USERS_LABEL_KEY, "root"),
+            (SSH_ALLOWED_GROUPS_LABEL_KEY, "developers"),
+        ]);
+        let groups = vec![String::from("Developers")];
+        assert!(is_user_allowed(&labels, "alice", &groups));
+        assert!(!is_user_allowed(&labels, "alice", &[]));
+        assert!(is_user_allowed(&labels, "root", &[]));
+    }
+
+    #[test]
+    fn opens_containers_without_access_labels() {
+        assert!(is_user_allowed(&HashMap::new(), "anyone", &[]));
+        assert!(is_user_allowed(&HashMap::new(), "", &[]));
+    }
+
+    #[test]
+    fn parses_allowed_users() {
+        assert_eq!(parse_allowed_users(" alice, bob,"), vec!["alice", "bob"]);
+        assert!(parse_allowed_users(" , ").is_empty());
+    }
+
+    #[test]
+    fn selects_first_matching_container() {
+        let containers = vec![
+            container(&[
+                (SSH_ENABLE_LABEL_KEY, "true"),
+                (SSH_HOSTNAME_LABEL_KEY, "db"),
+            ]),
+            container(&[
+                (SSH_ENABLE_LABEL_KEY, "true"),
+                (SSH_HOSTNAME_LABEL_KEY, "app"),
+                (SSH_MOTD_LABEL_KEY, "first"),
+            ]),
+            container(&[
+                (SSH_ENABLE_LABEL_KEY, "true"),
+                (SSH_HOSTNAME_LABEL_KEY, "app"),
+            ]),
+        ];
+        let selected = select_container(&containers, &args("app"), "root", &[], &[]);
+        assert_eq!(
+            selected.and_then(container_motd).as_deref(),
+            Some("first\r\n")
+        );
+        assert!(select_container(&containers, &args("web"), "", &[], &[]).is_none());
+    }
+
+    fn compose_container(project: &str, service: &str, replica: &str) -> ContainerSummary {
+        let mut container = container(&[
+            (SSH_ENABLE_LABEL_KEY, "true"),
+            (COMPOSE_PROJECT_LABEL_KEY, project),
+            (COMPOSE_SERVICE_LABEL_KEY, service),
+            (COMPOSE_REPLICA_LABEL_KEY, replica),
+        ]);
+        container.id = Some(format!("{}-{}-{}", project, service, replica));
+        container
+    }
+
+    #[test]
+    fn matches_compose_services() {
+        let containers = vec![
+            compose_container("shop", "db", "1"),
+            compose_container("shop", "web", "1"),
+            compose_container("blog", "web", "1"),
+            container(&[
+                (COMPOSE_PROJECT_LABEL_KEY, "shop"),
+                (COMPOSE_SERVICE_LABEL_KEY, "worker"),
+            ]),
+        ];
+        let selected = |target: &str| {
+            select_container(&containers, &args(target), "", &[], &[])
+                .and_then(|container| container.id.as_deref())
+        };
+        assert_eq!(selected("shop/web"), Some("shop-web-1"));
+        assert_eq!(selected("blog/web"), Some("blog-web-1"));
+        // Compose containers still have to opt in
+        assert_eq!(selected("shop/worker"), None);
+        assert_eq!(selected("shop/cache"), None);
+        assert_eq!(selected("shop/"), None);
+
+        let labels = containers[1].labels.clone().unwrap();
+        assert_eq!(
+            container_rejection(&labels, "blog/web", "root", &[]),
+            Some(Rejection::ComposeMismatch {
+                service: Some(String::from("shop/web/1"))
+            })
+        );
+        // A hostname label isn't needed, and doesn't match compose targets either
+        let mut hostname = labels.clone();
+        hostname.insert(String::from(SSH_HOSTNAME_LABEL_KEY), String::from("app"));
+        assert!(check_container_validity(&hostname, "shop/web", "root", &[]));
+        assert!(check_container_validity(&hostname, "app", "root", &[]));
+    }
+
+    #[test]
+    fn disambiguates_compose_replicas() {
+        let mut containers = vec![
+            compose_container("shop", "web", "3"),
+            compose_container("shop", "web", "1"),
+            compose_container("shop", "web", "2"),
+        ];
+        let selected = |containers: &[ContainerSummary], target: &str| {
+            select_container(containers, &args(target), "", &[], &[])
+                .and_then(|container| container.id.clone())
+        };
+        // Docker lists the newest container first, the lowest replica still wins
+        assert_eq!(
+            selected(&containers, "shop/web").as_deref(),
+            Some("shop-web-1")
+        );
+        assert_eq!(
+            selected(&containers, "shop/web/2").as_deref(),
+            Some("shop-web-2")
+        );
+        assert_eq!(selected(&containers, "shop/web/4"), None);
+
+        // A failing replica is skipped in favour of the next one
+        containers[1].status = Some(String::from("Up 5 minutes (unhealthy)"));
+        assert_eq!(
+            selected(&containers, "shop/web").as_deref(),
+            Some("shop-web-2")
+        );
+    }
+
+    #[test]
+    fn prefers_the_container_served_before() {
+        let mut containers = vec![
+            compose_container("shop", "web", "1"),
+            compose_container("shop", "web", "2"),
+        ];
+        for container in &mut containers {
+            container.state = Some(String::from(CONTAINER_STATE_RUNNING));
+        }
+        let args = args("shop/web");
+        let first = select_container(&containers, &args, "", &[], &[])
+            .and_then(|container| container.id.clone())
+            .unwrap();
+        assert_eq!(first, "shop-web-1");
+        // Replica 2 served the previous session, it keeps serving while it is present
+        for _ in 0..2 {
+            let preferred = select_preferred(&containers, &args, "", &[], &[], "shop-web-2");
+            assert_eq!(
+                preferred.and_then(|container| container.id.as_deref()),
+                Some("shop-web-2")
+            );
+        }
+        assert!(select_preferred(&containers, &args, "", &[], &[], "shop-web-9").is_none());
+
+        containers[1].state = Some(String::from("exited"));
+        assert!(select_preferred(&containers, &args, "", &[], &[], "shop-web-2").is_none());
+        containers.pop();
+        assert!(select_preferred(&containers, &args, "", &[], &[], "shop-web-2").is_none());
+    }
+
+    #[test]
+    fn skips_matching_containers_without_an_id() {
+        let mut containers = vec![
+            container(&[
+                (SSH_ENABLE_LABEL_KEY, "true"),
+                (SSH_HOSTNAME_LABEL_KEY, "app"),
+            ]),
+            container(&[
+                (SSH_ENABLE_LABEL_KEY, "true"),
+                (SSH_HOSTNAME_LABEL_KEY, "app"),
+            ]),
+        ];
+        containers[0].id = None;
+        containers[1].id = Some(String::from("abc"));
+        let selected = select_container(&containers, &args("app"), "", &[], &[]);
+        assert_eq!(selected.and_then(|c| c.id.as_deref()), Some("abc"));
+
+        containers.truncate(1);
+        assert!(select_container(&containers, &args("app"), "", &[], &[]).is_none());
+        assert!(matches!(
+            no_matching_container(&containers, &args("app"), "", &[], &[]),
+            TunnydError::MissingContainerId { target } if target == "app"
+        ));
+        assert!(matches!(
+            no_matching_container(&containers, &args("web"), "", &[], &[]),
+            TunnydError::ContainerNotFound { .. }
+        ));
+    }
+
+    #[test]
+    fn interpolates_container_env_into_labels() {
+        let env: HashMap<String, String> = [("HOSTNAME", "web-1"), ("DOMAIN", "example.com")]
+            .into_iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        assert_eq!(
+            interpolate("${HOSTNAME}.${DOMAIN}", &env).as_deref(),
+            Ok("web-1.example.com")
+        );
+        assert_eq!(interpolate("app", &env).as_deref(), Ok("app"));
+        assert_eq!(
+            interpolate("cost$5 ${open", &env).as_deref(),
+            Ok("cost$5 ${open")
+        );
+
+        let mut labels = labels(&[
+            (SSH_HOSTNAME_LABEL_KEY, "${HOSTNAME}.docker"),
+            ("com.example.path", "${DOMAIN}"),
+        ]);
+        interpolate_labels(&mut labels, &env, "0123456789ab");
+        assert_eq!(labels[SSH_HOSTNAME_LABEL_KEY], "web-1.docker");
+        // Only tunnyd labels are interpolated
+        assert_eq!(labels["com.example.path"], "${DOMAIN}");
+        assert!(check_container_validity(
+            &labels_enabled(labels),
+            "web-1.docker",
+            "",
+            &[]
+        ));
+    }
+
+    #[tokio::test]
+    async fn reads_allowed_users_from_files() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(file.path(), "# on-call\nalice, bob\n\ncarol\n").unwrap();
+        let reference = format!("{}{}", USERS_FILE_PREFIX, file.path().display());
+        let mut files = UsersFiles::default();
+        let allowed = |container: &ContainerSummary, user: &str| {
+            is_user_allowed(container.labels.as_ref().unwrap(), user, &[])
+        };
+
+        let mut listed =
+            container(&[(SSH_ALLOWED_USERS_LABEL_KEY, &format!("root, {}", reference))]);
+        resolve_users_files(&mut files, &mut listed).await;
+        for user in ["root", "alice", "bob", "carol"] {
+            assert!(allowed(&listed, user), "{} is listed", user);
+        }
+        assert!(!allowed(&listed, "mallory"));
+
+        // The contents are cached, a file that disappears keeps its users until they expire
+        drop(file);
+        let mut cached = container(&[(SSH_ALLOWED_USERS_LABEL_KEY, &reference)]);
+        resolve_users_files(&mut files, &mut cached).await;
+        assert!(allowed(&cached, "alice"));
+
+        // A missing file denies everybody instead of lifting the restriction
+        let mut missing = container(&[(
+            SSH_ALLOWED_USERS_LABEL_KEY,
+            "file:/nonexistent/tunnyd-users",
+        )]);
+        resolve_users_files(&mut UsersFiles::default(), &mut missing).await;
+        assert!(!allowed(&missing, "alice"));
+        assert!(!allowed(&missing, ""));
+    }
+
+    #[test]
+    fn keeps_labels_referencing_missing_variables() {
+        let env = HashMap::new();
+        assert_eq!(
+            interpolate("${NAME}.docker", &env),
+            Err(String::from("NAME"))
+        );
+        let mut labels = labels(&[(SSH_HOSTNAME_LABEL_KEY, "${NAME}.docker")]);
+        interpolate_labels(&mut labels, &env, "0123456789ab");
+        assert_eq!(labels[SSH_HOSTNAME_LABEL_KEY], "${NAME}.docker");
+        assert!(!check_container_validity(
+            &labels_enabled(labels),
+            ".docker",
+            "",
+            &[]
+        ));
+    }
+
+    fn labels_enabled(mut labels: HashMap<String, String>) -> HashMap<String, String> {
+        labels.insert(SSH_ENABLE_LABEL_KEY.to_string(), String::from("true"));
+        labels
+    }
+
+    #[test]
+    fn prefers_healthy_containers() {
+        let mut containers = vec![
+            container(&[
+                (SSH_ENABLE_LABEL_KEY, "true"),
+                (SSH_HOSTNAME_LABEL_KEY, "app"),
+                (SSH_MOTD_LABEL_KEY, "unhealthy"),
+            ]),
+            container(&[
+                (SSH_ENABLE_LABEL_KEY, "true"),
+                (SSH_HOSTNAME_LABEL_KEY, "app"),
+                (SSH_MOTD_LABEL_KEY, "healthy"),
+            ]),
+        ];
+        containers[0].status = Some(String::from("Up 5 minutes (unhealthy)"));
+        containers[1].status = Some(String::from("Up 5 minutes (healthy)"));
+        let selected = select_container(&containers, &args("app"), "", &[], &[]);
+        assert_eq!(
+            selected.and_then(container_motd).as_deref(),
+            Some("healthy\r\n")
+        );
+        // An unhealthy container is still better than none
+        containers.remove(1);
+        let selected = select_container(&containers, &args("app"), "", &[], &[]);
+        assert_eq!(
+            selected.and_then(container_motd).as_deref(),
+            Some("unhealthy\r\n")
+        );
+    }
+
+    #[test]
+    fn skips_containers_with_images_not_allowed() {
+        let mut containers = vec![
+            container(&[
+                (SSH_ENABLE_LABEL_KEY, "true"),
+                (SSH_HOSTNAME_LABEL_KEY, "app"),
+            ]),
+            container(&[
+                (SSH_ENABLE_LABEL_KEY, "true"),
+                (SSH_HOSTNAME_LABEL_KEY, "app"),
+                (SSH_MOTD_LABEL_KEY, "approved"),
+            ]),
+        ];
+        containers[0].image = Some(String::from("evil/shell:latest"));
+        containers[1].image = Some(String::from("registry.example.com/app:1.2"));
+        let images = vec![String::from("registry.example.com/app")];
+        let selected = select_container(&containers, &args("app"), "", &[], &images);
+        assert_eq!(
+            selected.and_then(container_motd).as_deref(),
+            Some("approved\r\n")
+        );
+        containers.remove(1);
+        assert!(select_container(&containers, &args("app"), "", &[], &images).is_none());
+        assert!(select_container(&containers, &args("app"), "", &[], &[]).is_some());
+        let candidates = diagnose_candidates(&containers, &args("app"), "", &[], &images);
+        assert_eq!(
+            candidates[0].rejection,
+            Some(Rejection::ImageNotAllowed {
+                image: String::from("evil/shell:latest")
+            })
+        );
+    }
+
+    #[test]
+    fn matches_image_patterns() {
+        let allowed = vec![String::from("nginx"), String::from("ghcr.io/acme/*")];
+        assert!(is_image_allowed(Some("nginx"), &allowed));
+        assert!(is_image_allowed(Some("nginx:1.25"), &allowed));
+        assert!(is_image_allowed(Some("ghcr.io/acme/api:2"), &allowed));
+        assert!(!is_image_allowed(Some("nginx-proxy"), &allowed));
+        assert!(!is_image_allowed(None, &allowed));
+        assert!(is_image_allowed(None, &[]));
+    }
+
+    #[test]
+    fn parses_api_versions() {
+        let version = parse_api_version("1.41").unwrap();
+        assert_eq!((version.major_version, version.minor_version), (1, 41));
+        assert!(parse_api_version("v1.30").is_ok());
+        assert!(parse_api_version("latest")
+            .unwrap_err()
+            .contains("Invalid Docker API version"));
+        assert!(parse_api_version("1.12")
+            .unwrap_err()
+            .contains("Unsupported Docker API version"));
+        assert!(parse_api_version("2.0").is_err());
+    }
+
+    #[test]
+    fn builds_clients_with_the_configured_version() {
+        let settings = DockerSettings {
+            context: None,
+            api_version: Some(String::from("1.40")),
+            host: None,
+        };
+        let version = settings.client_version().unwrap();
+        let docker = connect_to_host("unix:///var/run/docker.sock", &version).unwrap();
+        assert_eq!(docker.client_version(), version);
+        let defaults = DockerSettings::default().client_version().unwrap();
+        assert_eq!(defaults, *API_DEFAULT_VERSION);
+    }
+
+    fn running(hostname: &str) -> ContainerSummary {
+        let mut container = container(&[
+            (SSH_ENABLE_LABEL_KEY, "true"),
+            (SSH_HOSTNAME_LABEL_KEY, hostname),
+        ]);
+        container.state = Some(String::from(CONTAINER_STATE_RUNNING));
+        container.id = Some(format!("{}-id", hostname));
+        container
+    }
+
+    #[tokio::test]
+    async fn finds_containers_on_the_host_running_them() {
+        let hosts = vec![
+            mock_host("node-1", vec![running("db")]),
+            mock_host("node-2", vec![running("app")]),
+        ];
+        let (container, host) =
+            find_ssh_enabled_container_on_hosts(&hosts, &args("app"), "", &[], &[])
+                .await
+                .unwrap();
+        assert_eq!(container.id.as_deref(), Some("app-id"));
+        assert_eq!(hosts[host].name, "node-2");
+        let (_, host) = find_ssh_enabled_container_on_hosts(&hosts, &args("db"), "", &[], &[])
+            .await
+            .unwrap();
+        assert_eq!(hosts[host].name, "node-1");
+        let missing =
+            find_ssh_enabled_container_on_hosts(&hosts, &args("cache"), "", &[], &[]).await;
+        assert!(matches!(
+            missing,
+            Err(TunnydError::ContainerNotFound { .. })
+        ));
+    }
+
+    #[test]
+    fn skips_hosts_that_fail() {
+        let names = ["node-1", "node-2", "node-3"];
+        let results = vec![
+            Err(TunnydError::Docker(Error::RequestTimeoutError)),
+            Ok("second"),
+            Ok("third"),
+        ];
+        let found = first_match(&names, results, || TunnydError::DirectAddressingDisabled);
+        assert_eq!(found.unwrap(), ("second", 1));
+        let results: Vec<Result<&str, TunnydError>> = vec![
+            Err(TunnydError::Docker(Error::RequestTimeoutError)),
+            Err(TunnydError::ContainerNotFound {
+                target: String::from("app"),
+            }),
+        ];
+        let found = first_match(&names, results, || TunnydError::DirectAddressingDisabled);
+        assert!(matches!(found, Err(TunnydError::ContainerNotFound { .. })));
+        let results: Vec<Result<&str, TunnydError>> =
+            vec![Err(TunnydError::Docker(Error::RequestTimeoutError))];
+        let found = first_match(&names, results, || TunnydError::DirectAddressingDisabled);
+        assert!(matches!(found, Err(TunnydError::Docker(_))));
+    }
+
+    #[test]
+    fn reads_container_labels() {
+        let container = container(&[
+            (SSH_MOTD_LABEL_KEY, "hello\nworld"),
+            (SSH_DEFAULT_USER_LABEL_KEY, " app:app "),
+            (SSH_WORKDIR_LABEL_KEY, ""),
+            (SSH_IDLE_TIMEOUT_LABEL_KEY, " 600 "),
+            (SSH_READONLY_LABEL_KEY, "true"),
+        ]);
+        assert_eq!(
+            container_motd(&container).as_deref(),
+            Some("hello\r\nworld\r\n")
+        );
+        assert_eq!(
+            container_default_user(&container).as_deref(),
+            Some("app:app")
+        );
+        assert_eq!(container_workdir(&container), None);
+        assert_eq!(container_idle_timeout(&container), Some(600));
+        assert!(container_readonly(&container));
+    }
+
+    #[test]
+    fn reads_resource_limits() {
+        let limited = container(&[
+            (SSH_LIMITS_CPU_LABEL_KEY, "120"),
+            (SSH_LIMITS_MEMORY_LABEL_KEY, " 256m "),
+        ]);
+        assert_eq!(
+            container_limits(&limited).unwrap(),
+            ResourceLimits {
+                cpu_secs: Some(120),
+                memory_bytes: Some(256 * 1024 * 1024),
+            }
+        );
+        assert_eq!(
+            container_limits(&container(&[(SSH_LIMITS_CPU_LABEL_KEY, "")])).unwrap(),
+            ResourceLimits::default()
+        );
+        assert!(matches!(
+            container_limits(&container(&[(SSH_LIMITS_MEMORY_LABEL_KEY, "lots")])),
+            Err(TunnydError::InvalidLimit { .. })
+        ));
+    }
+
+    #[test]
+    fn ignores_invalid_idle_timeouts() {
+        let invalid = container(&[(SSH_IDLE_TIMEOUT_LABEL_KEY, "10m")]);
+        assert_eq!(container_idle_timeout(&invalid), None);
+        assert_eq!(container_idle_timeout(&container(&[])), None);
+    }
+
+    #[test]
+    fn reports_why_candidates_were_rejected() {
+        let mut containers = vec![
+            container(&[
+                (SSH_ENABLE_LABEL_KEY, "false"),
+                (SSH_HOSTNAME_LABEL_KEY, "app"),
+            ]),
+            container(&[
+                (SSH_ENABLE_LABEL_KEY, "true"),
+                (SSH_HOSTNAME_LABEL_KEY, "db"),
+            ]),
+            container(&[
+                (SSH_ENABLE_LABEL_KEY, "true"),
+                (SSH_HOSTNAME_LABEL_KEY, "app"),
+                (SSH_ALLOWED_USERS_LABEL_KEY, "git"),
+            ]),
+            container(&[
+                (SSH_ENABLE_LABEL_KEY, "true"),
+                (SSH_HOSTNAME_LABEL_KEY, "app"),
+            ]),
+            container(&[("com.example.other", "true")]),
+        ];
+        containers[3].names = Some(vec![String::from("/example")]);
+        let candidates = diagnose_candidates(&containers, &args("app"), "root", &[], &[]);
+        let rejections: Vec<Option<Rejection>> = candidates
+            .iter()
+            .map(|candidate| candidate.rejection.clone())
+            .collect();
+        assert_eq!(
+            rejections,
+            vec![
+                Some(Rejection::NotEnabled),
+                Some(Rejection::HostnameMismatch {
+                    hostname: String::from("db")
+                }),
+                Some(Rejection::UserNotAllowed {
+                    user: String::from("root")
+                }),
+                None,
+            ]
+        );
+        assert_eq!(candidates[3].name, "example");
+        assert_eq!(
+            candidates[1]
+                .labels
+                .get(SSH_HOSTNAME_LABEL_KEY)
+                .map(String::as_str),
+            Some("db")
+        );
+        assert_eq!(
+            Rejection::HostnameMismatch {
+                hostname: String::from("db")
+            }
+            .to_string(),
+            "tunnyD.hostname 'db' doesn't match the target"
+        );
+    }
+
+    #[tokio::test]
+    async fn retries_until_the_attempt_succeeds() {
+        let mut attempts = 0;
+        let result = retry_until(Duration::from_secs(5), Duration::from_millis(1), || {
+            attempts += 1;
+            let attempt = attempts;
+            async move {
+                match attempt {
+                    3 => Ok(attempt),
+                    _ => Err("not ready"),
+                }
+            }
+        })
+        .await;
+        assert_eq!(result, Ok(3));
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_max_wait() {
+        let mut attempts = 0;
+        let result: Result<(), &str> =
+            retry_until(Duration::from_millis(20), Duration::from_millis(1), || {
+                attempts += 1;
+                async { Err("not ready") }
+            })
+            .await;
+        assert_eq!(result, Err("not ready"));
+        assert!(attempts > 1);
+
+        let mut attempts = 0;
+        let result: Result<(), &str> =
+            retry_until(Duration::ZERO, Duration::from_millis(1), || {
+                attempts += 1;
+                async { Err("not ready") }
+            })
+            .await;
+        assert!(result.is_err());
+        assert_eq!(attempts, 1);
+    }
+
+    #[test]
+    fn lists_only_enabled_targets() {
+        let mut containers = vec![
+            container(&[
+                (SSH_ENABLE_LABEL_KEY, "true"),
+                (SSH_HOSTNAME_LABEL_KEY, " app "),
+                (SSH_ALLOWED_USERS_LABEL_KEY, "git,root"),
+            ]),
+            container(&[
+                (SSH_ENABLE_LABEL_KEY, "false"),
+                (SSH_HOSTNAME_LABEL_KEY, "db"),
+            ]),
+        ];
+        containers[0].id = Some(String::from("abc"));
+        assert_eq!(
+            enabled_targets(&containers),
+            vec![Target {
+                hostname: String::from("app"),
+                allowed_users: vec![String::from("git"), String::from("root")],
+                state: None,
+                id: Some(String::from("abc")),
+            }]
+        );
+    }
+
+    #[test]
+    fn lists_only_targets_the_user_is_allowed_on() {
+        let mut containers = vec![
+            container(&[
+                (SSH_ENABLE_LABEL_KEY, "true"),
+                (SSH_HOSTNAME_LABEL_KEY, "app"),
+                (SSH_ALLOWED_USERS_LABEL_KEY, "git"),
+            ]),
+            container(&[
+                (SSH_ENABLE_LABEL_KEY, "true"),
+                (SSH_HOSTNAME_LABEL_KEY, "db"),
+                (SSH_ALLOWED_USERS_LABEL_KEY, "root"),
+            ]),
+            container(&[
+                (SSH_ENABLE_LABEL_KEY, "true"),
+                (SSH_HOSTNAME_LABEL_KEY, "cache"),
+            ]),
+        ];
+        containers[2].image = Some(String::from("redis"));
+        let images = vec![String::from("nginx")];
+        let hostnames: Vec<String> = allowed_targets(&containers, "git", &[], &[])
+            .into_iter()
+            .map(|target| target.hostname)
+            .collect();
+        assert_eq!(hostnames, vec!["app", "cache"]);
+        assert!(allowed_targets(&containers[2..], "git", &[], &images).is_empty());
+    }
+
+    #[test]
+    fn resolves_containers_by_id() {
+        let containers = direct_containers();
+        let by_id = |target: &str, user: Option<&str>| {
+            let args = ContainerArgs {
+                selector: TargetSelector::Id,
+                ..args(target)
+            };
+            select_container(&containers, &args, user.unwrap_or_default(), &[], &[])
+                .and_then(|container| container.id.clone())
+        };
+        assert_eq!(
+            by_id("0123456789ab", None).as_deref(),
+            Some("0123456789abcdef")
+        );
+        assert_eq!(
+            by_id("1123456789abcdef", Some("git")).as_deref(),
+            Some("1123456789abcdef")
+        );
+        // Allowed users still apply, disabled containers and short prefixes never match
+        assert_eq!(by_id("1123456789abcdef", Some("root")), None);
+        assert_eq!(by_id("2123456789abcdef", None), None);
+        assert_eq!(by_id("0123", None), None);
+    }
+
+    #[test]
+    fn resolves_containers_by_name() {
+        let containers = direct_containers();
+        let by_name = |target: &str, user: Option<&str>| {
+            let args = ContainerArgs {
+                selector: TargetSelector::Name,
+                ..args(target)
+            };
+            select_container(&containers, &args, user.unwrap_or_default(), &[], &[])
+                .and_then(container_name)
+                .map(str::to_string)
+        };
+        assert_eq!(
+            by_name("example-1", Some("git")).as_deref(),
+            Some("example-1")
+        );
+        assert_eq!(by_name("example-1", Some("root")), None);
+        assert_eq!(by_name("example-2", None), None);
+        // The hostname label is not a name
+        assert_eq!(by_name("app", None), None);
+    }
+
+    #[test]
+    fn strips_leading_slash_from_names() {
+        let container = ContainerSummary {
+            names: Some(vec![String::from("/example")]),
+            ..Default::default()
+        };
+        assert_eq!(container_name(&container), Some("example"));
+        assert_eq!(container_name(&ContainerSummary::default()), None);
+    }
+
+    #[tokio::test]
+    async fn retries_only_requests_that_never_reached_docker() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let daemon = MockDocker::start(vec![("GET /_ping", Reply::Body(200, "OK".into()))]).await;
+        // The client lost its daemon, reconnecting finds it at the configured host again
+        let lost = connect_to_host(UNREACHABLE_DOCKER, &API_DEFAULT_VERSION).unwrap();
+        let client = DockerClient::new(lost, DockerSettings::default().with_host(&daemon.host));
+        let attempts = AtomicUsize::new(0);
+        let pong = client
+            .with_retry(|docker| {
+                attempts.fetch_add(1, Ordering::SeqCst);
+                async move { docker.ping().await }
+            })
+            .await;
+        assert_eq!(pong.unwrap(), "OK");
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+
+        let attempts = AtomicUsize::new(0);
+        let timed_out = client
+            .with_retry(|_| {
+                attempts.fetch_add(1, Ordering::SeqCst);
+                async { Err::<(), _>(Error::RequestTimeoutError) }
+            })
+            .await;
+        assert!(matches!(timed_out, Err(Error::RequestTimeoutError)));
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn serves_lookups_within_the_ttl_from_the_cache() {
+        let daemon = MockDocker::start(vec![(
+            "GET /containers/json",
+            Reply::json(&vec![running("app")]),
+        )])
+        .await;
+        let docker = daemon.client();
+        let cache = Mutex::new(ContainerCache::new(Duration::from_secs(60)));
+        for _ in 0..2 {
+            let container = find_ssh_enabled_container(&docker, &args("app"), "", &[], &[], &cache)
+                .await
+                .unwrap();
+            assert_eq!(container.id.as_deref(), Some("app-id"));
+        }
+        assert_eq!(daemon.requests("GET /containers/json").len(), 1);
+    }
+
+    #[tokio::test]
+    async fn looks_containers_up_with_the_given_client() {
+        let injected = MockDocker::start(vec![(
+            "GET /containers/json",
+            Reply::json(&vec![running("app")]),
+        )])
+        .await;
+        let other = MockDocker::start(vec![(
+            "GET /containers/json",
+            Reply::json(&Vec::<ContainerSummary>::new()),
+        )])
+        .await;
+        let cache = Mutex::new(ContainerCache::new(Duration::ZERO));
+        let container =
+            find_ssh_enabled_container(&injected.client(), &args("app"), "", &[], &[], &cache)
+                .await
+                .unwrap();
+        assert_eq!(container.id.as_deref(), Some("app-id"));
+        assert_eq!(injected.requests("GET /containers/json").len(), 1);
+        assert!(other.requests("GET /containers/json").is_empty());
+    }
 }
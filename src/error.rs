@@ -0,0 +1,86 @@
+use std::net::IpAddr;
+
+use thiserror::Error;
+
+/// Represents the errors tunnyd can run into while serving a client.
+///
+/// # Remarks
+///
+/// The variants let callers tell the failure modes apart, e.g. to pick the message shown to the
+/// client. The `anyhow::Error` used as the SSH handler error type converts from `TunnydError`.
+#[derive(Debug, Error)]
+pub enum TunnydError {
+    #[error("No container is configured for target '{target}'")]
+    ContainerNotFound { target: String },
+    #[error(
+        "The container matching target '{target}' can't be reached, Docker reported no id for it"
+    )]
+    MissingContainerId { target: String },
+    #[error("Addressing containers by id or name is disabled")]
+    DirectAddressingDisabled,
+    #[error("Shared sessions are disabled")]
+    SharedSessionsDisabled,
+    #[error("Attaching to the main process is disabled")]
+    AttachDisabled,
+    #[error("--attach can't be combined with a command or --share")]
+    AttachConflict,
+    #[error("Invalid user '{user}', expected user or user:group")]
+    InvalidUser { user: String },
+    #[error("Invalid working directory '{workdir}', expected an absolute path")]
+    InvalidWorkdir { workdir: String },
+    #[error(
+        "The container is not attached to network '{network}' named by its tunnyD.network label"
+    )]
+    UnknownNetwork { network: String },
+    #[error("Invalid {label} label '{value}', expected {expected}")]
+    InvalidLimit {
+        label: &'static str,
+        value: String,
+        expected: &'static str,
+    },
+    #[error("Command is not valid UTF-8 (invalid byte at offset {offset}), only UTF-8 arguments can be passed to Docker")]
+    InvalidEncoding { offset: usize },
+    #[error("{0}")]
+    InvalidArguments(clap::Error),
+    #[error("{reason}")]
+    HookDenied { reason: String },
+    #[error("Command is {length} bytes long, at most {limit} are accepted")]
+    CommandTooLong { length: usize, limit: usize },
+    #[error("Command has more than {limit} words")]
+    TooManyWords { limit: usize },
+    #[error("Command not permitted: '{command}'")]
+    CommandNotPermitted { command: String },
+    #[error("{command}: command not found")]
+    CommandNotFound { command: String },
+    #[error("Docker did not {operation} the exec within {timeout_secs} seconds")]
+    ExecTimeout {
+        operation: &'static str,
+        timeout_secs: u64,
+    },
+    #[error("User '{user}' already has the maximum of {limit} concurrent sessions open")]
+    UserSessionLimit { user: String, limit: usize },
+    #[error("Address {ip} already has the maximum of {limit} concurrent sessions open")]
+    AddressSessionLimit { ip: IpAddr, limit: usize },
+    #[error("The connection already has the maximum of {limit} channels open")]
+    ChannelLimit { limit: usize },
+    #[error("A terminal is required, connect with ssh -t")]
+    TtyRequired,
+    #[error("Client not ready")]
+    ClientNotReady,
+    #[error("{message}")]
+    ControlRequest { message: String },
+    #[error("Exec {exec_id} has no running process")]
+    ExecNotRunning { exec_id: String },
+    #[error("Exec {exec_id} runs on a remote Docker host and can't be signalled")]
+    RemoteSignal { exec_id: String },
+    #[error("Container {target} runs on a remote Docker host and can't be forwarded to")]
+    RemoteForward { target: String },
+    #[error(
+        "The process of exec {exec_id} isn't in the PID namespace of tunnyd and can't be signalled"
+    )]
+    ForeignPid { exec_id: String },
+    #[error("Docker error: {0}")]
+    Docker(#[from] bollard::errors::Error),
+    #[error("Failed to signal process: {0}")]
+    Signal(#[from] nix::Error),
+}
@@ -0,0 +1,349 @@
+use std::future::Future;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tokio::sync::Notify;
+use tokio::time::Instant;
+
+/// The window clients open session channels with until they adjust it, OpenSSH's default.
+pub const INITIAL_WINDOW_SIZE: u32 = 2 * 1024 * 1024;
+/// The largest chunk sent in one message unless configured otherwise, OpenSSH's default maximum
+/// packet size.
+const MAX_CHUNK_SIZE: usize = 32 * 1024;
+/// How many chunks a rate limited channel sends per second at most, keeping the output smooth
+/// instead of arriving in one burst per second.
+const RATE_CHUNKS_PER_SEC: u64 = 10;
+
+/// Tracks how many more bytes the client is willing to receive on a channel.
+///
+/// # Remarks
+///
+/// - Every byte sent is deducted from the window, and every window adjustment announced by the
+///   client replaces it with the new size.
+/// - Once the window is exhausted, senders wait for the next adjustment instead of letting the
+///   output pile up in the session's buffers. A client that never adjusts it stalls the output
+///   until the channel closes and its forwarding task is aborted.
+/// - With an output rate limit, senders additionally wait until the channel is below its rate,
+///   even if the client's window would take more.
+/// - Output is sent in chunks of at most `max_chunk` bytes, so a huge piece of output doesn't turn
+///   into a single huge write.
+#[derive(Debug)]
+pub struct ChannelWindow {
+    available: Mutex<u32>,
+    adjusted: Notify,
+    max_chunk: usize,
+    rate: Option<OutputRate>,
+}
+
+/// Paces the output of a channel to a fixed number of bytes per second.
+///
+/// # Fields
+///
+/// * `bytes_per_sec`: The rate the output is paced to.
+/// * `next`: When the output sent so far has been paid for, the next chunk waits until then.
+#[derive(Debug)]
+struct OutputRate {
+    bytes_per_sec: u64,
+    next: Mutex<Instant>,
+}
+
+impl OutputRate {
+    /// The largest chunk sent at once, a fraction of a second's worth of output.
+    fn max_chunk(&self) -> usize {
+        (self.bytes_per_sec / RATE_CHUNKS_PER_SEC).max(1) as usize
+    }
+
+    /// Waits until a chunk may be sent without exceeding the rate.
+    ///
+    /// # Arguments
+    ///
+    /// * `bytes` - The size of the chunk.
+    async fn pace(&self, bytes: usize) {
+        let start = {
+            let mut next = self.next.lock().unwrap_or_else(|e| e.into_inner());
+            let start = (*next).max(Instant::now());
+            let cost = Duration::from_secs_f64(bytes as f64 / self.bytes_per_sec as f64);
+            *next = start + cost;
+            start
+        };
+        tokio::time::sleep_until(start).await;
+    }
+}
+
+impl ChannelWindow {
+    /// Creates the window of a newly opened channel.
+    ///
+    /// # Arguments
+    ///
+    /// * `initial` - The number of bytes the client accepts before the first adjustment.
+    pub fn new(initial: u32) -> ChannelWindow {
+        ChannelWindow::limited(initial, MAX_CHUNK_SIZE, 0)
+    }
+
+    /// Creates the window of a newly opened channel with bounded writes and optionally paced
+    /// output.
+    ///
+    /// # Arguments
+    ///
+    /// * `initial` - The number of bytes the client accepts before the first adjustment.
+    /// * `max_chunk` - The largest chunk sent in one write, at least one byte is sent.
+    /// * `bytes_per_sec` - The most output sent per second, `0` means unlimited.
+    pub fn limited(initial: u32, max_chunk: usize, bytes_per_sec: u64) -> ChannelWindow {
+        ChannelWindow {
+            available: Mutex::new(initial),
+            adjusted: Notify::new(),
+            max_chunk: max_chunk.max(1),
+            rate: (bytes_per_sec > 0).then(|| OutputRate {
+                bytes_per_sec,
+                next: Mutex::new(Instant::now()),
+            }),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, u32> {
+        match self.available.lock() {
+            Ok(available) => available,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    /// Records a window adjustment and wakes the waiting senders.
+    ///
+    /// # Arguments
+    ///
+    /// * `new_size` - The size of the window after the adjustment.
+    pub fn adjust(&self, new_size: u32) {
+        *self.lock() = new_size;
+        self.adjusted.notify_waiters();
+    }
+
+    /// Takes the credit for the next chunk of output, waiting for the window to open if needed.
+    ///
+    /// # Arguments
+    ///
+    /// * `wanted` - The number of bytes waiting to be sent.
+    ///
+    /// # Returns
+    ///
+    /// The number of bytes that may be sent now, at most `wanted` and the channel's chunk size.
+    async fn reserve(&self, wanted: usize) -> usize {
+        let granted = self.reserve_window(wanted).await;
+        if let Some(rate) = &self.rate {
+            rate.pace(granted).await;
+        }
+        granted
+    }
+
+    /// Takes the window credit for the next chunk, waiting for the window to open if needed.
+    async fn reserve_window(&self, wanted: usize) -> usize {
+        let limit = self
+            .rate
+            .as_ref()
+            .map_or(self.max_chunk, OutputRate::max_chunk);
+        let wanted = wanted.min(self.max_chunk).min(limit);
+        loop {
+            let adjusted = self.adjusted.notified();
+            {
+                let mut available = self.lock();
+                if *available > 0 {
+                    let granted = wanted.min(*available as usize);
+                    *available -= granted as u32;
+                    return granted;
+                }
+            }
+            adjusted.await;
+        }
+    }
+}
+
+/// Sends output in chunks that fit into the client's window.
+///
+/// # Arguments
+///
+/// * `window` - The window of the channel.
+/// * `data` - The output to send.
+/// * `send` - Sends one chunk to the client.
+///
+/// # Returns
+///
+/// The error of the first chunk that couldn't be sent, e.g. because the channel is closed.
+pub async fn send_windowed<F, Fut, E>(
+    window: &ChannelWindow,
+    mut data: &[u8],
+    mut send: F,
+) -> Result<(), E>
+where
+    F: FnMut(Vec<u8>) -> Fut,
+    Fut: Future<Output = Result<(), E>>,
+{
+    while !data.is_empty() {
+        let granted = window.reserve(data.len()).await;
+        let (chunk, rest) = data.split_at(granted);
+        send(chunk.to_vec()).await?;
+        data = rest;
+    }
+    Ok(())
+}
+
+/// Decides when a channel that keeps rejecting output is given up on.
+///
+/// # Remarks
+///
+/// Only consecutive failures count, a successful write starts over.
+#[derive(Debug)]
+pub struct WriteFailures {
+    tolerated: u32,
+    consecutive: AtomicU32,
+}
+
+impl WriteFailures {
+    /// Creates the policy of a channel.
+    ///
+    /// # Arguments
+    ///
+    /// * `tolerated` - The number of consecutive failed writes tolerated, `0` gives up on the first.
+    pub fn new(tolerated: u32) -> WriteFailures {
+        WriteFailures {
+            tolerated,
+            consecutive: AtomicU32::new(0),
+        }
+    }
+
+    /// Records a successful write.
+    pub fn succeeded(&self) {
+        self.consecutive.store(0, Ordering::Relaxed);
+    }
+
+    /// Records a failed write.
+    ///
+    /// # Returns
+    ///
+    /// Whether the session should be torn down.
+    pub fn failed(&self) -> bool {
+        self.consecutive.fetch_add(1, Ordering::Relaxed) >= self.tolerated
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use tokio::sync::mpsc;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn transfers_large_output_within_the_window() {
+        const WINDOW: usize = 64 * 1024;
+        let payload: Vec<u8> = (0..5 * 1024 * 1024u32).map(|i| (i % 251) as u8).collect();
+        let window = Arc::new(ChannelWindow::new(WINDOW as u32));
+        // Bytes sent but not consumed by the client yet, which must never exceed the window
+        let outstanding = Arc::new(AtomicUsize::new(0));
+        let (sender, mut receiver) = mpsc::unbounded_channel::<Vec<u8>>();
+
+        // The client consumes the output and opens the window again once half of it is used up,
+        // announcing the new size like the SSH session does
+        let client_window = Arc::clone(&window);
+        let client_outstanding = Arc::clone(&outstanding);
+        let client = tokio::spawn(async move {
+            let mut received = Vec::new();
+            let mut consumed = 0;
+            while let Some(chunk) = receiver.recv().await {
+                received.extend_from_slice(&chunk);
+                client_outstanding.fetch_sub(chunk.len(), Ordering::SeqCst);
+                consumed += chunk.len();
+                if consumed >= WINDOW / 2 {
+                    consumed = 0;
+                    client_window
+                        .adjust((WINDOW - client_outstanding.load(Ordering::SeqCst)) as u32);
+                }
+            }
+            received
+        });
+
+        send_windowed(&window, &payload, |chunk| {
+            assert!(chunk.len() <= MAX_CHUNK_SIZE);
+            let total = outstanding.fetch_add(chunk.len(), Ordering::SeqCst) + chunk.len();
+            assert!(total <= WINDOW, "the window was overrun");
+            let sent = sender.send(chunk).map_err(|_| "closed");
+            async move { sent }
+        })
+        .await
+        .unwrap();
+        drop(sender);
+        assert!(client.await.unwrap() == payload);
+    }
+
+    #[tokio::test]
+    async fn splits_huge_output_into_bounded_writes() {
+        const CHUNK: usize = 4096;
+        // A single line without a newline, as a misbehaving container might print it
+        let payload: Vec<u8> = (0..CHUNK * 100 + 7).map(|i| (i % 251) as u8).collect();
+        let window = ChannelWindow::limited(u32::MAX, CHUNK, 0);
+        let mut writes = Vec::new();
+        send_windowed(&window, &payload, |chunk| {
+            writes.push(chunk);
+            async { Ok::<(), ()>(()) }
+        })
+        .await
+        .unwrap();
+        assert_eq!(writes.len(), 101);
+        assert!(writes.iter().all(|write| write.len() <= CHUNK));
+        assert!(writes.concat() == payload);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn paces_output_to_the_rate_limit() {
+        const RATE: u64 = 100 * 1024;
+        let window = ChannelWindow::limited(INITIAL_WINDOW_SIZE, MAX_CHUNK_SIZE, RATE);
+        let payload = vec![0u8; 50 * 1024];
+        let started = Instant::now();
+        let mut received = 0;
+        send_windowed(&window, &payload, |chunk| {
+            assert!(chunk.len() as u64 <= RATE / RATE_CHUNKS_PER_SEC);
+            received += chunk.len();
+            async { Ok::<(), ()>(()) }
+        })
+        .await
+        .unwrap();
+        let elapsed = started.elapsed();
+        assert_eq!(received, payload.len());
+        // The first chunk goes out right away, the other 40 KiB take 0.4s at 100 KiB/s
+        assert!(
+            elapsed >= Duration::from_millis(390) && elapsed <= Duration::from_millis(410),
+            "50 KiB took {:?}",
+            elapsed
+        );
+    }
+
+    #[tokio::test]
+    async fn stops_at_the_first_failed_chunk() {
+        let window = ChannelWindow::new(INITIAL_WINDOW_SIZE);
+        let mut chunks = 0;
+        let result = send_windowed(&window, &[0; MAX_CHUNK_SIZE * 3], |_| {
+            chunks += 1;
+            async { Err("closed") }
+        })
+        .await;
+        assert_eq!(result, Err("closed"));
+        assert_eq!(chunks, 1);
+    }
+
+    #[test]
+    fn fails_fast_without_tolerance() {
+        assert!(WriteFailures::new(0).failed());
+    }
+
+    #[test]
+    fn tears_down_after_repeated_write_failures() {
+        let failures = WriteFailures::new(2);
+        assert!(!failures.failed());
+        assert!(!failures.failed());
+        failures.succeeded();
+        assert!(!failures.failed());
+        assert!(!failures.failed());
+        assert!(failures.failed());
+    }
+}
@@ -0,0 +1,323 @@
+use std::net::{IpAddr, SocketAddr};
+
+use bollard::models::{ContainerSummary, EndpointSettings};
+use ipnet::IpNet;
+use log::{info, warn};
+use russh::server::{Handle, Msg};
+use russh::Channel;
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::docker::container_network;
+
+const LOCALHOST_NAMES: [&str; 3] = ["localhost", "127.0.0.1", "::1"];
+const ANY_ADDRESS_NAMES: [&str; 3] = ["", "0.0.0.0", "::"];
+
+/// A Docker network a container is attached to, as reported in the session info.
+///
+/// # Fields
+///
+/// * `name`: The name of the Docker network.
+/// * `address`: The container's address in the network, with its prefix length.
+/// * `gateway`: The gateway of the network, the host side reachable from the container.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AttachedNetwork {
+    pub name: String,
+    pub address: Option<IpNet>,
+    pub gateway: Option<IpAddr>,
+}
+
+/// Lists the Docker networks a container is attached to.
+///
+/// # Arguments
+///
+/// * `container` - The container summary to read the network settings and labels from.
+///
+/// # Returns
+///
+/// The networks, the one named by the `tunnyD.network` label first and the others by name.
+pub fn attached_networks(container: &ContainerSummary) -> Vec<AttachedNetwork> {
+    let preferred = container_network(container).ok().flatten();
+    let mut endpoints: Vec<(&String, &EndpointSettings)> = container
+        .network_settings
+        .as_ref()
+        .and_then(|settings| settings.networks.as_ref())
+        .map(|networks| networks.iter().collect())
+        .unwrap_or_default();
+    endpoints.sort_by_key(|(name, _)| (Some(name.as_str()) != preferred.as_deref(), *name));
+    endpoints
+        .into_iter()
+        .map(|(name, endpoint)| AttachedNetwork {
+            name: name.clone(),
+            address: endpoint
+                .ip_address
+                .as_deref()
+                .and_then(|ip| ip.parse::<IpAddr>().ok())
+                .and_then(|ip| IpNet::new(ip, endpoint.ip_prefix_len.unwrap_or(32) as u8).ok()),
+            gateway: endpoint
+                .gateway
+                .as_deref()
+                .and_then(|gateway| gateway.parse().ok()),
+        })
+        .collect()
+}
+
+/// Resolves the destination of a local port forward into the container's network.
+///
+/// # Arguments
+///
+/// * `container` - The container the client is connected to.
+/// * `host` - The host requested by the client.
+/// * `port` - The port requested by the client.
+/// * `whole_networks` - Whether any address on the container's networks may be forwarded to, rather
+///   than only the container's own addresses.
+///
+/// # Returns
+///
+/// The address to connect to, or `None` if the destination isn't reachable. `localhost` refers to
+/// the container itself and is mapped to its address in the first network of `attached_networks`.
+/// The gateways of the networks are never reachable, they are the Docker host itself.
+pub fn forward_destination(
+    container: &ContainerSummary,
+    host: &str,
+    port: u32,
+    whole_networks: bool,
+) -> Option<SocketAddr> {
+    let port = u16::try_from(port).ok()?;
+    let networks = attached_networks(container);
+    if LOCALHOST_NAMES.contains(&host) {
+        return networks
+            .iter()
+            .find_map(|network| network.address)
+            .map(|net| SocketAddr::new(net.addr(), port));
+    }
+    let ip = host.parse::<IpAddr>().ok()?;
+    if networks.iter().any(|network| network.gateway == Some(ip)) {
+        return None;
+    }
+    networks
+        .iter()
+        .filter_map(|network| network.address)
+        .any(|net| match whole_networks {
+            true => net.contains(&ip),
+            false => net.addr() == ip,
+        })
+        .then_some(SocketAddr::new(ip, port))
+}
+
+/// Connects to a forwarding destination and pumps bytes between it and the SSH channel until
+/// either side closes.
+///
+/// # Arguments
+///
+/// * `channel` - The `direct-tcpip` channel opened by the client.
+/// * `destination` - The address to connect to.
+pub async fn pump_direct_tcpip(channel: Channel<Msg>, destination: SocketAddr) {
+    let mut socket = match TcpStream::connect(destination).await {
+        Ok(socket) => socket,
+        Err(e) => {
+            warn!("Failed to connect forward to {}: {}", destination, e);
+            let _ = channel.close().await;
+            return;
+        }
+    };
+    let mut stream = channel.into_stream();
+    match tokio::io::copy_bidirectional(&mut stream, &mut socket).await {
+        Ok((sent, received)) => info!(
+            "Forward to {} closed, {} bytes sent, {} bytes received",
+            destination, sent, received
+        ),
+        Err(e) => warn!("Forward to {} failed: {}", destination, e),
+    }
+}
+
+/// Resolves the address a remote port forward listens on so the container can reach it.
+///
+/// # Arguments
+///
+/// * `container` - The container the client is connected to.
+/// * `address` - The bind address requested by the client.
+///
+/// # Returns
+///
+/// The gateway of one of the container's networks, which is the host side of the network and
+/// reachable from inside the container. `localhost` and the wildcard addresses map to the gateway
+/// of the first network of `attached_networks`. `None` if the requested address is not a gateway
+/// of the container.
+pub fn remote_forward_bind_address(container: &ContainerSummary, address: &str) -> Option<IpAddr> {
+    let gateways: Vec<IpAddr> = attached_networks(container)
+        .into_iter()
+        .filter_map(|network| network.gateway)
+        .collect();
+    if LOCALHOST_NAMES.contains(&address) || ANY_ADDRESS_NAMES.contains(&address) {
+        return gateways.first().copied();
+    }
+    let ip = address.parse::<IpAddr>().ok()?;
+    gateways.contains(&ip).then_some(ip)
+}
+
+/// Accepts connections on a remote port forward and hands each of them to the client through a
+/// `forwarded-tcpip` channel.
+///
+/// # Arguments
+///
+/// * `listener` - The bound listener of the forward.
+/// * `handle` - The handle of the session that requested the forward.
+/// * `address` - The bind address as requested by the client, reported back on every channel.
+/// * `port` - The port of the forward, reported back on every channel.
+///
+/// # Remarks
+///
+/// The task ends when the client can no longer open channels, i.e. once its connection is gone.
+pub async fn listen_forwarded_tcpip(
+    listener: TcpListener,
+    handle: Handle,
+    address: String,
+    port: u32,
+) {
+    loop {
+        let (mut socket, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                warn!(
+                    "Failed to accept on remote forward {}:{}: {}",
+                    address, port, e
+                );
+                continue;
+            }
+        };
+        let channel = match handle
+            .channel_open_forwarded_tcpip(
+                address.clone(),
+                port,
+                peer.ip().to_string(),
+                peer.port() as u32,
+            )
+            .await
+        {
+            Ok(channel) => channel,
+            Err(e) => {
+                info!(
+                    "Closing remote forward {}:{}, the client is gone: {:?}",
+                    address, port, e
+                );
+                return;
+            }
+        };
+        tokio::spawn(async move {
+            let mut stream = channel.into_stream();
+            if let Err(e) = tokio::io::copy_bidirectional(&mut stream, &mut socket).await {
+                warn!("Remote forward from {} failed: {}", peer, e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use bollard::models::{ContainerSummaryNetworkSettings, EndpointSettings};
+
+    use super::*;
+
+    fn container() -> ContainerSummary {
+        let endpoint = EndpointSettings {
+            ip_address: Some(String::from("172.18.0.5")),
+            ip_prefix_len: Some(16),
+            gateway: Some(String::from("172.18.0.1")),
+            ..Default::default()
+        };
+        ContainerSummary {
+            network_settings: Some(ContainerSummaryNetworkSettings {
+                networks: Some(HashMap::from([(String::from("app"), endpoint)])),
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn forwards_within_container_networks() {
+        let container = container();
+        assert_eq!(
+            forward_destination(&container, "localhost", 5432, false),
+            Some("172.18.0.5:5432".parse().unwrap())
+        );
+        assert_eq!(
+            forward_destination(&container, "172.18.0.5", 80, false),
+            Some("172.18.0.5:80".parse().unwrap())
+        );
+        // Other containers only with the whole networks allowed
+        assert_eq!(
+            forward_destination(&container, "172.18.3.4", 80, false),
+            None
+        );
+        assert_eq!(
+            forward_destination(&container, "172.18.3.4", 80, true),
+            Some("172.18.3.4:80".parse().unwrap())
+        );
+        // The Docker host never
+        assert_eq!(
+            forward_destination(&container, "172.18.0.1", 80, false),
+            None
+        );
+        assert_eq!(
+            forward_destination(&container, "172.18.0.1", 80, true),
+            None
+        );
+        assert_eq!(forward_destination(&container, "10.0.0.1", 80, true), None);
+        assert_eq!(
+            forward_destination(&container, "localhost", 70000, false),
+            None
+        );
+    }
+
+    #[test]
+    fn binds_remote_forwards_on_the_gateway() {
+        let container = container();
+        let gateway: IpAddr = "172.18.0.1".parse().unwrap();
+        assert_eq!(remote_forward_bind_address(&container, ""), Some(gateway));
+        assert_eq!(
+            remote_forward_bind_address(&container, "172.18.0.1"),
+            Some(gateway)
+        );
+        assert_eq!(remote_forward_bind_address(&container, "10.0.0.1"), None);
+    }
+
+    #[test]
+    fn prefers_the_labelled_network() {
+        let mut container = container();
+        let backend = EndpointSettings {
+            ip_address: Some(String::from("10.5.0.7")),
+            ip_prefix_len: Some(24),
+            gateway: Some(String::from("10.5.0.1")),
+            ..Default::default()
+        };
+        if let Some(networks) = container
+            .network_settings
+            .as_mut()
+            .and_then(|settings| settings.networks.as_mut())
+        {
+            networks.insert(String::from("backend"), backend);
+        }
+        let names = |container: &ContainerSummary| -> Vec<String> {
+            attached_networks(container)
+                .into_iter()
+                .map(|network| network.name)
+                .collect()
+        };
+        assert_eq!(names(&container), vec!["app", "backend"]);
+        container.labels = Some(HashMap::from([(
+            String::from("tunnyD.network"),
+            String::from("backend"),
+        )]));
+        assert_eq!(names(&container), vec!["backend", "app"]);
+        assert_eq!(
+            forward_destination(&container, "localhost", 80, false),
+            Some("10.5.0.7:80".parse().unwrap())
+        );
+        assert_eq!(
+            remote_forward_bind_address(&container, "localhost"),
+            Some("10.5.0.1".parse().unwrap())
+        );
+    }
+}
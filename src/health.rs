@@ -0,0 +1,140 @@
+use std::sync::Arc;
+
+use log::{error, info, warn};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::docker::DockerClient;
+use crate::listener::ListenEndpoint;
+
+/// Checks whether the server is ready to serve clients.
+///
+/// # Arguments
+///
+/// * `docker` - The Docker client of the server.
+/// * `endpoints` - The endpoints the SSH listeners are bound to.
+///
+/// # Returns
+///
+/// `Ok(())` if Docker answers a ping and every SSH listener accepts connections, otherwise the
+/// reason the server is unhealthy.
+async fn check_health(docker: &DockerClient, endpoints: &[ListenEndpoint]) -> Result<(), String> {
+    docker
+        .get()
+        .ping()
+        .await
+        .map_err(|e| format!("docker unreachable: {}", e))?;
+    for endpoint in endpoints {
+        endpoint
+            .probe()
+            .await
+            .map_err(|e| format!("ssh listener {} not bound: {}", endpoint, e))?;
+    }
+    Ok(())
+}
+
+/// Answers a single health probe.
+///
+/// The response is a minimal HTTP response, so the endpoint works for both HTTP and plain TCP probes:
+/// `200 OK` when healthy and `503 Service Unavailable` with the reason otherwise.
+async fn answer_probe(
+    mut stream: TcpStream,
+    docker: DockerClient,
+    endpoints: Arc<Vec<ListenEndpoint>>,
+) {
+    // The request itself is irrelevant, read what the probe sent so it doesn't see a reset
+    let mut request = [0u8; 1024];
+    let _ = stream.read(&mut request).await;
+
+    let (status, body) = match check_health(&docker, &endpoints).await {
+        Ok(()) => ("200 OK", String::from("healthy\n")),
+        Err(reason) => {
+            warn!("Health check failed: {}", reason);
+            (
+                "503 Service Unavailable",
+                format!("unhealthy: {}\n", reason),
+            )
+        }
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    if let Err(e) = stream.write_all(response.as_bytes()).await {
+        error!("Failed to answer health probe: {}", e);
+    }
+    let _ = stream.shutdown().await;
+}
+
+/// Serves the health check endpoint until the process exits.
+///
+/// # Arguments
+///
+/// * `listen` - The address to bind the endpoint to, e.g. `0.0.0.0:8080`.
+/// * `docker` - The Docker client of the server.
+/// * `endpoints` - The endpoints the SSH listeners are bound to.
+pub async fn serve_health(listen: String, docker: DockerClient, endpoints: Vec<ListenEndpoint>) {
+    let endpoints = Arc::new(endpoints);
+    let listener = match TcpListener::bind(&listen).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Failed to bind health endpoint to {}: {}", listen, e);
+            return;
+        }
+    };
+    info!("Health endpoint listening on {}", listen);
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                tokio::spawn(answer_probe(stream, docker.clone(), Arc::clone(&endpoints)));
+            }
+            Err(e) => error!("Failed to accept health probe: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{mock_host, MockDocker, Reply};
+
+    /// Sends a probe to `answer_probe` and returns the response.
+    async fn probe(docker: DockerClient, endpoints: Vec<ListenEndpoint>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        tokio::spawn(answer_probe(stream, docker, Arc::new(endpoints)));
+        client
+            .write_all(b"GET /health HTTP/1.1\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn reports_unhealthy_when_docker_is_unreachable() {
+        let docker = mock_host("local", Vec::new()).docker;
+        let response = probe(docker, Vec::new()).await;
+        assert!(response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+        assert!(response.contains("unhealthy: docker unreachable"));
+    }
+
+    #[tokio::test]
+    async fn reports_healthy_when_docker_answers_and_listeners_are_bound() {
+        let daemon = MockDocker::start(vec![("GET /_ping", Reply::Body(200, "OK".into()))]).await;
+        let ssh = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = ListenEndpoint::Tcp {
+            host: String::from("127.0.0.1"),
+            port: ssh.local_addr().unwrap().port(),
+        };
+        let response = probe(daemon.client(), vec![endpoint]).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("healthy\n"));
+    }
+}
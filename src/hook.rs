@@ -0,0 +1,338 @@
+use std::net::IpAddr;
+use std::process::Stdio;
+use std::time::Duration;
+
+use serde::Serialize;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::process::Command;
+
+use crate::config::ServerConfig;
+use crate::error::TunnydError;
+
+/// The most of a webhook response that is read, the status and a short reason fit easily.
+const MAX_RESPONSE_LENGTH: u64 = 64 * 1024;
+/// The longest reason shown to a client whose session was denied.
+const MAX_REASON_LENGTH: usize = 200;
+
+/// What the connect hook is told about a session request, sent to it as a JSON object.
+///
+/// # Fields
+///
+/// * `login`: The user name the connection authenticated with.
+/// * `user`: The container user the session runs as, `null` for the image's default.
+/// * `target`: The target the client asked for.
+/// * `container_id`: The ID of the container the target resolved to.
+/// * `container_name`: The name of that container, if it has one.
+/// * `source_ip`: The address the client connected from, if known.
+/// * `command`: The command the client sent, empty for interactive shells.
+#[derive(Debug, Serialize)]
+pub struct ConnectContext<'a> {
+    pub login: Option<&'a str>,
+    pub user: Option<&'a str>,
+    pub target: &'a str,
+    pub container_id: &'a str,
+    pub container_name: Option<&'a str>,
+    pub source_ip: Option<IpAddr>,
+    pub command: &'a str,
+}
+
+/// Asks the configured connect hooks whether a session may be opened.
+///
+/// # Arguments
+///
+/// * `config` - The server configuration, naming the hooks.
+/// * `context` - The session request.
+///
+/// # Returns
+///
+/// `Ok(())` if no hook is configured or every configured hook allowed the session, otherwise
+/// `TunnydError::HookDenied` with the reason the hook gave.
+///
+/// # Remarks
+///
+/// - The `connect_hook_command` gets the context on stdin and allows by exiting with `0`.
+/// - The `connect_hook_url` gets the context POSTed and allows by answering with a `2xx` status.
+/// - The first line a hook writes to stdout or its response body is shown to the client as the
+///   reason of a denial.
+/// - Hooks that fail, or don't answer within `connect_hook_timeout_secs`, deny the session.
+pub async fn run_connect_hook(
+    config: &ServerConfig,
+    context: &ConnectContext<'_>,
+) -> Result<(), TunnydError> {
+    if config.connect_hook_command.is_none() && config.connect_hook_url.is_none() {
+        return Ok(());
+    }
+    let payload = serde_json::to_vec(context).map_err(|e| TunnydError::HookDenied {
+        reason: format!("Failed to encode the hook context: {}", e),
+    })?;
+    let timeout = Duration::from_secs(config.connect_hook_timeout_secs);
+    if let Some(command) = &config.connect_hook_command {
+        within(timeout, run_hook_command(command, &payload)).await?;
+    }
+    if let Some(url) = &config.connect_hook_url {
+        within(timeout, post_webhook(url, &payload)).await?;
+    }
+    Ok(())
+}
+
+/// Runs a hook, denying the session if it doesn't decide within the timeout.
+async fn within<F>(timeout: Duration, hook: F) -> Result<(), TunnydError>
+where
+    F: std::future::Future<Output = Result<(), String>>,
+{
+    let decision = tokio::time::timeout(timeout, hook)
+        .await
+        .unwrap_or_else(|_| {
+            Err(format!(
+                "The connect hook did not answer within {} seconds",
+                timeout.as_secs()
+            ))
+        });
+    decision.map_err(|reason| TunnydError::HookDenied { reason })
+}
+
+/// Runs the hook command with the context on stdin.
+///
+/// # Returns
+///
+/// `Ok(())` if it exited with `0`, otherwise the reason of the denial.
+async fn run_hook_command(command: &[String], payload: &[u8]) -> Result<(), String> {
+    let (program, args) = command
+        .split_first()
+        .ok_or("connect_hook_command is empty")?;
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| format!("Failed to run the connect hook {}: {}", program, e))?;
+    if let Some(mut stdin) = child.stdin.take() {
+        // Hooks deciding without reading the context close stdin early, that isn't an error
+        let _ = stdin.write_all(payload).await;
+    }
+    let output = child
+        .wait_with_output()
+        .await
+        .map_err(|e| format!("The connect hook {} failed: {}", program, e))?;
+    if output.status.success() {
+        return Ok(());
+    }
+    Err(denial_reason(&output.stdout)
+        .unwrap_or_else(|| format!("Denied by the connect hook ({})", output.status)))
+}
+
+/// Splits a webhook URL into the address to connect to, the `Host` header and the path.
+///
+/// # Arguments
+///
+/// * `url` - The URL, e.g. `http://policy.internal:8080/connect`.
+///
+/// # Returns
+///
+/// The parts, or an error message if the URL isn't a plain `http://` URL. TLS isn't supported,
+/// point the hook at a local proxy or use the command hook for `https://` endpoints.
+pub fn parse_webhook_url(url: &str) -> Result<(String, String, String), String> {
+    let rest = url
+        .strip_prefix("http://")
+        .ok_or_else(|| format!("Unsupported connect_hook_url '{}', only http:// is", url))?;
+    let (authority, path) = match rest.find('/') {
+        Some(index) => rest.split_at(index),
+        None => (rest, "/"),
+    };
+    if authority.is_empty() {
+        return Err(format!("connect_hook_url '{}' has no host", url));
+    }
+    let has_port = match authority.rfind(']') {
+        Some(bracket) => authority[bracket..].contains(':'),
+        None => authority.contains(':'),
+    };
+    let address = match has_port {
+        true => authority.to_string(),
+        false => format!("{}:80", authority),
+    };
+    Ok((address, authority.to_string(), path.to_string()))
+}
+
+/// POSTs the context to the webhook.
+///
+/// # Returns
+///
+/// `Ok(())` if it answered with a `2xx` status, otherwise the reason of the denial.
+async fn post_webhook(url: &str, payload: &[u8]) -> Result<(), String> {
+    let (address, host, path) = parse_webhook_url(url)?;
+    let mut stream = TcpStream::connect(&address)
+        .await
+        .map_err(|e| format!("Failed to reach the connect hook {}: {}", url, e))?;
+    let mut request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        path,
+        host,
+        payload.len()
+    )
+    .into_bytes();
+    request.extend_from_slice(payload);
+    let mut response = Vec::new();
+    let exchanged = async {
+        stream.write_all(&request).await?;
+        (&mut stream)
+            .take(MAX_RESPONSE_LENGTH)
+            .read_to_end(&mut response)
+            .await
+    };
+    exchanged
+        .await
+        .map_err(|e| format!("The connect hook {} failed: {}", url, e))?;
+    webhook_decision(&response)
+}
+
+/// Reads the decision from a webhook response.
+///
+/// # Arguments
+///
+/// * `response` - The raw HTTP response.
+///
+/// # Returns
+///
+/// `Ok(())` for a `2xx` status, otherwise the reason of the denial.
+fn webhook_decision(response: &[u8]) -> Result<(), String> {
+    let text = String::from_utf8_lossy(response);
+    let (head, body) = text.split_once("\r\n\r\n").unwrap_or((&text, ""));
+    let status_line = head.lines().next().unwrap_or_default();
+    let status: u16 = status_line
+        .split(' ')
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(|| format!("Invalid connect hook response '{}'", status_line))?;
+    if (200..300).contains(&status) {
+        return Ok(());
+    }
+    Err(denial_reason(body.as_bytes())
+        .unwrap_or_else(|| format!("Denied by the connect hook ({})", status_line)))
+}
+
+/// Returns the first non-empty line a hook gave, shortened for the client.
+fn denial_reason(output: &[u8]) -> Option<String> {
+    let text = String::from_utf8_lossy(output);
+    let line = text.lines().map(str::trim).find(|line| !line.is_empty())?;
+    Some(line.chars().take(MAX_REASON_LENGTH).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    fn context() -> ConnectContext<'static> {
+        ConnectContext {
+            login: Some("alice"),
+            user: Some("www-data"),
+            target: "web.my-docker",
+            container_id: "0123456789ab",
+            container_name: Some("web"),
+            source_ip: Some("203.0.113.7".parse().unwrap()),
+            command: "ls -l",
+        }
+    }
+
+    fn shell_hook(script: &str) -> ServerConfig {
+        ServerConfig {
+            connect_hook_command: Some(vec![
+                String::from("sh"),
+                String::from("-c"),
+                script.to_string(),
+            ]),
+            ..Default::default()
+        }
+    }
+
+    /// Answers a single webhook request with the given response, returning the request.
+    async fn webhook(response: &'static str) -> (String, tokio::task::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/connect", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = vec![0u8; 4096];
+            let mut read = 0;
+            // The request is complete once the announced body arrived
+            while !String::from_utf8_lossy(&request[..read]).ends_with('}') {
+                read += stream.read(&mut request[read..]).await.unwrap();
+            }
+            stream.write_all(response.as_bytes()).await.unwrap();
+            String::from_utf8_lossy(&request[..read]).into_owned()
+        });
+        (url, server)
+    }
+
+    #[tokio::test]
+    async fn allows_sessions_without_hooks() {
+        assert!(run_connect_hook(&ServerConfig::default(), &context())
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn asks_the_hook_command() {
+        let allow = shell_hook("grep -q '\"login\":\"alice\"'");
+        assert!(run_connect_hook(&allow, &context()).await.is_ok());
+
+        let deny = shell_hook("echo 'outside of business hours'; exit 1");
+        match run_connect_hook(&deny, &context()).await {
+            Err(TunnydError::HookDenied { reason }) => {
+                assert_eq!(reason, "outside of business hours")
+            }
+            result => panic!("unexpected result {:?}", result),
+        }
+
+        let slow = ServerConfig {
+            connect_hook_timeout_secs: 0,
+            ..shell_hook("sleep 5")
+        };
+        assert!(run_connect_hook(&slow, &context()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn asks_the_webhook() {
+        let (url, server) = webhook("HTTP/1.1 204 No Content\r\nContent-Length: 0\r\n\r\n").await;
+        let config = ServerConfig {
+            connect_hook_url: Some(url),
+            ..Default::default()
+        };
+        assert!(run_connect_hook(&config, &context()).await.is_ok());
+        let request = server.await.unwrap();
+        assert!(request.starts_with("POST /connect HTTP/1.1\r\n"));
+        assert!(request.contains("\"source_ip\":\"203.0.113.7\""));
+
+        let (url, _server) =
+            webhook("HTTP/1.1 403 Forbidden\r\nContent-Length: 16\r\n\r\nMFA is required\n").await;
+        let config = ServerConfig {
+            connect_hook_url: Some(url),
+            ..Default::default()
+        };
+        match run_connect_hook(&config, &context()).await {
+            Err(TunnydError::HookDenied { reason }) => assert_eq!(reason, "MFA is required"),
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn parses_webhook_urls() {
+        assert_eq!(
+            parse_webhook_url("http://policy.internal:8080/v1/connect").unwrap(),
+            (
+                String::from("policy.internal:8080"),
+                String::from("policy.internal:8080"),
+                String::from("/v1/connect")
+            )
+        );
+        assert_eq!(
+            parse_webhook_url("http://[::1]").unwrap().0,
+            String::from("[::1]:80")
+        );
+        assert!(parse_webhook_url("https://policy.internal/").is_err());
+        assert!(parse_webhook_url("http:///connect").is_err());
+    }
+}
@@ -0,0 +1,157 @@
+/// How far a session channel got in setting up what it runs.
+///
+/// # Remarks
+///
+/// A session channel starts in `Setup`, where the client sends its `pty-req`, `env` and
+/// `window-change` requests. The first `shell`, `exec` or `subsystem` request moves it to `Starting`
+/// while the exec is created, then to `Running` once the exec started or to `Closed` if the session
+/// was denied or failed to start. Channels never go back to an earlier stage.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SessionStage {
+    #[default]
+    Setup,
+    Starting,
+    Running,
+    Closed,
+}
+
+/// What to do with a `window-change` request.
+///
+/// - `Store`: Remember the size, it is applied once the exec started.
+/// - `Apply`: Resize the running exec.
+/// - `Ignore`: The session is closed, nothing can be resized anymore.
+#[derive(Debug, PartialEq, Eq)]
+pub enum WindowChange {
+    Store,
+    Apply,
+    Ignore,
+}
+
+/// The per-channel state machine ordering the requests of a session.
+///
+/// # Fields
+///
+/// * `stage`: The stage the channel is in.
+///
+/// # Remarks
+///
+/// | Request         | `Setup`  | `Starting` | `Running`    | `Closed` |
+/// |-----------------|----------|------------|--------------|----------|
+/// | `pty-req`/`env` | accepted | refused    | refused      | refused  |
+/// | `window-change` | stored   | stored     | resizes exec | ignored  |
+/// | `shell`/`exec`  | starts   | refused    | refused      | refused  |
+/// | `signal`        | ignored  | ignored    | forwarded    | ignored  |
+///
+/// The exec is created with everything the setup requests asked for, so it is only created once
+/// the client sent `shell`, `exec` or `subsystem`, and setup requests arriving later are refused
+/// like OpenSSH does instead of silently not applying to the running exec.
+#[derive(Debug, Default)]
+pub struct SessionIntent {
+    stage: SessionStage,
+}
+
+impl SessionIntent {
+    /// Returns the stage the channel is in.
+    pub fn stage(&self) -> SessionStage {
+        self.stage
+    }
+
+    /// Checks whether a `pty-req` or `env` request may still change the session.
+    pub fn accepts_setup(&self) -> bool {
+        self.stage == SessionStage::Setup
+    }
+
+    /// Handles a `shell`, `exec` or `subsystem` request.
+    ///
+    /// # Returns
+    ///
+    /// `true` if the session may start, `false` if the channel already asked for a shell, a
+    /// command or a subsystem.
+    pub fn begin(&mut self) -> bool {
+        match self.stage {
+            SessionStage::Setup => {
+                self.stage = SessionStage::Starting;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Records the outcome of starting the session.
+    ///
+    /// # Arguments
+    ///
+    /// * `started` - Whether the exec started, `false` if the session was denied or failed.
+    ///
+    /// # Returns
+    ///
+    /// `true` if the session just started running, the stored terminal size has to be applied then.
+    pub fn finish_start(&mut self, started: bool) -> bool {
+        if self.stage != SessionStage::Starting {
+            return false;
+        }
+        self.stage = match started {
+            true => SessionStage::Running,
+            false => SessionStage::Closed,
+        };
+        started
+    }
+
+    /// Handles a `window-change` request.
+    pub fn window_change(&self) -> WindowChange {
+        match self.stage {
+            SessionStage::Setup | SessionStage::Starting => WindowChange::Store,
+            SessionStage::Running => WindowChange::Apply,
+            SessionStage::Closed => WindowChange::Ignore,
+        }
+    }
+
+    /// Checks whether a `signal` request has a process to go to.
+    pub fn accepts_signal(&self) -> bool {
+        self.stage == SessionStage::Running
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn follows_the_interactive_shell_ordering() {
+        // ssh -t: pty-req, env..., shell, then window-change and signal while running
+        let mut intent = SessionIntent::default();
+        assert!(intent.accepts_setup());
+        assert_eq!(intent.window_change(), WindowChange::Store);
+        assert!(!intent.accepts_signal());
+        assert!(intent.begin());
+        assert_eq!(intent.window_change(), WindowChange::Store);
+        assert!(intent.finish_start(true));
+        assert_eq!(intent.stage(), SessionStage::Running);
+        assert_eq!(intent.window_change(), WindowChange::Apply);
+        assert!(intent.accepts_signal());
+    }
+
+    #[test]
+    fn refuses_requests_out_of_order() {
+        let mut intent = SessionIntent::default();
+        assert!(intent.begin());
+        // A terminal or variable arriving after the command would not reach the exec
+        assert!(!intent.accepts_setup());
+        assert!(!intent.begin());
+        intent.finish_start(true);
+        assert!(!intent.accepts_setup());
+        assert!(!intent.begin());
+        assert!(!intent.finish_start(true));
+    }
+
+    #[test]
+    fn closes_sessions_that_fail_to_start() {
+        let mut intent = SessionIntent::default();
+        assert!(intent.begin());
+        assert!(!intent.finish_start(false));
+        assert_eq!(intent.stage(), SessionStage::Closed);
+        assert_eq!(intent.window_change(), WindowChange::Ignore);
+        assert!(!intent.accepts_signal());
+        assert!(!intent.begin());
+    }
+}
@@ -0,0 +1,137 @@
+/// The shell the command of a limited session is wrapped in, it applies the limits with `ulimit`
+/// and then replaces itself with the command.
+const LIMIT_SHELL: &str = "sh";
+/// The name the wrapping shell runs as, `$0` of the script.
+const LIMIT_SCRIPT_NAME: &str = "tunnyd-limits";
+
+/// The resource limits applied to the processes of a session.
+///
+/// # Fields
+///
+/// * `cpu_secs`: The CPU time in seconds each process may consume before it is killed.
+/// * `memory_bytes`: The virtual memory each process may allocate.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ResourceLimits {
+    pub cpu_secs: Option<u64>,
+    pub memory_bytes: Option<u64>,
+}
+
+impl ResourceLimits {
+    /// Wraps a command so it runs with the limits applied.
+    ///
+    /// # Arguments
+    ///
+    /// * `cmd` - The command of the exec.
+    ///
+    /// # Returns
+    ///
+    /// The command unchanged without limits. Otherwise a `sh -c` script setting the limits with
+    /// `ulimit` before `exec`ing the command, which is passed as the script's arguments so it is
+    /// never parsed by the shell. The session fails if a limit can't be set.
+    pub fn wrap(&self, cmd: Vec<String>) -> Vec<String> {
+        let mut script = Vec::new();
+        if let Some(secs) = self.cpu_secs {
+            script.push(format!("ulimit -t {}", secs));
+        }
+        if let Some(bytes) = self.memory_bytes {
+            // ulimit takes KiB, round up so tiny limits don't become unlimited
+            script.push(format!("ulimit -v {}", bytes.div_ceil(1024)));
+        }
+        if script.is_empty() {
+            return cmd;
+        }
+        script.push(String::from("exec \"$@\""));
+        let mut wrapped = vec![
+            LIMIT_SHELL.to_string(),
+            String::from("-c"),
+            script.join(" && "),
+            LIMIT_SCRIPT_NAME.to_string(),
+        ];
+        wrapped.extend(cmd);
+        wrapped
+    }
+}
+
+/// Parses a CPU time limit.
+///
+/// # Arguments
+///
+/// * `value` - A positive number of seconds, e.g. `300`.
+///
+/// # Returns
+///
+/// The seconds, or `None` if the value isn't a positive number of seconds.
+pub fn parse_cpu_secs(value: &str) -> Option<u64> {
+    value.trim().parse().ok().filter(|secs| *secs > 0)
+}
+
+/// Parses a memory limit in the format Docker uses.
+///
+/// # Arguments
+///
+/// * `value` - A positive number of bytes, optionally suffixed by `b`, `k`, `m` or `g`, e.g.
+///   `512m`.
+///
+/// # Returns
+///
+/// The bytes, or `None` if the value isn't a positive size or overflows.
+pub fn parse_memory(value: &str) -> Option<u64> {
+    let value = value.trim().to_ascii_lowercase();
+    let (number, unit) = match value.char_indices().find(|(_, c)| !c.is_ascii_digit()) {
+        Some((index, _)) => value.split_at(index),
+        None => (value.as_str(), ""),
+    };
+    let multiplier: u64 = match unit {
+        "" | "b" => 1,
+        "k" => 1024,
+        "m" => 1024 * 1024,
+        "g" => 1024 * 1024 * 1024,
+        _ => return None,
+    };
+    number
+        .parse::<u64>()
+        .ok()
+        .filter(|number| *number > 0)?
+        .checked_mul(multiplier)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_limits() {
+        assert_eq!(parse_cpu_secs(" 300 "), Some(300));
+        assert_eq!(parse_cpu_secs("0"), None);
+        assert_eq!(parse_cpu_secs("1.5"), None);
+        assert_eq!(parse_memory("512m"), Some(512 * 1024 * 1024));
+        assert_eq!(parse_memory("1G"), Some(1024 * 1024 * 1024));
+        assert_eq!(parse_memory("2048"), Some(2048));
+        assert_eq!(parse_memory("64kb"), None);
+        assert_eq!(parse_memory("m"), None);
+        assert_eq!(parse_memory("0m"), None);
+        assert_eq!(parse_memory("-1"), None);
+        assert_eq!(parse_memory("99999999999999999999g"), None);
+    }
+
+    #[test]
+    fn wraps_commands_with_limits() {
+        let cmd = vec![String::from("bash"), String::from("-l")];
+        assert_eq!(ResourceLimits::default().wrap(cmd.clone()), cmd);
+        let limits = ResourceLimits {
+            cpu_secs: Some(60),
+            memory_bytes: Some(1500),
+        };
+        assert_eq!(
+            limits.wrap(cmd),
+            vec![
+                "sh",
+                "-c",
+                "ulimit -t 60 && ulimit -v 2 && exec \"$@\"",
+                "tunnyd-limits",
+                "bash",
+                "-l",
+            ]
+        );
+    }
+}
@@ -0,0 +1,453 @@
+use std::fmt;
+use std::future::Future;
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::{info, warn};
+use russh::server::{Config, Server as _};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+use tokio::sync::Notify;
+
+use crate::notify::Readiness;
+use crate::proxy::{read_proxy_header, PROXY_HEADER_TIMEOUT};
+use crate::reload::snapshot;
+use crate::server::Server;
+
+const ANY_ADDRESS_NAMES: [&str; 3] = ["", "0.0.0.0", "::"];
+/// Log target of the messages operators need at startup, always logged at info level.
+pub const STARTUP_LOG_TARGET: &str = "tunnyd::startup";
+
+/// An address the SSH server accepts connections on.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ListenEndpoint {
+    /// A TCP address and port.
+    Tcp { host: String, port: u16 },
+    /// The path of a Unix domain socket.
+    Unix(PathBuf),
+}
+
+impl fmt::Display for ListenEndpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenEndpoint::Tcp { host, port } => write!(f, "{}:{}", host, port),
+            ListenEndpoint::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+impl FromStr for ListenEndpoint {
+    type Err = String;
+
+    /// Parses an endpoint written as `host:port`, `[ipv6]:port` or `unix:/path/to/socket`.
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = value.strip_prefix("unix:") {
+            if path.is_empty() {
+                return Err(format!(
+                    "Missing socket path in listen endpoint '{}'",
+                    value
+                ));
+            }
+            return Ok(ListenEndpoint::Unix(PathBuf::from(path)));
+        }
+        let (host, port) = value
+            .rsplit_once(':')
+            .ok_or_else(|| format!("Invalid listen endpoint '{}', expected host:port", value))?;
+        let port = port
+            .parse::<u16>()
+            .map_err(|_| format!("Invalid port in listen endpoint '{}'", value))?;
+        Ok(ListenEndpoint::Tcp {
+            host: host
+                .trim_start_matches('[')
+                .trim_end_matches(']')
+                .to_string(),
+            port,
+        })
+    }
+}
+
+impl ListenEndpoint {
+    /// Checks that the endpoint accepts connections.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(())` if a connection could be opened. Wildcard TCP addresses are probed on the loopback
+    /// address.
+    pub async fn probe(&self) -> Result<(), std::io::Error> {
+        match self {
+            ListenEndpoint::Tcp { host, port } => {
+                let host = match ANY_ADDRESS_NAMES.contains(&host.as_str()) {
+                    true => "127.0.0.1",
+                    false => host.as_str(),
+                };
+                TcpStream::connect((host, *port)).await.map(|_| ())
+            }
+            ListenEndpoint::Unix(path) => UnixStream::connect(path).await.map(|_| ()),
+        }
+    }
+
+    /// Removes the socket file of a Unix endpoint, logging failures.
+    pub fn cleanup(&self) {
+        if let ListenEndpoint::Unix(path) = self {
+            if let Err(e) = remove_socket(path) {
+                warn!("Failed to remove socket {}: {}", path.display(), e);
+            }
+        }
+    }
+
+    /// Accepts connections on the endpoint and serves them until the listener fails.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The SSH configuration shared by every connection.
+    /// * `server` - The server state every connection handler is cloned from.
+    /// * `readiness` - Told once the endpoint is bound.
+    ///
+    /// # Returns
+    ///
+    /// The error the listener stopped with.
+    pub async fn run(
+        &self,
+        config: Arc<Config>,
+        server: Server,
+        readiness: &Readiness,
+    ) -> Result<(), std::io::Error> {
+        match self {
+            ListenEndpoint::Tcp { host, port } => {
+                run_tcp(config, host, *port, server, readiness).await
+            }
+            ListenEndpoint::Unix(path) => run_unix(config, path, server, readiness).await,
+        }
+    }
+}
+
+/// Removes a Unix socket file if it exists.
+///
+/// # Remarks
+///
+/// Only sockets are removed, so a misconfigured path can't delete a regular file.
+fn remove_socket(path: &Path) -> Result<(), std::io::Error> {
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path),
+        Ok(_) => Err(std::io::Error::new(
+            std::io::ErrorKind::AlreadyExists,
+            "path exists and is not a socket",
+        )),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Accepts connections on a Unix socket and runs a SSH session over each of them.
+///
+/// # Arguments
+///
+/// * `config` - The SSH configuration shared by every connection.
+/// * `path` - The path of the socket, a stale socket left by a previous run is replaced.
+/// * `server` - The server state every connection handler is cloned from.
+/// * `readiness` - Told once the socket is bound.
+async fn run_unix(
+    config: Arc<Config>,
+    path: &Path,
+    mut server: Server,
+    readiness: &Readiness,
+) -> Result<(), std::io::Error> {
+    remove_socket(path)?;
+    let listener = UnixListener::bind(path)?;
+    info!(target: STARTUP_LOG_TARGET, "Listening on unix:{}", path.display());
+    readiness.bound();
+    loop {
+        let (stream, _) = listener.accept().await?;
+        // Unix sockets have no peer address, the IP filter treats these connections as allowed
+        let handler = server.new_client(None);
+        tokio::spawn(run_session(Arc::clone(&config), stream, handler));
+    }
+}
+
+/// Accepts TCP connections and runs a SSH session over each of them.
+///
+/// # Arguments
+///
+/// * `config` - The SSH configuration shared by every connection.
+/// * `host` - The address to bind to.
+/// * `port` - The port to bind to.
+/// * `server` - The server state every connection handler is cloned from.
+/// * `readiness` - Told once the address is bound.
+async fn run_tcp(
+    config: Arc<Config>,
+    host: &str,
+    port: u16,
+    mut server: Server,
+    readiness: &Readiness,
+) -> Result<(), std::io::Error> {
+    let listener = TcpListener::bind((host, port)).await?;
+    info!(target: STARTUP_LOG_TARGET, "Listening on {}", listener.local_addr()?);
+    readiness.bound();
+    loop {
+        let (mut stream, peer_addr) = listener.accept().await?;
+        if let Err(e) = stream.set_nodelay(true) {
+            warn!("Failed to set TCP_NODELAY for {}: {}", peer_addr, e);
+        }
+        let proxied = snapshot(&server.settings)
+            .trusted_proxies
+            .is_some_and(|proxies| proxies.is_allowed(peer_addr.ip()));
+        if !proxied {
+            let handler = server.new_client(Some(peer_addr));
+            tokio::spawn(run_session(Arc::clone(&config), stream, handler));
+            continue;
+        }
+        // The header is read off the accept loop, a slow proxy mustn't hold up other connections
+        let config = Arc::clone(&config);
+        let mut server = server.clone();
+        tokio::spawn(async move {
+            let header = tokio::time::timeout(PROXY_HEADER_TIMEOUT, read_proxy_header(&mut stream));
+            let client_addr = match header.await {
+                Ok(Ok(client_addr)) => client_addr.unwrap_or(peer_addr),
+                Ok(Err(e)) => {
+                    warn!("Dropping connection from proxy {}: {}", peer_addr, e);
+                    return;
+                }
+                Err(_) => {
+                    warn!(
+                        "Dropping connection from proxy {}: no PROXY header within {:?}",
+                        peer_addr, PROXY_HEADER_TIMEOUT
+                    );
+                    return;
+                }
+            };
+            let handler = server.new_client(Some(client_addr));
+            run_session(config, stream, handler).await;
+        });
+    }
+}
+
+/// Runs a SSH session over an accepted connection until it ends.
+///
+/// # Remarks
+///
+/// Connections that don't authenticate within the `auth_timeout_secs` of the handler's
+/// configuration are dropped, whether they stall in the handshake or in the login.
+async fn run_session<S>(config: Arc<Config>, stream: S, handler: Server)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let id = handler.id;
+    let authenticated = Arc::clone(&handler.authenticated);
+    let deadline = (handler.config.auth_timeout_secs > 0)
+        .then(|| Duration::from_secs(handler.config.auth_timeout_secs));
+    let connection = async move {
+        match russh::server::run_stream(config, stream, handler).await {
+            Ok(session) => {
+                if let Err(e) = session.await {
+                    warn!("Session ended with an error: {:?}", e);
+                }
+            }
+            Err(e) => warn!("Failed to start session: {:?}", e),
+        }
+    };
+    if with_auth_deadline(connection, &authenticated, deadline)
+        .await
+        .is_none()
+    {
+        warn!(
+            "Dropping client {}: not authenticated within {:?}",
+            id,
+            deadline.unwrap_or_default()
+        );
+    }
+}
+
+/// Runs a connection, giving up on it if it doesn't authenticate in time.
+///
+/// # Arguments
+///
+/// * `connection` - Runs the connection, dropping it closes the connection.
+/// * `authenticated` - Notified once the connection authenticated.
+/// * `deadline` - How long the connection may take to authenticate, `None` waits forever.
+///
+/// # Returns
+///
+/// The output of the connection, or `None` if it was dropped at the deadline.
+async fn with_auth_deadline<F>(
+    connection: F,
+    authenticated: &Notify,
+    deadline: Option<Duration>,
+) -> Option<F::Output>
+where
+    F: Future,
+{
+    tokio::pin!(connection);
+    if let Some(deadline) = deadline {
+        tokio::select! {
+            output = &mut connection => return Some(output),
+            _ = authenticated.notified() => {}
+            _ = tokio::time::sleep(deadline) => return None,
+        }
+    }
+    Some(connection.await)
+}
+
+#[cfg(test)]
+mod tests {
+    use russh::MethodSet;
+
+    use super::*;
+    use crate::testing::{connect_stream, loopback_container, open_server, ssh_config, wait_until};
+
+    #[test]
+    fn parses_endpoints() {
+        assert_eq!(
+            "0.0.0.0:2222".parse::<ListenEndpoint>(),
+            Ok(ListenEndpoint::Tcp {
+                host: String::from("0.0.0.0"),
+                port: 2222
+            })
+        );
+        assert_eq!(
+            "[::1]:22".parse::<ListenEndpoint>(),
+            Ok(ListenEndpoint::Tcp {
+                host: String::from("::1"),
+                port: 22
+            })
+        );
+        assert_eq!(
+            "unix:/run/tunnyd.sock".parse::<ListenEndpoint>(),
+            Ok(ListenEndpoint::Unix(PathBuf::from("/run/tunnyd.sock")))
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_endpoints() {
+        assert!("unix:".parse::<ListenEndpoint>().is_err());
+        assert!("localhost".parse::<ListenEndpoint>().is_err());
+        assert!("localhost:99999".parse::<ListenEndpoint>().is_err());
+    }
+
+    #[test]
+    fn displays_endpoints() {
+        let endpoint = ListenEndpoint::Unix(PathBuf::from("/run/tunnyd.sock"));
+        assert_eq!(endpoint.to_string(), "unix:/run/tunnyd.sock");
+    }
+
+    #[test]
+    fn only_removes_sockets() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("regular");
+        std::fs::write(&file, "keep").unwrap();
+        assert!(remove_socket(&file).is_err());
+        assert!(file.exists());
+        assert!(remove_socket(&dir.path().join("missing")).is_ok());
+    }
+
+    #[tokio::test]
+    async fn drops_connections_stalling_in_auth() {
+        use tokio::io::AsyncReadExt;
+
+        let (mut client, mut server) = tokio::io::duplex(64);
+        // The server waits for a handshake the client never sends
+        let connection = async move {
+            let mut handshake = [0u8; 1];
+            let _ = server.read(&mut handshake).await;
+        };
+        let authenticated = Notify::new();
+        let dropped =
+            with_auth_deadline(connection, &authenticated, Some(Duration::from_millis(50)));
+        assert!(tokio::time::timeout(Duration::from_secs(5), dropped)
+            .await
+            .expect("the deadline didn't fire")
+            .is_none());
+        // Dropping the connection closed it
+        let mut buf = [0u8; 1];
+        assert_eq!(client.read(&mut buf).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn keeps_authenticated_connections_past_the_deadline() {
+        let authenticated = Notify::new();
+        authenticated.notify_one();
+        let connection = async {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            "closed by the client"
+        };
+        let output =
+            with_auth_deadline(connection, &authenticated, Some(Duration::from_millis(10))).await;
+        assert_eq!(output, Some("closed by the client"));
+        assert_eq!(
+            with_auth_deadline(async { 1 }, &Notify::new(), None).await,
+            Some(1)
+        );
+    }
+
+    #[tokio::test]
+    async fn accepts_connections_on_a_unix_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let endpoint = ListenEndpoint::Unix(dir.path().join("tunnyd.sock"));
+        let server = open_server(Default::default(), vec![loopback_container("app")]);
+        let clients = Arc::clone(&server.clients);
+        let config = Arc::new(ssh_config(MethodSet::NONE));
+        let listener = tokio::spawn({
+            let endpoint = endpoint.clone();
+            async move { endpoint.run(config, server, &Readiness::new(1)).await }
+        });
+        let bound = &endpoint;
+        wait_until(move || async move { bound.probe().await.is_ok() }).await;
+
+        let ListenEndpoint::Unix(path) = &endpoint else {
+            unreachable!()
+        };
+        let stream = UnixStream::connect(path).await.unwrap();
+        let mut connection = connect_stream(stream).await;
+        assert!(connection.handle.authenticate_none("app").await.unwrap());
+        let _channel = connection.handle.channel_open_session().await.unwrap();
+        let clients = &clients;
+        wait_until(move || async move { clients.lock().await.len() == 1 }).await;
+
+        listener.abort();
+        endpoint.cleanup();
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn serves_every_endpoint_from_the_same_clients() {
+        let server = open_server(Default::default(), vec![loopback_container("app")]);
+        let config = Arc::new(ssh_config(MethodSet::NONE));
+        let mut endpoints = Vec::new();
+        for _ in 0..2 {
+            // Reserve a free port, the endpoint binds it again once released
+            let port = TcpListener::bind("127.0.0.1:0")
+                .await
+                .unwrap()
+                .local_addr()
+                .unwrap()
+                .port();
+            endpoints.push(ListenEndpoint::Tcp {
+                host: String::from("127.0.0.1"),
+                port,
+            });
+        }
+        let readiness = Arc::new(Readiness::new(endpoints.len()));
+        for endpoint in endpoints.clone() {
+            let (config, server, readiness) =
+                (Arc::clone(&config), server.clone(), Arc::clone(&readiness));
+            tokio::spawn(async move { endpoint.run(config, server, &readiness).await });
+        }
+
+        let mut connections = Vec::new();
+        for endpoint in &endpoints {
+            wait_until(move || async move { endpoint.probe().await.is_ok() }).await;
+            let ListenEndpoint::Tcp { host, port } = endpoint else {
+                unreachable!()
+            };
+            let stream = TcpStream::connect((host.as_str(), *port)).await.unwrap();
+            let mut connection = connect_stream(stream).await;
+            assert!(connection.handle.authenticate_none("app").await.unwrap());
+            let channel = connection.handle.channel_open_session().await.unwrap();
+            connections.push((connection, channel));
+        }
+        let clients = &server.clients;
+        wait_until(move || async move { clients.lock().await.len() == 2 }).await;
+    }
+}
@@ -1,67 +1,675 @@
 use std::collections::HashMap;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
 
-use russh::*;
-use tokio::sync::Mutex;
+use log::LevelFilter;
+use tokio::sync::{Mutex, Notify};
 
-use crate::docker::connect_to_docker;
+use crate::acl::AuthFailures;
+use crate::affinity::Affinity;
+use crate::algorithms::preferred;
+use crate::audit::AuditLog;
+use crate::auth::{
+    keyboard_interactive_enabled, method_set, AuthMethod, AuthPolicy, AUTHORIZED_KEYS_USER_TOKEN,
+};
+use crate::cli::{parse_daemon_args, DaemonCommand, ServeArgs};
+use crate::config::ServerConfig;
+use crate::docker::{
+    connect_to_docker_host, wait_for_docker, ContainerCache, DockerClient, DockerHost,
+    DockerSettings,
+};
+use crate::health::serve_health;
+use crate::listener::{ListenEndpoint, STARTUP_LOG_TARGET};
+use crate::notify::{sd_notify, Readiness};
+use crate::reload::{reload_on_hangup, snapshot, LiveSettings};
 use crate::server::Server;
+use crate::shared::SharedSessions;
+use crate::supervisor::{drain, shutdown_signal, supervise};
+use crate::usage::{UsageCache, USAGE_CACHE_TTL};
 
+mod acl;
+mod affinity;
+mod algorithms;
+mod audit;
+mod auth;
 mod cli;
+mod config;
+mod context;
+mod control;
 mod docker;
+mod error;
+mod flow;
+mod forwarding;
+mod health;
+mod hook;
+mod intent;
+mod limits;
+mod listener;
+mod notify;
+mod output;
+mod proxy;
+mod recording;
+mod reload;
+mod resolve;
 mod server;
+mod shared;
+mod supervisor;
+mod targets;
+#[cfg(test)]
+mod testing;
+mod usage;
+mod validate;
+
+const LOG_LEVELS: [LevelFilter; 6] = [
+    LevelFilter::Off,
+    LevelFilter::Error,
+    LevelFilter::Warn,
+    LevelFilter::Info,
+    LevelFilter::Debug,
+    LevelFilter::Trace,
+];
+
 #[tokio::main]
 async fn main() {
-    use tokio::sync::mpsc;
-    env_logger::builder()
-        .filter_level(log::LevelFilter::Warn)
-        .init();
+    let args = parse_daemon_args(std::env::args_os()).unwrap_or_else(|e| e.exit());
+
+    match args.command {
+        DaemonCommand::Serve(serve_args) if serve_args.test_config => {
+            // Problems are printed, keep the logs to warnings unless asked for more
+            init_logging("warn", args.verbosity);
+            if !validate::test_config(&serve_args).await {
+                std::process::exit(1);
+            }
+        }
+        DaemonCommand::Serve(serve_args) => {
+            let server_config = load_config(serve_args.config.as_deref());
+            init_logging(&server_config.log_level, args.verbosity);
+            serve(serve_args, server_config).await
+        }
+        DaemonCommand::ListTargets(list_args) => {
+            let server_config = load_config(list_args.config.as_deref());
+            init_logging("warn", args.verbosity);
+            if !targets::print_targets(list_args, server_config).await {
+                std::process::exit(1);
+            }
+        }
+        DaemonCommand::Resolve(resolve_args) => {
+            let server_config = load_config(resolve_args.config.as_deref());
+            // The report goes to stdout, keep the logs to warnings unless asked for more
+            init_logging("warn", args.verbosity);
+            if !resolve::resolve(resolve_args, server_config).await {
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+/// Raises or lowers a log level.
+///
+/// # Arguments
+///
+/// * `level` - The configured log level.
+/// * `verbosity` - The number of levels to raise (positive) or lower (negative) it by.
+///
+/// # Returns
+///
+/// The adjusted level, clamped between `off` and `trace`.
+fn adjust_log_level(level: LevelFilter, verbosity: i8) -> LevelFilter {
+    let index = LOG_LEVELS
+        .iter()
+        .position(|candidate| *candidate == level)
+        .unwrap_or(3) as i8;
+    LOG_LEVELS[(index + verbosity).clamp(0, LOG_LEVELS.len() as i8 - 1) as usize]
+}
+
+/// Loads the configuration, exiting if it can't be loaded.
+///
+/// # Arguments
+///
+/// * `path` - The configuration file, the defaults are used when unset.
+///
+/// # Remarks
+///
+/// Logging isn't set up yet since the configuration names the log level, so the error is printed to
+/// stderr.
+fn load_config(path: Option<&str>) -> ServerConfig {
+    ServerConfig::load(path).unwrap_or_else(|e| {
+        eprintln!("Failed to load configuration: {}", e);
+        std::process::exit(1)
+    })
+}
+
+/// Unwraps the result of a startup step, logging the error and exiting if it failed.
+///
+/// # Arguments
+///
+/// * `result` - The result of the step.
+/// * `context` - What the step sets up, prefixed to the error.
+fn or_exit<T>(result: Result<T, impl std::fmt::Display>, context: &str) -> T {
+    result.unwrap_or_else(|e| {
+        log::error!("{}: {}", context, e);
+        std::process::exit(1)
+    })
+}
+
+/// Sets up logging.
+///
+/// # Arguments
+///
+/// * `log_level` - The configured log level.
+/// * `verbosity` - The adjustment requested with `-v` and `-q`.
+///
+/// # Remarks
+///
+/// `RUST_LOG` takes precedence over both. Startup messages operators need, like the host key
+/// fingerprint, are logged unless logging is turned off entirely.
+fn init_logging(log_level: &str, verbosity: i8) {
+    log_builder(
+        log_level,
+        verbosity,
+        std::env::var("RUST_LOG").ok().as_deref(),
+    )
+    .init();
+}
+
+/// Builds the logger configured by `init_logging`.
+///
+/// # Arguments
+///
+/// * `log_level` - The configured log level.
+/// * `verbosity` - The adjustment requested with `-v` and `-q`.
+/// * `rust_log` - The filters from `RUST_LOG`, if set.
+fn log_builder(log_level: &str, verbosity: i8, rust_log: Option<&str>) -> env_logger::Builder {
+    let level = adjust_log_level(log_level.parse().unwrap_or(LevelFilter::Info), verbosity);
+    let mut builder = env_logger::Builder::new();
+    builder.filter_level(level).filter_module(
+        STARTUP_LOG_TARGET,
+        match level {
+            LevelFilter::Off => LevelFilter::Off,
+            level => level.max(LevelFilter::Info),
+        },
+    );
+    if let Some(filters) = rust_log {
+        builder.parse_filters(filters);
+    }
+    builder
+}
+
+/// Runs the SSH server until its listener stops.
+///
+/// # Arguments
+///
+/// * `args` - The arguments of the `serve` subcommand.
+/// * `server_config` - The loaded configuration.
+async fn serve(args: ServeArgs, server_config: ServerConfig) {
+    // The same checks as --test-config and reloads, so every problem is reported at once
+    let problems = validate::config_problems(&args, &server_config);
+    if !problems.is_empty() {
+        for problem in &problems {
+            log::error!("{}", problem);
+        }
+        std::process::exit(1);
+    }
+    let endpoints = or_exit(
+        listen_endpoints(&args, &server_config),
+        "Invalid listen endpoint",
+    );
+    let audit = or_exit(
+        AuditLog::open(&server_config.audit_log),
+        "Failed to open audit log",
+    );
+    let live = or_exit(
+        LiveSettings::new(server_config.clone()),
+        "Invalid configuration",
+    );
+    let preferred = or_exit(preferred(&server_config), "Invalid algorithm preferences");
+    let container_cache_ttl =
+        std::time::Duration::from_millis(server_config.container_cache_ttl_ms);
+
+    let docker_settings = DockerSettings::new(
+        args.docker_context
+            .clone()
+            .or_else(|| server_config.docker_context.clone()),
+        server_config.docker_api_version.clone(),
+    );
+    // A bad version would only be retried until the startup timeout
+    if let Err(e) = docker_settings.client_version() {
+        log::error!("{}", e);
+        std::process::exit(1);
+    }
+    let docker_wait = std::time::Duration::from_secs(server_config.docker_startup_timeout_secs);
+    let docker = match wait_for_docker(&docker_settings, docker_wait).await {
+        Ok(docker) => docker,
+        Err(e) => {
+            log::error!(
+                "Docker is not reachable after waiting {}s, giving up: {}",
+                docker_wait.as_secs(),
+                e
+            );
+            std::process::exit(1);
+        }
+    };
+
+    if server_config
+        .auth_methods
+        .contains(&AuthMethod::KeyboardInteractive)
+        && !keyboard_interactive_enabled(&server_config)
+    {
+        log::warn!(
+            "keyboard-interactive is enabled without a command or tokens, every attempt will fail"
+        );
+    }
+
+    let auth_policy = AuthPolicy::from_flag(args.insecure_allow_all);
+    match auth_policy {
+        AuthPolicy::InsecureAllowAll => log::warn!(
+            "INSECURE: --insecure-allow-all accepts any public key and the none method, never use it in production"
+        ),
+        AuthPolicy::Enforce => {
+            if server_config.auth_methods.contains(&AuthMethod::Publickey)
+                && server_config.authorized_keys_file.is_none()
+            {
+                log::warn!("publickey is enabled without an authorized_keys_file, every key will be refused");
+            }
+            if server_config
+                .authorized_keys_file
+                .as_ref()
+                .is_some_and(|path| !path.contains(AUTHORIZED_KEYS_USER_TOKEN))
+            {
+                log::warn!("authorized_keys_file has no %u, every key in it can log in as any user");
+            }
+            if server_config.auth_methods.contains(&AuthMethod::None) {
+                log::warn!("none is enabled but only accepted with --insecure-allow-all, it will be refused");
+            }
+        }
+    }
 
-    // Assuming the `connect_to_docker` function correctly initializes a `bollard::Docker` instance.
-    let docker = connect_to_docker().await.expect("Docker connection failed");
+    let host_key = russh_keys::key::KeyPair::generate_ed25519().unwrap();
+    match host_key.clone_public_key() {
+        Ok(public_key) => log::info!(
+            target: STARTUP_LOG_TARGET,
+            "Host key {} {}",
+            public_key.name(),
+            openssh_fingerprint(&public_key)
+        ),
+        Err(e) => log::warn!("Failed to read the public host key: {}", e),
+    }
 
     let config = russh::server::Config {
         inactivity_timeout: Some(std::time::Duration::from_secs(3600)),
         auth_rejection_time: std::time::Duration::from_secs(3),
         auth_rejection_time_initial: Some(std::time::Duration::from_secs(10)),
-        keys: vec![russh_keys::key::KeyPair::generate_ed25519().unwrap()],
-        methods: MethodSet::NONE,
+        keepalive_interval: keepalive_interval(&server_config),
+        keys: vec![host_key],
+        methods: method_set(&server_config.auth_methods),
+        preferred,
+        auth_banner: auth_banner(&server_config),
         ..Default::default()
     };
 
     let config = Arc::new(config);
+    let mut docker_hosts = Vec::with_capacity(server_config.docker_hosts.len() + 1);
+    for host in &server_config.docker_hosts {
+        let settings = docker_settings.with_host(host);
+        match connect_to_docker_host(&settings).await {
+            Ok(client) => docker_hosts.push(DockerHost {
+                name: host.clone(),
+                docker: DockerClient::new(client, settings),
+                cache: Arc::new(Mutex::new(ContainerCache::new(container_cache_ttl))),
+            }),
+            Err(e) => {
+                log::error!("Invalid Docker host {}: {}", host, e);
+                std::process::exit(1);
+            }
+        }
+    }
+    let docker = DockerClient::new(docker, docker_settings);
+    // The primary daemon is searched first so it wins when several hosts run a target
+    docker_hosts.insert(
+        0,
+        DockerHost {
+            name: String::from("default"),
+            docker: docker.clone(),
+            cache: Arc::new(Mutex::new(ContainerCache::new(container_cache_ttl))),
+        },
+    );
+
+    if let Some(health_listen) = server_config.health_listen.clone() {
+        tokio::spawn(serve_health(
+            health_listen,
+            docker.clone(),
+            endpoints.clone(),
+        ));
+    }
 
+    let auth_failures = AuthFailures::new(
+        server_config.auth_failure_threshold,
+        std::time::Duration::from_secs(server_config.auth_ban_secs),
+    );
     let server = Server {
         clients: Arc::new(Mutex::new(HashMap::new())),
         docker,
         id: 0,
+        next_id: Arc::new(AtomicUsize::new(0)),
+        login_user: None,
+        keyboard_responses: Vec::new(),
+        peer_addr: None,
+        config: Arc::clone(&live.config),
+        audit: Arc::new(audit),
+        ip_filter: Arc::clone(&live.ip_filter),
+        command_filter: Arc::clone(&live.command_filter),
+        settings: Arc::new(RwLock::new(live)),
+        auth_failures: Arc::new(auth_failures),
+        auth_policy,
+        docker_hosts: Arc::new(docker_hosts),
+        remote_forwards: Arc::new(Mutex::new(HashMap::new())),
+        shared_sessions: Arc::new(SharedSessions::default()),
+        usage_cache: Arc::new(Mutex::new(UsageCache::new(USAGE_CACHE_TTL))),
+        connection: None,
+        authenticated: Arc::new(Notify::new()),
+        affinity: Arc::new(Affinity::default()),
+        draining: Arc::new(AtomicBool::new(false)),
     };
 
-    let (tx, mut rx) = mpsc::channel(1);
+    // Containers can enable the idle timeout with a label even when it is disabled server-wide
+    tokio::spawn(server.clone().reap_idle_sessions());
+    tokio::spawn(reload_on_hangup(Arc::clone(&server.settings), args.clone()));
 
-    loop {
-        let config_clone = config.clone();
-        let server_clone = server.clone();
-        let tx_clone = tx.clone();
+    // Docker is reachable at this point, systemd is told the server is ready once every endpoint
+    // is bound
+    let readiness = Readiness::new(endpoints.len());
+    // Every listener is supervised on its own, the process only stops once one of them gives up
+    let listeners = futures::future::try_join_all(endpoints.iter().map(|endpoint| {
+        let name = endpoint.to_string();
+        let config = config.clone();
+        let server = server.clone();
+        let readiness = &readiness;
+        async move {
+            supervise(&name, || {
+                endpoint.run(config.clone(), server.clone(), readiness)
+            })
+            .await
+        }
+    }));
+    let result = tokio::select! {
+        result = listeners => result.map(|_| ()),
+        _ = shutdown_signal() => Ok(()),
+    };
+    sd_notify(&[("STOPPING", "1")]);
+    for endpoint in &endpoints {
+        endpoint.cleanup();
+    }
+    // The listeners are gone, connections that are still open get to finish their sessions
+    let drain_timeout = snapshot(&server.settings).config.drain_timeout_secs;
+    if result.is_ok() && drain_timeout > 0 {
+        server.draining.store(true, Ordering::SeqCst);
+        let clients = Arc::clone(&server.clients);
+        let open_sessions = || {
+            let clients = Arc::clone(&clients);
+            async move { clients.lock().await.len() }
+        };
+        tokio::select! {
+            _ = drain(open_sessions, std::time::Duration::from_secs(drain_timeout)) => {}
+            _ = shutdown_signal() => log::info!("Stopped draining"),
+        }
+    }
+    if result.is_err() {
+        std::process::exit(1);
+    }
+}
+
+/// Collects the endpoints the SSH server listens on.
+///
+/// # Arguments
+///
+/// * `args` - The arguments of the `serve` subcommand.
+/// * `config` - The loaded configuration.
+///
+/// # Returns
+///
+/// The endpoints given with `--listen` and `--unix-socket`, else the ones from the configuration,
+/// else `--bind` and `--port`. An error message if any endpoint can't be parsed.
+fn listen_endpoints(
+    args: &ServeArgs,
+    config: &ServerConfig,
+) -> Result<Vec<ListenEndpoint>, String> {
+    let mut endpoints = args
+        .listen
+        .iter()
+        .map(|value| value.parse::<ListenEndpoint>())
+        .collect::<Result<Vec<ListenEndpoint>, String>>()?;
+    if let Some(path) = &args.unix_socket {
+        endpoints.push(ListenEndpoint::Unix(path.into()));
+    }
+    if endpoints.is_empty() {
+        endpoints = config
+            .listen
+            .iter()
+            .map(|value| value.parse::<ListenEndpoint>())
+            .collect::<Result<Vec<ListenEndpoint>, String>>()?;
+    }
+    if endpoints.is_empty() {
+        endpoints.push(ListenEndpoint::Tcp {
+            host: args.bind.clone(),
+            port: args.port,
+        });
+    }
+    Ok(endpoints)
+}
+
+/// Returns how often keepalive requests are sent to clients.
+///
+/// # Arguments
+///
+/// * `server_config` - The loaded configuration.
+///
+/// # Returns
+///
+/// The interval, or `None` if keepalives are disabled.
+///
+/// # Remarks
+///
+/// Keepalives only keep the transport open, abandoned sessions are still closed by the idle timeout
+/// since they don't count as session activity.
+fn keepalive_interval(server_config: &ServerConfig) -> Option<std::time::Duration> {
+    (server_config.keepalive_interval_secs > 0)
+        .then(|| std::time::Duration::from_secs(server_config.keepalive_interval_secs))
+}
+
+/// Loads the banner sent to clients before authentication.
+///
+/// # Arguments
+///
+/// * `server_config` - The loaded configuration.
+///
+/// # Returns
+///
+/// The banner, leaked since it lives for the whole lifetime of the server, or `None` if no banner is
+/// configured or its file can't be read.
+fn auth_banner(server_config: &ServerConfig) -> Option<&'static str> {
+    server_config
+        .load_banner()
+        .map(|banner| &*Box::leak(banner.into_boxed_str()))
+}
 
+/// Formats the fingerprint of a host key the way `ssh-keygen -l` and `ssh` show it.
+///
+/// # Arguments
+///
+/// * `key` - The public host key.
+///
+/// # Returns
+///
+/// The SHA256 fingerprint as `SHA256:` followed by the unpadded base64 digest.
+fn openssh_fingerprint(key: &russh_keys::key::PublicKey) -> String {
+    format!("SHA256:{}", key.fingerprint().trim_end_matches('='))
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    fn serve_args(listen: &[&str], unix_socket: Option<&str>) -> ServeArgs {
+        ServeArgs {
+            bind: String::from("0.0.0.0"),
+            port: 2222,
+            listen: listen.iter().map(|value| value.to_string()).collect(),
+            unix_socket: unix_socket.map(str::to_string),
+            docker_context: None,
+            config: None,
+            test_config: false,
+            check_docker: false,
+            insecure_allow_all: false,
+        }
+    }
+
+    #[test]
+    fn adjusts_log_levels_within_bounds() {
+        assert_eq!(adjust_log_level(LevelFilter::Info, 1), LevelFilter::Debug);
+        assert_eq!(adjust_log_level(LevelFilter::Info, -2), LevelFilter::Error);
+        assert_eq!(adjust_log_level(LevelFilter::Trace, 5), LevelFilter::Trace);
+        assert_eq!(adjust_log_level(LevelFilter::Error, -5), LevelFilter::Off);
+    }
+
+    #[test]
+    fn command_line_endpoints_win_over_configuration() {
+        let config = ServerConfig {
+            listen: vec![String::from("127.0.0.1:22")],
+            ..Default::default()
+        };
+        let endpoints = listen_endpoints(
+            &serve_args(&["10.0.0.1:2222"], Some("/run/t.sock")),
+            &config,
+        );
+        assert_eq!(
+            endpoints.unwrap(),
+            vec![
+                ListenEndpoint::Tcp {
+                    host: String::from("10.0.0.1"),
+                    port: 2222
+                },
+                ListenEndpoint::Unix("/run/t.sock".into()),
+            ]
+        );
+        let endpoints = listen_endpoints(&serve_args(&[], None), &config).unwrap();
+        assert_eq!(endpoints[0].to_string(), "127.0.0.1:22");
+    }
+
+    #[test]
+    fn falls_back_to_bind_and_port() {
+        let endpoints = listen_endpoints(&serve_args(&[], None), &ServerConfig::default()).unwrap();
+        assert_eq!(endpoints[0].to_string(), "0.0.0.0:2222");
+    }
+
+    #[test]
+    fn lets_rust_log_override_the_configured_level() {
+        use log::Log;
+
+        let enabled = |logger: &env_logger::Logger, level: log::Level| {
+            logger.enabled(
+                &log::Metadata::builder()
+                    .level(level)
+                    .target("docker_ssh_adapter::server")
+                    .build(),
+            )
+        };
+        let configured = log_builder("warn", 0, None).build();
+        assert!(!enabled(&configured, log::Level::Info));
+        let overridden = log_builder("warn", 0, Some("debug")).build();
+        assert_eq!(overridden.filter(), LevelFilter::Debug);
+        assert!(enabled(&overridden, log::Level::Debug));
+        let quieted = log_builder("debug", 1, Some("error")).build();
+        assert!(!enabled(&quieted, log::Level::Warn));
+    }
+
+    #[test]
+    fn formats_fingerprints_like_openssh() {
+        let key = russh_keys::parse_public_key_base64(
+            "AAAAC3NzaC1lZDI1NTE5AAAAIE1Z2tvvxosGJ6n9nHPhxO5wjnJFPVsBYZpVpqOPFn6U",
+        )
+        .unwrap();
+        // As printed by ssh-keygen -l for the same key
+        assert_eq!(
+            openssh_fingerprint(&key),
+            "SHA256:7EXE2jWv+y/hWXo+tvLuq1RKWnMyRsZSw7G8epZ3PWg"
+        );
+    }
+
+    #[tokio::test]
+    async fn sends_the_banner_before_authentication() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(file.path(), "Authorized use only\r\n").unwrap();
+        let server_config = ServerConfig {
+            banner: Some(String::from("overridden by the file")),
+            banner_file: Some(file.path().display().to_string()),
+            ..Default::default()
+        };
+        let mut ssh = testing::ssh_config(russh::MethodSet::NONE);
+        ssh.auth_banner = auth_banner(&server_config);
+        let mut server =
+            testing::open_server(server_config, vec![testing::loopback_container("app")]);
+        let mut connection = testing::connect(&mut server, None, ssh).await;
+        assert!(connection.handle.authenticate_none("app").await.unwrap());
+        assert_eq!(
+            connection.banner.lock().unwrap().as_deref(),
+            Some("Authorized use only\r\n")
+        );
+
+        let missing = ServerConfig {
+            banner_file: Some(String::from("/nonexistent/banner")),
+            ..Default::default()
+        };
+        assert_eq!(auth_banner(&missing), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sends_keepalives_at_the_configured_interval() {
+        let server_config = ServerConfig {
+            keepalive_interval_secs: 30,
+            ..Default::default()
+        };
+        let mut ssh = testing::ssh_config(russh::MethodSet::NONE);
+        ssh.keepalive_interval = keepalive_interval(&server_config);
+        let mut server =
+            testing::open_server(server_config, vec![testing::loopback_container("app")]);
+
+        // Relay the connection to timestamp everything the server sends
+        let (server_stream, relay_server) = tokio::io::duplex(64 * 1024);
+        let (relay_client, client_stream) = tokio::io::duplex(64 * 1024);
+        testing::serve_stream(&mut server, None, ssh, server_stream);
+        let (mut from_server, mut to_server) = tokio::io::split(relay_server);
+        let (mut from_client, mut to_client) = tokio::io::split(relay_client);
+        let sent_at = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let recorded = Arc::clone(&sent_at);
         tokio::spawn(async move {
-            match russh::server::run(config_clone, ("0.0.0.0", 2222), server_clone).await {
-                Ok(_) => {
-                    println!("Server has closed successfully");
-                }
-                Err(e) => {
-                    // Send the error to the receiver
-                    tx_clone.send(e).await.unwrap();
+            let mut buffer = [0; 4096];
+            while let Ok(read @ 1..) = from_server.read(&mut buffer).await {
+                recorded.lock().unwrap().push(tokio::time::Instant::now());
+                if to_client.write_all(&buffer[..read]).await.is_err() {
+                    break;
                 }
             }
         });
+        tokio::spawn(async move { tokio::io::copy(&mut from_client, &mut to_server).await });
 
-        // Only retry if an error occurred, otherwise break the loop
-        if rx.recv().await.is_some() {
-            println!("Server error occurred. Retrying...");
-            continue;
-        } else {
-            break;
-        }
+        let mut connection = testing::connect_stream(client_stream).await;
+        assert!(connection.handle.authenticate_none("app").await.unwrap());
+        let start = tokio::time::Instant::now();
+        sent_at.lock().unwrap().clear();
+        tokio::time::sleep(std::time::Duration::from_secs(100)).await;
+
+        let mut seconds: Vec<u64> = sent_at
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|instant| instant.duration_since(start).as_secs_f64().round() as u64)
+            .collect();
+        seconds.dedup();
+        assert_eq!(seconds, vec![30, 60, 90]);
+
+        let disabled = ServerConfig::default();
+        assert_eq!(keepalive_interval(&disabled), None);
     }
 }
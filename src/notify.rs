@@ -0,0 +1,117 @@
+use std::os::unix::net::UnixDatagram;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use log::{debug, warn};
+
+/// Environment variable systemd passes the notification socket in, set for `Type=notify` units.
+const NOTIFY_SOCKET_ENV: &str = "NOTIFY_SOCKET";
+
+/// Builds a service state notification in the format systemd expects.
+///
+/// # Arguments
+///
+/// * `state` - The variables to send, e.g. `("READY", "1")`.
+///
+/// # Returns
+///
+/// One `NAME=value` line per variable.
+fn notify_message(state: &[(&str, &str)]) -> String {
+    state
+        .iter()
+        .map(|(name, value)| format!("{}={}\n", name, value.replace('\n', " ")))
+        .collect()
+}
+
+/// Sends a service state notification to systemd.
+///
+/// # Arguments
+///
+/// * `state` - The variables to send, e.g. `("READY", "1")`.
+///
+/// # Remarks
+///
+/// Does nothing unless `NOTIFY_SOCKET` is set, i.e. when not run as a `Type=notify` unit. Sockets
+/// in the abstract namespace are given with a leading `@`. Failures are logged, never fatal.
+pub fn sd_notify(state: &[(&str, &str)]) {
+    let Some(socket) = std::env::var_os(NOTIFY_SOCKET_ENV) else {
+        return;
+    };
+    let message = notify_message(state);
+    let sent = UnixDatagram::unbound().and_then(|datagram| {
+        match socket.to_str().and_then(|socket| socket.strip_prefix('@')) {
+            Some(name) => {
+                use std::os::linux::net::SocketAddrExt;
+                let address = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+                datagram.send_to_addr(message.as_bytes(), &address)
+            }
+            None => datagram.send_to(message.as_bytes(), &socket),
+        }
+    });
+    match sent {
+        Ok(_) => debug!("Notified systemd: {}", message.trim_end()),
+        Err(e) => warn!("Failed to notify systemd: {}", e),
+    }
+}
+
+/// Tells systemd the server is ready once every listener is bound.
+///
+/// # Remarks
+///
+/// Listeners restarted later report again, readiness is only sent once.
+pub struct Readiness {
+    pending: AtomicUsize,
+}
+
+impl Readiness {
+    /// Creates the readiness of a server.
+    ///
+    /// # Arguments
+    ///
+    /// * `listeners` - The number of listeners that have to be bound.
+    pub fn new(listeners: usize) -> Readiness {
+        Readiness {
+            pending: AtomicUsize::new(listeners),
+        }
+    }
+
+    /// Records a bound listener.
+    ///
+    /// # Returns
+    ///
+    /// `true` if it was the last one and readiness was sent.
+    pub fn bound(&self) -> bool {
+        let previous = self
+            .pending
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |pending| {
+                pending.checked_sub(1)
+            });
+        if previous != Ok(1) {
+            return false;
+        }
+        sd_notify(&[("READY", "1"), ("STATUS", "Accepting connections")]);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builds_notify_messages() {
+        assert_eq!(notify_message(&[("READY", "1")]), "READY=1\n");
+        assert_eq!(
+            notify_message(&[("STOPPING", "1"), ("STATUS", "Shutting\ndown")]),
+            "STOPPING=1\nSTATUS=Shutting down\n"
+        );
+    }
+
+    #[test]
+    fn is_ready_once_every_listener_is_bound() {
+        let readiness = Readiness::new(2);
+        assert!(!readiness.bound());
+        assert!(readiness.bound());
+        // A restarted listener doesn't report readiness again
+        assert!(!readiness.bound());
+    }
+}
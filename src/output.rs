@@ -0,0 +1,182 @@
+/// Holds back the incomplete UTF-8 sequence at the end of the output, so a multibyte character
+/// split across two Docker frames is forwarded in one piece.
+///
+/// # Remarks
+///
+/// Invalid UTF-8 is passed through untouched, only a valid but unfinished trailing sequence is
+/// buffered until the next chunk completes it.
+#[derive(Debug, Default)]
+pub struct Utf8Buffer {
+    pending: Vec<u8>,
+}
+
+impl Utf8Buffer {
+    /// Appends a chunk of output.
+    ///
+    /// # Arguments
+    ///
+    /// * `chunk` - The bytes received from the container.
+    ///
+    /// # Returns
+    ///
+    /// The bytes that can be forwarded, everything but an incomplete trailing UTF-8 sequence.
+    pub fn push(&mut self, chunk: &[u8]) -> Vec<u8> {
+        self.pending.extend_from_slice(chunk);
+        let mut start = 0;
+        loop {
+            match std::str::from_utf8(&self.pending[start..]) {
+                Ok(_) => return std::mem::take(&mut self.pending),
+                Err(e) => match e.error_len() {
+                    // Invalid bytes are forwarded as they are, keep looking after them
+                    Some(len) => start += e.valid_up_to() + len,
+                    None => {
+                        let tail = self.pending.split_off(start + e.valid_up_to());
+                        return std::mem::replace(&mut self.pending, tail);
+                    }
+                },
+            }
+        }
+    }
+
+    /// Returns whatever is still buffered, called once the output has ended.
+    pub fn flush(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.pending)
+    }
+}
+
+/// Translates bare line feeds to carriage return and line feed, for programs writing to a
+/// terminal that doesn't do the translation itself.
+///
+/// # Remarks
+///
+/// Line feeds already preceded by a carriage return are left alone, also when the pair is split
+/// across two chunks.
+#[derive(Debug, Default)]
+pub struct CrlfTranslator {
+    last_was_cr: bool,
+}
+
+impl CrlfTranslator {
+    /// Translates a chunk of output.
+    ///
+    /// # Arguments
+    ///
+    /// * `chunk` - The bytes received from the container.
+    ///
+    /// # Returns
+    ///
+    /// The chunk with every bare `\n` replaced by `\r\n`.
+    pub fn translate(&mut self, chunk: &[u8]) -> Vec<u8> {
+        let mut translated = Vec::with_capacity(chunk.len());
+        for &byte in chunk {
+            if byte == b'\n' && !self.last_was_cr {
+                translated.push(b'\r');
+            }
+            translated.push(byte);
+            self.last_was_cr = byte == b'\r';
+        }
+        translated
+    }
+}
+
+/// The transformations applied to the output of a session before it is sent to the client.
+///
+/// # Fields
+///
+/// * `utf8`: Holds back incomplete UTF-8 sequences, if enabled.
+/// * `crlf`: Translates bare line feeds for terminals, if enabled.
+#[derive(Debug, Default)]
+pub struct OutputTransform {
+    utf8: Option<Utf8Buffer>,
+    crlf: Option<CrlfTranslator>,
+}
+
+impl OutputTransform {
+    /// Creates the transformations of a session.
+    ///
+    /// # Arguments
+    ///
+    /// * `buffer_utf8` - Whether incomplete UTF-8 sequences are held back.
+    /// * `translate_crlf` - Whether bare line feeds are translated to `\r\n`.
+    ///
+    /// # Returns
+    ///
+    /// The `OutputTransform`, or `None` if the output is passed through untouched.
+    pub fn new(buffer_utf8: bool, translate_crlf: bool) -> Option<OutputTransform> {
+        (buffer_utf8 || translate_crlf).then(|| OutputTransform {
+            utf8: buffer_utf8.then(Utf8Buffer::default),
+            crlf: translate_crlf.then(CrlfTranslator::default),
+        })
+    }
+
+    /// Transforms a chunk of output.
+    ///
+    /// # Returns
+    ///
+    /// The bytes to forward, possibly empty while a UTF-8 sequence is held back.
+    pub fn apply(&mut self, chunk: &[u8]) -> Vec<u8> {
+        let bytes = match &mut self.utf8 {
+            Some(utf8) => utf8.push(chunk),
+            None => chunk.to_vec(),
+        };
+        match &mut self.crlf {
+            Some(crlf) => crlf.translate(&bytes),
+            None => bytes,
+        }
+    }
+
+    /// Returns whatever is still held back, called once the output has ended.
+    pub fn flush(&mut self) -> Vec<u8> {
+        let bytes = match &mut self.utf8 {
+            Some(utf8) => utf8.flush(),
+            None => Vec::new(),
+        };
+        match &mut self.crlf {
+            Some(crlf) => crlf.translate(&bytes),
+            None => bytes,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn holds_back_split_characters() {
+        let mut buffer = Utf8Buffer::default();
+        let euro = "€".as_bytes();
+        assert_eq!(buffer.push(&[b'a', euro[0], euro[1]]), b"a");
+        assert_eq!(buffer.push(&euro[2..]), euro);
+        assert!(buffer.flush().is_empty());
+    }
+
+    #[test]
+    fn passes_invalid_bytes_through() {
+        let mut buffer = Utf8Buffer::default();
+        assert_eq!(buffer.push(&[0xff, b'a']), vec![0xff, b'a']);
+    }
+
+    #[test]
+    fn translates_bare_line_feeds() {
+        let mut crlf = CrlfTranslator::default();
+        assert_eq!(crlf.translate(b"a\nb\r\nc\r"), b"a\r\nb\r\nc\r");
+        assert_eq!(crlf.translate(b"\nd\n"), b"\nd\r\n");
+    }
+
+    #[test]
+    fn translates_only_when_enabled() {
+        assert!(OutputTransform::new(false, false).is_none());
+        let mut tty = OutputTransform::new(false, true).unwrap();
+        assert_eq!(tty.apply(b"ls\n"), b"ls\r\n");
+        let mut raw = OutputTransform::new(true, false).unwrap();
+        assert_eq!(raw.apply(b"ls\n"), b"ls\n");
+    }
+
+    #[test]
+    fn flushes_unfinished_sequences() {
+        let mut buffer = Utf8Buffer::default();
+        assert!(buffer.push(&[0xe2, 0x82]).is_empty());
+        assert_eq!(buffer.flush(), vec![0xe2, 0x82]);
+    }
+}
@@ -0,0 +1,196 @@
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// The signature every PROXY protocol v2 header starts with.
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+/// The longest v1 header allowed by the specification, including the CRLF.
+const V1_MAX_LENGTH: usize = 107;
+/// The longest v2 address block accepted, the addresses and a few TLVs fit easily.
+const V2_MAX_LENGTH: usize = 4096;
+/// How long a trusted proxy may take to send the header after connecting.
+pub const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Reads the PROXY protocol header a load balancer sends before the SSH traffic.
+///
+/// # Arguments
+///
+/// * `stream` - The accepted connection, nothing past the header is read from it.
+///
+/// # Returns
+///
+/// The address of the client the proxy forwards, `None` for health checks of the proxy itself
+/// (`LOCAL` and `UNKNOWN`), or an `InvalidData` error if the connection doesn't start with a valid
+/// v1 or v2 header.
+pub async fn read_proxy_header<S>(stream: &mut S) -> Result<Option<SocketAddr>, Error>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut first = [0u8; 1];
+    stream.read_exact(&mut first).await?;
+    match first[0] {
+        b'P' => {
+            // Read byte by byte, the SSH version line follows right after the header
+            let mut line = vec![first[0]];
+            while !line.ends_with(b"\r\n") {
+                if line.len() >= V1_MAX_LENGTH {
+                    return Err(invalid("PROXY v1 header is too long"));
+                }
+                line.push(stream.read_u8().await?);
+            }
+            parse_v1(&line).map_err(|e| invalid(&e))
+        }
+        b'\r' => {
+            let mut header = [0u8; 16];
+            header[0] = first[0];
+            stream.read_exact(&mut header[1..]).await?;
+            let length = u16::from_be_bytes([header[14], header[15]]) as usize;
+            if length > V2_MAX_LENGTH {
+                return Err(invalid("PROXY v2 header is too long"));
+            }
+            let mut addresses = vec![0u8; length];
+            stream.read_exact(&mut addresses).await?;
+            parse_v2(&header, &addresses).map_err(|e| invalid(&e))
+        }
+        _ => Err(invalid("Connection doesn't start with a PROXY header")),
+    }
+}
+
+/// Builds the error returned for a malformed header.
+fn invalid(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message.to_string())
+}
+
+/// Parses a PROXY protocol v1 header.
+///
+/// # Arguments
+///
+/// * `line` - The header, e.g. `PROXY TCP4 203.0.113.7 10.0.0.2 51234 22\r\n`.
+///
+/// # Returns
+///
+/// The source address, `None` for `UNKNOWN` connections, or an error message.
+fn parse_v1(line: &[u8]) -> Result<Option<SocketAddr>, String> {
+    let line = std::str::from_utf8(line)
+        .ok()
+        .and_then(|line| line.strip_suffix("\r\n"))
+        .ok_or("PROXY v1 header is not a CRLF terminated line")?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", protocol @ ("TCP4" | "TCP6"), source, _, source_port, _] => {
+            let ip: IpAddr = source
+                .parse()
+                .map_err(|_| format!("Invalid source address '{}'", source))?;
+            if ip.is_ipv4() != (*protocol == "TCP4") {
+                return Err(format!("Source address {} is not {}", ip, protocol));
+            }
+            let port: u16 = source_port
+                .parse()
+                .map_err(|_| format!("Invalid source port '{}'", source_port))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(format!("Invalid PROXY v1 header '{}'", line)),
+    }
+}
+
+/// Parses a PROXY protocol v2 header.
+///
+/// # Arguments
+///
+/// * `header` - The fixed 16 byte part: signature, version and command, family and length.
+/// * `addresses` - The address block following it, possibly with TLVs at its end.
+///
+/// # Returns
+///
+/// The source address, `None` for `LOCAL` connections and families other than TCP or UDP over
+/// IPv4 and IPv6, or an error message.
+fn parse_v2(header: &[u8; 16], addresses: &[u8]) -> Result<Option<SocketAddr>, String> {
+    if header[..12] != V2_SIGNATURE {
+        return Err(String::from("Invalid PROXY v2 signature"));
+    }
+    if header[12] >> 4 != 2 {
+        return Err(format!("Unsupported PROXY version {}", header[12] >> 4));
+    }
+    match header[12] & 0x0f {
+        0 => return Ok(None),
+        1 => {}
+        command => return Err(format!("Unsupported PROXY v2 command {}", command)),
+    }
+    let port = |offset: usize| u16::from_be_bytes([addresses[offset], addresses[offset + 1]]);
+    match header[13] >> 4 {
+        1 if addresses.len() >= 12 => {
+            let ip = Ipv4Addr::new(addresses[0], addresses[1], addresses[2], addresses[3]);
+            Ok(Some(SocketAddr::new(IpAddr::V4(ip), port(8))))
+        }
+        2 if addresses.len() >= 36 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&addresses[..16]);
+            Ok(Some(SocketAddr::new(
+                IpAddr::V6(Ipv6Addr::from(octets)),
+                port(32),
+            )))
+        }
+        1 | 2 => Err(String::from("PROXY v2 address block is too short")),
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn reads_the_client_address_of_a_v1_header() {
+        let mut stream: &[u8] =
+            b"PROXY TCP4 203.0.113.7 10.0.0.2 51234 22\r\nSSH-2.0-OpenSSH_9.6\r\n";
+        let addr = read_proxy_header(&mut stream).await.unwrap();
+        assert_eq!(addr, Some("203.0.113.7:51234".parse().unwrap()));
+        // The SSH traffic is left for the server
+        assert_eq!(stream, b"SSH-2.0-OpenSSH_9.6\r\n");
+
+        let mut stream: &[u8] = b"PROXY TCP6 2001:db8::7 2001:db8::2 4000 22\r\n";
+        let addr = read_proxy_header(&mut stream).await.unwrap();
+        assert_eq!(addr, Some("[2001:db8::7]:4000".parse().unwrap()));
+
+        let mut stream: &[u8] = b"PROXY UNKNOWN\r\n";
+        assert_eq!(read_proxy_header(&mut stream).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn reads_the_client_address_of_a_v2_header() {
+        let mut data = V2_SIGNATURE.to_vec();
+        data.extend_from_slice(&[0x21, 0x11, 0, 12]);
+        data.extend_from_slice(&[198, 51, 100, 9, 10, 0, 0, 2]);
+        data.extend_from_slice(&[0xc8, 0x1f, 0, 22]);
+        data.extend_from_slice(b"SSH-2.0");
+        let mut stream = data.as_slice();
+        let addr = read_proxy_header(&mut stream).await.unwrap();
+        assert_eq!(addr, Some("198.51.100.9:51231".parse().unwrap()));
+        assert_eq!(stream, b"SSH-2.0");
+
+        let mut local = V2_SIGNATURE.to_vec();
+        local.extend_from_slice(&[0x20, 0x00, 0, 0]);
+        assert_eq!(
+            read_proxy_header(&mut local.as_slice()).await.unwrap(),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_connections_without_a_valid_header() {
+        let invalid: [&[u8]; 4] = [
+            b"SSH-2.0-OpenSSH_9.6\r\n",
+            b"PROXY TCP4 2001:db8::7 10.0.0.2 1 22\r\n",
+            b"PROXY TCP4 203.0.113.7 10.0.0.2 51234\r\n",
+            &[b'P'; 200],
+        ];
+        for data in invalid {
+            let mut stream = data;
+            let e = read_proxy_header(&mut stream).await.unwrap_err();
+            assert_eq!(e.kind(), ErrorKind::InvalidData);
+        }
+    }
+}
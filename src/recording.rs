@@ -0,0 +1,113 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::sync::Mutex;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use log::error;
+use serde_json::json;
+
+/// Records the output of an interactive session as an asciinema v2 cast file.
+///
+/// # Remarks
+///
+/// - The first line of the file is the JSON header describing the terminal.
+/// - Every following line is an `[elapsed_seconds, "o", data]` output event.
+pub struct SessionRecorder {
+    started: Instant,
+    writer: Mutex<BufWriter<File>>,
+}
+
+/// Renders the path of a recording from the configured template.
+///
+/// # Arguments
+///
+/// * `template` - The path template, supporting the `{session}`, `{user}` and `{timestamp}` placeholders.
+/// * `session` - The identifier of the session.
+/// * `user` - The SSH user name of the session.
+///
+/// # Returns
+///
+/// The rendered path. Path separators in the user name are replaced so it can't escape the
+/// configured directory.
+pub fn render_recording_path(template: &str, session: &str, user: &str) -> String {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    template
+        .replace("{session}", session)
+        .replace("{user}", &user.replace(['/', '\\'], "_"))
+        .replace("{timestamp}", &timestamp.to_string())
+}
+
+impl SessionRecorder {
+    /// Creates the cast file and writes its header.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path of the cast file.
+    /// * `width` - The width of the terminal in columns.
+    /// * `height` - The height of the terminal in rows.
+    /// * `term` - The terminal type requested by the client.
+    ///
+    /// # Returns
+    ///
+    /// The `SessionRecorder`, or the error encountered while creating the file.
+    pub fn create(
+        path: &str,
+        width: u32,
+        height: u32,
+        term: &str,
+    ) -> Result<SessionRecorder, std::io::Error> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        let header = json!({
+            "version": 2,
+            "width": width,
+            "height": height,
+            "timestamp": timestamp,
+            "env": { "TERM": term },
+        });
+        writeln!(writer, "{}", header)?;
+        writer.flush()?;
+        Ok(SessionRecorder {
+            started: Instant::now(),
+            writer: Mutex::new(writer),
+        })
+    }
+
+    /// Appends an output event to the cast file.
+    ///
+    /// # Arguments
+    ///
+    /// * `data` - The bytes sent to the client.
+    pub fn output(&self, data: &[u8]) {
+        let event = json!([
+            self.started.elapsed().as_secs_f64(),
+            "o",
+            String::from_utf8_lossy(data)
+        ]);
+        let mut writer = match self.writer.lock() {
+            Ok(writer) => writer,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        if let Err(e) = writeln!(writer, "{}", event).and_then(|_| writer.flush()) {
+            error!("Failed to write session recording: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_recording_paths() {
+        let path = render_recording_path("/var/log/{user}-{session}.cast", "7", "../root");
+        assert_eq!(path, "/var/log/.._root-7.cast");
+        assert!(!render_recording_path("{timestamp}", "7", "root").contains('{'));
+    }
+}
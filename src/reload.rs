@@ -0,0 +1,334 @@
+use std::sync::{Arc, PoisonError, RwLock};
+
+use log::{error, info, warn};
+
+use crate::acl::{CommandFilter, IpFilter, IpPolicy};
+use crate::cli::ServeArgs;
+use crate::config::ServerConfig;
+use crate::validate::config_problems;
+
+/// The settings connections are started with, swapped as a whole when the configuration is
+/// reloaded.
+///
+/// # Fields
+///
+/// * `config`: The loaded configuration.
+/// * `ip_filter`: The IP allow/deny lists built from the configuration.
+/// * `command_filter`: The command allowlist built from the configuration.
+/// * `trusted_proxies`: The addresses allowed to send PROXY headers, `None` if the PROXY protocol
+///   is disabled.
+#[derive(Clone)]
+pub struct LiveSettings {
+    pub config: Arc<ServerConfig>,
+    pub ip_filter: Arc<IpFilter>,
+    pub command_filter: Arc<CommandFilter>,
+    pub trusted_proxies: Option<Arc<IpFilter>>,
+}
+
+impl LiveSettings {
+    /// Builds the settings of a configuration.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The configuration.
+    ///
+    /// # Returns
+    ///
+    /// The settings, or an error message if the IP lists, the trusted proxies or the command
+    /// allowlist are invalid.
+    pub fn new(config: ServerConfig) -> Result<LiveSettings, String> {
+        let ip_filter = IpFilter::new(
+            &config.allow_ips,
+            &config.deny_ips,
+            config.default_ip_policy,
+        )
+        .map_err(|e| format!("Invalid IP allow/deny list: {}", e))?;
+        let command_filter =
+            CommandFilter::new(&config.allowed_commands, config.allow_interactive_shell)
+                .map_err(|e| format!("Invalid command allowlist: {}", e))?;
+        let trusted_proxies = IpFilter::new(&config.trusted_proxies, &[], IpPolicy::Deny)
+            .map_err(|e| format!("Invalid trusted_proxies: {}", e))?;
+        Ok(LiveSettings {
+            trusted_proxies: config.proxy_protocol.then(|| Arc::new(trusted_proxies)),
+            config: Arc::new(config),
+            ip_filter: Arc::new(ip_filter),
+            command_filter: Arc::new(command_filter),
+        })
+    }
+}
+
+/// The settings shared by the listeners, new connections take a snapshot of them.
+pub type SharedSettings = Arc<RwLock<LiveSettings>>;
+
+/// Returns the settings a new connection starts with.
+///
+/// # Arguments
+///
+/// * `settings` - The shared settings.
+pub fn snapshot(settings: &RwLock<LiveSettings>) -> LiveSettings {
+    settings
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clone()
+}
+
+/// Records a setting that only takes effect on a restart, keeping its current value if the reloaded
+/// configuration changes it.
+///
+/// # Arguments
+///
+/// * `name` - The name of the setting in the configuration file.
+/// * `current` - The value the server runs with.
+/// * `reloaded` - The value of the reloaded configuration, replaced by `current` if they differ.
+/// * `kept` - The names of the settings whose change was ignored so far.
+fn keep_current<T: Clone + PartialEq>(
+    name: &'static str,
+    current: &T,
+    reloaded: &mut T,
+    kept: &mut Vec<&'static str>,
+) {
+    if current != reloaded {
+        *reloaded = current.clone();
+        kept.push(name);
+    }
+}
+
+/// Keeps the values of the settings that are only used while the server starts.
+///
+/// # Arguments
+///
+/// * `current` - The configuration the server runs with.
+/// * `reloaded` - The reloaded configuration, its restart-only settings are replaced by the current
+///   ones.
+///
+/// # Returns
+///
+/// The names of the restart-only settings the reloaded configuration changed.
+///
+/// # Remarks
+///
+/// These settings are baked into the listeners, the SSH transport configuration, the audit log, the
+/// Docker clients and the login ban when the server starts. Swapping them in for new connections
+/// would only apply them halfway, e.g. offer the old authentication methods but accept the new
+/// ones.
+fn keep_restart_only(current: &ServerConfig, reloaded: &mut ServerConfig) -> Vec<&'static str> {
+    let mut kept = Vec::new();
+    keep_current(
+        "log_level",
+        &current.log_level,
+        &mut reloaded.log_level,
+        &mut kept,
+    );
+    keep_current("listen", &current.listen, &mut reloaded.listen, &mut kept);
+    keep_current(
+        "health_listen",
+        &current.health_listen,
+        &mut reloaded.health_listen,
+        &mut kept,
+    );
+    keep_current(
+        "audit_log",
+        &current.audit_log,
+        &mut reloaded.audit_log,
+        &mut kept,
+    );
+    keep_current(
+        "docker_context",
+        &current.docker_context,
+        &mut reloaded.docker_context,
+        &mut kept,
+    );
+    keep_current(
+        "docker_hosts",
+        &current.docker_hosts,
+        &mut reloaded.docker_hosts,
+        &mut kept,
+    );
+    keep_current(
+        "docker_api_version",
+        &current.docker_api_version,
+        &mut reloaded.docker_api_version,
+        &mut kept,
+    );
+    keep_current(
+        "docker_startup_timeout_secs",
+        &current.docker_startup_timeout_secs,
+        &mut reloaded.docker_startup_timeout_secs,
+        &mut kept,
+    );
+    keep_current(
+        "container_cache_ttl_ms",
+        &current.container_cache_ttl_ms,
+        &mut reloaded.container_cache_ttl_ms,
+        &mut kept,
+    );
+    keep_current(
+        "auth_methods",
+        &current.auth_methods,
+        &mut reloaded.auth_methods,
+        &mut kept,
+    );
+    keep_current(
+        "ciphers",
+        &current.ciphers,
+        &mut reloaded.ciphers,
+        &mut kept,
+    );
+    keep_current(
+        "kex_algorithms",
+        &current.kex_algorithms,
+        &mut reloaded.kex_algorithms,
+        &mut kept,
+    );
+    keep_current("macs", &current.macs, &mut reloaded.macs, &mut kept);
+    keep_current(
+        "compression",
+        &current.compression,
+        &mut reloaded.compression,
+        &mut kept,
+    );
+    keep_current("banner", &current.banner, &mut reloaded.banner, &mut kept);
+    keep_current(
+        "banner_file",
+        &current.banner_file,
+        &mut reloaded.banner_file,
+        &mut kept,
+    );
+    keep_current(
+        "keepalive_interval_secs",
+        &current.keepalive_interval_secs,
+        &mut reloaded.keepalive_interval_secs,
+        &mut kept,
+    );
+    keep_current(
+        "auth_failure_threshold",
+        &current.auth_failure_threshold,
+        &mut reloaded.auth_failure_threshold,
+        &mut kept,
+    );
+    keep_current(
+        "auth_ban_secs",
+        &current.auth_ban_secs,
+        &mut reloaded.auth_ban_secs,
+        &mut kept,
+    );
+    kept
+}
+
+/// Loads the configuration again and swaps it in for new connections.
+///
+/// # Arguments
+///
+/// * `settings` - The shared settings.
+/// * `args` - The arguments of the `serve` subcommand, naming the configuration file.
+///
+/// # Returns
+///
+/// An error message if the configuration can't be loaded or fails validation, the current settings
+/// are kept then.
+///
+/// # Remarks
+///
+/// Open connections keep the settings they started with. Settings that are only used while the
+/// server starts, see `keep_restart_only`, keep their current values with a warning and need a
+/// restart to change.
+pub fn reload(settings: &RwLock<LiveSettings>, args: &ServeArgs) -> Result<(), String> {
+    let mut config = ServerConfig::load(args.config.as_deref())
+        .map_err(|e| format!("Failed to load configuration: {}", e))?;
+    let problems = config_problems(args, &config);
+    if !problems.is_empty() {
+        return Err(problems.join(", "));
+    }
+    for name in keep_restart_only(&snapshot(settings).config, &mut config) {
+        warn!(
+            "{} changed, keeping the current value until a restart",
+            name
+        );
+    }
+    let reloaded = LiveSettings::new(config)?;
+    *settings.write().unwrap_or_else(PoisonError::into_inner) = reloaded;
+    Ok(())
+}
+
+/// Reloads the configuration every time the process receives SIGHUP.
+///
+/// # Arguments
+///
+/// * `settings` - The shared settings.
+/// * `args` - The arguments of the `serve` subcommand, naming the configuration file.
+pub async fn reload_on_hangup(settings: SharedSettings, args: ServeArgs) {
+    let mut hangup = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            warn!("Failed to listen for SIGHUP, reloading is disabled: {}", e);
+            return;
+        }
+    };
+    while hangup.recv().await.is_some() {
+        info!("Received SIGHUP, reloading the configuration");
+        match reload(&settings, &args) {
+            Ok(()) => info!("Reloaded the configuration, new connections use it"),
+            Err(e) => error!("Keeping the current configuration: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn serve_args(config: &tempfile::NamedTempFile) -> ServeArgs {
+        ServeArgs {
+            bind: String::from("0.0.0.0"),
+            port: 2222,
+            listen: Vec::new(),
+            unix_socket: None,
+            docker_context: None,
+            config: Some(config.path().display().to_string()),
+            test_config: false,
+            check_docker: false,
+            insecure_allow_all: false,
+        }
+    }
+
+    #[test]
+    fn reloads_settings_for_new_connections() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(file.path(), "welcome_message = \"Hello\"\n").unwrap();
+        let args = serve_args(&file);
+        let config = ServerConfig::load(args.config.as_deref()).unwrap();
+        let settings = RwLock::new(LiveSettings::new(config).unwrap());
+        let open_connection = snapshot(&settings);
+
+        std::fs::write(
+            file.path(),
+            "welcome_message = \"Hi\"\nallowed_commands = [\"ls\"]\n",
+        )
+        .unwrap();
+        reload(&settings, &args).unwrap();
+        let new_connection = snapshot(&settings);
+        assert_eq!(new_connection.config.welcome_message, "Hi");
+        assert!(!new_connection
+            .command_filter
+            .is_allowed(&[String::from("id")]));
+        // Connections that were already open keep their settings
+        assert_eq!(open_connection.config.welcome_message, "Hello");
+
+        // Settings baked in at startup keep their values until a restart
+        std::fs::write(
+            file.path(),
+            "welcome_message = \"Hey\"\nauth_methods = [\"keyboard-interactive\"]\nauth_ban_secs = 1\n",
+        )
+        .unwrap();
+        reload(&settings, &args).unwrap();
+        let restarted = ServerConfig::default();
+        let reloaded = snapshot(&settings);
+        assert_eq!(reloaded.config.welcome_message, "Hey");
+        assert_eq!(reloaded.config.auth_methods, restarted.auth_methods);
+        assert_eq!(reloaded.config.auth_ban_secs, restarted.auth_ban_secs);
+
+        std::fs::write(file.path(), "allowed_commands = [\"(\"]\n").unwrap();
+        assert!(reload(&settings, &args).is_err());
+        assert_eq!(snapshot(&settings).config.welcome_message, "Hi");
+    }
+}
@@ -0,0 +1,107 @@
+use crate::cli::{ContainerArgs, ResolveArgs, TargetSelector};
+use crate::config::ServerConfig;
+use crate::docker::{
+    connect_to_docker, container_name, find_ssh_enabled_container, list_candidates, ContainerCache,
+    DockerClient, DockerSettings,
+};
+
+/// Resolves a target against the live Docker daemon and prints the outcome, without starting the
+/// SSH server.
+///
+/// # Arguments
+///
+/// * `args` - The arguments of the `resolve` subcommand.
+/// * `config` - The loaded configuration, providing the user groups and the Docker context.
+///
+/// # Returns
+///
+/// `true` if a container matched.
+///
+/// # Remarks
+///
+/// Every container carrying tunnyd labels is listed with its labels and the reason it was
+/// rejected, followed by the container `find_ssh_enabled_container` picks.
+pub async fn resolve(args: ResolveArgs, config: ServerConfig) -> bool {
+    let settings = DockerSettings::new(
+        args.docker_context.or(config.docker_context.clone()),
+        config.docker_api_version.clone(),
+    );
+    let docker = match connect_to_docker(&settings).await {
+        Ok(docker) => DockerClient::new(docker, settings),
+        Err(e) => {
+            eprintln!("Failed to connect to Docker: {}", e);
+            return false;
+        }
+    };
+    let container_args = ContainerArgs {
+        user: args.user,
+        target: args.target,
+        selector: TargetSelector::Hostname,
+        command: Vec::new(),
+        share: None,
+        attach: false,
+    };
+    let user = container_args.user.as_deref().unwrap_or_default();
+    let groups = config.groups_of(user);
+
+    let candidates = match list_candidates(
+        &docker,
+        &container_args,
+        user,
+        groups,
+        &config.allowed_images,
+    )
+    .await
+    {
+        Ok(candidates) => candidates,
+        Err(e) => {
+            eprintln!("Failed to list containers: {}", e);
+            return false;
+        }
+    };
+    if candidates.is_empty() {
+        println!("No container carries tunnyD labels");
+    }
+    for candidate in &candidates {
+        let verdict = match &candidate.rejection {
+            Some(rejection) => format!("rejected, {}", rejection),
+            None => String::from("matches"),
+        };
+        println!(
+            "{} ({}): {}",
+            candidate.name,
+            candidate.state.as_deref().unwrap_or("unknown state"),
+            verdict
+        );
+        for (key, value) in &candidate.labels {
+            println!("    {}={}", key, value);
+        }
+    }
+
+    let cache = tokio::sync::Mutex::new(ContainerCache::new(std::time::Duration::ZERO));
+    match find_ssh_enabled_container(
+        &docker,
+        &container_args,
+        user,
+        groups,
+        &config.allowed_images,
+        &cache,
+    )
+    .await
+    {
+        Ok(container) => {
+            println!(
+                "Target '{}' resolves to {}",
+                container_args.target,
+                container_name(&container)
+                    .or(container.id.as_deref())
+                    .unwrap_or_default()
+            );
+            true
+        }
+        Err(e) => {
+            println!("{}", e);
+            false
+        }
+    }
+}
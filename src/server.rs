@@ -1,280 +1,3257 @@
 use std::collections::HashMap;
 use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
 use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use anyhow::anyhow;
 use async_trait::async_trait;
-use bollard::container::LogOutput;
+use bollard::container::{AttachContainerOptions, AttachContainerResults, InspectContainerOptions};
+use bollard::container::{LogOutput, LogsOptions};
 use bollard::errors::Error;
-use bollard::exec::{CreateExecOptions, StartExecOptions, StartExecResults};
+use bollard::exec::{CreateExecOptions, ResizeExecOptions, StartExecOptions, StartExecResults};
+use bollard::models::{ContainerState, ContainerSummary};
 use bollard::Docker;
 use futures::{Stream, StreamExt};
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::Pid;
 use russh::server::{Auth, Handle, Msg, Session};
-use russh::{server, Channel, ChannelId, CryptoVec};
+use russh::Pty;
+use russh::{server, Channel, ChannelId, CryptoVec, Sig};
 use russh_keys::key;
 use tokio::io::{AsyncWrite, AsyncWriteExt};
-use tokio::sync::Mutex;
+use tokio::net::TcpListener;
+use tokio::sync::{Mutex, Notify};
+use tokio::task::JoinHandle;
 
-use crate::cli::{parse_and_match_args, ContainerArgs};
-use crate::docker::find_ssh_enabled_container;
-use log::{error, info};
+use crate::acl::{AuthFailures, CommandFilter, IpFilter};
+use crate::affinity::Affinity;
+use crate::audit::{AuditEvent, AuditLog};
+use crate::auth::{
+    load_authorized_keys, method_set, verify_keyboard_interactive, AuthMethod, AuthPolicy,
+};
+use crate::cli::{
+    args_from_login, is_valid_name, is_valid_user_spec, login_identity, parse_and_match_args,
+    ContainerArgs, TargetSelector,
+};
+use crate::config::{ExecTemplate, ServerConfig};
+use crate::control::{ControlRequest, ControlResponse, LineBuffer, CONTROL_SUBSYSTEM};
+use crate::docker::{
+    any_container_allows_on_hosts, container_default_user, container_idle_timeout,
+    container_limits, container_motd, container_name, container_network, container_readonly,
+    container_workdir, find_preferred_container, find_ssh_enabled_container_on_hosts,
+    is_connection_error, targets_for_user, DockerClient, DockerHost,
+};
+use crate::error::TunnydError;
+use crate::flow::{send_windowed, ChannelWindow, WriteFailures, INITIAL_WINDOW_SIZE};
+use crate::forwarding::{
+    attached_networks, forward_destination, listen_forwarded_tcpip, pump_direct_tcpip,
+    remote_forward_bind_address,
+};
+use crate::hook::{run_connect_hook, ConnectContext};
+use crate::intent::{SessionIntent, WindowChange};
+use crate::limits::ResourceLimits;
+use crate::output::OutputTransform;
+use crate::recording::{render_recording_path, SessionRecorder};
+use crate::reload::{snapshot, SharedSettings};
+use crate::shared::{Attachment, SharedKey, SharedSessions};
+use crate::usage::{container_usage, UsageCache};
+use log::{error, info, warn};
+
+const SFTP_SUBSYSTEM: &str = "sftp";
+/// The command that reports the resolved session instead of running anything in the container.
+const INFO_COMMAND: &str = "__tunnyd_info";
+const EXIT_STATUS_FAILURE: u32 = 1;
+/// Reported when the container stopped under the session, mirroring the status `ssh` itself uses
+/// when the remote side goes away.
+const EXIT_STATUS_CONTAINER_STOPPED: u32 = 255;
+/// The exit status shells report for a command that doesn't exist.
+const EXIT_STATUS_COMMAND_NOT_FOUND: u32 = 127;
+/// Reported when reading the output of the exec from Docker failed mid-session.
+const EXIT_STATUS_STREAM_FAILED: u32 = 254;
+/// Exit codes above this report a process killed by the signal numbered by the difference.
+const SIGNAL_EXIT_CODE_BASE: i64 = 128;
+/// Messages the container runtime fails an exec with when its executable doesn't exist.
+const COMMAND_NOT_FOUND_MESSAGES: [&str; 2] =
+    ["executable file not found", "no such file or directory"];
+const EXTENDED_DATA_STDERR: u32 = 1;
+const IDLE_CHECK_MIN_INTERVAL: Duration = Duration::from_secs(1);
+const IDLE_CHECK_MAX_INTERVAL: Duration = Duration::from_secs(30);
+const SFTP_SERVER_PATHS: [&str; 4] = [
+    "/usr/lib/openssh/sftp-server",
+    "/usr/libexec/openssh/sftp-server",
+    "/usr/lib/ssh/sftp-server",
+    "/usr/libexec/sftp-server",
+];
 
 /// Represents a pair of output and input streams.
 ///
 /// # Remarks
 ///
 /// - The `output` field is a shared, thread-safe, mutable reference to a stream of log outputs.
-/// - The `input` field is a pinned, boxed, asynchronous write trait object which can be safely
-///   sent across threads.
+/// - The `input` field is a shared, thread-safe, mutable reference to a pinned, boxed,
+///   asynchronous write trait object, so input can be written without holding the clients lock.
 pub struct OutputInputPair {
     output:
         Arc<Mutex<Pin<Box<dyn Stream<Item = Result<LogOutput, bollard::errors::Error>> + Send>>>>,
-    input: Pin<Box<dyn AsyncWrite + Send>>,
+    input: Arc<Mutex<Pin<Box<dyn AsyncWrite + Send>>>>,
 }
 
-/// Represents a SSH client.
+/// Represents the pseudo-terminal requested by a client.
 ///
 /// # Fields
 ///
-/// - `session_handle`: A handle to the SSH session.
-/// - `io`: Optional pair of output and input streams.
+/// - `term`: The terminal type, e.g. `xterm-256color`.
+/// - `cols`: The width of the terminal in columns.
+/// - `rows`: The height of the terminal in rows.
+pub struct PtyRequest {
+    term: String,
+    cols: u32,
+    rows: u32,
+}
+
+/// Tracks when a session last saw input or output.
 ///
 /// # Remarks
 ///
-/// - The `session_handle` field provides access to the session functionality of the SSH client,
-///   allowing the execution of commands, shell access, and file transfer.
-/// - The `io` field is an optional pair of output and input streams used for interacting with the SSH
-///   client. If `None`, the client does not have any associated streams.
-pub struct Client {
-    session_handle: russh::server::Handle,
-    io: Option<OutputInputPair>,
+/// Clones share the same timestamp, so the output forwarding task and the input handler can both
+/// mark the session as active.
+#[derive(Clone)]
+pub struct Activity {
+    last: Arc<std::sync::Mutex<Instant>>,
 }
 
-/// Represents an ssh server.
+impl PtyRequest {
+    /// Returns the size of the terminal as Docker takes it, in columns and rows.
+    fn size(&self) -> (u16, u16) {
+        let clamp = |value: u32| u16::try_from(value).unwrap_or(u16::MAX);
+        (clamp(self.cols), clamp(self.rows))
+    }
+}
+
+impl Activity {
+    fn new() -> Activity {
+        Activity {
+            last: Arc::new(std::sync::Mutex::new(Instant::now())),
+        }
+    }
+
+    /// Marks the session as active now.
+    fn touch(&self) {
+        match self.last.lock() {
+            Ok(mut last) => *last = Instant::now(),
+            Err(poisoned) => *poisoned.into_inner() = Instant::now(),
+        }
+    }
+
+    /// Returns how long the session has been idle.
+    fn idle_for(&self) -> Duration {
+        match self.last.lock() {
+            Ok(last) => last.elapsed(),
+            Err(poisoned) => poisoned.into_inner().elapsed(),
+        }
+    }
+}
+
+/// Counts the bytes a session received from and sent to its client.
 ///
 /// # Remarks
 ///
-/// - The `clients` field is a shared, thread-safe, mutable reference to a hash map storing the
-///   clients connected to the server.
-/// - The `docker` field is an instance of the `bollard::docker` struct, representing the Docker api
-///   associated with the server.
-/// - The `id` field is an identifier associated with the server.
-#[derive(Clone)]
-pub struct Server {
-    pub(crate) clients: Arc<Mutex<HashMap<(usize, ChannelId), Client>>>,
-    pub(crate) docker: Docker,
-    pub(crate) id: usize,
+/// Clones share the same counters, so the output forwarding task and the input handler can both
+/// account for the traffic of the session.
+#[derive(Clone, Default)]
+pub struct Traffic {
+    received: Arc<AtomicU64>,
+    sent: Arc<AtomicU64>,
 }
 
-/// Creates a closure that forwards the output of a container to a session channel.
+impl Traffic {
+    /// Accounts for input the client sent.
+    fn add_received(&self, bytes: usize) {
+        self.received.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Accounts for output delivered to the client.
+    fn add_sent(&self, bytes: usize) {
+        self.sent.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Returns the bytes received from the client so far.
+    fn received(&self) -> u64 {
+        self.received.load(Ordering::Relaxed)
+    }
+
+    /// Returns the bytes sent to the client so far.
+    fn sent(&self) -> u64 {
+        self.sent.load(Ordering::Relaxed)
+    }
+}
+
+/// Represents the per-session settings of an exec process.
+///
+/// # Fields
+///
+/// - `cmd`: The command the exec runs.
+/// - `env`: The environment variables of the exec, as `NAME=value` pairs.
+/// - `working_dir`: The directory the exec starts in, the image default when `None`.
+/// - `readonly`: Whether the container only allows read-only sessions, stdin is then not attached.
+#[derive(Default)]
+pub struct ExecSettings {
+    cmd: Vec<String>,
+    env: Vec<String>,
+    working_dir: Option<String>,
+    readonly: bool,
+}
+
+/// Represents a started Docker exec process.
+///
+/// # Fields
+///
+/// - `id`: The ID of the exec, used to inspect it once the process has exited.
+/// - `tty`: Whether the exec was allocated a TTY.
+/// - `results`: The attached streams of the exec.
+pub struct ExecProcess {
+    id: String,
+    tty: bool,
+    results: StartExecResults,
+}
+
+/// Builds the options of a session's exec from the configured template.
 ///
 /// # Arguments
 ///
-/// * `channel` - The ID of the channel to send the output to.
-/// * `cloned_handle` - A cloned handle to the session.
+/// * `template` - The configured exec template, providing the TTY and attach flags.
+/// * `args` - The container arguments, providing the user.
+/// * `settings` - The per-session settings, providing the command, environment and directory.
 ///
 /// # Returns
 ///
-/// A boxed closure that takes a `Result<LogOutput, Error>` as input and returns a `Pin<Box<dyn Future<Output = ()> + Send + 'static>>`.
+/// The `CreateExecOptions` borrowing from the arguments.
+fn exec_options<'a>(
+    template: &ExecTemplate,
+    args: &'a ContainerArgs,
+    settings: &'a ExecSettings,
+) -> CreateExecOptions<&'a str> {
+    CreateExecOptions {
+        attach_stdout: Some(template.attach_stdout),
+        attach_stderr: Some(template.attach_stderr),
+        attach_stdin: Some(template.attach_stdin && !settings.readonly),
+        cmd: Some(settings.cmd.iter().map(|s| s.as_str()).collect()),
+        env: Some(settings.env.iter().map(|s| s.as_str()).collect()),
+        tty: Some(template.tty),
+        user: args.user.as_deref(),
+        working_dir: settings.working_dir.as_deref(),
+        ..Default::default()
+    }
+}
+
+/// Runs a command through the configured exec wrapper.
 ///
-/// #Example
-/// ```
-/// let output = [`Stream<Item=Result<LogOutput, Error>>`]
-/// let session_handle = /* Create your session handle */;
-/// let channel = /* Define your channel */;
+/// # Arguments
 ///
-///     output
-///         .for_each(forward_container_output_to_session(channel, cloned_handle))
-///         .await;
-///```
-
-fn forward_container_output_to_session(
-    channel: ChannelId,
-    cloned_handle: Arc<Mutex<Handle>>,
-) -> Box<
-    dyn Fn(Result<LogOutput, Error>) -> Pin<Box<dyn Future<Output = ()> + Send + 'static>>
-        + Send
-        + 'static,
-> {
-    Box::new(move |item: Result<LogOutput, Error>| {
-        let session_handle_clone = Arc::clone(&cloned_handle);
-        Box::pin(async move {
-            let handle = session_handle_clone.lock().await;
-            match item {
-                Ok(data) => {
-                    let handle_result = handle
-                        .data(channel, CryptoVec::from(data.into_bytes().to_vec()))
-                        .await;
-                    match handle_result {
-                        Ok(_) => println!("Data method success!"),
-                        Err(e) => eprintln!("An error occurred: {:?}", e),
-                    }
-                }
-                Err(e) => {
-                    handle
-                        .data(
-                            channel,
-                            CryptoVec::from(format!("Error: {}", e).into_bytes().to_vec()),
-                        )
-                        .await
-                        .expect("Failed to send error message");
-                }
-            }
-            drop(handle); // Explicitly drop the lock here
-        })
-    })
-}
-impl server::Server for Server {
-    type Handler = Self;
-    fn new_client(&mut self, _: Option<std::net::SocketAddr>) -> Self {
-        let cloned_self = self.clone();
-        self.id += 1;
-        cloned_self
+/// * `wrapper` - The `exec_wrapper` arguments, if set.
+/// * `command` - The command of the exec.
+///
+/// # Returns
+///
+/// The wrapper's arguments followed by the command's, each passed on as a separate argument.
+fn wrap_command(wrapper: Option<&[String]>, command: Vec<String>) -> Vec<String> {
+    match wrapper {
+        Some(wrapper) => wrapper.iter().cloned().chain(command).collect(),
+        None => command,
     }
 }
-impl Server {
-    /// Create and start an exec process for a Docker container.
-    ///
-    /// # Arguments
-    ///
-    /// - `docker`: A reference to the Docker client.
-    /// - `args`: The container arguments.
-    /// - `container_id`: The ID of the container.
-    ///
-    /// # Returns
-    ///
-    /// A `Result` containing `StartExecResults` if the exec process is created and started successfully,
-    /// or an `anyhow::Error` if an error occurred.
-    async fn create_and_start_exec(
-        &self,
-        docker: &Docker,
-        args: &ContainerArgs,
-        container_id: &str,
-    ) -> Result<StartExecResults, anyhow::Error> {
-        info!("Creating and starting exec for container {}", container_id);
 
-        let options = CreateExecOptions {
-            attach_stdout: Some(true),
-            attach_stderr: Some(true),
-            attach_stdin: Some(true),
-            cmd: Some(vec!["bash"]),
-            tty: Some(true),
-            user: args.user.as_ref().map(|s| s.as_str()),
-            ..Default::default()
-        };
+/// Fills in the default user of the container and checks the user a session runs as.
+///
+/// # Arguments
+///
+/// * `args` - The container arguments, the user is set to the container's default if missing.
+/// * `container` - The resolved container.
+/// * `login_user` - The user name the connection authenticated with, used when neither the client
+///   nor the container pick a user. `None` leaves the choice to the image.
+///
+/// # Returns
+///
+/// A `TunnydError::InvalidUser` if the user isn't a valid user specification.
+///
+/// # Remarks
+///
+/// The login user must be a plain name, a `user:group` login can't pick the group.
+fn apply_session_user(
+    args: &mut ContainerArgs,
+    container: &ContainerSummary,
+    login_user: Option<&str>,
+) -> Result<(), TunnydError> {
+    if args.user.is_none() {
+        args.user = container_default_user(container);
+    }
+    if let (None, Some(login)) = (&args.user, login_user) {
+        if !is_valid_name(login) {
+            return Err(TunnydError::InvalidUser {
+                user: login.to_string(),
+            });
+        }
+        args.user = Some(login.to_string());
+    }
+    match args
+        .user
+        .as_deref()
+        .filter(|user| !is_valid_user_spec(user))
+    {
+        Some(user) => Err(TunnydError::InvalidUser {
+            user: user.to_string(),
+        }),
+        None => Ok(()),
+    }
+}
 
-        let exec = match docker.create_exec(container_id, options).await {
-            Ok(ex) => {
-                info!("Exec created successfully");
-                ex
-            }
-            Err(e) => {
-                error!("Failed to create exec: {}", e);
-                return Err(e.into());
-            }
-        };
+/// Describes why the output of a session ended.
+#[derive(Debug, PartialEq, Eq)]
+pub enum SessionEnd {
+    /// The exec process exited, with its exit code if Docker reported one.
+    ProcessExited(Option<i64>),
+    /// The exec process was terminated by a signal, e.g. killed by the OOM killer.
+    ProcessKilled(Signal),
+    /// The container itself is no longer running, e.g. because it was stopped or OOM-killed.
+    ContainerStopped { oom_killed: bool },
+}
 
-        let start_options = StartExecOptions {
-            detach: false,
-            ..Default::default()
-        };
+/// Builds the message written to a client when its session ends.
+///
+/// # Arguments
+///
+/// * `end` - Why the session ended.
+/// * `exit_banner` - The message shown when the process exited, empty to show none.
+/// * `tty` - Whether the session has a TTY.
+///
+/// # Returns
+///
+/// The message terminated by `\r\n`, or `None` if there is none. Sessions without a TTY only get
+/// told that the container stopped, banners would end up in the output of piped commands.
+fn exit_message(end: &SessionEnd, exit_banner: &str, tty: bool) -> Option<String> {
+    let message = match end {
+        SessionEnd::ContainerStopped { oom_killed: true } => {
+            "Target container stopped (out of memory)"
+        }
+        SessionEnd::ContainerStopped { oom_killed: false } => "Target container stopped",
+        _ if !tty => return None,
+        SessionEnd::ProcessExited(_) => exit_banner.trim_end(),
+        SessionEnd::ProcessKilled(_) => "Container process killed",
+    };
+    if message.is_empty() {
+        return None;
+    }
+    Some(format!(
+        "{}\r\n",
+        message.replace("\r\n", "\n").replace('\n', "\r\n")
+    ))
+}
 
-        let results = match docker.start_exec(&exec.id, Some(start_options)).await {
-            Ok(res) => {
-                info!("Exec started successfully");
-                res
+/// Renders a message template shown to clients.
+///
+/// # Arguments
+///
+/// * `template` - The configured template.
+/// * `values` - The placeholders, without braces, and the values they are replaced by.
+///
+/// # Returns
+///
+/// The rendered message with line feeds normalised to `\r\n`, trailing whitespace trimmed.
+/// Placeholders are replaced in a single pass, so values containing braces are never expanded
+/// themselves. Unknown placeholders are kept as written.
+fn render_message(template: &str, values: &[(&str, &str)]) -> String {
+    let mut rendered = String::new();
+    let mut rest = template.trim_end();
+    while let Some(open) = rest.find('{') {
+        rendered.push_str(&rest[..open]);
+        rest = &rest[open..];
+        let value = rest.find('}').and_then(|close| {
+            let value = values.iter().find(|(name, _)| *name == &rest[1..close])?;
+            Some((close, value.1))
+        });
+        match value {
+            Some((close, value)) => {
+                rendered.push_str(value);
+                rest = &rest[close + 1..];
             }
-            Err(e) => {
-                error!("Failed to start exec: {}", e);
-                return Err(e.into());
+            None => {
+                rendered.push('{');
+                rest = &rest[1..];
             }
-        };
+        }
+    }
+    rendered.push_str(rest);
+    rendered.replace("\r\n", "\n").replace('\n', "\r\n")
+}
 
-        Ok(results)
+/// Decides why the output of a session ended.
+///
+/// # Arguments
+///
+/// * `exit_code` - The exit code of the exec process, if Docker reported one.
+/// * `state` - The state of the container once the output ended, if it could be inspected.
+///
+/// # Returns
+///
+/// `SessionEnd::ContainerStopped` if the container is known to be no longer running,
+/// `SessionEnd::ProcessKilled` if the exit code reports a signal, otherwise
+/// `SessionEnd::ProcessExited`.
+fn session_end(exit_code: Option<i64>, state: Option<&ContainerState>) -> SessionEnd {
+    match state {
+        Some(state) if state.running == Some(false) => SessionEnd::ContainerStopped {
+            oom_killed: state.oom_killed.unwrap_or(false),
+        },
+        _ => match exit_code.and_then(termination_signal) {
+            Some(signal) => SessionEnd::ProcessKilled(signal),
+            None => SessionEnd::ProcessExited(exit_code),
+        },
     }
+}
 
-    async fn handle_output(
-        &self,
-        process: StartExecResults,
-        channel: ChannelId,
-        session_handle: Handle,
-        client_id: (usize, ChannelId),
-    ) {
-        if let StartExecResults::Attached { input, output } = process {
-            self.link_io(channel, session_handle, client_id, input, output)
-                .await;
-        };
+/// Reads the signal that terminated a process from its exit code.
+///
+/// # Arguments
+///
+/// * `exit_code` - The exit code Docker reported for the exec.
+///
+/// # Returns
+///
+/// The signal, or `None` for a normal exit.
+///
+/// # Remarks
+///
+/// Docker only reports an exit code, which follows the shell convention of 128 plus the signal
+/// number for killed processes. A process exiting with such a code on its own can't be told apart.
+fn termination_signal(exit_code: i64) -> Option<Signal> {
+    if exit_code <= SIGNAL_EXIT_CODE_BASE {
+        return None;
     }
+    i32::try_from(exit_code - SIGNAL_EXIT_CODE_BASE)
+        .ok()
+        .and_then(|number| Signal::try_from(number).ok())
+}
 
-    /// Establishes a link between an input stream and an output stream and the client's session.
-    ///
-    /// # Arguments
-    ///
-    /// * `channel` - The ID of the channel used for communication.
-    /// * `session_handle` - The handle to the session.
-    /// * `client_id` - The ID of the client.
-    /// * `input` - The input stream to read from.
-    /// * `output` - The output stream to write to.
-    ///
-    async fn link_io(
-        &self,
-        channel: ChannelId,
-        session_handle: Handle,
-        client_id: (usize, ChannelId),
-        input: Pin<Box<dyn AsyncWrite + Send>>,
-        output: Pin<Box<dyn Stream<Item = Result<LogOutput, bollard::errors::Error>> + Send>>,
-    ) {
-        let clients = Arc::clone(&self.clients);
-        let mut clients_locked = clients.lock().await;
-        let client = clients_locked
-            .get_mut(&client_id)
-            .expect("Client not found");
-        let output = Arc::new(Mutex::new(output));
-        client.io = Some(OutputInputPair {
-            input,
-            output: Arc::clone(&output),
-        });
-        let session_handle = Arc::new(Mutex::new(session_handle.clone()));
-        let output_clone = Arc::clone(&output);
-        let cloned_handle = Arc::clone(&session_handle);
-        tokio::spawn(async move {
-            let mut locked_output = output_clone.lock().await;
-            let stream: &mut Pin<
-                Box<dyn Stream<Item = Result<LogOutput, bollard::errors::Error>> + Send>,
-            > = &mut *locked_output;
-            stream
-                .for_each(forward_container_output_to_session(channel, cloned_handle))
-                .await;
-            let cloned_handle_2 = Arc::clone(&session_handle);
-            let handle = cloned_handle_2.lock().await;
-            handle
-                .data(
-                    channel,
-                    CryptoVec::from("Docker Container exited process \r\n".as_bytes().to_vec()),
-                )
-                .await
-                .expect("TODO: panic message");
-            handle.close(channel).await.expect("")
-        });
+/// Maps a Unix signal to its name in an SSH `exit-signal` message.
+///
+/// # Arguments
+///
+/// * `signal` - The signal that terminated the process.
+///
+/// # Returns
+///
+/// The SSH signal, signals without a standard SSH name are sent by their name without `SIG`.
+fn ssh_signal(signal: Signal) -> Sig {
+    match signal {
+        Signal::SIGABRT => Sig::ABRT,
+        Signal::SIGALRM => Sig::ALRM,
+        Signal::SIGFPE => Sig::FPE,
+        Signal::SIGHUP => Sig::HUP,
+        Signal::SIGILL => Sig::ILL,
+        Signal::SIGINT => Sig::INT,
+        Signal::SIGKILL => Sig::KILL,
+        Signal::SIGPIPE => Sig::PIPE,
+        Signal::SIGQUIT => Sig::QUIT,
+        Signal::SIGSEGV => Sig::SEGV,
+        Signal::SIGTERM => Sig::TERM,
+        Signal::SIGUSR1 => Sig::USR1,
+        signal => Sig::Custom(signal.as_str().trim_start_matches("SIG").to_string()),
     }
 }
 
-#[async_trait]
-impl server::Handler for Server {
-    type Error = anyhow::Error;
+/// Decides after how long without activity a session is closed.
+///
+/// # Arguments
+///
+/// * `default_secs` - The server-wide idle timeout.
+/// * `label_secs` - The idle timeout set by the container's label, if any.
+///
+/// # Returns
+///
+/// The timeout, the label taking precedence, or `None` if it is disabled.
+fn session_idle_timeout(default_secs: u64, label_secs: Option<u64>) -> Option<Duration> {
+    let secs = label_secs.unwrap_or(default_secs);
+    (secs > 0).then(|| Duration::from_secs(secs))
+}
 
-    async fn channel_close(
-        self,
-        _: ChannelId,
-        session: Session,
-    ) -> Result<(Self, Session), Self::Error> {
-        Ok((self, session))
+/// How often idle sessions are looked for.
+///
+/// # Arguments
+///
+/// * `timeout` - The shortest idle timeout of the sessions, if any session has one.
+///
+/// # Returns
+///
+/// A few times per timeout period, between once a second and every 30 seconds.
+fn idle_check_interval(timeout: Option<Duration>) -> Duration {
+    match timeout {
+        Some(timeout) => (timeout / 4).clamp(IDLE_CHECK_MIN_INTERVAL, IDLE_CHECK_MAX_INTERVAL),
+        None => IDLE_CHECK_MAX_INTERVAL,
     }
+}
 
-    async fn channel_eof(
-        self,
-        _: ChannelId,
+/// Records the end of a session in the audit log.
+///
+/// # Arguments
+///
+/// * `audit` - The audit log.
+/// * `login_user` - The user name the connection authenticated with.
+/// * `client` - The session that ended.
+fn record_session_closed(audit: &AuditLog, login_user: Option<&str>, client: &Client) {
+    let (bytes_received, bytes_sent) = (client.traffic.received(), client.traffic.sent());
+    info!(
+        "Session of {} on container {} closed after {} bytes in and {} bytes out",
+        login_user.unwrap_or("an unknown user"),
+        client.container_id.as_deref().unwrap_or("-"),
+        bytes_received,
+        bytes_sent
+    );
+    audit.record(
+        client.peer_addr,
+        login_user,
+        AuditEvent::SessionClosed {
+            container_id: client.container_id.as_deref(),
+            duration_secs: client.opened_at.elapsed().as_secs_f64(),
+            exit_code: client.exit_code,
+            bytes_received,
+            bytes_sent,
+        },
+    );
+}
+
+/// Sends a signal to the process of an exec through its host PID.
+///
+/// # Arguments
+///
+/// * `docker` - The Docker client the exec is inspected with.
+/// * `exec_id` - The ID of the exec whose process is signalled.
+/// * `signal` - The signal to send.
+///
+/// # Returns
+///
+/// `TunnydError::ExecNotRunning` if the exec has no process anymore, `TunnydError::ForeignPid` if
+/// the PID doesn't name a process of the container in the PID namespace of tunnyd, or the error of
+/// the inspection or of the signal.
+///
+/// # Remarks
+///
+/// Docker reports the PID in the namespace of the Docker host. When tunnyd runs in a container or
+/// talks to a daemon on another machine, the same number names an unrelated process or none at
+/// all, so the PID is only signalled once its cgroup shows it belongs to the exec's container.
+async fn signal_exec_process(
+    docker: &Docker,
+    exec_id: &str,
+    signal: Signal,
+) -> Result<(), TunnydError> {
+    let inspect = docker.inspect_exec(exec_id).await?;
+    let pid = inspect
+        .pid
+        .filter(|pid| *pid > 0)
+        .ok_or_else(|| TunnydError::ExecNotRunning {
+            exec_id: exec_id.to_string(),
+        })?;
+    let owned = inspect.container_id.as_deref().is_some_and(|container_id| {
+        std::fs::read_to_string(format!("/proc/{}/cgroup", pid))
+            .is_ok_and(|cgroup| cgroup_names_container(&cgroup, container_id))
+    });
+    if !owned {
+        return Err(TunnydError::ForeignPid {
+            exec_id: exec_id.to_string(),
+        });
+    }
+    kill(Pid::from_raw(pid as i32), signal)?;
+    info!("Sent {} to exec {} (pid {})", signal, exec_id, pid);
+    Ok(())
+}
+
+/// Checks whether the cgroup of a process places it in a container.
+///
+/// # Arguments
+///
+/// * `cgroup` - The contents of `/proc/<pid>/cgroup`.
+/// * `container_id` - The full ID of the container.
+///
+/// # Returns
+///
+/// `true` if one of the cgroup paths names the container, e.g. `/docker/<id>` or
+/// `/system.slice/docker-<id>.scope`.
+fn cgroup_names_container(cgroup: &str, container_id: &str) -> bool {
+    !container_id.is_empty()
+        && cgroup
+            .lines()
+            .filter_map(|line| line.splitn(3, ':').nth(2))
+            .any(|path| path.contains(container_id))
+}
+
+/// Writes the control character of a signal to the stdin of a terminal session.
+///
+/// # Arguments
+///
+/// * `input` - The stdin of the exec.
+/// * `signal` - The signal requested by the client.
+///
+/// # Returns
+///
+/// `true` if the signal has a control character and it was written, `false` if the signal has to
+/// be sent to the process instead.
+async fn signal_terminal<W>(input: &mut W, signal: &Sig) -> Result<bool, std::io::Error>
+where
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    match signal_control_byte(signal) {
+        Some(byte) => write_input(input, &[byte]).await.map(|_| true),
+        None => Ok(false),
+    }
+}
+
+/// Returns the stdin client input is written to.
+///
+/// # Arguments
+///
+/// * `readonly` - Whether the session is read-only.
+/// * `io` - The streams of the exec linked to the session, if any.
+///
+/// # Returns
+///
+/// A handle to the stdin of the exec, or `None` if the session has no exec yet or is read-only.
+/// The input is dropped then.
+fn session_input(
+    readonly: bool,
+    io: Option<&OutputInputPair>,
+) -> Option<Arc<Mutex<Pin<Box<dyn AsyncWrite + Send>>>>> {
+    match readonly {
+        true => None,
+        false => io.map(|io| Arc::clone(&io.input)),
+    }
+}
+
+/// Writes client input to the stdin of an exec and flushes it right away.
+///
+/// # Arguments
+///
+/// * `input` - The stdin of the exec.
+/// * `data` - The bytes received from the client.
+///
+/// # Returns
+///
+/// The error encountered while writing, if any.
+///
+/// # Remarks
+///
+/// `write_all` retries partial and interrupted writes and waits while the stream isn't ready.
+/// The flush keeps keystrokes from sitting in a buffer, which interactive programs like `vi`
+/// would otherwise only see once more input arrives.
+async fn write_input<W>(input: &mut W, data: &[u8]) -> Result<(), std::io::Error>
+where
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    input.write_all(data).await?;
+    input.flush().await
+}
+
+/// Wraps the streams of a container's main process for `link_io`.
+///
+/// # Arguments
+///
+/// * `container_id` - The ID of the container, standing in for the exec ID.
+/// * `tty` - Whether the main process has a TTY.
+/// * `results` - The streams returned by attaching to the container.
+///
+/// # Returns
+///
+/// The `Attachment`, never shared with other channels.
+fn main_process_attachment(
+    container_id: &str,
+    tty: bool,
+    results: AttachContainerResults,
+) -> Attachment {
+    Attachment {
+        exec_id: container_id.to_string(),
+        tty,
+        shared: false,
+        main_process: true,
+        input: results.input,
+        output: results.output,
+    }
+}
+
+/// Forwards the output of an exec opened on a control channel as `output` responses.
+///
+/// # Arguments
+///
+/// * `channel` - The ID of the control channel.
+/// * `session_handle` - The handle to the session the channel belongs to.
+/// * `output` - The output stream of the exec.
+/// * `docker` - The Docker client the exec is inspected with once its output ends.
+/// * `container_id` - The ID of the container the exec runs in.
+/// * `exec_id` - The ID of the exec, reported in the `opened` response sent before any output.
+///
+/// # Remarks
+///
+/// Once the output ends the exit code of the exec is sent as an `exit` response and the channel is
+/// closed, with the exit code as exit status if Docker reported one.
+async fn forward_control_output(
+    channel: ChannelId,
+    session_handle: Handle,
+    output: Arc<Mutex<Pin<Box<dyn Stream<Item = Result<LogOutput, Error>> + Send>>>>,
+    docker: Docker,
+    container_id: String,
+    exec_id: String,
+) {
+    let opened = ControlResponse::Opened {
+        container_id,
+        exec_id: exec_id.clone(),
+    };
+    if session_handle
+        .data(channel, CryptoVec::from(opened.to_line()))
+        .await
+        .is_err()
+    {
+        return;
+    }
+    let mut output = output.lock().await;
+    while let Some(chunk) = output.next().await {
+        let response = match chunk {
+            Ok(chunk) => match ControlResponse::from_output(chunk) {
+                Some(response) => response,
+                None => continue,
+            },
+            Err(e) => {
+                error!(
+                    "Output of exec {} on channel {} failed: {}",
+                    exec_id, channel, e
+                );
+                ControlResponse::Error {
+                    message: stream_error_message(&e).trim().to_string(),
+                }
+            }
+        };
+        if session_handle
+            .data(channel, CryptoVec::from(response.to_line()))
+            .await
+            .is_err()
+        {
+            return;
+        }
+    }
+    let code = match docker.inspect_exec(&exec_id).await {
+        Ok(inspect) => inspect.exit_code,
+        Err(e) => {
+            error!("Failed to inspect exec {}: {}", exec_id, e);
+            None
+        }
+    };
+    let exit = ControlResponse::Exit { code };
+    let _ = session_handle
+        .data(channel, CryptoVec::from(exit.to_line()))
+        .await;
+    if let Some(code) = code {
+        let status = code.clamp(0, u32::MAX as i64) as u32;
+        let _ = session_handle.exit_status_request(channel, status).await;
+    }
+    let _ = session_handle.eof(channel).await;
+    let _ = session_handle.close(channel).await;
+}
+
+/// Aborts a task once dropped, tying the lifetime of a background task to its owner.
+struct AbortOnDrop(Option<JoinHandle<()>>);
+
+impl AbortOnDrop {
+    fn new(task: JoinHandle<()>) -> AbortOnDrop {
+        AbortOnDrop(Some(task))
+    }
+
+    /// Lets the task run to completion instead, for tasks dropping their own owner.
+    fn detach(mut self) {
+        self.0.take();
+    }
+}
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        if let Some(task) = self.0.take() {
+            task.abort();
+        }
+    }
+}
+
+/// Starts the timer enforcing the maximum duration of a session.
+///
+/// # Arguments
+///
+/// * `limit` - The maximum duration of the session.
+/// * `on_expiry` - Ends the session, called once `limit` has passed.
+///
+/// # Returns
+///
+/// The timer, which is cancelled when dropped.
+fn start_session_timer<F, Fut>(limit: Duration, on_expiry: F) -> AbortOnDrop
+where
+    F: FnOnce() -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    AbortOnDrop::new(tokio::spawn(async move {
+        tokio::time::sleep(limit).await;
+        on_expiry().await;
+    }))
+}
+
+/// Represents a SSH client.
+///
+/// # Fields
+///
+/// - `session_handle`: A handle to the SSH session.
+/// - `io`: Optional pair of output and input streams.
+/// - `peer_addr`: The source address of the connection the session belongs to.
+/// - `user`: The login name the connection authenticated with, if known.
+/// - `opened_at`: When the session was opened.
+/// - `activity`: When the session last saw input or output.
+/// - `traffic`: The bytes the session received from and sent to the client.
+/// - `idle_timeout`: How long the session may go without activity before it is closed, the
+///   server-wide value until a container with a `tunnyD.idle.timeout` label is resolved.
+/// - `container_id`: The ID of the container the session was linked to, once resolved.
+/// - `docker_host`: The index of the Docker host the container runs on, `0` being the primary one.
+/// - `exec_id`: The ID of the exec process linked to the session, once started.
+/// - `tty`: Whether the exec process linked to the session was allocated a TTY.
+/// - `readonly`: Whether the container only allows read-only sessions, input is then dropped.
+/// - `pty`: The pseudo-terminal requested by the client, if any.
+/// - `env`: The accepted environment variables sent by the client, as `NAME=value` pairs.
+/// - `intent`: Which of the session's requests arrived so far, deciding which ones are still
+///   accepted.
+/// - `motd`: The message of the day sent once the exec is attached, before any of its output.
+/// - `exit_code`: The exit code of the exec process, once it has exited.
+/// - `window`: The flow-control window of the channel, tracking how much output the client accepts.
+/// - `session_timer`: Closes the session once it reaches the maximum session duration, if one is
+///   configured. Dropping the client cancels it.
+/// - `forwarding`: The task forwarding the output of the exec, once attached. Dropping the client,
+///   e.g. when the channel is closed, aborts it and with it the exec's streams.
+/// - `control`: The pending request data of a channel speaking the control subsystem's JSON line
+///   protocol, `None` for every other channel.
+///
+/// # Remarks
+///
+/// - The `session_handle` field provides access to the session functionality of the SSH client,
+///   allowing the execution of commands, shell access, and file transfer.
+/// - The `io` field is an optional pair of output and input streams used for interacting with the SSH
+///   client. If `None`, the client does not have any associated streams.
+pub struct Client {
+    session_handle: russh::server::Handle,
+    io: Option<OutputInputPair>,
+    peer_addr: Option<SocketAddr>,
+    user: Option<String>,
+    opened_at: Instant,
+    activity: Activity,
+    traffic: Traffic,
+    idle_timeout: Option<Duration>,
+    container_id: Option<String>,
+    docker_host: usize,
+    exec_id: Option<String>,
+    tty: bool,
+    readonly: bool,
+    pty: Option<PtyRequest>,
+    env: Vec<String>,
+    intent: SessionIntent,
+    motd: Option<String>,
+    exit_code: Option<i64>,
+    window: Arc<ChannelWindow>,
+    session_timer: Option<AbortOnDrop>,
+    forwarding: Option<AbortOnDrop>,
+    control: Option<LineBuffer>,
+}
+
+/// Represents an ssh server.
+///
+/// # Remarks
+///
+/// - The `clients` field is a shared, thread-safe, mutable reference to a hash map storing the
+///   clients connected to the server.
+/// - The `docker` field is a shared, reconnecting wrapper around the `bollard::docker` struct,
+///   representing the Docker api associated with the server.
+/// - The `id` field is the identifier of the connection, it is only meaningful on the
+///   per-connection handlers.
+/// - The `next_id` field hands out connection ids, it is shared by every listener so ids stay
+///   unique across listeners and listener restarts.
+/// - The `login_user` field is the user name the connection authenticated with, it is only set on
+///   the per-connection handlers.
+/// - The `keyboard_responses` field collects the answers of an ongoing keyboard-interactive
+///   authentication, it is only set on the per-connection handlers.
+/// - The `peer_addr` field is the source address of the connection, it is only set on the
+///   per-connection handlers.
+/// - The `config` field is the shared runtime configuration of the server.
+/// - The `settings` field holds the configuration new connections start with, it is swapped when
+///   the configuration is reloaded. `config`, `ip_filter` and `command_filter` are taken from it
+///   when a connection starts and stay the same until it ends.
+/// - The `audit` field is the shared audit log sessions are recorded in.
+/// - The `ip_filter` field decides which source addresses may authenticate.
+/// - The `command_filter` field decides which commands clients may run.
+/// - The `auth_failures` field tracks failed authentications per source address.
+/// - The `auth_policy` field decides whether `publickey` and `none` are verified or accept every
+///   client, the latter only with `--insecure-allow-all`.
+/// - The `docker_hosts` field holds the Docker hosts containers are searched on, each with the
+///   container list cache shared by every connection. The primary host, using the `docker` client,
+///   comes first.
+/// - The `remote_forwards` field holds the listener tasks of the active remote port forwards, keyed
+///   by the connection id, the requested address and the port.
+/// - The `shared_sessions` field holds the execs shared with `--share`, by container, user and name.
+/// - The `usage_cache` field holds the recently fetched resource usage of containers.
+/// - The `connection` field closes the sessions of the connection once it ended, it is only set on
+///   the per-connection handlers.
+/// - The `authenticated` field is notified once the connection authenticated, disarming its auth
+///   deadline. Every connection gets its own.
+/// - The `affinity` field remembers the container each user's hostname targets resolved to, so
+///   quick reconnects land on the same replica.
+/// - The `draining` field is set once the server is shutting down, new sessions are refused then
+///   while the open ones may still end on their own.
+#[derive(Clone)]
+pub struct Server {
+    pub(crate) clients: Arc<Mutex<HashMap<(usize, ChannelId), Client>>>,
+    pub(crate) docker: DockerClient,
+    pub(crate) id: usize,
+    pub(crate) next_id: Arc<AtomicUsize>,
+    pub(crate) login_user: Option<String>,
+    pub(crate) keyboard_responses: Vec<String>,
+    pub(crate) peer_addr: Option<SocketAddr>,
+    pub(crate) config: Arc<ServerConfig>,
+    pub(crate) settings: SharedSettings,
+    pub(crate) audit: Arc<AuditLog>,
+    pub(crate) ip_filter: Arc<IpFilter>,
+    pub(crate) command_filter: Arc<CommandFilter>,
+    pub(crate) auth_failures: Arc<AuthFailures>,
+    pub(crate) auth_policy: AuthPolicy,
+    pub(crate) docker_hosts: Arc<Vec<DockerHost>>,
+    pub(crate) remote_forwards: Arc<Mutex<HashMap<(usize, String, u32), JoinHandle<()>>>>,
+    pub(crate) shared_sessions: Arc<SharedSessions>,
+    pub(crate) usage_cache: Arc<Mutex<UsageCache>>,
+    pub(crate) connection: Option<Arc<ConnectionGuard>>,
+    pub(crate) authenticated: Arc<Notify>,
+    pub(crate) affinity: Arc<Affinity>,
+    pub(crate) draining: Arc<AtomicBool>,
+}
+
+/// Closes the sessions of a connection once its handler is dropped, i.e. when the connection ended
+/// without the client closing its channels, so they no longer count against the session limits,
+/// and stops its remote port forwards.
+pub struct ConnectionGuard {
+    id: usize,
+    clients: Arc<Mutex<HashMap<(usize, ChannelId), Client>>>,
+    remote_forwards: Arc<Mutex<HashMap<(usize, String, u32), JoinHandle<()>>>>,
+    audit: Arc<AuditLog>,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        let id = self.id;
+        let clients = Arc::clone(&self.clients);
+        let remote_forwards = Arc::clone(&self.remote_forwards);
+        let audit = Arc::clone(&self.audit);
+        runtime.spawn(async move {
+            // Listeners of a dropped connection would otherwise keep their ports bound
+            cancel_remote_forwards(&remote_forwards, id).await;
+            let mut clients = clients.lock().await;
+            let open: Vec<(usize, ChannelId)> = clients
+                .keys()
+                .filter(|(connection, _)| *connection == id)
+                .copied()
+                .collect();
+            for client_id in open {
+                if let Some(client) = clients.remove(&client_id) {
+                    info!(
+                        "Closing channel {} of client {}, the connection ended",
+                        client_id.1, id
+                    );
+                    record_session_closed(&audit, client.user.as_deref(), &client);
+                }
+            }
+        });
+    }
+}
+
+/// Stops every remote port forward of a connection.
+///
+/// # Arguments
+///
+/// * `forwards` - The listener tasks of the active remote port forwards.
+/// * `id` - The ID of the connection.
+async fn cancel_remote_forwards(
+    forwards: &Mutex<HashMap<(usize, String, u32), JoinHandle<()>>>,
+    id: usize,
+) {
+    forwards
+        .lock()
+        .await
+        .retain(|(owner, address, port), task| {
+            if *owner != id {
+                return true;
+            }
+            info!(
+                "Cancelling remote forward {}:{} of client {}",
+                address, port, id
+            );
+            task.abort();
+            false
+        });
+}
+
+/// Checks whether one more session would exceed the per-user or per-address session limit.
+///
+/// # Arguments
+///
+/// * `open` - The login name and source address of every open session.
+/// * `user` - The login name of the new session, if known.
+/// * `ip` - The source address of the new session, if known.
+/// * `per_user` - The maximum number of sessions per login name, `0` means unlimited.
+/// * `per_ip` - The maximum number of sessions per source address, `0` means unlimited.
+///
+/// # Returns
+///
+/// The limit the new session would exceed, or `None` if it may be opened.
+fn session_limit_error<'a>(
+    open: impl Iterator<Item = (Option<&'a str>, Option<IpAddr>)>,
+    user: Option<&str>,
+    ip: Option<IpAddr>,
+    per_user: usize,
+    per_ip: usize,
+) -> Option<TunnydError> {
+    let (mut user_sessions, mut ip_sessions) = (0, 0);
+    for (open_user, open_ip) in open {
+        user_sessions += usize::from(user.is_some() && open_user == user);
+        ip_sessions += usize::from(ip.is_some() && open_ip == ip);
+    }
+    match (user, ip) {
+        (Some(user), _) if per_user != 0 && user_sessions >= per_user => {
+            Some(TunnydError::UserSessionLimit {
+                user: user.to_string(),
+                limit: per_user,
+            })
+        }
+        (_, Some(ip)) if per_ip != 0 && ip_sessions >= per_ip => {
+            Some(TunnydError::AddressSessionLimit { ip, limit: per_ip })
+        }
+        _ => None,
+    }
+}
+
+/// Checks whether one more session channel would exceed the channel limit of its connection.
+///
+/// # Arguments
+///
+/// * `open` - The connection ID of every open session channel.
+/// * `connection` - The ID of the connection opening the channel.
+/// * `limit` - The maximum number of channels per connection, `0` means unlimited.
+///
+/// # Returns
+///
+/// A `TunnydError::ChannelLimit` if the connection already has `limit` channels open, otherwise
+/// `None`.
+fn channel_limit_error(
+    open: impl Iterator<Item = usize>,
+    connection: usize,
+    limit: usize,
+) -> Option<TunnydError> {
+    if limit == 0 {
+        return None;
+    }
+    let channels = open.filter(|id| *id == connection).count();
+    (channels >= limit).then_some(TunnydError::ChannelLimit { limit })
+}
+
+/// Returns the control character a terminal turns into the given signal.
+///
+/// # Arguments
+///
+/// * `signal` - The signal requested by the SSH client.
+///
+/// # Returns
+///
+/// The control byte (`^C` for INT, `^\` for QUIT), or `None` if the signal has no terminal equivalent.
+fn signal_control_byte(signal: &Sig) -> Option<u8> {
+    match signal {
+        Sig::INT => Some(0x03),
+        Sig::QUIT => Some(0x1c),
+        _ => None,
+    }
+}
+
+/// Maps a SSH signal name to the Unix signal forwarded to the exec process.
+///
+/// # Arguments
+///
+/// * `signal` - The signal requested by the SSH client.
+///
+/// # Returns
+///
+/// The Unix signal, or `None` if the signal is not forwarded.
+fn unix_signal(signal: &Sig) -> Option<Signal> {
+    match signal {
+        Sig::INT => Some(Signal::SIGINT),
+        Sig::TERM => Some(Signal::SIGTERM),
+        Sig::HUP => Some(Signal::SIGHUP),
+        Sig::QUIT => Some(Signal::SIGQUIT),
+        Sig::KILL => Some(Signal::SIGKILL),
+        Sig::USR1 => Some(Signal::SIGUSR1),
+        _ => None,
+    }
+}
+
+/// Translates the error Docker failed to create or start an exec with.
+///
+/// # Arguments
+///
+/// * `error` - The Docker error.
+/// * `command` - The command of the exec.
+///
+/// # Returns
+///
+/// `TunnydError::CommandNotFound` if the runtime couldn't find the executable, otherwise the
+/// Docker error.
+fn exec_start_error(error: Error, command: &[&str]) -> TunnydError {
+    let message = match &error {
+        Error::DockerResponseServerError { message, .. } => message.to_lowercase(),
+        _ => return error.into(),
+    };
+    match COMMAND_NOT_FOUND_MESSAGES
+        .iter()
+        .any(|pattern| message.contains(pattern))
+    {
+        true => TunnydError::CommandNotFound {
+            command: command.first().copied().unwrap_or_default().to_string(),
+        },
+        false => error.into(),
+    }
+}
+
+/// Checks that a session has a pseudo-terminal if the configuration requires one.
+///
+/// # Arguments
+///
+/// * `require_tty` - Whether sessions need a pseudo-terminal.
+/// * `pty` - The pseudo-terminal requested by the client before the session request, if any.
+///
+/// # Returns
+///
+/// `TunnydError::TtyRequired` if a pseudo-terminal is required but none was requested.
+fn check_tty(require_tty: bool, pty: Option<&PtyRequest>) -> Result<(), TunnydError> {
+    match require_tty && pty.is_none() {
+        true => Err(TunnydError::TtyRequired),
+        false => Ok(()),
+    }
+}
+
+/// Returns the exit status reported to the client when a session request fails.
+///
+/// # Returns
+///
+/// `127` for a command that doesn't exist, like shells do, otherwise `1`.
+fn failure_exit_status(error: &TunnydError) -> u32 {
+    match error {
+        TunnydError::CommandNotFound { .. } => EXIT_STATUS_COMMAND_NOT_FOUND,
+        _ => EXIT_STATUS_FAILURE,
+    }
+}
+
+/// Describes the container and user a session resolves to.
+///
+/// # Arguments
+///
+/// * `container` - The resolved container.
+/// * `user` - The effective container user, `None` for the image's default user.
+///
+/// # Returns
+///
+/// A JSON object with the container's `id`, `name`, `image`, the `networks` it is attached to and
+/// the `network` its `tunnyD.network` label picks, and the `user`, followed by a newline.
+fn session_info(container: &ContainerSummary, user: Option<&str>) -> String {
+    let networks: Vec<serde_json::Value> = attached_networks(container)
+        .into_iter()
+        .map(|network| {
+            serde_json::json!({
+                "name": network.name,
+                "address": network.address.map(|address| address.to_string()),
+                "gateway": network.gateway,
+            })
+        })
+        .collect();
+    let info = serde_json::json!({
+        "id": container.id,
+        "name": container_name(container),
+        "image": container.image,
+        "network": container_network(container).ok().flatten(),
+        "networks": networks,
+        "user": user,
+    });
+    format!("{}\n", info)
+}
+
+/// Tells the client why its request failed and closes the channel.
+///
+/// # Arguments
+///
+/// * `session` - The session the channel belongs to.
+/// * `channel` - The ID of the channel to close.
+/// * `message` - The human-readable reason, written to the client's stderr.
+/// * `exit_status` - The exit status reported to the client.
+///
+/// # Remarks
+///
+/// The request itself is acknowledged so clients display the message and exit with the given
+/// status instead of a generic "request failed" error.
+fn fail_channel(session: &mut Session, channel: ChannelId, message: &str, exit_status: u32) {
+    session.channel_success(channel);
+    session.extended_data(
+        channel,
+        EXTENDED_DATA_STDERR,
+        CryptoVec::from(format!("{}\r\n", message).into_bytes()),
+    );
+    session.exit_status_request(channel, exit_status);
+    session.eof(channel);
+    session.close(channel);
+}
+
+/// Creates a closure that forwards the output of a container to a session channel.
+///
+/// # Arguments
+///
+/// * `channel` - The ID of the channel to send the output to.
+/// * `cloned_handle` - A cloned handle to the session.
+/// * `recorder` - The recorder output is also written to, if the session is being recorded.
+/// * `activity` - The activity tracker of the session, touched for every output chunk.
+/// * `traffic` - The traffic counters of the session, every delivered byte is counted.
+/// * `transform` - The transformations applied to the output, if any are enabled for the session.
+/// * `window` - The flow-control window of the channel, output waits for it to open and
+///   is sent in chunks no larger than its chunk size.
+/// * `failures` - The write failure policy of the channel.
+///
+/// # Returns
+///
+/// A boxed closure that takes a `LogOutput` as input and returns a `Pin<Box<dyn Future<Output = bool> + Send + 'static>>`,
+/// resolving to `false` once the channel failed too often and the session should be torn down.
+///
+/// # Example
+///
+/// ```ignore
+/// // `output` is the `Stream<Item = Result<LogOutput, Error>>` of a started exec
+/// let forward = forward_container_output_to_session(channel, cloned_handle, None, activity, traffic, None, window, failures);
+/// let end = pump_output(&mut output, forward).await;
+/// ```
+
+fn forward_container_output_to_session(
+    channel: ChannelId,
+    cloned_handle: Arc<Mutex<Handle>>,
+    recorder: Option<Arc<SessionRecorder>>,
+    activity: Activity,
+    traffic: Traffic,
+    transform: Option<Arc<std::sync::Mutex<OutputTransform>>>,
+    window: Arc<ChannelWindow>,
+    failures: Arc<WriteFailures>,
+) -> Box<dyn Fn(LogOutput) -> Pin<Box<dyn Future<Output = bool> + Send + 'static>> + Send + 'static>
+{
+    Box::new(move |data: LogOutput| {
+        let session_handle_clone = Arc::clone(&cloned_handle);
+        let recorder = recorder.clone();
+        let activity = activity.clone();
+        let traffic = traffic.clone();
+        let transform = transform.clone();
+        let window = Arc::clone(&window);
+        let failures = Arc::clone(&failures);
+        Box::pin(async move {
+            let handle = session_handle_clone.lock().await;
+            activity.touch();
+            let raw = data.into_bytes();
+            // Untransformed output is sent straight from Docker's buffer, chunk by chunk
+            let transformed = transform.as_ref().map(|transform| match transform.lock() {
+                Ok(mut transform) => transform.apply(&raw),
+                Err(poisoned) => poisoned.into_inner().apply(&raw),
+            });
+            let bytes: &[u8] = transformed.as_deref().unwrap_or(&raw[..]);
+            if bytes.is_empty() {
+                return true;
+            }
+            if let Some(recorder) = &recorder {
+                recorder.output(bytes);
+            }
+            let sent = send_counted(&window, &traffic, bytes, |chunk| {
+                handle.data(channel, CryptoVec::from(chunk))
+            })
+            .await;
+            if sent.is_ok() {
+                failures.succeeded();
+                return true;
+            }
+            warn!("Failed to send output to channel {}, it is closed", channel);
+            !failures.failed()
+        })
+    })
+}
+
+/// Sends output within the client's window, counting the bytes that were delivered.
+///
+/// # Arguments
+///
+/// * `window` - The window of the channel.
+/// * `traffic` - The traffic counters of the session.
+/// * `data` - The output to send.
+/// * `send` - Sends one chunk to the client.
+///
+/// # Returns
+///
+/// The error of the first chunk that couldn't be sent, the chunks sent before it are counted.
+async fn send_counted<F, Fut, E>(
+    window: &ChannelWindow,
+    traffic: &Traffic,
+    data: &[u8],
+    mut send: F,
+) -> Result<(), E>
+where
+    F: FnMut(Vec<u8>) -> Fut,
+    Fut: Future<Output = Result<(), E>>,
+{
+    send_windowed(window, data, |chunk| {
+        let len = chunk.len();
+        let sent = send(chunk);
+        async move {
+            sent.await?;
+            traffic.add_sent(len);
+            Ok(())
+        }
+    })
+    .await
+}
+
+/// Describes how forwarding the output of an exec ended.
+#[derive(Debug)]
+enum OutputEnd {
+    /// The exec closed its output.
+    Finished,
+    /// The client's channel kept rejecting the output.
+    Undeliverable,
+    /// Reading the output from Docker failed.
+    Failed(Error),
+}
+
+/// Forwards the output of an exec until it ends.
+///
+/// # Arguments
+///
+/// * `output` - The output stream of the exec.
+/// * `forward` - Forwards one chunk, resolving to `false` once the output can't be delivered.
+///
+/// # Returns
+///
+/// How the forwarding ended. Output following a stream error is not forwarded.
+async fn pump_output<S, F, Fut>(output: &mut S, forward: F) -> OutputEnd
+where
+    S: Stream<Item = Result<LogOutput, Error>> + Unpin,
+    F: Fn(LogOutput) -> Fut,
+    Fut: Future<Output = bool>,
+{
+    while let Some(item) = output.next().await {
+        match item {
+            Ok(data) if forward(data).await => {}
+            Ok(_) => return OutputEnd::Undeliverable,
+            Err(e) => return OutputEnd::Failed(e),
+        }
+    }
+    OutputEnd::Finished
+}
+
+/// Forwards the scrollback of a container followed by the output of an exec.
+///
+/// # Arguments
+///
+/// * `scrollback` - The recent logs of the container, if scrollback is enabled.
+/// * `output` - The output stream of the exec.
+/// * `forward` - Forwards one chunk, resolving to `false` once the output can't be delivered.
+///
+/// # Returns
+///
+/// How forwarding the output of the exec ended. Containers whose logs can't be read, e.g. because
+/// their logging driver doesn't support it, just go without scrollback.
+async fn pump_with_scrollback<B, S, F, Fut>(
+    scrollback: Option<B>,
+    output: &mut S,
+    forward: F,
+) -> OutputEnd
+where
+    B: Stream<Item = Result<LogOutput, Error>> + Unpin,
+    S: Stream<Item = Result<LogOutput, Error>> + Unpin,
+    F: Fn(LogOutput) -> Fut,
+    Fut: Future<Output = bool>,
+{
+    if let Some(mut scrollback) = scrollback {
+        match pump_output(&mut scrollback, &forward).await {
+            OutputEnd::Finished => {}
+            OutputEnd::Undeliverable => return OutputEnd::Undeliverable,
+            OutputEnd::Failed(e) => warn!("Failed to read the scrollback: {}", e),
+        }
+    }
+    pump_output(output, forward).await
+}
+
+/// Explains a failed output stream to the user.
+///
+/// # Arguments
+///
+/// * `error` - The error the stream failed with.
+///
+/// # Returns
+///
+/// The message written to the client's stderr, suggesting to reconnect when the connection to
+/// Docker was lost rather than Docker failing the exec.
+fn stream_error_message(error: &Error) -> String {
+    match is_connection_error(error) {
+        true => format!(
+            "\r\nLost the connection to Docker ({}). The session was closed, reconnect to start a new one.\r\n",
+            error
+        ),
+        false => format!("\r\nDocker failed the session: {}\r\n", error),
+    }
+}
+
+/// Checks that a port forward can reach a resolved container.
+///
+/// # Arguments
+///
+/// * `container` - The container the login name resolved to.
+/// * `host` - The index of the Docker host it runs on.
+/// * `target` - The target of the login name, used in the error.
+///
+/// # Returns
+///
+/// The container, or a `TunnydError` if it runs on an additional Docker host or isn't attached to
+/// its SSH network.
+///
+/// # Remarks
+///
+/// Forwards are connected and bound from the host tunnyd runs on, which only shares the networks of
+/// the primary daemon's containers. On any other host the addresses would reach whatever happens to
+/// use them locally.
+fn forward_container(
+    container: ContainerSummary,
+    host: usize,
+    target: &str,
+) -> Result<ContainerSummary, TunnydError> {
+    if host != 0 {
+        return Err(TunnydError::RemoteForward {
+            target: target.to_string(),
+        });
+    }
+    container_network(&container).map(|_| container)
+}
+
+/// Sends a signal to the process of an exec.
+///
+/// # Arguments
+///
+/// * `docker` - The client of the Docker host the exec runs on.
+/// * `host` - The index of the Docker host.
+/// * `exec_id` - The ID of the exec whose process is signalled.
+/// * `signal` - The signal to send.
+///
+/// # Remarks
+///
+/// Docker has no API to signal an exec, so the host PID reported by the exec inspection is
+/// signalled directly. This requires tunnyd to share the PID namespace of the Docker host, so
+/// execs on additional Docker hosts can't be signalled, and neither can execs of the primary
+/// daemon when tunnyd doesn't see their process, see `signal_exec_process`.
+async fn signal_exec(
+    docker: &Docker,
+    host: usize,
+    exec_id: &str,
+    signal: Signal,
+) -> Result<(), TunnydError> {
+    if host != 0 {
+        return Err(TunnydError::RemoteSignal {
+            exec_id: exec_id.to_string(),
+        });
+    }
+    signal_exec_process(docker, exec_id, signal).await
+}
+
+/// Hangs up the process of an exec nobody reads the output of anymore, logging failures.
+///
+/// # Arguments
+///
+/// * `docker` - The client of the Docker host the exec runs on.
+/// * `host` - The index of the Docker host.
+/// * `exec_id` - The ID of the exec.
+async fn hang_up_exec(docker: &Docker, host: usize, exec_id: &str) {
+    if let Err(e) = signal_exec(docker, host, exec_id, Signal::SIGHUP).await {
+        warn!("Failed to hang up exec {}: {}", exec_id, e);
+    }
+}
+impl server::Server for Server {
+    type Handler = Self;
+    fn new_client(&mut self, peer_addr: Option<SocketAddr>) -> Self {
+        // The peer address is only stored on the per-connection clone, so the next connection
+        // starts from the template's `None` rather than inheriting this one.
+        let mut cloned_self = self.clone();
+        cloned_self.id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        cloned_self.peer_addr = peer_addr;
+        cloned_self.authenticated = Arc::new(Notify::new());
+        let settings = snapshot(&self.settings);
+        cloned_self.config = settings.config;
+        cloned_self.ip_filter = settings.ip_filter;
+        cloned_self.command_filter = settings.command_filter;
+        cloned_self.connection = Some(Arc::new(ConnectionGuard {
+            id: cloned_self.id,
+            clients: Arc::clone(&self.clients),
+            remote_forwards: Arc::clone(&self.remote_forwards),
+            audit: Arc::clone(&self.audit),
+        }));
+        match peer_addr {
+            Some(addr) => info!("Client {} connected from {}", cloned_self.id, addr),
+            None => info!(
+                "Client {} connected from an unknown address",
+                cloned_self.id
+            ),
+        }
+        if cloned_self.auth_policy == AuthPolicy::InsecureAllowAll {
+            warn!(
+                "INSECURE: client {} is not authenticated, --insecure-allow-all accepts any key and the none method",
+                cloned_self.id
+            );
+        }
+        cloned_self
+    }
+}
+impl Server {
+    /// Checks whether one more session of the connection would exceed a session limit.
+    ///
+    /// # Arguments
+    ///
+    /// * `clients` - The open sessions.
+    /// * `user` - The login name of the connection.
+    ///
+    /// # Returns
+    ///
+    /// The limit the session would exceed, or `None` if it may be opened.
+    fn session_limit(
+        &self,
+        clients: &HashMap<(usize, ChannelId), Client>,
+        user: Option<&str>,
+    ) -> Option<TunnydError> {
+        session_limit_error(
+            clients.values().map(|client| {
+                (
+                    client.user.as_deref(),
+                    client.peer_addr.map(|addr| addr.ip()),
+                )
+            }),
+            user,
+            self.peer_addr.map(|addr| addr.ip()),
+            self.config.max_sessions_per_user,
+            self.config.max_sessions_per_ip,
+        )
+    }
+
+    /// Checks whether the source address of the connection may authenticate.
+    ///
+    /// # Returns
+    ///
+    /// `true` if the address passes the IP allow/deny lists. Connections without a known source
+    /// address are treated as allowed.
+    fn peer_allowed(&self) -> bool {
+        match self.peer_addr {
+            Some(addr) => self.ip_filter.is_allowed(addr.ip()),
+            None => true,
+        }
+    }
+
+    /// Checks whether the source address of the connection is banned for failing authentication.
+    ///
+    /// # Returns
+    ///
+    /// `true` while the address is banned. Every auth handler checks this before verifying
+    /// anything, so a banned client learns nothing from its attempts and runs no verification.
+    fn peer_banned(&self) -> bool {
+        let Some(ip) = self
+            .peer_addr
+            .map(|addr| addr.ip())
+            .filter(|ip| self.auth_failures.is_banned(*ip))
+        else {
+            return false;
+        };
+        warn!(
+            "Rejecting client {} from {}: address is banned",
+            self.id, ip
+        );
+        true
+    }
+
+    /// Decides whether a login may proceed, penalizing and banning addresses that keep failing.
+    ///
+    /// # Arguments
+    ///
+    /// - `login`: The user name the client authenticates with.
+    ///
+    /// # Returns
+    ///
+    /// `true` if the login can reach a container. Rejections are delayed by the penalty of the
+    /// address.
+    async fn check_login(&self, login: &str) -> bool {
+        let ip = self.peer_addr.map(|addr| addr.ip());
+        let limit = self.session_limit(&*self.clients.lock().await, Some(login));
+        if let Some(e) = limit {
+            warn!("Rejecting client {}: {}", self.id, e);
+            return false;
+        }
+        if self.login_allowed(login).await {
+            if let Some(ip) = ip {
+                self.auth_failures.record_success(ip);
+            }
+            return true;
+        }
+        warn!(
+            "Rejecting client {} from {:?}: login {} can't access any container",
+            self.id, self.peer_addr, login
+        );
+        self.penalize().await;
+        false
+    }
+
+    /// Rejects an authentication attempt using a method that isn't configured.
+    ///
+    /// # Arguments
+    ///
+    /// - `method`: The method the client attempted.
+    ///
+    /// # Returns
+    ///
+    /// `Some(Auth::Reject)` listing the configured methods if `method` isn't one of them.
+    fn reject_unconfigured(&self, method: AuthMethod) -> Option<Auth> {
+        if self.config.auth_methods.contains(&method) {
+            return None;
+        }
+        info!(
+            "Rejecting {:?} authentication of client {}: method not enabled",
+            method, self.id
+        );
+        Some(Auth::Reject {
+            proceed_with_methods: Some(method_set(&self.config.auth_methods)),
+        })
+    }
+
+    /// Completes the authentication of the connection, disarming its auth deadline.
+    ///
+    /// # Arguments
+    ///
+    /// - `user`: The user name the client authenticated with.
+    fn accept_login(&mut self, user: &str) -> Auth {
+        self.login_user = Some(user.to_string());
+        self.authenticated.notify_one();
+        Auth::Accept
+    }
+
+    /// Records a failed authentication of the connection's address and waits out its penalty.
+    async fn penalize(&self) {
+        if let Some(addr) = self.peer_addr {
+            tokio::time::sleep(self.auth_failures.record_failure(addr.ip())).await;
+        }
+    }
+
+    /// Builds the keyboard-interactive round asking the given prompt.
+    fn keyboard_prompt(&self, index: usize) -> Auth {
+        Auth::Partial {
+            name: "tunnyd".into(),
+            instructions: "".into(),
+            prompts: vec![(
+                self.config.keyboard_interactive_prompts[index]
+                    .clone()
+                    .into(),
+                false,
+            )]
+            .into(),
+        }
+    }
+
+    /// Checks whether a login name can reach any container, so hopeless logins are rejected
+    /// during authentication rather than once a session is requested.
+    ///
+    /// # Arguments
+    ///
+    /// - `login`: The user name the client authenticates with.
+    ///
+    /// # Returns
+    ///
+    /// `true` if the login, read as `[user@]target`, resolves to a container the user is allowed
+    /// on, or if the login is a user allowed on at least one container, which is the case when the
+    /// target is sent with the exec request instead. Docker errors deny the login.
+    async fn login_allowed(&self, login: &str) -> bool {
+        let args = args_from_login(login);
+        if self
+            .resolve_container(&args, login_identity(login))
+            .await
+            .is_ok()
+        {
+            return true;
+        }
+        if args.user.is_some() {
+            return false;
+        }
+        match any_container_allows_on_hosts(&self.docker_hosts, login, self.config.groups_of(login))
+            .await
+        {
+            Ok(allowed) => allowed,
+            Err(e) => {
+                error!("Failed to check containers for login {}: {}", login, e);
+                false
+            }
+        }
+    }
+
+    /// Create and start an exec process for a Docker container.
+    ///
+    /// # Arguments
+    ///
+    /// - `docker`: A reference to the Docker client.
+    /// - `args`: The container arguments.
+    /// - `container_id`: The ID of the container.
+    /// - `settings`: The per-session settings of the exec.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the `ExecProcess` if the exec process is created and started successfully,
+    /// or a `TunnydError` if an error occurred.
+    async fn create_and_start_exec(
+        &self,
+        docker: &DockerClient,
+        args: &ContainerArgs,
+        container_id: &str,
+        settings: &ExecSettings,
+    ) -> Result<ExecProcess, TunnydError> {
+        let options = exec_options(&self.config.exec, args, settings);
+        self.start_exec(docker, container_id, options).await
+    }
+
+    /// Create and start an exec process for a Docker container with the given options.
+    ///
+    /// # Arguments
+    ///
+    /// - `docker`: A reference to the Docker client.
+    /// - `container_id`: The ID of the container.
+    /// - `options`: The options used to create the exec process.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the `ExecProcess` if the exec process is created and started successfully,
+    /// or a `TunnydError` if an error occurred.
+    async fn start_exec(
+        &self,
+        docker: &DockerClient,
+        container_id: &str,
+        options: CreateExecOptions<&str>,
+    ) -> Result<ExecProcess, TunnydError> {
+        info!("Creating and starting exec for container {}", container_id);
+        let tty = options.tty.unwrap_or(false);
+        let command = options.cmd.clone().unwrap_or_default();
+
+        let exec = match self
+            .with_exec_timeout(
+                "create",
+                docker.with_retry(|docker| {
+                    let options = options.clone();
+                    async move { docker.create_exec(container_id, options).await }
+                }),
+            )
+            .await?
+        {
+            Ok(ex) => {
+                info!("Exec created successfully");
+                ex
+            }
+            Err(e) => {
+                error!("Failed to create exec: {}", e);
+                return Err(exec_start_error(e, &command));
+            }
+        };
+
+        let start_options = StartExecOptions {
+            detach: false,
+            ..Default::default()
+        };
+
+        let results = match self
+            .with_exec_timeout(
+                "start",
+                docker.get().start_exec(&exec.id, Some(start_options)),
+            )
+            .await?
+        {
+            Ok(res) => {
+                info!("Exec started successfully");
+                res
+            }
+            Err(e) => {
+                error!("Failed to start exec: {}", e);
+                return Err(exec_start_error(e, &command));
+            }
+        };
+
+        Ok(ExecProcess {
+            id: exec.id,
+            tty,
+            results,
+        })
+    }
+
+    /// Bounds a Docker exec operation by the configured exec timeout.
+    ///
+    /// # Arguments
+    ///
+    /// - `operation`: What the operation does to the exec, used in the error.
+    /// - `future`: The Docker operation.
+    ///
+    /// # Returns
+    ///
+    /// The result of the operation, or `TunnydError::ExecTimeout` if it didn't finish in time.
+    async fn with_exec_timeout<T, F>(
+        &self,
+        operation: &'static str,
+        future: F,
+    ) -> Result<T, TunnydError>
+    where
+        F: Future<Output = T>,
+    {
+        let timeout_secs = self.config.exec_timeout_secs;
+        let timeout = match timeout_secs {
+            0 => Duration::MAX,
+            secs => Duration::from_secs(secs),
+        };
+        tokio::time::timeout(timeout, future).await.map_err(|_| {
+            let e = TunnydError::ExecTimeout {
+                operation,
+                timeout_secs,
+            };
+            error!("{}", e);
+            e
+        })
+    }
+
+    /// Decides which command an exec runs.
+    ///
+    /// # Arguments
+    ///
+    /// - `args`: The container arguments.
+    ///
+    /// # Returns
+    ///
+    /// The configured `forced_command` if set, otherwise the command requested by the client, or
+    /// the interactive shell if the client didn't request one. Runs through the `exec_wrapper` if
+    /// one is set.
+    fn exec_command(&self, args: &ContainerArgs) -> Vec<String> {
+        let command = match &self.config.forced_command {
+            Some(forced) => {
+                info!(
+                    "Client {} runs forced command {:?} instead of {:?}",
+                    self.id, forced, args.command
+                );
+                forced.clone()
+            }
+            None if !args.command.is_empty() => args.command.clone(),
+            None => self.config.exec.cmd.clone(),
+        };
+        wrap_command(self.config.exec_wrapper.as_deref(), command)
+    }
+
+    /// Resolves the container a client asked for.
+    ///
+    /// # Arguments
+    ///
+    /// - `args`: The container arguments.
+    /// - `user`: The user the connection authenticated as. The container has to allow this user or
+    ///   one of its groups, the user the session runs as doesn't grant any access.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the container summary, its ID and the index of the Docker host it runs
+    /// on, or a `TunnydError` if no container matches or the matching container has no ID.
+    ///
+    /// # Remarks
+    ///
+    /// With `session_affinity_secs` set, a hostname target the user was served within that window
+    /// resolves to the same container again while it still matches. Otherwise, or once that
+    /// container is gone, the target is resolved as usual.
+    async fn resolve_container(
+        &self,
+        args: &ContainerArgs,
+        user: &str,
+    ) -> Result<(ContainerSummary, String, usize), TunnydError> {
+        if args.selector != TargetSelector::Hostname && !self.config.allow_direct_addressing {
+            return Err(TunnydError::DirectAddressingDisabled);
+        }
+        let groups = self.config.groups_of(user);
+        let window = Duration::from_secs(self.config.session_affinity_secs);
+        let login = self.login_user.as_deref().unwrap_or_default();
+        let sticky = args.selector == TargetSelector::Hostname && !window.is_zero();
+        if sticky {
+            if let Some(found) = self
+                .preferred_container(args, user, groups, login, window)
+                .await
+            {
+                return Ok(found);
+            }
+        }
+        let (container, host) = find_ssh_enabled_container_on_hosts(
+            &self.docker_hosts,
+            args,
+            user,
+            groups,
+            &self.config.allowed_images,
+        )
+        .await?;
+        let container_id = container
+            .id
+            .clone()
+            .ok_or_else(|| TunnydError::MissingContainerId {
+                target: args.target.clone(),
+            })?;
+        if sticky {
+            self.affinity
+                .remember(login, &args.target, &container_id, host, window);
+        }
+        Ok((container, container_id, host))
+    }
+
+    /// Looks up the container that served the user's target within the affinity window.
+    ///
+    /// # Arguments
+    ///
+    /// - `args`: The container arguments.
+    /// - `user`: The user the connection authenticated as.
+    /// - `groups`: The groups the user is a member of.
+    /// - `login`: The user name the connection authenticated with.
+    /// - `window`: How long a served container is preferred.
+    ///
+    /// # Returns
+    ///
+    /// The container, its ID and the index of its Docker host, or `None` if the target wasn't
+    /// served recently or that container no longer matches.
+    async fn preferred_container(
+        &self,
+        args: &ContainerArgs,
+        user: &str,
+        groups: &[String],
+        login: &str,
+        window: Duration,
+    ) -> Option<(ContainerSummary, String, usize)> {
+        let (container_id, host) = self.affinity.preferred(login, &args.target, window)?;
+        let docker_host = self.docker_hosts.get(host)?;
+        let found = find_preferred_container(
+            docker_host,
+            args,
+            user,
+            groups,
+            &self.config.allowed_images,
+            &container_id,
+        )
+        .await;
+        match found {
+            Ok(Some(container)) => {
+                info!(
+                    "Reusing container {} that served {} to {} before",
+                    container_id, args.target, login
+                );
+                Some((container, container_id, host))
+            }
+            Ok(None) => {
+                info!(
+                    "Container {} that served {} before is gone, resolving it again",
+                    container_id, args.target
+                );
+                None
+            }
+            Err(e) => {
+                warn!(
+                    "Failed to look up container {} on Docker host {}: {}",
+                    container_id, docker_host.name, e
+                );
+                None
+            }
+        }
+    }
+
+    /// Returns the user the connection authenticated as, see `login_identity`.
+    fn login_name(&self) -> &str {
+        login_identity(self.login_user.as_deref().unwrap_or_default())
+    }
+
+    /// Returns the user sessions run as when neither the client nor the container pick one.
+    ///
+    /// # Returns
+    ///
+    /// The user the connection authenticated as, or `None` if `default_user_from_login` is
+    /// disabled.
+    fn default_user(&self) -> Option<&str> {
+        Some(self.login_name())
+            .filter(|user| self.config.default_user_from_login && !user.is_empty())
+    }
+
+    /// Returns the client of a Docker host.
+    ///
+    /// # Arguments
+    ///
+    /// - `host`: The index of the host, as returned by `resolve_container`.
+    fn host_docker(&self, host: usize) -> &DockerClient {
+        self.docker_hosts
+            .get(host)
+            .map_or(&self.docker, |host| &host.docker)
+    }
+
+    /// Locates an `sftp-server` binary inside a Docker container.
+    ///
+    /// # Arguments
+    ///
+    /// - `docker`: A reference to the Docker client.
+    /// - `args`: The container arguments.
+    /// - `container_id`: The ID of the container.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the path of the `sftp-server` binary, or `None` if the container
+    /// doesn't provide one.
+    async fn locate_sftp_server(
+        &self,
+        docker: &DockerClient,
+        args: &ContainerArgs,
+        container_id: &str,
+    ) -> Result<Option<String>, TunnydError> {
+        let script = format!(
+            "for p in {}; do if [ -x \"$p\" ]; then echo \"$p\"; exit 0; fi; done; command -v sftp-server",
+            SFTP_SERVER_PATHS.join(" ")
+        );
+        let options = CreateExecOptions {
+            attach_stdout: Some(true),
+            attach_stderr: Some(false),
+            attach_stdin: Some(false),
+            cmd: Some(vec!["sh", "-c", script.as_str()]),
+            tty: Some(false),
+            user: args.user.as_ref().map(|s| s.as_str()),
+            ..Default::default()
+        };
+
+        let mut located = String::new();
+        if let StartExecResults::Attached { mut output, .. } = self
+            .start_exec(docker, container_id, options)
+            .await?
+            .results
+        {
+            while let Some(item) = output.next().await {
+                if let LogOutput::StdOut { message } = item? {
+                    located.push_str(&String::from_utf8_lossy(&message));
+                }
+            }
+        }
+
+        let located = located.trim();
+        if located.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(located.to_string()))
+        }
+    }
+
+    /// Resizes the TTY of an exec.
+    ///
+    /// # Arguments
+    ///
+    /// - `host`: The index of the Docker host the exec runs on.
+    /// - `exec_id`: The ID of the exec.
+    /// - `cols`: The width of the terminal in columns.
+    /// - `rows`: The height of the terminal in rows.
+    async fn resize_exec(
+        &self,
+        host: usize,
+        exec_id: &str,
+        cols: u16,
+        rows: u16,
+    ) -> Result<(), TunnydError> {
+        let options = ResizeExecOptions {
+            height: rows,
+            width: cols,
+        };
+        self.host_docker(host)
+            .get()
+            .resize_exec(exec_id, options)
+            .await
+            .map_err(TunnydError::from)
+    }
+
+    /// Moves a session channel from its setup to starting what it runs.
+    ///
+    /// # Arguments
+    ///
+    /// - `channel`: The ID of the channel the `shell`, `exec` or `subsystem` request was made on.
+    /// - `request`: The name of the request, used in the log.
+    /// - `session`: The session the channel belongs to, the request is refused on it if the channel
+    ///   already started something.
+    ///
+    /// # Returns
+    ///
+    /// `true` if the session may start.
+    async fn begin_session(
+        &self,
+        channel: ChannelId,
+        request: &str,
+        session: &mut Session,
+    ) -> bool {
+        let began = self
+            .clients
+            .lock()
+            .await
+            .get_mut(&(self.id, channel))
+            .is_some_and(|client| client.intent.begin());
+        if !began {
+            info!(
+                "Refusing {} request on channel {} of client {}, it already started a session",
+                request, channel, self.id
+            );
+            session.channel_failure(channel);
+        }
+        began
+    }
+
+    /// Records whether a session channel started running and applies the terminal size the client
+    /// asked for meanwhile.
+    ///
+    /// # Arguments
+    ///
+    /// - `channel`: The ID of the channel.
+    async fn finish_session_start(&self, channel: ChannelId) {
+        let resize = {
+            let mut clients = self.clients.lock().await;
+            let client = match clients.get_mut(&(self.id, channel)) {
+                Some(client) => client,
+                None => return,
+            };
+            let started = client.io.is_some();
+            match (client.intent.finish_start(started), client.tty, &client.pty) {
+                (true, true, Some(pty)) => client
+                    .exec_id
+                    .clone()
+                    .map(|exec_id| (exec_id, client.docker_host, pty.size())),
+                _ => None,
+            }
+        };
+        if let Some((exec_id, host, (cols, rows))) = resize {
+            if let Err(e) = self.resize_exec(host, &exec_id, cols, rows).await {
+                warn!("Failed to size the terminal of exec {}: {}", exec_id, e);
+            }
+        }
+    }
+
+    /// Closes sessions that have been idle for longer than their idle timeout.
+    ///
+    /// Runs until the process exits, checking the sessions a few times per period of the shortest
+    /// timeout. Users are sent a notice before their session is closed.
+    pub async fn reap_idle_sessions(self) {
+        let mut interval =
+            idle_check_interval(session_idle_timeout(self.config.idle_timeout_secs, None));
+        loop {
+            tokio::time::sleep(interval).await;
+            // Follows reloads, the timeouts of open sessions were fixed when they started
+            let default_timeout =
+                session_idle_timeout(snapshot(&self.settings).config.idle_timeout_secs, None);
+            let idle: Vec<(ChannelId, Handle, Duration)> = {
+                let clients = self.clients.lock().await;
+                let shortest = clients
+                    .values()
+                    .filter_map(|client| client.idle_timeout)
+                    .chain(default_timeout)
+                    .min();
+                interval = idle_check_interval(shortest);
+                clients
+                    .iter()
+                    .filter_map(|((_, channel), client)| {
+                        let timeout = client.idle_timeout?;
+                        (client.activity.idle_for() >= timeout)
+                            .then(|| (*channel, client.session_handle.clone(), timeout))
+                    })
+                    .collect()
+            };
+            for (channel, handle, timeout) in idle {
+                info!(
+                    "Closing channel {} after {:?} of inactivity",
+                    channel, timeout
+                );
+                let notice = format!(
+                    "\r\nSession closed after {} seconds of inactivity\r\n",
+                    timeout.as_secs()
+                );
+                let _ = handle
+                    .data(channel, CryptoVec::from(notice.into_bytes()))
+                    .await;
+                let _ = handle.close(channel).await;
+            }
+        }
+    }
+
+    /// Records the container a session was linked to and writes the exec event to the audit log.
+    ///
+    /// # Arguments
+    ///
+    /// * `client_id` - The ID of the client.
+    /// * `host` - The index of the Docker host the container runs on.
+    /// * `container_id` - The ID of the container the session was linked to.
+    /// * `container_name` - The name of the container, if it has one.
+    /// * `command` - The command requested by the client.
+    async fn record_exec(
+        &self,
+        client_id: (usize, ChannelId),
+        host: usize,
+        container_id: &str,
+        container_name: Option<&str>,
+        command: &str,
+    ) {
+        if let Some(client) = self.clients.lock().await.get_mut(&client_id) {
+            client.container_id = Some(container_id.to_string());
+            client.docker_host = host;
+        }
+        self.audit.record(
+            self.peer_addr,
+            self.login_user.as_deref(),
+            AuditEvent::Exec {
+                container_id,
+                container_name,
+                command,
+            },
+        );
+    }
+
+    /// Checks the working directory of a container and the command a session asks to run.
+    ///
+    /// # Arguments
+    ///
+    /// * `args` - The container arguments, providing the command.
+    /// * `container` - The resolved container.
+    ///
+    /// # Returns
+    ///
+    /// The working directory of the exec, or a `TunnydError` if the `tunnyD.workdir` label isn't
+    /// an absolute path or the command isn't permitted.
+    fn check_session_command(
+        &self,
+        args: &ContainerArgs,
+        container: &ContainerSummary,
+    ) -> Result<Option<String>, TunnydError> {
+        let working_dir = container_workdir(container);
+        if let Some(workdir) = working_dir
+            .as_deref()
+            .filter(|workdir| !Path::new(workdir).is_absolute())
+        {
+            return Err(TunnydError::InvalidWorkdir {
+                workdir: workdir.to_string(),
+            });
+        }
+        if self.config.forced_command.is_none() && !self.command_filter.is_allowed(&args.command) {
+            return Err(TunnydError::CommandNotPermitted {
+                command: match args.command.is_empty() {
+                    true => self.config.exec.cmd.join(" "),
+                    false => args.command.join(" "),
+                },
+            });
+        }
+        Ok(working_dir)
+    }
+
+    /// Asks the connect hooks whether a session may be opened.
+    ///
+    /// # Arguments
+    ///
+    /// * `args` - The container arguments of the request.
+    /// * `container` - The resolved container.
+    /// * `command` - The raw command sent by the client.
+    ///
+    /// # Returns
+    ///
+    /// `TunnydError::HookDenied` if a hook denied the session.
+    async fn check_connect_hook(
+        &self,
+        args: &ContainerArgs,
+        container: &ContainerSummary,
+        command: &str,
+    ) -> Result<(), TunnydError> {
+        let context = ConnectContext {
+            login: self.login_user.as_deref(),
+            user: args.user.as_deref(),
+            target: &args.target,
+            container_id: container.id.as_deref().unwrap_or_default(),
+            container_name: container_name(container),
+            source_ip: self.peer_addr.map(|addr| addr.ip()),
+            command,
+        };
+        run_connect_hook(&self.config, &context).await
+    }
+
+    /// Refuses a session request, sending the client the configured denied message.
+    ///
+    /// # Arguments
+    ///
+    /// * `session` - The session the channel belongs to.
+    /// * `channel` - The ID of the channel the request was made on.
+    /// * `args` - The container arguments of the request.
+    /// * `e` - Why the request was refused.
+    fn deny_session(
+        &self,
+        session: &mut Session,
+        channel: ChannelId,
+        args: &ContainerArgs,
+        e: &TunnydError,
+    ) {
+        warn!("Client {} session request failed: {}", self.id, e);
+        let message = render_message(
+            &self.config.denied_message,
+            &[
+                ("target", &args.target),
+                ("user", args.user.as_deref().unwrap_or_default()),
+                ("reason", &e.to_string()),
+            ],
+        );
+        fail_channel(session, channel, &message, EXIT_STATUS_FAILURE);
+    }
+
+    /// Builds the exec settings of a session and applies the container's labels to its client.
+    ///
+    /// # Arguments
+    ///
+    /// * `client_id` - The ID of the client the session belongs to.
+    /// * `host` - The index of the Docker host the container runs on.
+    /// * `args` - The container arguments, providing the command.
+    /// * `container` - The resolved container.
+    /// * `working_dir` - The directory the exec starts in.
+    /// * `limits` - The resource limits of the container, the command is wrapped to apply them.
+    ///
+    /// # Returns
+    ///
+    /// The `ExecSettings`, with the environment the client sent.
+    async fn session_settings(
+        &self,
+        client_id: (usize, ChannelId),
+        host: usize,
+        args: &ContainerArgs,
+        container: &ContainerSummary,
+        working_dir: Option<String>,
+        limits: ResourceLimits,
+    ) -> ExecSettings {
+        let readonly = container_readonly(container);
+        let interactive = self
+            .clients
+            .lock()
+            .await
+            .get(&client_id)
+            .is_some_and(|client| client.pty.is_some());
+        let usage = match interactive && self.config.usage_motd {
+            true => container_usage(self.host_docker(host), container, &self.usage_cache)
+                .await
+                .and_then(|usage| usage.summary()),
+            false => None,
+        };
+        let mut clients = self.clients.lock().await;
+        ExecSettings {
+            cmd: limits.wrap(self.exec_command(args)),
+            env: match clients.get_mut(&client_id) {
+                Some(client) => {
+                    // Sessions without a terminal are piped, a greeting would end up in their output
+                    let welcome = match client.pty {
+                        Some(_) if !self.config.welcome_message.trim().is_empty() => {
+                            Some(render_message(
+                                &self.config.welcome_message,
+                                &[
+                                    ("target", &args.target),
+                                    ("user", args.user.as_deref().unwrap_or_default()),
+                                ],
+                            ))
+                        }
+                        _ => None,
+                    };
+                    let welcome = welcome.map(|welcome| format!("{}\r\n", welcome));
+                    let motd = [welcome, usage, container_motd(container)]
+                        .into_iter()
+                        .flatten()
+                        .collect::<String>();
+                    client.motd = (!motd.is_empty()).then_some(motd);
+                    client.readonly = readonly;
+                    client.idle_timeout = session_idle_timeout(
+                        self.config.idle_timeout_secs,
+                        container_idle_timeout(container),
+                    );
+                    client.env.clone()
+                }
+                None => Vec::new(),
+            },
+            working_dir,
+            readonly,
+        }
+    }
+
+    /// Handles a request received on a control subsystem channel.
+    ///
+    /// # Arguments
+    ///
+    /// * `channel` - The ID of the channel the request was received on.
+    /// * `request` - The request.
+    /// * `session_handle` - The handle to the session, output of an opened exec is sent through it.
+    ///
+    /// # Returns
+    ///
+    /// The response to send right away, if any. Output of an opened exec follows asynchronously.
+    async fn handle_control_request(
+        &self,
+        channel: ChannelId,
+        request: ControlRequest,
+        session_handle: Handle,
+    ) -> Option<ControlResponse> {
+        let client_id = (self.id, channel);
+        let result = match request {
+            ControlRequest::ListTargets => {
+                let user = self.login_name();
+                targets_for_user(
+                    &self.docker_hosts,
+                    user,
+                    self.config.groups_of(user),
+                    &self.config.allowed_images,
+                )
+                .await
+                .map(|targets| Some(ControlResponse::Targets { targets }))
+            }
+            ControlRequest::Open {
+                target,
+                user,
+                command,
+                tty,
+            } => {
+                let args = ContainerArgs {
+                    user,
+                    target,
+                    selector: TargetSelector::Hostname,
+                    command,
+                    share: None,
+                    attach: false,
+                };
+                self.open_control_exec(channel, args, tty, session_handle)
+                    .await
+                    .map(|_| None)
+            }
+            ControlRequest::Input { data } => {
+                let input = match self.clients.lock().await.get_mut(&client_id) {
+                    Some(client) => {
+                        client.activity.touch();
+                        Ok(session_input(client.readonly, client.io.as_ref()))
+                    }
+                    None => Err(TunnydError::ClientNotReady),
+                };
+                match input {
+                    Ok(Some(input)) => write_input(&mut *input.lock().await, data.as_bytes())
+                        .await
+                        .map(|_| None)
+                        .map_err(|e| TunnydError::ControlRequest {
+                            message: format!("Failed to write input: {}", e),
+                        }),
+                    Ok(None) => Err(TunnydError::ControlRequest {
+                        message: String::from("No writable exec is open"),
+                    }),
+                    Err(e) => Err(e),
+                }
+            }
+            ControlRequest::Resize { cols, rows } => {
+                let exec_id = self
+                    .clients
+                    .lock()
+                    .await
+                    .get(&client_id)
+                    .and_then(|client| {
+                        client
+                            .exec_id
+                            .clone()
+                            .map(|exec_id| (exec_id, client.docker_host))
+                    });
+                match exec_id {
+                    Some((exec_id, host)) => self
+                        .resize_exec(host, &exec_id, cols, rows)
+                        .await
+                        .map(|_| None),
+                    None => Err(TunnydError::ControlRequest {
+                        message: String::from("No exec is open"),
+                    }),
+                }
+            }
+        };
+        result.unwrap_or_else(|e| {
+            warn!("Client {} control request failed: {}", self.id, e);
+            Some(ControlResponse::Error {
+                message: e.to_string(),
+            })
+        })
+    }
+
+    /// Resolves the target of a control `open` request and starts an exec in it.
+    ///
+    /// # Arguments
+    ///
+    /// * `channel` - The ID of the control channel.
+    /// * `args` - The container arguments of the request.
+    /// * `tty` - Whether the exec is allocated a TTY.
+    /// * `session_handle` - The handle to the session the output of the exec is sent through.
+    ///
+    /// # Returns
+    ///
+    /// A `TunnydError` if the target or command isn't permitted, a connect hook denied the exec or
+    /// the exec couldn't be started.
+    ///
+    /// # Remarks
+    ///
+    /// A control channel runs one exec at a time, like any other session channel. The `opened`
+    /// response is sent by the forwarding task, ahead of any output of the exec. With `require_tty`
+    /// set, the control channel itself needs a pseudo-terminal, the `tty` of the request doesn't
+    /// count since the exec is still driven by the protocol.
+    async fn open_control_exec(
+        &self,
+        channel: ChannelId,
+        mut args: ContainerArgs,
+        tty: bool,
+        session_handle: Handle,
+    ) -> Result<(), TunnydError> {
+        let client_id = (self.id, channel);
+        let already_open = self
+            .clients
+            .lock()
+            .await
+            .get(&client_id)
+            .is_some_and(|client| client.exec_id.is_some());
+        if already_open {
+            return Err(TunnydError::ControlRequest {
+                message: String::from("An exec is already open on this channel"),
+            });
+        }
+        check_tty(
+            self.config.require_tty,
+            self.clients
+                .lock()
+                .await
+                .get(&client_id)
+                .and_then(|client| client.pty.as_ref()),
+        )?;
+        let (container, container_id, host) =
+            self.resolve_container(&args, self.login_name()).await?;
+        apply_session_user(&mut args, &container, self.default_user())?;
+        let command = args.command.join(" ");
+        self.check_connect_hook(&args, &container, &command).await?;
+        let working_dir = self.check_session_command(&args, &container)?;
+        let limits = container_limits(&container)?;
+        container_network(&container)?;
+        self.record_exec(
+            client_id,
+            host,
+            &container_id,
+            container_name(&container),
+            &command,
+        )
+        .await;
+        let settings = self
+            .session_settings(client_id, host, &args, &container, working_dir, limits)
+            .await;
+        let template = ExecTemplate {
+            tty,
+            ..self.config.exec.clone()
+        };
+        let options = exec_options(&template, &args, &settings);
+        let process = self
+            .start_exec(self.host_docker(host), &container_id, options)
+            .await?;
+        let StartExecResults::Attached { input, output } = process.results else {
+            return Err(TunnydError::ControlRequest {
+                message: String::from("The exec was started detached"),
+            });
+        };
+        let output = Arc::new(Mutex::new(output));
+        let exec_id = process.id;
+        let forwarding = tokio::spawn(forward_control_output(
+            channel,
+            session_handle,
+            Arc::clone(&output),
+            self.host_docker(host).get(),
+            container_id,
+            exec_id.clone(),
+        ));
+        if let Some(client) = self.clients.lock().await.get_mut(&client_id) {
+            client.exec_id = Some(exec_id);
+            client.tty = process.tty;
+            client.io = Some(OutputInputPair {
+                input: Arc::new(Mutex::new(input)),
+                output,
+            });
+            client.forwarding = Some(AbortOnDrop::new(forwarding));
+        }
+        Ok(())
+    }
+
+    /// Starts the `sftp-server` of the container the login name resolves to and links it to the
+    /// channel.
+    ///
+    /// # Arguments
+    ///
+    /// * `channel` - The ID of the channel the subsystem was requested on.
+    /// * `session` - The session the channel belongs to.
+    ///
+    /// # Remarks
+    ///
+    /// Failures the client can act on, like an unknown target or a container without an
+    /// `sftp-server`, are reported on the channel through `fail_channel` rather than returned.
+    async fn open_sftp_session(
+        &self,
+        channel: ChannelId,
+        session: &mut Session,
+    ) -> Result<(), anyhow::Error> {
+        let args = args_from_login(self.login_user.as_deref().unwrap_or_default());
+        let client_id = (self.id, channel);
+
+        let (container, container_id, host) =
+            match self.resolve_container(&args, self.login_name()).await {
+                Ok(resolved) => resolved,
+                Err(
+                    e @ (TunnydError::ContainerNotFound { .. }
+                    | TunnydError::MissingContainerId { .. }),
+                ) => {
+                    warn!("Client {} sftp request failed: {}", self.id, e);
+                    fail_channel(session, channel, &e.to_string(), EXIT_STATUS_FAILURE);
+                    return Ok(());
+                }
+                Err(e) => return Err(e.into()),
+            };
+        if let Err(e) = self
+            .check_connect_hook(&args, &container, SFTP_SUBSYSTEM)
+            .await
+        {
+            warn!("Client {} sftp request failed: {}", self.id, e);
+            fail_channel(session, channel, &e.to_string(), EXIT_STATUS_FAILURE);
+            return Ok(());
+        }
+
+        let sftp_server = match self
+            .locate_sftp_server(self.host_docker(host), &args, container_id.as_str())
+            .await?
+        {
+            Some(path) => path,
+            None => {
+                error!("No sftp-server found in container {}", container_id);
+                fail_channel(
+                    session,
+                    channel,
+                    "The target container does not provide an sftp-server",
+                    EXIT_STATUS_FAILURE,
+                );
+                return Ok(());
+            }
+        };
+
+        self.record_exec(
+            client_id,
+            host,
+            &container_id,
+            container_name(&container),
+            &sftp_server,
+        )
+        .await;
+        let sftp_command = wrap_command(self.config.exec_wrapper.as_deref(), vec![sftp_server]);
+        let options = CreateExecOptions {
+            attach_stdout: Some(true),
+            attach_stderr: Some(true),
+            attach_stdin: Some(true),
+            cmd: Some(sftp_command.iter().map(String::as_str).collect()),
+            tty: Some(false),
+            user: args.user.as_ref().map(|s| s.as_str()),
+            ..Default::default()
+        };
+        let process = match self
+            .start_exec(self.host_docker(host), container_id.as_str(), options)
+            .await
+        {
+            Ok(process) => process,
+            Err(e @ TunnydError::ExecTimeout { .. }) => {
+                fail_channel(session, channel, &e.to_string(), EXIT_STATUS_FAILURE);
+                return Ok(());
+            }
+            Err(e) => return Err(e.into()),
+        };
+        self.handle_output(process, channel, session.handle(), client_id, None)
+            .await;
+
+        session.channel_success(channel);
+        Ok(())
+    }
+
+    /// Resolves the container of a session request, starts the exec and links it to the channel.
+    ///
+    /// # Arguments
+    ///
+    /// * `channel` - The ID of the channel the request was made on.
+    /// * `args` - The container arguments, an empty command opens the interactive shell.
+    /// * `command` - The raw command sent by the client, recorded in the audit log.
+    /// * `session` - The session the channel belongs to.
+    ///
+    /// # Remarks
+    ///
+    /// Failures the client can act on, like an unknown target or an invalid user, are reported on
+    /// the channel through `fail_channel` rather than returned.
+    async fn open_container_session(
+        &self,
+        channel: ChannelId,
+        mut args: ContainerArgs,
+        command: &str,
+        session: &mut Session,
+    ) -> Result<(), anyhow::Error> {
+        let client_id = (self.id, channel);
+
+        let tty_check = {
+            let clients = self.clients.lock().await;
+            let pty = clients
+                .get(&client_id)
+                .and_then(|client| client.pty.as_ref());
+            check_tty(self.config.require_tty, pty)
+        };
+        if let Err(e) = tty_check {
+            self.deny_session(session, channel, &args, &e);
+            return Ok(());
+        }
+        if args.share.is_some() && !self.config.allow_shared_sessions {
+            let e = TunnydError::SharedSessionsDisabled;
+            self.deny_session(session, channel, &args, &e);
+            return Ok(());
+        }
+        if args.attach && !self.config.allow_attach {
+            self.deny_session(session, channel, &args, &TunnydError::AttachDisabled);
+            return Ok(());
+        }
+        if args.attach && (args.share.is_some() || !args.command.is_empty()) {
+            self.deny_session(session, channel, &args, &TunnydError::AttachConflict);
+            return Ok(());
+        }
+
+        let (container, id, host) = match self.resolve_container(&args, self.login_name()).await {
+            Ok(resolved) => resolved,
+            Err(
+                e @ (TunnydError::ContainerNotFound { .. }
+                | TunnydError::MissingContainerId { .. }
+                | TunnydError::DirectAddressingDisabled),
+            ) => {
+                self.deny_session(session, channel, &args, &e);
+                return Ok(());
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if let Err(e) = apply_session_user(&mut args, &container, self.default_user()) {
+            self.deny_session(session, channel, &args, &e);
+            return Ok(());
+        }
+        if let Err(e) = self.check_connect_hook(&args, &container, command).await {
+            self.deny_session(session, channel, &args, &e);
+            return Ok(());
+        }
+        if args.command == [INFO_COMMAND] {
+            info!("Client {} queried its session info", self.id);
+            session.channel_success(channel);
+            session.data(
+                channel,
+                CryptoVec::from(session_info(&container, args.user.as_deref()).into_bytes()),
+            );
+            session.exit_status_request(channel, 0);
+            session.eof(channel);
+            session.close(channel);
+            return Ok(());
+        }
+        if args.attach {
+            self.record_exec(client_id, host, &id, container_name(&container), command)
+                .await;
+            self.attach_main_process(
+                channel,
+                client_id,
+                self.host_docker(host),
+                &id,
+                container_readonly(&container),
+                session,
+            )
+            .await?;
+            return Ok(());
+        }
+        let checked = self
+            .check_session_command(&args, &container)
+            .and_then(|working_dir| {
+                container_network(&container)?;
+                Ok((working_dir, container_limits(&container)?))
+            });
+        let (working_dir, limits) = match checked {
+            Ok(checked) => checked,
+            Err(e) => {
+                self.deny_session(session, channel, &args, &e);
+                return Ok(());
+            }
+        };
+        self.record_exec(client_id, host, &id, container_name(&container), command)
+            .await;
+        let settings = self
+            .session_settings(client_id, host, &args, &container, working_dir, limits)
+            .await;
+        let shared = args.share.as_ref().map(|name| SharedKey {
+            container_id: id.clone(),
+            user: args.user.clone(),
+            name: name.clone(),
+        });
+        // Joining clients get the running shell, whatever command they asked for
+        if let Some(attachment) = shared
+            .as_ref()
+            .and_then(|key| self.shared_sessions.attach(key))
+        {
+            info!(
+                "Client {} joined shared session {:?} of exec {}",
+                self.id, args.share, attachment.exec_id
+            );
+            if let Some(client) = self.clients.lock().await.get_mut(&client_id) {
+                client.exec_id = Some(attachment.exec_id.clone());
+                client.tty = attachment.tty;
+            }
+            self.link_io(channel, session.handle(), client_id, attachment)
+                .await;
+            session.request_success();
+            session.channel_success(channel);
+            return Ok(());
+        }
+        let process = match self
+            .create_and_start_exec(self.host_docker(host), &args, id.as_str(), &settings)
+            .await
+        {
+            Ok(process) => process,
+            Err(e @ (TunnydError::ExecTimeout { .. } | TunnydError::CommandNotFound { .. })) => {
+                warn!("Client {} session request failed: {}", self.id, e);
+                fail_channel(session, channel, &e.to_string(), failure_exit_status(&e));
+                return Ok(());
+            }
+            Err(e) => return Err(e.into()),
+        };
+        self.handle_output(process, channel, session.handle(), client_id, shared)
+            .await;
+
+        session.request_success();
+        session.channel_success(channel);
+        Ok(())
+    }
+
+    /// Attaches a channel to the streams of the container's main process, like `docker attach`.
+    ///
+    /// # Arguments
+    ///
+    /// * `channel` - The ID of the channel the request was made on.
+    /// * `client_id` - The ID of the client.
+    /// * `docker` - The client of the Docker host the container runs on.
+    /// * `container_id` - The ID of the container.
+    /// * `readonly` - Whether the container only allows read-only sessions.
+    /// * `session` - The session the channel belongs to.
+    ///
+    /// # Returns
+    ///
+    /// The Docker error encountered while inspecting or attaching to the container, if any.
+    ///
+    /// # Remarks
+    ///
+    /// The main process only has a TTY if the container was created with one, and only reads
+    /// stdin if the container was created with an open stdin. Otherwise the input is dropped and the
+    /// output arrives as separate stdout and stderr frames.
+    async fn attach_main_process(
+        &self,
+        channel: ChannelId,
+        client_id: (usize, ChannelId),
+        docker: &DockerClient,
+        container_id: &str,
+        readonly: bool,
+        session: &mut Session,
+    ) -> Result<(), TunnydError> {
+        let docker = docker.get();
+        let config = docker
+            .inspect_container(container_id, None::<InspectContainerOptions>)
+            .await?
+            .config;
+        let tty = config
+            .as_ref()
+            .and_then(|config| config.tty)
+            .unwrap_or(false);
+        let open_stdin = config
+            .as_ref()
+            .and_then(|config| config.open_stdin)
+            .unwrap_or(false);
+        let stdin = open_stdin && !readonly;
+        let options = AttachContainerOptions::<String> {
+            stdin: Some(stdin),
+            stdout: Some(true),
+            stderr: Some(true),
+            stream: Some(true),
+            logs: Some(false),
+            ..Default::default()
+        };
+        let results = docker.attach_container(container_id, Some(options)).await?;
+        info!(
+            "Client {} attached to the main process of container {}",
+            self.id, container_id
+        );
+        if let Some(client) = self.clients.lock().await.get_mut(&client_id) {
+            client.tty = tty;
+            client.readonly = !stdin;
+        }
+        let attachment = main_process_attachment(container_id, tty, results);
+        self.link_io(channel, session.handle(), client_id, attachment)
+            .await;
+        session.request_success();
+        session.channel_success(channel);
+        Ok(())
+    }
+
+    async fn handle_output(
+        &self,
+        process: ExecProcess,
+        channel: ChannelId,
+        session_handle: Handle,
+        client_id: (usize, ChannelId),
+        shared: Option<SharedKey>,
+    ) {
+        if let Some(client) = self.clients.lock().await.get_mut(&client_id) {
+            client.exec_id = Some(process.id.clone());
+            client.tty = process.tty;
+        }
+        if let StartExecResults::Attached { input, output } = process.results {
+            let attachment = match shared {
+                Some(key) => {
+                    self.shared_sessions
+                        .share(key, process.id, process.tty, input, output)
+                }
+                None => Attachment {
+                    exec_id: process.id,
+                    tty: process.tty,
+                    shared: false,
+                    main_process: false,
+                    input,
+                    output,
+                },
+            };
+            self.link_io(channel, session_handle, client_id, attachment)
+                .await;
+        };
+    }
+
+    /// Establishes a link between an input stream and an output stream and the client's session.
+    ///
+    /// # Arguments
+    ///
+    /// * `channel` - The ID of the channel used for communication.
+    /// * `session_handle` - The handle to the session.
+    /// * `client_id` - The ID of the client.
+    /// * `attachment` - The input and output streams of the exec. Its ID is inspected for the exit
+    ///   code once the output ends.
+    ///
+    async fn link_io(
+        &self,
+        channel: ChannelId,
+        session_handle: Handle,
+        client_id: (usize, ChannelId),
+        attachment: Attachment,
+    ) {
+        let Attachment {
+            exec_id,
+            shared,
+            main_process,
+            input,
+            output,
+            ..
+        } = attachment;
+        let clients = Arc::clone(&self.clients);
+        let mut clients_locked = clients.lock().await;
+        let client = clients_locked
+            .get_mut(&client_id)
+            .expect("Client not found");
+        let output = Arc::new(Mutex::new(output));
+        client.io = Some(OutputInputPair {
+            input: Arc::new(Mutex::new(input)),
+            output: Arc::clone(&output),
+        });
+        let recorder = match (&self.config.recording_path, client.tty, &client.pty) {
+            (Some(template), true, Some(pty)) => {
+                let path = render_recording_path(
+                    template,
+                    &format!("{}-{}", client_id.0, channel),
+                    self.login_user.as_deref().unwrap_or_default(),
+                );
+                match SessionRecorder::create(&path, pty.cols, pty.rows, &pty.term) {
+                    Ok(recorder) => {
+                        info!("Recording session to {}", path);
+                        Some(Arc::new(recorder))
+                    }
+                    Err(e) => {
+                        error!("Failed to create session recording {}: {}", path, e);
+                        None
+                    }
+                }
+            }
+            _ => None,
+        };
+        let motd = client.motd.take();
+        let container_id = client.container_id.clone();
+        let activity = client.activity.clone();
+        let traffic = client.traffic.clone();
+        let window = Arc::clone(&client.window);
+        let failures = Arc::new(WriteFailures::new(self.config.tolerated_write_failures));
+        // Raw passthrough unless enabled, terminals cope with sequences split across writes. Line
+        // feeds are only translated for clients that requested a terminal
+        let transform = OutputTransform::new(
+            self.config.buffer_partial_utf8,
+            self.config.tty_translate_newlines && client.pty.is_some(),
+        )
+        .map(|transform| Arc::new(std::sync::Mutex::new(transform)));
+        let session_handle = Arc::new(Mutex::new(session_handle.clone()));
+        let output_clone = Arc::clone(&output);
+        let cloned_handle = Arc::clone(&session_handle);
+        let docker_host = client.docker_host;
+        let docker = self.host_docker(docker_host).get();
+        let task_clients = Arc::clone(&self.clients);
+        let audit = Arc::clone(&self.audit);
+        let login_user = self.login_user.clone();
+        let scrollback_lines = self.config.scrollback_lines;
+        let exit_banner = self.config.exit_banner.clone();
+        let tty = client.tty;
+        let forwarding = tokio::spawn(async move {
+            if let Some(motd) = motd {
+                let handle = session_handle.lock().await;
+                if let Err(e) = handle
+                    .data(channel, CryptoVec::from(motd.into_bytes()))
+                    .await
+                {
+                    error!("Failed to send motd: {:?}", e);
+                }
+            }
+            let mut locked_output = output_clone.lock().await;
+            let stream: &mut Pin<
+                Box<dyn Stream<Item = Result<LogOutput, bollard::errors::Error>> + Send>,
+            > = &mut *locked_output;
+            let forward = forward_container_output_to_session(
+                channel,
+                cloned_handle,
+                recorder.clone(),
+                activity,
+                traffic,
+                transform.clone(),
+                window,
+                failures,
+            );
+            let scrollback = container_id
+                .as_deref()
+                .filter(|_| scrollback_lines > 0)
+                .map(|container_id| {
+                    let options = LogsOptions {
+                        stdout: true,
+                        stderr: true,
+                        tail: scrollback_lines.to_string(),
+                        ..Default::default()
+                    };
+                    Box::pin(docker.logs(container_id, Some(options)))
+                });
+            match pump_with_scrollback(scrollback, stream, forward).await {
+                OutputEnd::Finished => {}
+                OutputEnd::Undeliverable => {
+                    warn!(
+                        "Tearing down channel {} of client {}, its output can't be delivered",
+                        channel, client_id.0
+                    );
+                    // Dropping the client closes the stdin of the exec, the hang up stops programs
+                    // that don't read it. Other clients still use a shared exec
+                    let removed = task_clients.lock().await.remove(&client_id);
+                    if let Some(mut client) = removed {
+                        // This task still has to hang up the exec and close the channel
+                        if let Some(forwarding) = client.forwarding.take() {
+                            forwarding.detach();
+                        }
+                        record_session_closed(&audit, login_user.as_deref(), &client);
+                    }
+                    if !shared && !main_process {
+                        hang_up_exec(&docker, docker_host, &exec_id).await;
+                    }
+                    let _ = session_handle.lock().await.close(channel).await;
+                    return;
+                }
+                OutputEnd::Failed(e) => {
+                    error!(
+                        "Output of exec {} on channel {} failed: {}",
+                        exec_id, channel, e
+                    );
+                    // Nothing reads the output of the exec anymore
+                    if !shared && !main_process {
+                        hang_up_exec(&docker, docker_host, &exec_id).await;
+                    }
+                    let handle = session_handle.lock().await;
+                    let message = stream_error_message(&e);
+                    let _ = handle
+                        .extended_data(
+                            channel,
+                            EXTENDED_DATA_STDERR,
+                            CryptoVec::from(message.into_bytes()),
+                        )
+                        .await;
+                    let _ = handle
+                        .exit_status_request(channel, EXIT_STATUS_STREAM_FAILED)
+                        .await;
+                    let _ = handle.eof(channel).await;
+                    let _ = handle.close(channel).await;
+                    return;
+                }
+            }
+            let remainder = match &transform {
+                Some(transform) => match transform.lock() {
+                    Ok(mut transform) => transform.flush(),
+                    Err(poisoned) => poisoned.into_inner().flush(),
+                },
+                None => Vec::new(),
+            };
+            if !remainder.is_empty() {
+                if let Some(recorder) = &recorder {
+                    recorder.output(&remainder);
+                }
+                let _ = session_handle
+                    .lock()
+                    .await
+                    .data(channel, CryptoVec::from(remainder))
+                    .await;
+            }
+            let state = match &container_id {
+                Some(container_id) => match docker
+                    .inspect_container(container_id, None::<InspectContainerOptions>)
+                    .await
+                {
+                    Ok(inspect) => inspect.state,
+                    Err(e) => {
+                        error!("Failed to inspect container {}: {}", container_id, e);
+                        None
+                    }
+                },
+                None => None,
+            };
+            let exit_code = match main_process {
+                // The main process has no exec, its exit code is the one of the stopped container
+                true => state
+                    .as_ref()
+                    .filter(|state| state.running == Some(false))
+                    .and_then(|state| state.exit_code),
+                false => match docker.inspect_exec(&exec_id).await {
+                    Ok(inspect) => inspect.exit_code,
+                    Err(e) => {
+                        error!("Failed to inspect exec {}: {}", exec_id, e);
+                        None
+                    }
+                },
+            };
+            if let Some(client) = task_clients.lock().await.get_mut(&client_id) {
+                client.exit_code = exit_code;
+            }
+            let end = session_end(exit_code, state.as_ref());
+            let message = exit_message(&end, &exit_banner, tty);
+            let mut killed_by = None;
+            let exit_status = match end {
+                SessionEnd::ContainerStopped { .. } => {
+                    warn!(
+                        "Container {:?} of channel {} stopped during the session",
+                        container_id, channel
+                    );
+                    Some(EXIT_STATUS_CONTAINER_STOPPED)
+                }
+                SessionEnd::ProcessExited(code) => {
+                    code.map(|code| code.clamp(0, u32::MAX as i64) as u32)
+                }
+                SessionEnd::ProcessKilled(signal) => {
+                    info!(
+                        "Exec {} of channel {} was killed by {}",
+                        exec_id, channel, signal
+                    );
+                    killed_by = Some(signal);
+                    None
+                }
+            };
+            let cloned_handle_2 = Arc::clone(&session_handle);
+            let handle = cloned_handle_2.lock().await;
+            if let Some(message) = message {
+                let message = CryptoVec::from(message.into_bytes());
+                // Piped sessions only get told the container stopped, on stderr to keep stdout clean
+                let sent = match tty {
+                    true => handle.data(channel, message).await,
+                    false => {
+                        handle
+                            .extended_data(channel, EXTENDED_DATA_STDERR, message)
+                            .await
+                    }
+                };
+                if let Err(e) = sent {
+                    error!("Failed to send exit message: {:?}", e);
+                }
+            }
+            if let Some(exit_status) = exit_status {
+                let _ = handle.exit_status_request(channel, exit_status).await;
+            }
+            // Docker doesn't report whether a core was dumped
+            if let Some(signal) = killed_by {
+                let _ = handle
+                    .exit_signal_request(
+                        channel,
+                        ssh_signal(signal),
+                        false,
+                        format!("Killed by {}", signal),
+                        String::new(),
+                    )
+                    .await;
+            }
+            let _ = handle.close(channel).await;
+        });
+        client.forwarding = Some(AbortOnDrop::new(forwarding));
+    }
+}
+
+#[async_trait]
+impl server::Handler for Server {
+    type Error = anyhow::Error;
+
+    async fn channel_close(
+        self,
+        channel: ChannelId,
+        session: Session,
+    ) -> Result<(Self, Session), Self::Error> {
+        let (client, last_session) = {
+            let mut clients = self.clients.lock().await;
+            let client = clients.remove(&(self.id, channel));
+            let last_session = !clients.keys().any(|(id, _)| *id == self.id);
+            (client, last_session)
+        };
+        if client.is_some() && last_session {
+            cancel_remote_forwards(&self.remote_forwards, self.id).await;
+        }
+        if let Some(client) = client {
+            record_session_closed(&self.audit, self.login_user.as_deref(), &client);
+        }
+        Ok((self, session))
+    }
+
+    async fn window_adjusted(
+        self,
+        channel: ChannelId,
+        new_size: u32,
+        mut session: Session,
+    ) -> Result<(Self, Session), Self::Error> {
+        // Data russh held back for lack of window goes out first, like the default handler does
+        session.flush_pending(channel);
+        if let Some(client) = self.clients.lock().await.get(&(self.id, channel)) {
+            client.window.adjust(new_size);
+        }
+        Ok((self, session))
+    }
+
+    async fn channel_eof(
+        self,
+        _: ChannelId,
         session: Session,
     ) -> Result<(Self, Session), Self::Error> {
         Ok((self, session))
@@ -284,18 +3261,243 @@ impl server::Handler for Server {
         channel: Channel<Msg>,
         session: Session,
     ) -> Result<(Self, bool, Session), Self::Error> {
+        if self.draining.load(Ordering::SeqCst) {
+            info!(
+                "Refusing session for client {}: the server is shutting down",
+                self.id
+            );
+            return Ok((self, false, session));
+        }
         {
             let mut clients = self.clients.lock().await;
+            let max_sessions = self.config.max_sessions;
+            if max_sessions != 0 && clients.len() >= max_sessions {
+                warn!(
+                    "Refusing session for client {} ({:?}): maximum of {} concurrent sessions reached",
+                    self.id, self.peer_addr, max_sessions
+                );
+                return Ok((self, false, session));
+            }
+            if let Some(e) = self.session_limit(&clients, self.login_user.as_deref()) {
+                warn!("Refusing session for client {}: {}", self.id, e);
+                return Ok((self, false, session));
+            }
+            if let Some(e) = channel_limit_error(
+                clients.keys().map(|(id, _)| *id),
+                self.id,
+                self.config.max_channels_per_connection,
+            ) {
+                warn!("Refusing session for client {}: {}", self.id, e);
+                return Ok((self, false, session));
+            }
+            let limit = self.config.max_session_duration_secs;
+            let session_timer = (limit > 0).then(|| {
+                let handle = session.handle();
+                let channel = channel.id();
+                let client_id = self.id;
+                start_session_timer(Duration::from_secs(limit), move || async move {
+                    info!(
+                        "Closing channel {} of client {} after the maximum session duration of {}s",
+                        channel, client_id, limit
+                    );
+                    let notice = format!(
+                        "\r\nSession closed after reaching the maximum duration of {} seconds\r\n",
+                        limit
+                    );
+                    let _ = handle
+                        .data(channel, CryptoVec::from(notice.into_bytes()))
+                        .await;
+                    let _ = handle.close(channel).await;
+                })
+            });
             clients.insert(
                 (self.id, channel.id()),
                 Client {
                     session_handle: session.handle(),
                     io: None,
+                    peer_addr: self.peer_addr,
+                    user: self.login_user.clone(),
+                    opened_at: Instant::now(),
+                    activity: Activity::new(),
+                    traffic: Traffic::default(),
+                    idle_timeout: session_idle_timeout(self.config.idle_timeout_secs, None),
+                    container_id: None,
+                    docker_host: 0,
+                    exec_id: None,
+                    tty: false,
+                    readonly: false,
+                    pty: None,
+                    env: Vec::new(),
+                    intent: SessionIntent::default(),
+                    motd: None,
+                    exit_code: None,
+                    window: Arc::new(ChannelWindow::limited(
+                        INITIAL_WINDOW_SIZE,
+                        self.config.max_output_chunk_bytes,
+                        self.config.max_output_bytes_per_sec,
+                    )),
+                    session_timer,
+                    forwarding: None,
+                    control: None,
                 },
             );
         }
+        self.audit.record(
+            self.peer_addr,
+            self.login_user.as_deref(),
+            AuditEvent::SessionOpened,
+        );
+        Ok((self, true, session))
+    }
+    /// Opens a local port forward (`ssh -L`) into the container the login name resolves to.
+    /// `localhost` refers to the container itself, other addresses on its networks are only allowed
+    /// with `forward_to_networks` and the gateways never.
+    async fn channel_open_direct_tcpip(
+        self,
+        channel: Channel<Msg>,
+        host_to_connect: &str,
+        port_to_connect: u32,
+        originator_address: &str,
+        originator_port: u32,
+        session: Session,
+    ) -> Result<(Self, bool, Session), Self::Error> {
+        if self.draining.load(Ordering::SeqCst) {
+            info!(
+                "Refusing forward for client {}: the server is shutting down",
+                self.id
+            );
+            return Ok((self, false, session));
+        }
+        let args = args_from_login(self.login_user.as_deref().unwrap_or_default());
+        let resolved = self
+            .resolve_container(&args, self.login_name())
+            .await
+            .and_then(|(container, _, host)| forward_container(container, host, &args.target));
+        let container = match resolved {
+            Ok(container) => container,
+            Err(e) => {
+                warn!("Refusing forward for client {}: {}", self.id, e);
+                return Ok((self, false, session));
+            }
+        };
+        let destination = match forward_destination(
+            &container,
+            host_to_connect,
+            port_to_connect,
+            self.config.forward_to_networks,
+        ) {
+            Some(destination) => destination,
+            None => {
+                warn!(
+                    "Refusing forward for client {} to {}:{}, not reachable through the container",
+                    self.id, host_to_connect, port_to_connect
+                );
+                return Ok((self, false, session));
+            }
+        };
+        info!(
+            "Forwarding {}:{} to {} for client {}",
+            originator_address, originator_port, destination, self.id
+        );
+        tokio::spawn(pump_direct_tcpip(channel, destination));
+        Ok((self, true, session))
+    }
+
+    /// Starts a remote port forward (`ssh -R`). The listener is bound on the host side of the
+    /// container's network, so processes in the container reach it through their gateway address.
+    async fn tcpip_forward(
+        self,
+        address: &str,
+        port: &mut u32,
+        session: Session,
+    ) -> Result<(Self, bool, Session), Self::Error> {
+        if !self.config.allow_remote_forwarding {
+            info!("Refusing remote forward for client {}: disabled", self.id);
+            return Ok((self, false, session));
+        }
+        let args = args_from_login(self.login_user.as_deref().unwrap_or_default());
+        let resolved = self
+            .resolve_container(&args, self.login_name())
+            .await
+            .and_then(|(container, _, host)| forward_container(container, host, &args.target));
+        let bind_address = match resolved {
+            Ok(container) => remote_forward_bind_address(&container, address),
+            Err(e) => {
+                warn!("Refusing remote forward for client {}: {}", self.id, e);
+                return Ok((self, false, session));
+            }
+        };
+        let bind_address = match bind_address {
+            Some(bind_address) => bind_address,
+            None => {
+                warn!(
+                    "Refusing remote forward for client {} on {}, not reachable from the container",
+                    self.id, address
+                );
+                return Ok((self, false, session));
+            }
+        };
+        let Ok(bind_port) = u16::try_from(*port) else {
+            warn!(
+                "Refusing remote forward for client {} on port {}, not a TCP port",
+                self.id, port
+            );
+            return Ok((self, false, session));
+        };
+        let listener = match TcpListener::bind((bind_address, bind_port)).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                warn!(
+                    "Failed to bind remote forward {}:{} for client {}: {}",
+                    bind_address, port, self.id, e
+                );
+                return Ok((self, false, session));
+            }
+        };
+        if *port == 0 {
+            *port = listener.local_addr()?.port() as u32;
+        }
+        info!(
+            "Remote forward {}:{} listening on {}:{} for client {}",
+            address, port, bind_address, port, self.id
+        );
+        let task = tokio::spawn(listen_forwarded_tcpip(
+            listener,
+            session.handle(),
+            address.to_string(),
+            *port,
+        ));
+        self.remote_forwards
+            .lock()
+            .await
+            .insert((self.id, address.to_string(), *port), task);
         Ok((self, true, session))
     }
+
+    async fn cancel_tcpip_forward(
+        self,
+        address: &str,
+        port: u32,
+        session: Session,
+    ) -> Result<(Self, bool, Session), Self::Error> {
+        let task = self
+            .remote_forwards
+            .lock()
+            .await
+            .remove(&(self.id, address.to_string(), port));
+        match task {
+            Some(task) => {
+                info!(
+                    "Cancelled remote forward {}:{} of client {}",
+                    address, port, self.id
+                );
+                task.abort();
+                Ok((self, true, session))
+            }
+            None => Ok((self, false, session)),
+        }
+    }
+
     async fn channel_open_confirmation(
         self,
         _: ChannelId,
@@ -312,42 +3514,413 @@ impl server::Handler for Server {
         data: &[u8],
         mut session: Session,
     ) -> Result<(Self, Session), Self::Error> {
-        let args = parse_and_match_args(data);
+        if !self.begin_session(channel, "exec", &mut session).await {
+            return Ok((self, session));
+        }
+        match parse_and_match_args(
+            data,
+            self.config.max_command_bytes,
+            self.config.max_command_words,
+        ) {
+            Ok(args) => {
+                self.open_container_session(
+                    channel,
+                    args,
+                    &String::from_utf8_lossy(data),
+                    &mut session,
+                )
+                .await?
+            }
+            Err(e) => {
+                warn!("Client {} session request failed: {}", self.id, e);
+                fail_channel(&mut session, channel, &e.to_string(), EXIT_STATUS_FAILURE);
+            }
+        }
+        self.finish_session_start(channel).await;
+        Ok((self, session))
+    }
+
+    /// Opens an interactive shell for clients that don't send a command, e.g. `ssh -t
+    /// target@gateway`. The target, and optionally the container user, are taken from the login
+    /// name just like for the `sftp` subsystem.
+    async fn shell_request(
+        self,
+        channel: ChannelId,
+        mut session: Session,
+    ) -> Result<(Self, Session), Self::Error> {
+        if !self.begin_session(channel, "shell", &mut session).await {
+            return Ok((self, session));
+        }
+        let args = args_from_login(self.login_user.as_deref().unwrap_or_default());
+        self.open_container_session(channel, args, "", &mut session)
+            .await?;
+        self.finish_session_start(channel).await;
+        Ok((self, session))
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn pty_request(
+        self,
+        channel: ChannelId,
+        term: &str,
+        col_width: u32,
+        row_height: u32,
+        _: u32,
+        _: u32,
+        _: &[(Pty, u32)],
+        mut session: Session,
+    ) -> Result<(Self, Session), Self::Error> {
+        let accepted = match self.clients.lock().await.get_mut(&(self.id, channel)) {
+            Some(client) if client.intent.accepts_setup() => {
+                client.pty = Some(PtyRequest {
+                    term: term.to_string(),
+                    cols: col_width,
+                    rows: row_height,
+                });
+                true
+            }
+            _ => false,
+        };
+        match accepted {
+            true => session.channel_success(channel),
+            false => {
+                info!(
+                    "Refusing pty request on channel {} of client {}, its session already started",
+                    channel, self.id
+                );
+                session.channel_failure(channel);
+            }
+        }
+        Ok((self, session))
+    }
+
+    /// Resizes the terminal of a session. Sizes sent before the exec started are applied once it
+    /// did, later ones resize the exec's TTY right away.
+    async fn window_change_request(
+        self,
+        channel: ChannelId,
+        col_width: u32,
+        row_height: u32,
+        _: u32,
+        _: u32,
+        session: Session,
+    ) -> Result<(Self, Session), Self::Error> {
+        let resize = {
+            let mut clients = self.clients.lock().await;
+            let client = match clients.get_mut(&(self.id, channel)) {
+                Some(client) => client,
+                None => return Ok((self, session)),
+            };
+            if let Some(pty) = client.pty.as_mut() {
+                pty.cols = col_width;
+                pty.rows = row_height;
+            }
+            match (client.intent.window_change(), client.tty, &client.pty) {
+                (WindowChange::Apply, true, Some(pty)) => client
+                    .exec_id
+                    .clone()
+                    .map(|exec_id| (exec_id, client.docker_host, pty.size())),
+                _ => None,
+            }
+        };
+        if let Some((exec_id, host, (cols, rows))) = resize {
+            if let Err(e) = self.resize_exec(host, &exec_id, cols, rows).await {
+                warn!("Failed to resize the terminal of exec {}: {}", exec_id, e);
+            }
+        }
+        Ok((self, session))
+    }
+
+    async fn env_request(
+        self,
+        channel: ChannelId,
+        variable_name: &str,
+        variable_value: &str,
+        mut session: Session,
+    ) -> Result<(Self, Session), Self::Error> {
+        if !self.config.accepts_env(variable_name) {
+            info!(
+                "Ignoring environment variable {} not in accept_env",
+                variable_name
+            );
+            return Ok((self, session));
+        }
+        let accepted = match self.clients.lock().await.get_mut(&(self.id, channel)) {
+            Some(client) if client.intent.accepts_setup() => {
+                client
+                    .env
+                    .push(format!("{}={}", variable_name, variable_value));
+                true
+            }
+            _ => false,
+        };
+        match accepted {
+            true => session.channel_success(channel),
+            false => {
+                info!(
+                    "Refusing environment variable {} on channel {} of client {}, its session already started",
+                    variable_name, channel, self.id
+                );
+                session.channel_failure(channel);
+            }
+        }
+        Ok((self, session))
+    }
+
+    /// Forwards a signal requested by the client to the exec process.
+    ///
+    /// On TTY sessions INT and QUIT are written to the container's stdin as `^C` and `^\`, so the
+    /// terminal inside the container delivers them to the foreground process group just like a
+    /// local terminal would. Every other signal, and every signal on sessions without a TTY, is sent
+    /// to the exec process through its host PID. Signals arriving before the session is running have
+    /// no process to go to and are ignored.
+    async fn signal(
+        self,
+        channel: ChannelId,
+        signal_name: Sig,
+        session: Session,
+    ) -> Result<(Self, Session), Self::Error> {
         let client_id = (self.id, channel);
+        let (input, exec_id) = {
+            let mut clients = self.clients.lock().await;
+            let client = match clients.get_mut(&client_id) {
+                Some(c) => c,
+                None => return Ok((self, session)),
+            };
+            if !client.intent.accepts_signal() {
+                info!(
+                    "Ignoring signal {:?}, the session on channel {} isn't running",
+                    signal_name, channel
+                );
+                return Ok((self, session));
+            }
+            let input = client
+                .tty
+                .then(|| session_input(client.readonly, client.io.as_ref()))
+                .flatten();
+            let exec_id = client
+                .exec_id
+                .clone()
+                .map(|exec_id| (exec_id, client.docker_host));
+            (input, exec_id)
+        };
+        // Written without the clients lock, a stalled exec stdin mustn't block every other session
+        if let Some(input) = input {
+            if signal_terminal(&mut *input.lock().await, &signal_name).await? {
+                return Ok((self, session));
+            }
+        }
 
-        let container_id = match find_ssh_enabled_container(&args).await {
-            Ok(t) => t.id.ok_or(anyhow!("Container Id not found")),
-            Err(e) => Err(anyhow!(e)),
-        };
-        match container_id {
-            Ok(id) => {
-                let process = self
-                    .create_and_start_exec(&self.docker, &args, id.as_str())
-                    .await?;
-                let _ = self
-                    .handle_output(process, channel, session.handle(), client_id)
-                    .await;
+        match (exec_id, unix_signal(&signal_name)) {
+            (Some((exec_id, host)), Some(signal)) => {
+                if let Err(e) = signal_exec(&self.docker.get(), host, &exec_id, signal).await {
+                    warn!(
+                        "Failed to forward {:?} to exec {}: {}",
+                        signal_name, exec_id, e
+                    );
+                }
             }
-            Err(e) => return Err(e),
+            (_, None) => info!("Ignoring unsupported signal {:?}", signal_name),
+            (None, _) => info!("Ignoring signal {:?}, no exec is running", signal_name),
         }
+        Ok((self, session))
+    }
 
-        session.request_success();
-        session.channel_success(channel);
+    async fn subsystem_request(
+        self,
+        channel: ChannelId,
+        name: &str,
+        mut session: Session,
+    ) -> Result<(Self, Session), Self::Error> {
+        if name != CONTROL_SUBSYSTEM && name != SFTP_SUBSYSTEM {
+            info!("Rejecting unsupported subsystem {}", name);
+            session.channel_failure(channel);
+            return Ok((self, session));
+        }
+        if !self.begin_session(channel, "subsystem", &mut session).await {
+            return Ok((self, session));
+        }
+        if name == CONTROL_SUBSYSTEM {
+            if let Some(client) = self.clients.lock().await.get_mut(&(self.id, channel)) {
+                client.control = Some(LineBuffer::default());
+                // Control channels run their own protocol, they have nothing left to set up
+                client.intent.finish_start(true);
+            }
+            info!("Client {} opened the control subsystem", self.id);
+            session.channel_success(channel);
+            return Ok((self, session));
+        }
+        self.open_sftp_session(channel, &mut session).await?;
+        self.finish_session_start(channel).await;
         Ok((self, session))
     }
 
     async fn auth_publickey(
-        self,
-        _: &str,
-        _: &key::PublicKey,
+        mut self,
+        user: &str,
+        public_key: &key::PublicKey,
     ) -> Result<(Self, server::Auth), Self::Error> {
-        // Purposely left this way, don't change or refactor
-        Ok((self, server::Auth::Accept))
+        if self.peer_banned() {
+            return Ok((
+                self,
+                server::Auth::Reject {
+                    proceed_with_methods: None,
+                },
+            ));
+        }
+        if let Some(reject) = self.reject_unconfigured(AuthMethod::Publickey) {
+            return Ok((self, reject));
+        }
+        if !self.peer_allowed() {
+            warn!(
+                "Rejecting client {} from {:?}: address not allowed",
+                self.id, self.peer_addr
+            );
+            return Ok((
+                self,
+                server::Auth::Reject {
+                    proceed_with_methods: None,
+                },
+            ));
+        }
+        let authorized = match self.auth_policy {
+            AuthPolicy::Enforce => load_authorized_keys(&self.config, login_identity(user)).await,
+            AuthPolicy::InsecureAllowAll => Vec::new(),
+        };
+        if !self.auth_policy.accepts_key(&authorized, public_key) {
+            // Clients offer their keys one after the other, so an unknown key counts toward a
+            // ban but isn't delayed
+            info!(
+                "Rejecting key {} of client {} for {}: not in the authorized keys",
+                public_key.fingerprint(),
+                self.id,
+                user
+            );
+            if let Some(addr) = self.peer_addr {
+                self.auth_failures.record_failure(addr.ip());
+            }
+            return Ok((
+                self,
+                server::Auth::Reject {
+                    proceed_with_methods: None,
+                },
+            ));
+        }
+        if !self.check_login(user).await {
+            return Ok((
+                self,
+                server::Auth::Reject {
+                    proceed_with_methods: None,
+                },
+            ));
+        }
+        let accepted = self.accept_login(user);
+        Ok((self, accepted))
     }
 
-    async fn auth_none(self, _: &str) -> Result<(Self, Auth), Self::Error> {
-        // Purposely left this way, don't change or refactor
-        Ok((self, server::Auth::Accept))
+    /// Asks the configured prompts one round at a time and verifies the collected responses once
+    /// every prompt has been answered.
+    async fn auth_keyboard_interactive(
+        mut self,
+        user: &str,
+        _: &str,
+        response: Option<server::Response<'async_trait>>,
+    ) -> Result<(Self, Auth), Self::Error> {
+        let reject = Auth::Reject {
+            proceed_with_methods: None,
+        };
+        if self.peer_banned() {
+            return Ok((self, reject));
+        }
+        if let Some(reject) = self.reject_unconfigured(AuthMethod::KeyboardInteractive) {
+            return Ok((self, reject));
+        }
+        if !self.peer_allowed() {
+            warn!(
+                "Rejecting client {} from {:?}: address not allowed",
+                self.id, self.peer_addr
+            );
+            return Ok((self, reject));
+        }
+        match response {
+            None => self.keyboard_responses.clear(),
+            Some(response) => self
+                .keyboard_responses
+                .extend(response.map(|answer| String::from_utf8_lossy(answer).into_owned())),
+        }
+        let answered = self.keyboard_responses.len();
+        if answered < self.config.keyboard_interactive_prompts.len() {
+            let prompt = self.keyboard_prompt(answered);
+            return Ok((self, prompt));
+        }
+        let responses = std::mem::take(&mut self.keyboard_responses);
+        if !verify_keyboard_interactive(&self.config, user, &responses).await {
+            warn!(
+                "Rejecting client {} from {:?}: keyboard-interactive verification failed for {}",
+                self.id, self.peer_addr, user
+            );
+            self.penalize().await;
+            return Ok((self, reject));
+        }
+        if !self.check_login(user).await {
+            return Ok((self, reject));
+        }
+        let accepted = self.accept_login(user);
+        Ok((self, accepted))
+    }
+    async fn auth_none(mut self, user: &str) -> Result<(Self, Auth), Self::Error> {
+        if self.peer_banned() {
+            return Ok((
+                self,
+                Auth::Reject {
+                    proceed_with_methods: None,
+                },
+            ));
+        }
+        if let Some(reject) = self.reject_unconfigured(AuthMethod::None) {
+            return Ok((self, reject));
+        }
+        if !self.auth_policy.accepts_none() {
+            info!(
+                "Rejecting none authentication of client {}: only accepted with --insecure-allow-all",
+                self.id
+            );
+            let others: Vec<AuthMethod> = self
+                .config
+                .auth_methods
+                .iter()
+                .copied()
+                .filter(|method| *method != AuthMethod::None)
+                .collect();
+            return Ok((
+                self,
+                Auth::Reject {
+                    proceed_with_methods: Some(method_set(&others)),
+                },
+            ));
+        }
+        if !self.peer_allowed() {
+            warn!(
+                "Rejecting client {} from {:?}: address not allowed",
+                self.id, self.peer_addr
+            );
+            return Ok((
+                self,
+                server::Auth::Reject {
+                    proceed_with_methods: None,
+                },
+            ));
+        }
+        if !self.check_login(user).await {
+            return Ok((
+                self,
+                server::Auth::Reject {
+                    proceed_with_methods: None,
+                },
+            ));
+        }
+        let accepted = self.accept_login(user);
+        Ok((self, accepted))
     }
 
     async fn data(
@@ -363,14 +3936,33 @@ impl server::Handler for Server {
             let mut locked_clients = clients.lock().await;
             let client = match locked_clients.get_mut(&client_id) {
                 Some(c) => c,
-                None => return Err(Self::Error::msg("Client Not ready")), // Just an example, replace with the actual error type
+                None => return Err(TunnydError::ClientNotReady.into()),
             };
-            match &mut client.io {
-                None => {}
-                Some(io) => {
-                    // If io.input.write(data) is asynchronous, it should have .await to complete the operation
-                    // Also, handle potential errors returned by the write function
-                    io.input.write_all(data).await.map_or((), |_| ())
+            client.activity.touch();
+            client.traffic.add_received(data.len());
+            if let Some(control) = client.control.as_mut() {
+                let requests = control.push(data);
+                drop(locked_clients);
+                for request in requests {
+                    let response = match request {
+                        Ok(request) => {
+                            self.handle_control_request(channel, request, session.handle())
+                                .await
+                        }
+                        Err(message) => Some(ControlResponse::Error { message }),
+                    };
+                    if let Some(response) = response {
+                        session.data(channel, CryptoVec::from(response.to_line()));
+                    }
+                }
+                return Ok((self, session));
+            }
+            // The write waits for the exec to take the input, other channels mustn't wait with it
+            let input = session_input(client.readonly, client.io.as_ref());
+            drop(locked_clients);
+            if let Some(input) = input {
+                if let Err(e) = write_input(&mut *input.lock().await, data).await {
+                    warn!("Failed to write input of client {:?}: {}", client_id, e);
                 }
             }
         } // end of self borrow
@@ -379,3 +3971,1371 @@ impl server::Handler for Server {
         Ok((self, session))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::task::{Context, Poll};
+
+    use bollard::models::{ContainerSummaryNetworkSettings, EndpointSettings};
+    use russh::MethodSet;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpStream;
+
+    use super::*;
+    use crate::testing::{
+        connect, docker_server, exec_routes, loopback_container, mock_host, open_server,
+        read_session, ssh_config, test_server, wait_until, MockDocker, Reply,
+    };
+
+    /// Accepts at most two bytes per write and counts the flushes.
+    #[derive(Default)]
+    struct SlowWriter {
+        written: Vec<u8>,
+        flushes: usize,
+    }
+
+    impl AsyncWrite for SlowWriter {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<Result<usize, std::io::Error>> {
+            let len = buf.len().min(2);
+            self.written.extend_from_slice(&buf[..len]);
+            Poll::Ready(Ok(len))
+        }
+
+        fn poll_flush(
+            mut self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+        ) -> Poll<Result<(), std::io::Error>> {
+            self.flushes += 1;
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+        ) -> Poll<Result<(), std::io::Error>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    fn server_error(message: &str) -> Error {
+        Error::DockerResponseServerError {
+            status_code: 500,
+            message: message.to_string(),
+        }
+    }
+
+    #[test]
+    fn missing_commands_exit_with_127() {
+        let e = exec_start_error(
+            server_error(
+                "OCI runtime exec failed: exec failed: unable to start container process: exec: \"htop\": executable file not found in $PATH: unknown",
+            ),
+            &["htop", "-d", "10"],
+        );
+        assert_eq!(e.to_string(), "htop: command not found");
+        assert_eq!(failure_exit_status(&e), 127);
+        let e = exec_start_error(
+            server_error("exec: \"/opt/tool\": stat /opt/tool: no such file or directory"),
+            &["/opt/tool"],
+        );
+        assert_eq!(failure_exit_status(&e), 127);
+    }
+
+    #[test]
+    fn other_exec_errors_exit_with_1() {
+        let e = exec_start_error(server_error("container is not running"), &["ls"]);
+        assert!(matches!(e, TunnydError::Docker(_)));
+        assert_eq!(failure_exit_status(&e), EXIT_STATUS_FAILURE);
+    }
+
+    fn pty() -> PtyRequest {
+        PtyRequest {
+            term: String::from("xterm-256color"),
+            cols: 80,
+            rows: 24,
+        }
+    }
+
+    #[test]
+    fn clamps_terminal_sizes_for_docker() {
+        assert_eq!(pty().size(), (80, 24));
+        let huge = PtyRequest {
+            cols: 100_000,
+            ..pty()
+        };
+        assert_eq!(huge.size(), (u16::MAX, 24));
+    }
+
+    #[test]
+    fn accepts_sessions_with_a_tty() {
+        assert!(check_tty(true, Some(&pty())).is_ok());
+        assert!(check_tty(false, Some(&pty())).is_ok());
+        assert!(check_tty(false, None).is_ok());
+    }
+
+    #[test]
+    fn rejects_sessions_without_a_tty() {
+        assert!(matches!(
+            check_tty(true, None),
+            Err(TunnydError::TtyRequired)
+        ));
+    }
+
+    #[tokio::test]
+    async fn closes_sessions_at_the_cap() {
+        let (closed, mut expired) = tokio::sync::oneshot::channel();
+        let _timer = start_session_timer(Duration::from_millis(10), move || async move {
+            let _ = closed.send(());
+        });
+        tokio::time::timeout(Duration::from_secs(5), &mut expired)
+            .await
+            .expect("the session timer didn't fire")
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn cancels_the_timer_when_the_session_ends() {
+        let (closed, mut expired) = tokio::sync::oneshot::channel::<()>();
+        let timer = start_session_timer(Duration::from_millis(10), move || async move {
+            let _ = closed.send(());
+        });
+        drop(timer);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        // The sender was dropped with the aborted task without sending
+        assert!(matches!(
+            expired.try_recv(),
+            Err(tokio::sync::oneshot::error::TryRecvError::Closed)
+        ));
+    }
+
+    #[tokio::test]
+    async fn closing_the_channel_aborts_its_forwarding_task() {
+        // The receiver stands in for the exec's output stream held by the task
+        let spawn = || {
+            let (mut sender, output) = tokio::sync::oneshot::channel::<()>();
+            let task = AbortOnDrop::new(tokio::spawn(async move {
+                let _output = output;
+                std::future::pending::<()>().await;
+            }));
+            (task, async move { sender.closed().await })
+        };
+
+        let (forwarding, closed) = spawn();
+        drop(forwarding);
+        tokio::time::timeout(Duration::from_secs(5), closed)
+            .await
+            .expect("the forwarding task is still running");
+
+        // A task that detached itself keeps running
+        let (forwarding, closed) = spawn();
+        forwarding.detach();
+        assert!(tokio::time::timeout(Duration::from_millis(50), closed)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn flushes_every_input_chunk() {
+        let mut writer = SlowWriter::default();
+        write_input(&mut writer, b"hello").await.unwrap();
+        write_input(&mut writer, b":wq").await.unwrap();
+        assert_eq!(writer.written, b"hello:wq");
+        assert_eq!(writer.flushes, 2);
+    }
+
+    #[tokio::test]
+    async fn writes_interrupts_to_the_terminal() {
+        let mut writer = SlowWriter::default();
+        assert!(signal_terminal(&mut writer, &Sig::INT).await.unwrap());
+        assert_eq!(writer.written, b"\x03");
+        assert_eq!(writer.flushes, 1);
+        assert!(signal_terminal(&mut writer, &Sig::QUIT).await.unwrap());
+        // TERM has no control character, it goes to the process
+        assert!(!signal_terminal(&mut writer, &Sig::TERM).await.unwrap());
+        assert_eq!(writer.written, b"\x03\x1c");
+    }
+
+    #[tokio::test]
+    async fn never_signals_execs_of_other_hosts() {
+        // Nothing listens there, the guard has to answer before Docker is asked for the PID
+        let docker =
+            Docker::connect_with_http("tcp://127.0.0.1:1", 1, &bollard::API_DEFAULT_VERSION)
+                .unwrap();
+        assert!(matches!(
+            signal_exec(&docker, 1, "abc", Signal::SIGHUP).await,
+            Err(TunnydError::RemoteSignal { .. })
+        ));
+    }
+
+    #[test]
+    fn signals_only_processes_of_the_container() {
+        let id = "8c3f6b1e2d4a";
+        assert!(cgroup_names_container(
+            "0::/system.slice/docker-8c3f6b1e2d4a.scope\n",
+            id
+        ));
+        assert!(cgroup_names_container(
+            "12:pids:/docker/8c3f6b1e2d4a\n1:name=systemd:/docker/8c3f6b1e2d4a\n",
+            id
+        ));
+        // Another process with the same number, or a cgroup namespace hiding the path
+        assert!(!cgroup_names_container(
+            "0::/user.slice/user-1000.slice/session-2.scope\n",
+            id
+        ));
+        assert!(!cgroup_names_container("0::/\n", id));
+        assert!(!cgroup_names_container("0::/\n", ""));
+    }
+
+    #[tokio::test]
+    async fn counts_the_traffic_of_a_session() {
+        let traffic = Traffic::default();
+        // The input handler and the output task share the counters through clones
+        let input = traffic.clone();
+        input.add_received(1500);
+        input.add_received(12);
+
+        let window = ChannelWindow::new(INITIAL_WINDOW_SIZE);
+        let payload = vec![b'x'; 100_000];
+        let mut delivered = 0;
+        send_counted(&window, &traffic, &payload, |chunk| {
+            delivered += chunk.len();
+            async { Ok::<(), ()>(()) }
+        })
+        .await
+        .unwrap();
+        assert_eq!(delivered, 100_000);
+        assert_eq!(traffic.sent(), 100_000);
+        assert_eq!(traffic.received(), 1512);
+
+        // Output the client never got isn't counted
+        let failed = Traffic::default();
+        let result = send_counted(&window, &failed, &payload, |_| async { Err("closed") }).await;
+        assert_eq!(result, Err("closed"));
+        assert_eq!(failed.sent(), 0);
+    }
+
+    #[test]
+    fn builds_exec_options_from_template() {
+        let template = ExecTemplate {
+            tty: false,
+            attach_stderr: false,
+            ..Default::default()
+        };
+        let args = ContainerArgs {
+            user: Some(String::from("app")),
+            target: String::from("app"),
+            selector: TargetSelector::Hostname,
+            command: Vec::new(),
+            share: None,
+            attach: false,
+        };
+        let settings = ExecSettings {
+            cmd: vec![String::from("ls")],
+            env: vec![String::from("LANG=C")],
+            working_dir: Some(String::from("/srv")),
+            readonly: false,
+        };
+        let options = exec_options(&template, &args, &settings);
+        assert_eq!(options.tty, Some(false));
+        assert_eq!(options.attach_stderr, Some(false));
+        assert_eq!(options.cmd, Some(vec!["ls"]));
+        assert_eq!(options.env, Some(vec!["LANG=C"]));
+        assert_eq!(options.user, Some("app"));
+        assert_eq!(options.working_dir, Some("/srv"));
+        assert_eq!(options.attach_stdin, Some(true));
+        let readonly = ExecSettings {
+            readonly: true,
+            ..settings
+        };
+        let options = exec_options(&template, &args, &readonly);
+        assert_eq!(options.attach_stdin, Some(false));
+    }
+
+    #[test]
+    fn wraps_exec_command_in_container_limits() {
+        let container = ContainerSummary {
+            labels: Some(HashMap::from([
+                (String::from("tunnyD.limits.cpu"), String::from("30")),
+                (String::from("tunnyD.limits.memory"), String::from("1m")),
+            ])),
+            ..Default::default()
+        };
+        let args = ContainerArgs {
+            user: None,
+            target: String::from("app"),
+            selector: TargetSelector::Hostname,
+            command: Vec::new(),
+            share: None,
+            attach: false,
+        };
+        let limits = container_limits(&container).unwrap();
+        let settings = ExecSettings {
+            cmd: limits.wrap(vec![String::from("bash")]),
+            ..Default::default()
+        };
+        let options = exec_options(&ExecTemplate::default(), &args, &settings);
+        assert_eq!(
+            options.cmd,
+            Some(vec![
+                "sh",
+                "-c",
+                "ulimit -t 30 && ulimit -v 1024 && exec \"$@\"",
+                "tunnyd-limits",
+                "bash",
+            ])
+        );
+    }
+
+    #[test]
+    fn runs_execs_through_the_wrapper() {
+        let wrapper = vec![
+            String::from("env"),
+            String::from("-i"),
+            String::from("HOME=/home/app user"),
+        ];
+        let args = ContainerArgs {
+            user: None,
+            target: String::from("app"),
+            selector: TargetSelector::Hostname,
+            command: Vec::new(),
+            share: None,
+            attach: false,
+        };
+        let settings = ExecSettings {
+            cmd: wrap_command(
+                Some(&wrapper),
+                vec![String::from("bash"), String::from("-l")],
+            ),
+            ..Default::default()
+        };
+        let options = exec_options(&ExecTemplate::default(), &args, &settings);
+        assert_eq!(
+            options.cmd,
+            Some(vec!["env", "-i", "HOME=/home/app user", "bash", "-l"])
+        );
+        assert_eq!(wrap_command(None, vec![String::from("bash")]), vec!["bash"]);
+    }
+
+    #[test]
+    fn defaults_the_session_user_to_the_login_user() {
+        let args = ContainerArgs {
+            user: None,
+            target: String::from("web"),
+            selector: TargetSelector::Hostname,
+            command: Vec::new(),
+            share: None,
+            attach: false,
+        };
+        let container = ContainerSummary::default();
+        let mut defaulted = args.clone();
+        apply_session_user(&mut defaulted, &container, Some("alice")).unwrap();
+        assert_eq!(defaulted.user.as_deref(), Some("alice"));
+        let mut image_default = args.clone();
+        apply_session_user(&mut image_default, &container, None).unwrap();
+        assert_eq!(image_default.user, None);
+        let mut invalid = args.clone();
+        assert!(matches!(
+            apply_session_user(&mut invalid, &container, Some("alice:root")),
+            Err(TunnydError::InvalidUser { user }) if user == "alice:root"
+        ));
+        let labelled = ContainerSummary {
+            labels: Some(HashMap::from([(
+                String::from("tunnyD.default.user"),
+                String::from("app"),
+            )])),
+            ..Default::default()
+        };
+        let mut from_label = args.clone();
+        apply_session_user(&mut from_label, &labelled, Some("alice")).unwrap();
+        assert_eq!(from_label.user.as_deref(), Some("app"));
+    }
+
+    #[test]
+    fn keeps_an_explicit_session_user() {
+        let mut args = ContainerArgs {
+            user: Some(String::from("root")),
+            target: String::from("web"),
+            selector: TargetSelector::Hostname,
+            command: Vec::new(),
+            share: None,
+            attach: false,
+        };
+        apply_session_user(&mut args, &ContainerSummary::default(), Some("alice")).unwrap();
+        assert_eq!(args.user.as_deref(), Some("root"));
+        args.user = Some(String::from("root;id"));
+        assert!(matches!(
+            apply_session_user(&mut args, &ContainerSummary::default(), Some("alice")),
+            Err(TunnydError::InvalidUser { .. })
+        ));
+    }
+
+    fn output(text: &str) -> Result<LogOutput, Error> {
+        Ok(LogOutput::StdOut {
+            message: text.as_bytes().to_vec().into(),
+        })
+    }
+
+    #[tokio::test]
+    async fn stops_at_a_stream_error() {
+        let broken = std::io::Error::from(std::io::ErrorKind::ConnectionReset);
+        let mut stream = futures::stream::iter(vec![
+            output("before"),
+            Err(Error::IOError { err: broken }),
+            output("after"),
+        ]);
+        let forwarded = std::sync::Mutex::new(Vec::new());
+        let end = pump_output(&mut stream, |data| {
+            forwarded.lock().unwrap().push(data.into_bytes().to_vec());
+            async { true }
+        })
+        .await;
+        assert!(matches!(end, OutputEnd::Failed(Error::IOError { .. })));
+        assert_eq!(forwarded.into_inner().unwrap(), vec![b"before".to_vec()]);
+    }
+
+    #[tokio::test]
+    async fn sends_the_scrollback_before_the_live_output() {
+        let forwarded = std::sync::Mutex::new(Vec::new());
+        let forward = |data: LogOutput| {
+            forwarded
+                .lock()
+                .unwrap()
+                .extend_from_slice(&data.into_bytes());
+            async { true }
+        };
+        let scrollback = futures::stream::iter(vec![output("panic: "), output("oops\n")]);
+        let mut live = futures::stream::iter(vec![output("$ ")]);
+        let end = pump_with_scrollback(Some(scrollback), &mut live, &forward).await;
+        assert!(matches!(end, OutputEnd::Finished));
+        assert_eq!(forwarded.lock().unwrap().as_slice(), b"panic: oops\n$ ");
+
+        // Logs that can't be read don't keep the live output from being forwarded
+        forwarded.lock().unwrap().clear();
+        let scrollback = futures::stream::iter(vec![Err(server_error(
+            "logging driver does not support reading",
+        ))]);
+        let mut live = futures::stream::iter(vec![output("$ ")]);
+        pump_with_scrollback(Some(scrollback), &mut live, &forward).await;
+        assert_eq!(forwarded.lock().unwrap().as_slice(), b"$ ");
+    }
+
+    #[tokio::test]
+    async fn bridges_the_output_of_the_main_process() {
+        let results = AttachContainerResults {
+            output: Box::pin(futures::stream::iter(vec![
+                output("booting\n"),
+                Ok(LogOutput::StdErr {
+                    message: b"warning\n".to_vec().into(),
+                }),
+            ])),
+            input: Box::pin(tokio::io::sink()),
+        };
+        let mut attachment = main_process_attachment("abc", false, results);
+        assert!(attachment.main_process);
+        assert!(!attachment.shared);
+        assert_eq!(attachment.exec_id, "abc");
+        let forwarded = std::sync::Mutex::new(Vec::new());
+        let end = pump_output(&mut attachment.output, |data| {
+            forwarded
+                .lock()
+                .unwrap()
+                .extend_from_slice(&data.into_bytes());
+            async { true }
+        })
+        .await;
+        assert!(matches!(end, OutputEnd::Finished));
+        assert_eq!(forwarded.into_inner().unwrap(), b"booting\nwarning\n");
+        write_input(&mut attachment.input, b"q").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn reports_how_the_output_ended() {
+        let mut stream = futures::stream::iter(vec![output("a"), output("b")]);
+        let end = pump_output(&mut stream, |_| async { true }).await;
+        assert!(matches!(end, OutputEnd::Finished));
+        let mut stream = futures::stream::iter(vec![output("a"), output("b")]);
+        let end = pump_output(&mut stream, |_| async { false }).await;
+        assert!(matches!(end, OutputEnd::Undeliverable));
+    }
+
+    #[test]
+    fn tells_transient_from_fatal_stream_errors() {
+        let lost = Error::IOError {
+            err: std::io::Error::from(std::io::ErrorKind::ConnectionReset),
+        };
+        assert!(stream_error_message(&lost).contains("reconnect"));
+        let failed = Error::DockerResponseServerError {
+            status_code: 500,
+            message: String::from("exec died"),
+        };
+        let message = stream_error_message(&failed);
+        assert!(message.starts_with("\r\nDocker failed the session"));
+        assert!(message.contains("exec died"));
+    }
+
+    #[test]
+    fn ignores_input_of_readonly_sessions() {
+        let io = OutputInputPair {
+            output: Arc::new(Mutex::new(Box::pin(futures::stream::empty()))),
+            input: Arc::new(Mutex::new(Box::pin(SlowWriter::default()))),
+        };
+        assert!(session_input(true, Some(&io)).is_none());
+        assert!(session_input(false, Some(&io)).is_some());
+        assert!(session_input(false, None).is_none());
+    }
+
+    #[test]
+    fn reports_stopped_containers() {
+        let stopped = ContainerState {
+            running: Some(false),
+            oom_killed: Some(true),
+            ..Default::default()
+        };
+        assert_eq!(
+            session_end(Some(137), Some(&stopped)),
+            SessionEnd::ContainerStopped { oom_killed: true }
+        );
+        let running = ContainerState {
+            running: Some(true),
+            ..Default::default()
+        };
+        assert_eq!(
+            session_end(Some(0), Some(&running)),
+            SessionEnd::ProcessExited(Some(0))
+        );
+        assert_eq!(session_end(None, None), SessionEnd::ProcessExited(None));
+    }
+
+    #[test]
+    fn container_idle_timeout_takes_precedence() {
+        assert_eq!(
+            session_idle_timeout(300, Some(3600)),
+            Some(Duration::from_secs(3600))
+        );
+        assert_eq!(
+            session_idle_timeout(0, Some(60)),
+            Some(Duration::from_secs(60))
+        );
+        assert_eq!(session_idle_timeout(300, Some(0)), None);
+        assert_eq!(
+            session_idle_timeout(300, None),
+            Some(Duration::from_secs(300))
+        );
+        assert_eq!(session_idle_timeout(0, None), None);
+        assert_eq!(
+            idle_check_interval(Some(Duration::from_secs(20))),
+            Duration::from_secs(5)
+        );
+    }
+
+    #[test]
+    fn describes_the_resolved_session() {
+        let container = ContainerSummary {
+            id: Some(String::from("0123456789ab")),
+            names: Some(vec![String::from("/web")]),
+            image: Some(String::from("nginx:1.25")),
+            ..Default::default()
+        };
+        let info: serde_json::Value =
+            serde_json::from_str(&session_info(&container, Some("www-data"))).unwrap();
+        assert_eq!(
+            info,
+            serde_json::json!({
+                "id": "0123456789ab",
+                "name": "web",
+                "image": "nginx:1.25",
+                "network": null,
+                "networks": [],
+                "user": "www-data",
+            })
+        );
+        let info: serde_json::Value =
+            serde_json::from_str(&session_info(&container, None)).unwrap();
+        assert!(info["user"].is_null());
+    }
+
+    #[test]
+    fn reports_the_networks_of_the_session() {
+        let endpoint = |ip: &str, gateway: &str| EndpointSettings {
+            ip_address: Some(ip.to_string()),
+            ip_prefix_len: Some(24),
+            gateway: Some(gateway.to_string()),
+            ..Default::default()
+        };
+        let container = ContainerSummary {
+            labels: Some(HashMap::from([(
+                String::from("tunnyD.network"),
+                String::from("backend"),
+            )])),
+            network_settings: Some(ContainerSummaryNetworkSettings {
+                networks: Some(HashMap::from([
+                    (String::from("frontend"), endpoint("10.1.0.5", "10.1.0.1")),
+                    (String::from("backend"), endpoint("10.2.0.5", "10.2.0.1")),
+                ])),
+            }),
+            ..Default::default()
+        };
+        let info: serde_json::Value =
+            serde_json::from_str(&session_info(&container, None)).unwrap();
+        assert_eq!(info["network"], "backend");
+        assert_eq!(
+            info["networks"],
+            serde_json::json!([
+                {"name": "backend", "address": "10.2.0.5/24", "gateway": "10.2.0.1"},
+                {"name": "frontend", "address": "10.1.0.5/24", "gateway": "10.1.0.1"},
+            ])
+        );
+
+        let unattached = ContainerSummary {
+            labels: Some(HashMap::from([(
+                String::from("tunnyD.network"),
+                String::from("storage"),
+            )])),
+            ..container
+        };
+        assert!(matches!(
+            container_network(&unattached),
+            Err(TunnydError::UnknownNetwork { network }) if network == "storage"
+        ));
+    }
+
+    #[test]
+    fn limits_sessions_per_user_and_address() {
+        let alice_ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let bob_ip: IpAddr = "10.0.0.2".parse().unwrap();
+        let open = [
+            (Some("alice"), Some(alice_ip)),
+            (Some("alice"), Some(alice_ip)),
+            (Some("bob"), Some(bob_ip)),
+        ];
+        let limit = |user, ip, per_user, per_ip| {
+            session_limit_error(open.iter().copied(), Some(user), Some(ip), per_user, per_ip)
+        };
+        assert!(matches!(
+            limit("alice", alice_ip, 2, 0),
+            Some(TunnydError::UserSessionLimit { limit: 2, .. })
+        ));
+        // Bob is unaffected by Alice reaching her limit
+        assert!(limit("bob", bob_ip, 2, 0).is_none());
+        assert!(limit("alice", alice_ip, 3, 0).is_none());
+        assert!(limit("alice", alice_ip, 0, 0).is_none());
+        // The address limit applies across users
+        let e = limit("carol", alice_ip, 0, 2).unwrap();
+        assert_eq!(
+            e.to_string(),
+            "Address 10.0.0.1 already has the maximum of 2 concurrent sessions open"
+        );
+        assert!(limit("carol", bob_ip, 0, 2).is_none());
+    }
+
+    #[test]
+    fn limits_channels_per_connection() {
+        let open = [1, 1, 2];
+        let limit = |connection, limit| channel_limit_error(open.into_iter(), connection, limit);
+        let e = limit(1, 2).unwrap();
+        assert_eq!(
+            e.to_string(),
+            "The connection already has the maximum of 2 channels open"
+        );
+        // Connection 2 is unaffected by connection 1 reaching its limit
+        assert!(limit(2, 2).is_none());
+        assert!(limit(3, 1).is_none());
+        assert!(limit(1, 3).is_none());
+        assert!(limit(1, 0).is_none());
+    }
+
+    #[test]
+    fn shows_the_exit_banner_on_tty_sessions_only() {
+        let exited = SessionEnd::ProcessExited(Some(0));
+        assert_eq!(
+            exit_message(&exited, "Bye\n", true).as_deref(),
+            Some("Bye\r\n")
+        );
+        assert_eq!(
+            exit_message(&exited, "Session over\nSee you", true).as_deref(),
+            Some("Session over\r\nSee you\r\n")
+        );
+        assert_eq!(exit_message(&exited, "Bye", false), None);
+        assert_eq!(exit_message(&exited, "", true), None);
+        let killed = SessionEnd::ProcessKilled(Signal::SIGKILL);
+        assert_eq!(exit_message(&killed, "Bye", false), None);
+        // Piped sessions are still told the container went away
+        let stopped = SessionEnd::ContainerStopped { oom_killed: false };
+        assert_eq!(
+            exit_message(&stopped, "Bye", false).as_deref(),
+            Some("Target container stopped\r\n")
+        );
+    }
+
+    #[test]
+    fn renders_denied_messages_for_each_reason() {
+        let template = "Access to {target} as {user} denied: {reason}\nContact ops@example.com";
+        let reasons = [
+            TunnydError::ContainerNotFound {
+                target: String::from("app"),
+            },
+            TunnydError::DirectAddressingDisabled,
+            TunnydError::InvalidUser {
+                user: String::from("root;"),
+            },
+            TunnydError::CommandNotPermitted {
+                command: String::from("rm -rf /"),
+            },
+            TunnydError::TtyRequired,
+        ];
+        for reason in reasons {
+            let rendered = render_message(
+                template,
+                &[
+                    ("target", "app"),
+                    ("user", "root"),
+                    ("reason", &reason.to_string()),
+                ],
+            );
+            assert_eq!(
+                rendered,
+                format!(
+                    "Access to app as root denied: {}\r\nContact ops@example.com",
+                    reason
+                )
+            );
+        }
+    }
+
+    #[test]
+    fn renders_placeholders_in_a_single_pass() {
+        assert_eq!(
+            render_message(
+                "{reason} ({target}) {unknown} {",
+                &[("target", "{reason}"), ("reason", "no")]
+            ),
+            "no ({reason}) {unknown} {"
+        );
+        assert_eq!(
+            render_message("{reason}\n", &[("reason", "denied")]),
+            "denied"
+        );
+    }
+
+    #[test]
+    fn reports_processes_killed_by_a_signal() {
+        let running = ContainerState {
+            running: Some(true),
+            ..Default::default()
+        };
+        assert_eq!(
+            session_end(Some(137), Some(&running)),
+            SessionEnd::ProcessKilled(Signal::SIGKILL)
+        );
+        assert_eq!(
+            session_end(Some(139), None),
+            SessionEnd::ProcessKilled(Signal::SIGSEGV)
+        );
+        assert_eq!(
+            session_end(Some(128), None),
+            SessionEnd::ProcessExited(Some(128))
+        );
+        assert_eq!(
+            session_end(Some(1), None),
+            SessionEnd::ProcessExited(Some(1))
+        );
+        assert!(matches!(ssh_signal(Signal::SIGKILL), Sig::KILL));
+        assert!(matches!(ssh_signal(Signal::SIGUSR2), Sig::Custom(name) if name == "USR2"));
+    }
+
+    #[tokio::test]
+    async fn counts_rejected_keys_toward_a_ban() {
+        use russh::server::{Handler, Server as _};
+
+        let config = ServerConfig {
+            auth_failure_threshold: 2,
+            ..Default::default()
+        };
+        let peer: SocketAddr = "192.0.2.7:50000".parse().unwrap();
+        let key = russh_keys::key::KeyPair::generate_ed25519()
+            .unwrap()
+            .clone_public_key()
+            .unwrap();
+        let client = test_server(config).new_client(Some(peer));
+        let (client, auth) = client.auth_publickey("alice", &key).await.unwrap();
+        assert!(matches!(auth, Auth::Reject { .. }));
+        assert!(!client.auth_failures.is_banned(peer.ip()));
+        let (client, _) = client.auth_publickey("alice", &key).await.unwrap();
+        assert!(client.auth_failures.is_banned(peer.ip()));
+    }
+
+    #[tokio::test]
+    async fn rejects_banned_addresses_before_prompting() {
+        use russh::server::{Handler, Server as _};
+
+        let mut config = ServerConfig {
+            auth_methods: vec![AuthMethod::KeyboardInteractive],
+            auth_failure_threshold: 1,
+            ..Default::default()
+        };
+        config
+            .keyboard_interactive_tokens
+            .insert(String::from("alice"), String::from("s3cret"));
+        let peer: SocketAddr = "192.0.2.7:50000".parse().unwrap();
+        let mut template = test_server(config);
+        let client = template.new_client(Some(peer));
+        let (client, auth) = client
+            .auth_keyboard_interactive("alice", "", None)
+            .await
+            .unwrap();
+        assert!(matches!(auth, Auth::Partial { .. }));
+
+        client.auth_failures.record_failure(peer.ip());
+        let client = template.new_client(Some(peer));
+        let (_, auth) = client
+            .auth_keyboard_interactive("alice", "", None)
+            .await
+            .unwrap();
+        assert!(matches!(auth, Auth::Reject { .. }));
+    }
+
+    #[tokio::test]
+    async fn forwards_local_ports_into_the_container() {
+        let mut server = open_server(ServerConfig::default(), vec![loopback_container("app")]);
+        let destination = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = destination.local_addr().unwrap().port();
+        let mut connection = connect(&mut server, None, ssh_config(MethodSet::NONE)).await;
+        assert!(connection.handle.authenticate_none("app").await.unwrap());
+        let channel = connection
+            .handle
+            .channel_open_direct_tcpip("localhost", port as u32, "127.0.0.1", 40000)
+            .await
+            .unwrap();
+        let (mut socket, _) = destination.accept().await.unwrap();
+        let mut stream = channel.into_stream();
+        let mut received = [0; 4];
+        stream.write_all(b"ping").await.unwrap();
+        socket.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"ping");
+        socket.write_all(b"pong").await.unwrap();
+        stream.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"pong");
+    }
+
+    #[tokio::test]
+    async fn stops_remote_forwards_when_the_connection_drops() {
+        let config = ServerConfig {
+            allow_remote_forwarding: true,
+            ..Default::default()
+        };
+        let mut server = open_server(config, vec![loopback_container("app")]);
+        let port = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let mut connection = connect(&mut server, None, ssh_config(MethodSet::NONE)).await;
+        assert!(connection.handle.authenticate_none("app").await.unwrap());
+        assert!(connection
+            .handle
+            .tcpip_forward("localhost", port as u32)
+            .await
+            .unwrap());
+
+        let mut socket = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        let mut stream = connection.forwarded.recv().await.unwrap().into_stream();
+        let mut received = [0; 4];
+        socket.write_all(b"ping").await.unwrap();
+        stream.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"ping");
+        stream.write_all(b"pong").await.unwrap();
+        socket.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"pong");
+
+        // No session channel was ever opened, so only the connection ending cancels the forward
+        connection
+            .handle
+            .disconnect(russh::Disconnect::ByApplication, "", "en")
+            .await
+            .unwrap();
+        let cancelled = tokio::time::timeout(Duration::from_secs(5), async {
+            while !server.remote_forwards.lock().await.is_empty() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        });
+        assert!(cancelled.await.is_ok());
+        assert!(TcpStream::connect(("127.0.0.1", port)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn refuses_forwards_into_containers_on_other_hosts() {
+        let config = ServerConfig {
+            allow_remote_forwarding: true,
+            ..Default::default()
+        };
+        let mut server = open_server(config, Vec::new());
+        server.docker_hosts = Arc::new(vec![
+            mock_host("local", Vec::new()),
+            mock_host("remote", vec![loopback_container("app")]),
+        ]);
+        let destination = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = destination.local_addr().unwrap().port();
+        let mut connection = connect(&mut server, None, ssh_config(MethodSet::NONE)).await;
+        assert!(connection.handle.authenticate_none("app").await.unwrap());
+        assert!(connection
+            .handle
+            .channel_open_direct_tcpip("localhost", port as u32, "127.0.0.1", 40000)
+            .await
+            .is_err());
+        assert!(!connection
+            .handle
+            .tcpip_forward("localhost", 0)
+            .await
+            .unwrap());
+        assert!(server.remote_forwards.lock().await.is_empty());
+
+        assert!(matches!(
+            forward_container(loopback_container("app"), 1, "app"),
+            Err(TunnydError::RemoteForward { .. })
+        ));
+        assert!(forward_container(loopback_container("app"), 0, "app").is_ok());
+    }
+
+    #[tokio::test]
+    async fn refuses_remote_forwards_beyond_tcp_ports() {
+        let config = ServerConfig {
+            allow_remote_forwarding: true,
+            ..Default::default()
+        };
+        let mut server = open_server(config, vec![loopback_container("app")]);
+        let mut connection = connect(&mut server, None, ssh_config(MethodSet::NONE)).await;
+        assert!(connection.handle.authenticate_none("app").await.unwrap());
+        // 65536 + 22 used to bind port 22
+        assert!(!connection
+            .handle
+            .tcpip_forward("localhost", 65558)
+            .await
+            .unwrap());
+        assert!(server.remote_forwards.lock().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn refuses_sessions_beyond_max_sessions() {
+        let config = ServerConfig {
+            max_sessions: 2,
+            ..Default::default()
+        };
+        let mut server = open_server(config, vec![loopback_container("app")]);
+        let mut connection = connect(&mut server, None, ssh_config(MethodSet::NONE)).await;
+        assert!(connection.handle.authenticate_none("app").await.unwrap());
+        let first = connection.handle.channel_open_session().await.unwrap();
+        let _second = connection.handle.channel_open_session().await.unwrap();
+        assert!(connection.handle.channel_open_session().await.is_err());
+        // The refused session left the open ones alone
+        assert_eq!(server.clients.lock().await.len(), 2);
+
+        first.close().await.unwrap();
+        let clients = &server.clients;
+        wait_until(move || async move { clients.lock().await.len() == 1 }).await;
+        assert!(connection.handle.channel_open_session().await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn keeps_the_peer_address_of_each_connection() {
+        let mut server = open_server(ServerConfig::default(), vec![loopback_container("app")]);
+        let peer: SocketAddr = "192.0.2.7:50000".parse().unwrap();
+        let mut connection = connect(&mut server, Some(peer), ssh_config(MethodSet::NONE)).await;
+        // A later connection must not overwrite the address of the first one
+        let mut other = connect(&mut server, None, ssh_config(MethodSet::NONE)).await;
+        assert!(connection.handle.authenticate_none("app").await.unwrap());
+        assert!(other.handle.authenticate_none("app").await.unwrap());
+        let _channel = connection.handle.channel_open_session().await.unwrap();
+        let clients = server.clients.lock().await;
+        let client = clients.values().next().unwrap();
+        assert_eq!(client.peer_addr, Some(peer));
+    }
+
+    #[tokio::test]
+    async fn records_interactive_sessions_as_asciicast() {
+        let directory = tempfile::tempdir().unwrap();
+        let daemon = MockDocker::start(exec_routes(Reply::Stream(b"hello\r\n".to_vec()))).await;
+        let config = ServerConfig {
+            recording_path: Some(format!("{}/{{session}}.cast", directory.path().display())),
+            ..Default::default()
+        };
+        let mut server = docker_server(config, &daemon);
+        let mut connection = connect(&mut server, None, ssh_config(MethodSet::NONE)).await;
+        assert!(connection.handle.authenticate_none("app").await.unwrap());
+        let mut channel = connection.handle.channel_open_session().await.unwrap();
+        channel
+            .request_pty(true, "xterm", 100, 30, 0, 0, &[])
+            .await
+            .unwrap();
+        channel.request_shell(true).await.unwrap();
+        let output = read_session(&mut channel).await;
+        assert!(String::from_utf8_lossy(&output.stdout).contains("hello"));
+        assert_eq!(output.exit_status, Some(0));
+
+        let path = std::fs::read_dir(directory.path())
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap()
+            .path();
+        let cast = std::fs::read_to_string(path).unwrap();
+        let mut lines = cast.lines();
+        let header: serde_json::Value = serde_json::from_str(lines.next().unwrap()).unwrap();
+        assert_eq!(header["version"], 2);
+        assert_eq!(header["width"], 100);
+        assert_eq!(header["height"], 30);
+        assert_eq!(header["env"]["TERM"], "xterm");
+        let mut recorded = String::new();
+        for line in lines {
+            let event: serde_json::Value = serde_json::from_str(line).unwrap();
+            assert!(event[0].as_f64().is_some());
+            assert_eq!(event[1], "o");
+            recorded.push_str(event[2].as_str().unwrap());
+        }
+        assert!(recorded.starts_with("hello"));
+    }
+
+    #[tokio::test]
+    async fn passes_accepted_variables_to_the_exec() {
+        let daemon = MockDocker::start(exec_routes(Reply::Stream(Vec::new()))).await;
+        let mut server = docker_server(ServerConfig::default(), &daemon);
+        let mut connection = connect(&mut server, None, ssh_config(MethodSet::NONE)).await;
+        assert!(connection.handle.authenticate_none("app").await.unwrap());
+        let mut channel = connection.handle.channel_open_session().await.unwrap();
+        channel.set_env(true, "LANG", "C.UTF-8").await.unwrap();
+        channel
+            .set_env(true, "SECRET_TOKEN", "hunter2")
+            .await
+            .unwrap();
+        channel.request_shell(true).await.unwrap();
+        read_session(&mut channel).await;
+
+        let created = daemon.requests("POST /containers/app-id/exec");
+        let options: serde_json::Value = serde_json::from_str(&created[0]).unwrap();
+        let env: Vec<&str> = options["Env"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter_map(|variable| variable.as_str())
+            .collect();
+        assert!(env.contains(&"LANG=C.UTF-8"));
+        assert!(!env
+            .iter()
+            .any(|variable| variable.starts_with("SECRET_TOKEN=")));
+    }
+
+    #[tokio::test]
+    async fn fails_sessions_when_docker_hangs_creating_the_exec() {
+        let mut routes = exec_routes(Reply::Stream(Vec::new()));
+        routes.retain(|(route, _)| *route != "POST /containers/app-id/exec");
+        routes.push(("POST /containers/app-id/exec", Reply::Hang));
+        let daemon = MockDocker::start(routes).await;
+        let config = ServerConfig {
+            exec_timeout_secs: 1,
+            ..Default::default()
+        };
+        let mut server = docker_server(config, &daemon);
+        let mut connection = connect(&mut server, None, ssh_config(MethodSet::NONE)).await;
+        assert!(connection.handle.authenticate_none("app").await.unwrap());
+        let mut channel = connection.handle.channel_open_session().await.unwrap();
+        channel.request_shell(true).await.unwrap();
+        let output = read_session(&mut channel).await;
+        assert_eq!(
+            String::from_utf8_lossy(&output.stderr),
+            "Docker did not create the exec within 1 seconds\r\n"
+        );
+        assert_eq!(output.exit_status, Some(EXIT_STATUS_FAILURE));
+        assert!(daemon.requests("POST /exec/exec-1/start").is_empty());
+    }
+
+    #[tokio::test]
+    async fn sends_the_motd_before_the_shell_output() {
+        let daemon = MockDocker::start(exec_routes(Reply::Stream(b"$ ".to_vec()))).await;
+        let mut container = loopback_container("app");
+        if let Some(labels) = container.labels.as_mut() {
+            labels.insert(
+                String::from("tunnyD.motd"),
+                String::from("Welcome to app\nStaging only"),
+            );
+        }
+        let mut server = open_server(ServerConfig::default(), Vec::new());
+        server.docker_hosts = Arc::new(vec![daemon.host("local", vec![container])]);
+        let mut connection = connect(&mut server, None, ssh_config(MethodSet::NONE)).await;
+        assert!(connection.handle.authenticate_none("app").await.unwrap());
+        let mut channel = connection.handle.channel_open_session().await.unwrap();
+        channel
+            .request_pty(true, "xterm", 80, 24, 0, 0, &[])
+            .await
+            .unwrap();
+        channel.request_shell(true).await.unwrap();
+        let output = read_session(&mut channel).await;
+        assert!(String::from_utf8_lossy(&output.stdout)
+            .starts_with("Welcome to app\r\nStaging only\r\n$ "));
+    }
+
+    #[tokio::test]
+    async fn explains_unresolved_targets_on_the_channel() {
+        let mut unidentified = loopback_container("noid");
+        unidentified.id = None;
+        let mut server = open_server(
+            ServerConfig::default(),
+            vec![loopback_container("app"), unidentified],
+        );
+        let mut connection = connect(&mut server, None, ssh_config(MethodSet::NONE)).await;
+        assert!(connection.handle.authenticate_none("app").await.unwrap());
+        for target in ["ghost", "noid"] {
+            let mut channel = connection.handle.channel_open_session().await.unwrap();
+            channel
+                .exec(true, format!("tunnyd --target {}", target))
+                .await
+                .unwrap();
+            let output = read_session(&mut channel).await;
+            let message = String::from_utf8_lossy(&output.stderr).to_string();
+            assert!(message.contains(target), "{:?}", message);
+            assert_eq!(output.exit_status, Some(EXIT_STATUS_FAILURE));
+        }
+        // The connection survived both failures
+        assert!(connection.handle.channel_open_session().await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn tunnels_to_an_echo_server_in_the_container_network() {
+        let echo = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = echo.local_addr().unwrap().port() as u32;
+        tokio::spawn(async move {
+            let (mut socket, _) = echo.accept().await.unwrap();
+            let (mut reader, mut writer) = socket.split();
+            let _ = tokio::io::copy(&mut reader, &mut writer).await;
+        });
+        let mut server = open_server(ServerConfig::default(), vec![loopback_container("app")]);
+        let mut connection = connect(&mut server, None, ssh_config(MethodSet::NONE)).await;
+        assert!(connection.handle.authenticate_none("app").await.unwrap());
+        // Outside the container's networks
+        assert!(connection
+            .handle
+            .channel_open_direct_tcpip("10.0.0.1", port, "127.0.0.1", 40000)
+            .await
+            .is_err());
+
+        let channel = connection
+            .handle
+            .channel_open_direct_tcpip("127.0.0.1", port, "127.0.0.1", 40000)
+            .await
+            .unwrap();
+        let (mut reader, mut writer) = tokio::io::split(channel.into_stream());
+        // Larger than a channel window, so both directions have to flow at once
+        let payload: Vec<u8> = (0..4 * 1024 * 1024u32).map(|i| (i % 251) as u8).collect();
+        let mut echoed = vec![0; payload.len()];
+        let (written, read) =
+            tokio::join!(writer.write_all(&payload), reader.read_exact(&mut echoed));
+        written.unwrap();
+        read.unwrap();
+        assert!(echoed == payload);
+    }
+
+    #[tokio::test]
+    async fn reaps_idle_sessions_and_keeps_active_ones() {
+        let config = ServerConfig {
+            idle_timeout_secs: 1,
+            ..Default::default()
+        };
+        let mut server = open_server(config, vec![loopback_container("app")]);
+        tokio::spawn(server.clone().reap_idle_sessions());
+        let mut connection = connect(&mut server, None, ssh_config(MethodSet::NONE)).await;
+        assert!(connection.handle.authenticate_none("app").await.unwrap());
+        let mut idle = connection.handle.channel_open_session().await.unwrap();
+        let active = connection.handle.channel_open_session().await.unwrap();
+        let active = &active;
+        let typing = move |period: Duration| async move {
+            let started = Instant::now();
+            while started.elapsed() < period {
+                active.data(&b"."[..]).await.unwrap();
+                tokio::time::sleep(Duration::from_millis(200)).await;
+            }
+        };
+        let reaped = tokio::select! {
+            output = read_session(&mut idle) => output,
+            _ = typing(Duration::from_secs(10)) => panic!("the idle session wasn't reaped"),
+        };
+        assert!(String::from_utf8_lossy(&reaped.stdout)
+            .contains("Session closed after 1 seconds of inactivity"));
+        // The active session outlives several idle timeouts
+        typing(Duration::from_secs(3)).await;
+        assert_eq!(server.clients.lock().await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn rejects_logins_without_an_allowed_container_at_authentication() {
+        let mut container = loopback_container("app");
+        container
+            .labels
+            .get_or_insert_with(HashMap::new)
+            .insert(String::from("tunnyD.allowed.users"), String::from("alice"));
+        let mut server = open_server(Default::default(), vec![container]);
+        let mut connection = connect(&mut server, None, ssh_config(MethodSet::NONE)).await;
+        assert!(!connection
+            .handle
+            .authenticate_none("mallory@app")
+            .await
+            .unwrap());
+        assert!(!connection.handle.authenticate_none("ghost").await.unwrap());
+        assert!(connection
+            .handle
+            .authenticate_none("alice@app")
+            .await
+            .unwrap());
+
+        // The target can also come with the exec request, a user allowed somewhere gets in
+        let mut connection = connect(&mut server, None, ssh_config(MethodSet::NONE)).await;
+        assert!(connection.handle.authenticate_none("alice").await.unwrap());
+    }
+
+    /// A loopback container only the given comma separated users may access.
+    fn allowing(hostname: &str, users: &str) -> ContainerSummary {
+        let mut container = loopback_container(hostname);
+        container
+            .labels
+            .get_or_insert_with(HashMap::new)
+            .insert(String::from("tunnyD.allowed.users"), users.to_string());
+        container
+    }
+
+    /// Sends a request line on a control channel and reads the response line.
+    async fn control_request(
+        channel: &mut Channel<russh::client::Msg>,
+        request: &str,
+    ) -> ControlResponse {
+        channel
+            .data(format!("{}\n", request).as_bytes())
+            .await
+            .unwrap();
+        let mut line = Vec::new();
+        while !line.ends_with(b"\n") {
+            match channel.wait().await {
+                Some(russh::ChannelMsg::Data { data }) => line.extend_from_slice(&data),
+                Some(_) => {}
+                None => panic!("the control channel closed"),
+            }
+        }
+        serde_json::from_slice(&line).unwrap()
+    }
+
+    #[tokio::test]
+    async fn checks_container_access_against_the_login_user() {
+        let daemon = MockDocker::start(exec_routes(Reply::Stream(Vec::new()))).await;
+        let mut server = open_server(ServerConfig::default(), Vec::new());
+        server.docker_hosts = Arc::new(vec![daemon.host(
+            "local",
+            vec![allowing("app", "alice"), allowing("db", "bob")],
+        )]);
+        let run = |login: &'static str, command: &'static str| {
+            let mut server = server.clone();
+            async move {
+                let mut connection = connect(&mut server, None, ssh_config(MethodSet::NONE)).await;
+                assert!(connection.handle.authenticate_none(login).await.unwrap());
+                let mut channel = connection.handle.channel_open_session().await.unwrap();
+                channel.exec(true, command).await.unwrap();
+                read_session(&mut channel).await
+            }
+        };
+
+        // Picking alice as the session user doesn't give bob alice's access
+        let output = run("bob", "tunnyd --target app --user alice").await;
+        assert_eq!(output.exit_status, Some(EXIT_STATUS_FAILURE));
+        assert!(daemon.requests("POST /containers/app-id/exec").is_empty());
+
+        // alice gets in without --user, and the session runs as alice
+        let output = run("alice", "tunnyd --target app").await;
+        assert_eq!(output.exit_status, Some(0));
+        let created = daemon.requests("POST /containers/app-id/exec");
+        let options: serde_json::Value = serde_json::from_str(&created[0]).unwrap();
+        assert_eq!(options["User"], "alice");
+    }
+
+    #[tokio::test]
+    async fn applies_the_session_checks_to_control_opens() {
+        let daemon = MockDocker::start(exec_routes(Reply::Stream(Vec::new()))).await;
+        let config = ServerConfig {
+            require_tty: true,
+            ..Default::default()
+        };
+        let mut server = open_server(config, Vec::new());
+        server.docker_hosts = Arc::new(vec![daemon.host(
+            "local",
+            vec![allowing("app", "alice"), allowing("db", "bob")],
+        )]);
+        let mut connection = connect(&mut server, None, ssh_config(MethodSet::NONE)).await;
+        assert!(connection.handle.authenticate_none("bob").await.unwrap());
+        let open = r#"{"type":"open","target":"app","user":"alice"}"#;
+
+        let mut control = connection.handle.channel_open_session().await.unwrap();
+        control
+            .request_subsystem(true, CONTROL_SUBSYSTEM)
+            .await
+            .unwrap();
+        assert_eq!(
+            control_request(&mut control, open).await,
+            ControlResponse::Error {
+                message: TunnydError::TtyRequired.to_string()
+            }
+        );
+
+        // The user field only picks who the exec runs as, bob still can't reach alice's container
+        let mut control = connection.handle.channel_open_session().await.unwrap();
+        control
+            .request_pty(true, "xterm", 80, 24, 0, 0, &[])
+            .await
+            .unwrap();
+        control
+            .request_subsystem(true, CONTROL_SUBSYSTEM)
+            .await
+            .unwrap();
+        assert_eq!(
+            control_request(&mut control, open).await,
+            ControlResponse::Error {
+                message: TunnydError::ContainerNotFound {
+                    target: String::from("app")
+                }
+                .to_string()
+            }
+        );
+        assert!(daemon.requests("POST /containers/app-id/exec").is_empty());
+    }
+
+    #[tokio::test]
+    async fn asks_the_connect_hook_before_control_opens() {
+        let daemon = MockDocker::start(exec_routes(Reply::Stream(Vec::new()))).await;
+        let config = ServerConfig {
+            connect_hook_command: Some(vec![
+                String::from("sh"),
+                String::from("-c"),
+                String::from("echo 'maintenance window'; exit 1"),
+            ]),
+            ..Default::default()
+        };
+        let mut server = docker_server(config, &daemon);
+        let mut connection = connect(&mut server, None, ssh_config(MethodSet::NONE)).await;
+        assert!(connection.handle.authenticate_none("app").await.unwrap());
+        let mut control = connection.handle.channel_open_session().await.unwrap();
+        control
+            .request_subsystem(true, CONTROL_SUBSYSTEM)
+            .await
+            .unwrap();
+        let response = control_request(&mut control, r#"{"type":"open","target":"app"}"#).await;
+        assert_eq!(
+            response,
+            ControlResponse::Error {
+                message: TunnydError::HookDenied {
+                    reason: String::from("maintenance window")
+                }
+                .to_string()
+            }
+        );
+        assert!(daemon.requests("POST /containers/app-id/exec").is_empty());
+    }
+}
@@ -0,0 +1,304 @@
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Weak};
+
+use bollard::container::LogOutput;
+use futures::{Stream, StreamExt};
+use log::{info, warn};
+use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::{broadcast, Mutex};
+
+/// Output chunks kept for attached channels that fall behind, older chunks are skipped for them.
+const OUTPUT_BACKLOG: usize = 1024;
+/// Size of the pipe between an attached channel and the input of the shared exec.
+const INPUT_PIPE_SIZE: usize = 8 * 1024;
+
+/// Identifies a shared session.
+///
+/// # Fields
+///
+/// - `container_id`: The ID of the container the exec runs in.
+/// - `user`: The container user the exec runs as, so nobody joins a shell of another user.
+/// - `name`: The name the clients agreed on.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct SharedKey {
+    pub container_id: String,
+    pub user: Option<String>,
+    pub name: String,
+}
+
+/// The exec of a shared session, shared by every channel attached to it.
+///
+/// # Remarks
+///
+/// - Output is broadcast to every attached channel, input of every channel is merged into the
+///   exec's stdin.
+/// - The stdin of the exec is closed once the last channel detaches, ending the shell.
+struct SharedExec {
+    exec_id: String,
+    tty: bool,
+    input: Mutex<Pin<Box<dyn AsyncWrite + Send>>>,
+    output: broadcast::Sender<Option<LogOutput>>,
+    ended: AtomicBool,
+}
+
+/// The streams of a channel attached to an exec, shared or not.
+///
+/// # Fields
+///
+/// - `exec_id`: The ID of the exec.
+/// - `tty`: Whether the exec was allocated a TTY.
+/// - `input`: Writes to the stdin of the exec.
+/// - `output`: The output of the exec.
+/// - `shared`: Whether other channels may be attached to the exec as well.
+/// - `main_process`: Whether the streams are those of the container's main process rather than of
+///   an exec, `exec_id` is the ID of the container then. The main process is never hung up.
+pub struct Attachment {
+    pub exec_id: String,
+    pub tty: bool,
+    pub shared: bool,
+    pub main_process: bool,
+    pub input: Pin<Box<dyn AsyncWrite + Send>>,
+    pub output: Pin<Box<dyn Stream<Item = Result<LogOutput, bollard::errors::Error>> + Send>>,
+}
+
+/// The shared sessions of the server, by their key.
+///
+/// # Remarks
+///
+/// Sessions are only referenced weakly, they live as long as one of their channels is attached.
+#[derive(Default)]
+pub struct SharedSessions {
+    sessions: std::sync::Mutex<HashMap<SharedKey, Weak<SharedExec>>>,
+}
+
+impl SharedSessions {
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<SharedKey, Weak<SharedExec>>> {
+        match self.sessions.lock() {
+            Ok(sessions) => sessions,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    /// Attaches a channel to a running shared session.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The key of the session.
+    ///
+    /// # Returns
+    ///
+    /// The attachment, or `None` if no session with this key is running. Output produced before the
+    /// channel attached is not replayed.
+    pub fn attach(&self, key: &SharedKey) -> Option<Attachment> {
+        let mut sessions = self.lock();
+        let exec = match sessions.get(key).and_then(Weak::upgrade) {
+            Some(exec) => exec,
+            None => {
+                sessions.remove(key);
+                return None;
+            }
+        };
+        // Subscribed before checking whether the output ended, so the end can't be missed
+        let receiver = exec.output.subscribe();
+        if exec.ended.load(Ordering::SeqCst) {
+            sessions.remove(key);
+            return None;
+        }
+        Some(attachment(exec, receiver))
+    }
+
+    /// Shares a newly started exec under a key and attaches the first channel to it.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The key other channels attach with.
+    /// * `exec_id` - The ID of the exec.
+    /// * `tty` - Whether the exec was allocated a TTY.
+    /// * `input` - The stdin of the exec.
+    /// * `output` - The output of the exec.
+    ///
+    /// # Returns
+    ///
+    /// The attachment of the first channel.
+    pub fn share(
+        &self,
+        key: SharedKey,
+        exec_id: String,
+        tty: bool,
+        input: Pin<Box<dyn AsyncWrite + Send>>,
+        mut output: Pin<Box<dyn Stream<Item = Result<LogOutput, bollard::errors::Error>> + Send>>,
+    ) -> Attachment {
+        let (sender, receiver) = broadcast::channel(OUTPUT_BACKLOG);
+        let exec = Arc::new(SharedExec {
+            exec_id,
+            tty,
+            input: Mutex::new(input),
+            output: sender.clone(),
+            ended: AtomicBool::new(false),
+        });
+        let task_exec = Arc::downgrade(&exec);
+        tokio::spawn(async move {
+            while let Some(item) = output.next().await {
+                match item {
+                    Ok(item) => {
+                        let _ = sender.send(Some(item));
+                    }
+                    Err(e) => {
+                        warn!("Failed to read the output of a shared exec: {}", e);
+                        break;
+                    }
+                }
+            }
+            if let Some(exec) = task_exec.upgrade() {
+                exec.ended.store(true, Ordering::SeqCst);
+            }
+            let _ = sender.send(None);
+        });
+        info!("Sharing exec {} as {:?}", exec.exec_id, key.name);
+        self.lock().insert(key, Arc::downgrade(&exec));
+        attachment(exec, receiver)
+    }
+}
+
+/// Attaches a channel to a shared exec.
+///
+/// # Arguments
+///
+/// * `exec` - The shared exec.
+/// * `receiver` - The output subscription of the channel.
+///
+/// # Returns
+///
+/// The attachment, whose input is pumped into the exec's stdin until the channel drops it.
+fn attachment(
+    exec: Arc<SharedExec>,
+    receiver: broadcast::Receiver<Option<LogOutput>>,
+) -> Attachment {
+    let (writer, mut reader) = tokio::io::duplex(INPUT_PIPE_SIZE);
+    let exec_id = exec.exec_id.clone();
+    let tty = exec.tty;
+    let output_exec = Arc::clone(&exec);
+    tokio::spawn(async move {
+        let mut buffer = vec![0; INPUT_PIPE_SIZE];
+        loop {
+            let read = match reader.read(&mut buffer).await {
+                Ok(0) | Err(_) => break,
+                Ok(read) => read,
+            };
+            let mut input = exec.input.lock().await;
+            if input.write_all(&buffer[..read]).await.is_err() || input.flush().await.is_err() {
+                break;
+            }
+        }
+    });
+    // The output keeps the exec, and with it its stdin, alive until the channel is done with it
+    let output =
+        futures::stream::unfold((receiver, output_exec), |(mut receiver, exec)| async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(Some(item)) => return Some((Ok(item), (receiver, exec))),
+                    Ok(None) | Err(broadcast::error::RecvError::Closed) => return None,
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => warn!(
+                        "Skipped {} output chunks of shared exec {} for a slow channel",
+                        skipped, exec.exec_id
+                    ),
+                }
+            }
+        });
+    Attachment {
+        exec_id,
+        tty,
+        shared: true,
+        main_process: false,
+        input: Box::pin(writer),
+        output: Box::pin(output),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::channel::mpsc;
+    use tokio::io::DuplexStream;
+
+    use super::*;
+
+    fn key(name: &str) -> SharedKey {
+        SharedKey {
+            container_id: String::from("0123456789ab"),
+            user: Some(String::from("root")),
+            name: name.to_string(),
+        }
+    }
+
+    fn chunk(text: &str) -> Result<LogOutput, bollard::errors::Error> {
+        Ok(LogOutput::StdOut {
+            message: text.as_bytes().to_vec().into(),
+        })
+    }
+
+    fn share(
+        sessions: &SharedSessions,
+    ) -> (
+        Attachment,
+        mpsc::UnboundedSender<Result<LogOutput, bollard::errors::Error>>,
+        DuplexStream,
+    ) {
+        let (output, stream) = mpsc::unbounded();
+        let (input, stdin) = tokio::io::duplex(1024);
+        let attachment = sessions.share(
+            key("debug"),
+            String::from("exec"),
+            true,
+            Box::pin(input),
+            Box::pin(stream),
+        );
+        (attachment, output, stdin)
+    }
+
+    async fn collect(attachment: Attachment) -> Vec<u8> {
+        attachment
+            .output
+            .map(|item| item.unwrap().into_bytes().to_vec())
+            .concat()
+            .await
+    }
+
+    #[tokio::test]
+    async fn broadcasts_output_to_every_attached_channel() {
+        let sessions = SharedSessions::default();
+        let (first, output, _stdin) = share(&sessions);
+        let second = sessions.attach(&key("debug")).unwrap();
+        assert_eq!(second.exec_id, "exec");
+        assert!(sessions.attach(&key("other")).is_none());
+
+        output.unbounded_send(chunk("hello ")).unwrap();
+        output.unbounded_send(chunk("world")).unwrap();
+        drop(output);
+        assert_eq!(collect(first).await, b"hello world");
+        assert_eq!(collect(second).await, b"hello world");
+        assert!(sessions.attach(&key("debug")).is_none());
+    }
+
+    #[tokio::test]
+    async fn merges_the_input_of_every_attached_channel() {
+        let sessions = SharedSessions::default();
+        let (mut first, _output, mut stdin) = share(&sessions);
+        let mut second = sessions.attach(&key("debug")).unwrap();
+
+        first.input.write_all(b"ls\n").await.unwrap();
+        let mut buffer = [0; 3];
+        stdin.read_exact(&mut buffer).await.unwrap();
+        assert_eq!(&buffer, b"ls\n");
+        second.input.write_all(b"pwd\n").await.unwrap();
+        let mut buffer = [0; 4];
+        stdin.read_exact(&mut buffer).await.unwrap();
+        assert_eq!(&buffer, b"pwd\n");
+
+        // The stdin of the exec is closed once every channel detached
+        drop(first);
+        drop(second);
+        assert_eq!(stdin.read(&mut buffer).await.unwrap(), 0);
+    }
+}
@@ -0,0 +1,207 @@
+use std::future::Future;
+use std::io::ErrorKind;
+use std::time::{Duration, Instant};
+
+use log::{error, info, warn};
+
+const RESTART_INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+const RESTART_MAX_BACKOFF: Duration = Duration::from_secs(30);
+const RESTART_MAX_ATTEMPTS: u32 = 10;
+/// A listener that stayed up this long is considered healthy again and gets a fresh retry budget.
+const RESTART_RESET_AFTER: Duration = Duration::from_secs(60);
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(250);
+/// How often the sessions still open are logged while draining.
+const DRAIN_LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Computes the delay before restarting a failed listener.
+///
+/// # Arguments
+///
+/// * `attempt` - The number of the restart attempt, starting at 1.
+///
+/// # Returns
+///
+/// The initial backoff doubled for every previous attempt, capped at the maximum backoff.
+pub fn restart_backoff(attempt: u32) -> Duration {
+    let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+    RESTART_INITIAL_BACKOFF
+        .saturating_mul(factor)
+        .min(RESTART_MAX_BACKOFF)
+}
+
+/// Checks whether a listener error can't be fixed by restarting the listener.
+///
+/// # Arguments
+///
+/// * `error` - The error the listener stopped with.
+///
+/// # Returns
+///
+/// `true` for errors such as the address being in use or the port requiring privileges.
+pub fn is_fatal_listener_error(error: &std::io::Error) -> bool {
+    matches!(
+        error.kind(),
+        ErrorKind::AddrInUse | ErrorKind::AddrNotAvailable | ErrorKind::PermissionDenied
+    )
+}
+
+/// Runs a listener, restarting it with an exponential backoff when it fails.
+///
+/// # Arguments
+///
+/// * `name` - The name of the listener used in the logs.
+/// * `run` - Starts the listener and resolves once it stops.
+///
+/// # Returns
+///
+/// `Ok(())` once the listener closes successfully, or the last error if the error is fatal or the
+/// listener keeps failing after the maximum number of restarts.
+pub async fn supervise<F, Fut>(name: &str, mut run: F) -> Result<(), std::io::Error>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<(), std::io::Error>>,
+{
+    let mut attempt = 0;
+    loop {
+        let started = Instant::now();
+        match run().await {
+            Ok(()) => {
+                info!("Listener {} has closed successfully", name);
+                return Ok(());
+            }
+            Err(e) if is_fatal_listener_error(&e) => {
+                error!("Listener {} failed with a fatal error: {}", name, e);
+                return Err(e);
+            }
+            Err(e) => {
+                if started.elapsed() >= RESTART_RESET_AFTER {
+                    attempt = 0;
+                }
+                attempt += 1;
+                if attempt > RESTART_MAX_ATTEMPTS {
+                    error!(
+                        "Listener {} failed {} times in a row, giving up: {}",
+                        name, RESTART_MAX_ATTEMPTS, e
+                    );
+                    return Err(e);
+                }
+                let delay = restart_backoff(attempt);
+                warn!(
+                    "Listener {} failed: {}. Restarting in {:?} (attempt {}/{})",
+                    name, e, delay, attempt, RESTART_MAX_ATTEMPTS
+                );
+                tokio::time::sleep(delay).await;
+            }
+        }
+    }
+}
+
+/// Resolves once the process is asked to shut down with SIGINT or SIGTERM.
+pub async fn shutdown_signal() {
+    let mut terminate =
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(terminate) => terminate,
+            Err(e) => {
+                warn!("Failed to listen for SIGTERM: {}", e);
+                let _ = tokio::signal::ctrl_c().await;
+                return;
+            }
+        };
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => info!("Received SIGINT, shutting down"),
+        _ = terminate.recv() => info!("Received SIGTERM, shutting down"),
+    }
+}
+
+/// Waits for the active sessions to end before the process exits.
+///
+/// # Arguments
+///
+/// * `active` - Counts the sessions still open.
+/// * `deadline` - How long to wait at most.
+///
+/// # Returns
+///
+/// `true` once no session is open anymore, `false` if sessions were still open at the deadline.
+pub async fn drain<F, Fut>(mut active: F, deadline: Duration) -> bool
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = usize>,
+{
+    let started = Instant::now();
+    let mut logged_at: Option<Instant> = None;
+    loop {
+        let open = active().await;
+        if open == 0 {
+            info!("All sessions have ended");
+            return true;
+        }
+        let elapsed = started.elapsed();
+        if elapsed >= deadline {
+            warn!(
+                "Drain deadline of {:?} passed with {} sessions still open",
+                deadline, open
+            );
+            return false;
+        }
+        if logged_at.map_or(true, |at| at.elapsed() >= DRAIN_LOG_INTERVAL) {
+            info!("Draining, waiting for {} sessions to end", open);
+            logged_at = Some(Instant::now());
+        }
+        tokio::time::sleep(DRAIN_POLL_INTERVAL.min(deadline - elapsed)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        assert_eq!(restart_backoff(1), RESTART_INITIAL_BACKOFF);
+        assert_eq!(restart_backoff(2), Duration::from_secs(1));
+        assert_eq!(restart_backoff(20), RESTART_MAX_BACKOFF);
+    }
+
+    #[test]
+    fn address_errors_are_fatal() {
+        assert!(is_fatal_listener_error(&std::io::Error::from(
+            ErrorKind::AddrInUse
+        )));
+        assert!(!is_fatal_listener_error(&std::io::Error::from(
+            ErrorKind::ConnectionReset
+        )));
+    }
+
+    #[tokio::test]
+    async fn drain_gives_up_on_lingering_sessions_at_the_deadline() {
+        let started = Instant::now();
+        let drained = drain(|| async { 1 }, Duration::from_millis(100)).await;
+        assert!(!drained);
+        assert!(started.elapsed() >= Duration::from_millis(100));
+    }
+
+    #[tokio::test]
+    async fn drain_ends_once_the_last_session_closed() {
+        let open = Arc::new(AtomicUsize::new(1));
+        let closing = Arc::clone(&open);
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            closing.store(0, Ordering::SeqCst);
+        });
+        let started = Instant::now();
+        let drained = drain(
+            || {
+                let open = Arc::clone(&open);
+                async move { open.load(Ordering::SeqCst) }
+            },
+            Duration::from_secs(30),
+        )
+        .await;
+        assert!(drained);
+        assert!(started.elapsed() < Duration::from_secs(30));
+    }
+}
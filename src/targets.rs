@@ -0,0 +1,142 @@
+use crate::cli::ListTargetsArgs;
+use crate::config::ServerConfig;
+use crate::docker::{connect_to_docker, list_targets, DockerClient, DockerSettings, Target};
+
+const ID_DISPLAY_LENGTH: usize = 12;
+
+/// Lists the containers exposing a tunnyd hostname, without starting the SSH server.
+///
+/// # Arguments
+///
+/// * `args` - The arguments of the `list-targets` subcommand.
+/// * `config` - The loaded configuration, providing the Docker context.
+///
+/// # Returns
+///
+/// `true` if the containers could be listed.
+pub async fn print_targets(args: ListTargetsArgs, config: ServerConfig) -> bool {
+    let settings = DockerSettings::new(
+        args.docker_context.or(config.docker_context.clone()),
+        config.docker_api_version.clone(),
+    );
+    let docker = match connect_to_docker(&settings).await {
+        Ok(docker) => DockerClient::new(docker, settings),
+        Err(e) => {
+            eprintln!("Failed to connect to Docker: {}", e);
+            return false;
+        }
+    };
+    let targets = match list_targets(&docker).await {
+        Ok(targets) => targets,
+        Err(e) => {
+            eprintln!("Failed to list containers: {}", e);
+            return false;
+        }
+    };
+    match args.json {
+        true => println!("{}", format_json(&targets)),
+        false => print!("{}", format_table(&targets)),
+    }
+    true
+}
+
+/// Formats targets as a JSON array.
+fn format_json(targets: &[Target]) -> String {
+    serde_json::to_string_pretty(targets).unwrap_or_else(|_| String::from("[]"))
+}
+
+/// Formats targets as a table with one row per container.
+///
+/// # Remarks
+///
+/// Ids are shortened like `docker ps` does, and `-` stands for a missing value. A container without
+/// allowed users is open to every user unless it restricts access by group.
+fn format_table(targets: &[Target]) -> String {
+    let rows: Vec<[String; 4]> = targets
+        .iter()
+        .map(|target| {
+            [
+                target.hostname.clone(),
+                target.state.clone().unwrap_or_else(|| String::from("-")),
+                target
+                    .id
+                    .as_deref()
+                    .map(|id| id.chars().take(ID_DISPLAY_LENGTH).collect())
+                    .unwrap_or_else(|| String::from("-")),
+                match target.allowed_users.is_empty() {
+                    true => String::from("-"),
+                    false => target.allowed_users.join(","),
+                },
+            ]
+        })
+        .collect();
+    let header = [
+        String::from("HOSTNAME"),
+        String::from("STATE"),
+        String::from("ID"),
+        String::from("ALLOWED USERS"),
+    ];
+    let widths: Vec<usize> = (0..header.len())
+        .map(|column| {
+            std::iter::once(&header)
+                .chain(&rows)
+                .map(|row| row[column].len())
+                .max()
+                .unwrap_or_default()
+        })
+        .collect();
+    std::iter::once(&header)
+        .chain(&rows)
+        .map(|row| {
+            let line = row
+                .iter()
+                .zip(&widths)
+                .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+                .collect::<Vec<String>>()
+                .join("  ");
+            format!("{}\n", line.trim_end())
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn targets() -> Vec<Target> {
+        vec![
+            Target {
+                hostname: String::from("my-name.my-docker"),
+                allowed_users: vec![String::from("git"), String::from("root")],
+                state: Some(String::from("running")),
+                id: Some(String::from("0123456789abcdef")),
+            },
+            Target {
+                hostname: String::from("db"),
+                allowed_users: Vec::new(),
+                state: None,
+                id: None,
+            },
+        ]
+    }
+
+    #[test]
+    fn formats_table() {
+        assert_eq!(
+            format_table(&targets()),
+            "HOSTNAME           STATE    ID            ALLOWED USERS\n\
+             my-name.my-docker  running  0123456789ab  git,root\n\
+             db                 -        -             -\n"
+        );
+    }
+
+    #[test]
+    fn formats_json() {
+        let json: serde_json::Value = serde_json::from_str(&format_json(&targets())).unwrap();
+        assert_eq!(json[0]["hostname"], "my-name.my-docker");
+        assert_eq!(json[0]["allowed_users"][1], "root");
+        assert_eq!(json[0]["id"], "0123456789abcdef");
+        assert!(json[1]["state"].is_null());
+        assert_eq!(format_json(&[]), "[]");
+    }
+}
@@ -0,0 +1,545 @@
+//! Helpers shared by the tests of several modules: a server wired to Docker hosts answering from
+//! their cache, a fake Docker daemon, and a SSH client connected to the server in process.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicUsize};
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use bollard::models::{ContainerSummary, ContainerSummaryNetworkSettings, EndpointSettings};
+use bollard::Docker;
+use russh::client::{self, Msg};
+use russh::server::Server as _;
+use russh::{Channel, ChannelMsg, MethodSet};
+use russh_keys::key;
+use serde::Serialize;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, Mutex, Notify};
+
+use crate::acl::AuthFailures;
+use crate::affinity::Affinity;
+use crate::audit::AuditLog;
+use crate::auth::{AuthMethod, AuthPolicy};
+use crate::config::ServerConfig;
+use crate::docker::{ContainerCache, DockerClient, DockerHost, DockerSettings};
+use crate::server::Server;
+use crate::shared::SharedSessions;
+use crate::usage::UsageCache;
+
+/// An address nothing listens on, the clients built on it fail every request.
+pub const UNREACHABLE_DOCKER: &str = "tcp://127.0.0.1:1";
+
+/// Builds a Docker host that only answers container lookups from its cache.
+///
+/// # Arguments
+///
+/// * `name` - The name of the host.
+/// * `containers` - The containers the host runs.
+pub fn mock_host(name: &str, containers: Vec<ContainerSummary>) -> DockerHost {
+    let settings = DockerSettings::default().with_host(UNREACHABLE_DOCKER);
+    let docker =
+        Docker::connect_with_http(UNREACHABLE_DOCKER, 1, &bollard::API_DEFAULT_VERSION).unwrap();
+    DockerHost {
+        name: name.to_string(),
+        docker: DockerClient::new(docker, settings),
+        cache: Arc::new(Mutex::new(ContainerCache::preloaded(containers))),
+    }
+}
+
+/// A canned answer of the fake Docker daemon.
+#[derive(Clone)]
+pub enum Reply {
+    /// Answers with a status and a body.
+    Body(u16, String),
+    /// Upgrades the connection like an attached exec start, sends the output and hangs up.
+    Stream(Vec<u8>),
+    /// Upgrades the connection like an attached exec start and echoes the input.
+    Echo,
+    /// Never answers, like a daemon that stopped responding.
+    Hang,
+}
+
+impl Reply {
+    /// Answers with a value serialized as JSON.
+    pub fn json<T: Serialize>(value: &T) -> Reply {
+        Reply::Body(200, serde_json::to_string(value).unwrap())
+    }
+}
+
+/// A Docker daemon on a loopback port answering requests from canned replies.
+///
+/// # Fields
+///
+/// * `host`: The Docker host to connect to, `tcp://127.0.0.1:<port>`.
+/// * `requests`: The route and body of every request received.
+pub struct MockDocker {
+    pub host: String,
+    requests: Arc<std::sync::Mutex<Vec<(String, String)>>>,
+}
+
+impl MockDocker {
+    /// Starts the daemon.
+    ///
+    /// # Arguments
+    ///
+    /// * `routes` - The replies keyed by `METHOD /path`, without the API version and the query.
+    ///   Other routes are answered with a 404.
+    pub async fn start(routes: Vec<(&str, Reply)>) -> MockDocker {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let host = format!("tcp://{}", listener.local_addr().unwrap());
+        let routes: Arc<HashMap<String, Reply>> = Arc::new(
+            routes
+                .into_iter()
+                .map(|(route, reply)| (route.to_string(), reply))
+                .collect(),
+        );
+        let requests = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let received = Arc::clone(&requests);
+        tokio::spawn(async move {
+            while let Ok((socket, _)) = listener.accept().await {
+                tokio::spawn(serve_docker(
+                    socket,
+                    Arc::clone(&routes),
+                    Arc::clone(&received),
+                ));
+            }
+        });
+        MockDocker { host, requests }
+    }
+
+    /// Returns the bodies of the requests received on a route.
+    ///
+    /// # Arguments
+    ///
+    /// * `route` - The route, `METHOD /path` like in `start`.
+    pub fn requests(&self, route: &str) -> Vec<String> {
+        self.requests
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(received, _)| received == route)
+            .map(|(_, body)| body.clone())
+            .collect()
+    }
+
+    /// Builds a client of the daemon, reconnecting to it as well.
+    pub fn client(&self) -> DockerClient {
+        let docker =
+            Docker::connect_with_http(&self.host, 5, &bollard::API_DEFAULT_VERSION).unwrap();
+        DockerClient::new(docker, DockerSettings::default().with_host(&self.host))
+    }
+
+    /// Builds a Docker host whose requests go to the daemon, except the container lookups
+    /// answered from its cache.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the host.
+    /// * `containers` - The containers the host runs.
+    pub fn host(&self, name: &str, containers: Vec<ContainerSummary>) -> DockerHost {
+        DockerHost {
+            name: name.to_string(),
+            docker: self.client(),
+            cache: Arc::new(Mutex::new(ContainerCache::preloaded(containers))),
+        }
+    }
+}
+
+/// The routes of a daemon running a single exec, `exec-1`, in the container `app-id`, which exits
+/// with 0 once its output ended.
+///
+/// # Arguments
+///
+/// * `start` - The reply to the start of the exec, carrying its output.
+pub fn exec_routes(start: Reply) -> Vec<(&'static str, Reply)> {
+    vec![
+        (
+            "POST /containers/app-id/exec",
+            Reply::Body(201, String::from(r#"{"Id":"exec-1"}"#)),
+        ),
+        ("POST /exec/exec-1/start", start),
+        ("POST /exec/exec-1/resize", Reply::Body(200, String::new())),
+        (
+            "GET /containers/app-id/json",
+            Reply::Body(200, String::from(r#"{"State":{"Running":true}}"#)),
+        ),
+        (
+            "GET /exec/exec-1/json",
+            Reply::Body(200, String::from(r#"{"ExitCode":0,"Running":false}"#)),
+        ),
+    ]
+}
+
+/// Answers the requests of one connection to the fake Docker daemon.
+async fn serve_docker(
+    mut socket: TcpStream,
+    routes: Arc<HashMap<String, Reply>>,
+    requests: Arc<std::sync::Mutex<Vec<(String, String)>>>,
+) {
+    const UPGRADED: &[u8] = b"HTTP/1.1 101 UPGRADED\r\nContent-Type: application/vnd.docker.raw-stream\r\nConnection: Upgrade\r\nUpgrade: tcp\r\n\r\n";
+    let mut buffer = Vec::new();
+    while let Some((route, body)) = read_request(&mut socket, &mut buffer).await {
+        requests.lock().unwrap().push((route.clone(), body));
+        let (status, body) = match routes.get(&route) {
+            Some(Reply::Body(status, body)) => (*status, body.clone()),
+            Some(Reply::Stream(output)) => {
+                let _ = socket.write_all(UPGRADED).await;
+                let _ = socket.write_all(output).await;
+                // Hang up the output only, dropping the socket with unread input could reset it
+                // before the client read the output
+                let _ = socket.shutdown().await;
+                while matches!(socket.read(&mut [0; 4096]).await, Ok(read) if read > 0) {}
+                return;
+            }
+            Some(Reply::Echo) => {
+                let _ = socket.write_all(UPGRADED).await;
+                let _ = socket.write_all(&buffer).await;
+                let (mut reader, mut writer) = socket.split();
+                let _ = tokio::io::copy(&mut reader, &mut writer).await;
+                return;
+            }
+            Some(Reply::Hang) => std::future::pending().await,
+            None => (404, format!("{{\"message\":\"no route {}\"}}", route)),
+        };
+        let response = format!(
+            "HTTP/1.1 {} Mock\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+            status,
+            body.len(),
+            body
+        );
+        if socket.write_all(response.as_bytes()).await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Reads the next request of a connection to the fake Docker daemon.
+///
+/// # Returns
+///
+/// The route of the request as `METHOD /path` without the API version and the query, and its
+/// body. `None` once the client hung up.
+async fn read_request(socket: &mut TcpStream, buffer: &mut Vec<u8>) -> Option<(String, String)> {
+    let mut chunk = [0; 4096];
+    let head_end = loop {
+        if let Some(end) = buffer.windows(4).position(|window| window == b"\r\n\r\n") {
+            break end + 4;
+        }
+        match socket.read(&mut chunk).await {
+            Ok(0) | Err(_) => return None,
+            Ok(read) => buffer.extend_from_slice(&chunk[..read]),
+        }
+    };
+    let head = String::from_utf8_lossy(&buffer[..head_end]).to_string();
+    let length = head
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, value)| value.trim().parse::<usize>().ok())
+        .unwrap_or(0);
+    while buffer.len() < head_end + length {
+        match socket.read(&mut chunk).await {
+            Ok(0) | Err(_) => return None,
+            Ok(read) => buffer.extend_from_slice(&chunk[..read]),
+        }
+    }
+    let body = String::from_utf8_lossy(&buffer[head_end..head_end + length]).to_string();
+    buffer.drain(..head_end + length);
+    let mut request_line = head.split_whitespace();
+    let method = request_line.next()?;
+    let path = request_line.next()?.split('?').next()?;
+    let path = match path.strip_prefix("/v1.") {
+        Some(versioned) => &versioned[versioned.find('/')?..],
+        None => path,
+    };
+    Some((format!("{} {}", method, path), body))
+}
+
+/// Builds a running SSH-enabled container attached to a network on the loopback address, so
+/// forwards into the container and back to its gateway stay on the test machine.
+///
+/// # Arguments
+///
+/// * `hostname` - The value of the hostname label, the container ID is `<hostname>-id`.
+pub fn loopback_container(hostname: &str) -> ContainerSummary {
+    let endpoint = EndpointSettings {
+        ip_address: Some(String::from("127.0.0.1")),
+        ip_prefix_len: Some(8),
+        gateway: Some(String::from("127.0.0.1")),
+        ..Default::default()
+    };
+    ContainerSummary {
+        id: Some(format!("{}-id", hostname)),
+        state: Some(String::from("running")),
+        labels: Some(HashMap::from([
+            (String::from("tunnyD.enable"), String::from("true")),
+            (String::from("tunnyD.hostname"), hostname.to_string()),
+        ])),
+        network_settings: Some(ContainerSummaryNetworkSettings {
+            networks: Some(HashMap::from([(String::from("loopback"), endpoint)])),
+        }),
+        ..Default::default()
+    }
+}
+
+/// Builds the server template with a primary Docker client nothing listens behind and no
+/// Docker hosts.
+pub fn test_server(config: ServerConfig) -> Server {
+    let docker =
+        Docker::connect_with_http(UNREACHABLE_DOCKER, 1, &bollard::API_DEFAULT_VERSION).unwrap();
+    let live = crate::reload::LiveSettings::new(config).unwrap();
+    Server {
+        clients: Arc::new(Mutex::new(HashMap::new())),
+        docker: DockerClient::new(docker, Default::default()),
+        id: 0,
+        next_id: Arc::new(AtomicUsize::new(0)),
+        login_user: None,
+        keyboard_responses: Vec::new(),
+        peer_addr: None,
+        config: Arc::clone(&live.config),
+        audit: Arc::new(AuditLog::open(&live.config.audit_log).unwrap()),
+        ip_filter: Arc::clone(&live.ip_filter),
+        command_filter: Arc::clone(&live.command_filter),
+        auth_failures: Arc::new(AuthFailures::new(
+            live.config.auth_failure_threshold,
+            Duration::from_secs(live.config.auth_ban_secs),
+        )),
+        settings: Arc::new(std::sync::RwLock::new(live)),
+        auth_policy: AuthPolicy::Enforce,
+        docker_hosts: Arc::new(Vec::new()),
+        remote_forwards: Arc::new(Mutex::new(HashMap::new())),
+        shared_sessions: Arc::new(SharedSessions::default()),
+        usage_cache: Arc::new(Mutex::new(UsageCache::new(Duration::from_secs(5)))),
+        connection: None,
+        authenticated: Arc::new(Notify::new()),
+        affinity: Arc::new(Affinity::default()),
+        draining: Arc::new(AtomicBool::new(false)),
+    }
+}
+
+/// Builds a server letting any login in with the `none` method, its containers served by a single
+/// Docker host.
+///
+/// # Arguments
+///
+/// * `config` - The server configuration, its authentication methods are replaced by `none`.
+/// * `containers` - The containers of the Docker host.
+pub fn open_server(config: ServerConfig, containers: Vec<ContainerSummary>) -> Server {
+    let mut server = test_server(ServerConfig {
+        auth_methods: vec![AuthMethod::None],
+        ..config
+    });
+    server.auth_policy = AuthPolicy::InsecureAllowAll;
+    server.docker_hosts = Arc::new(vec![mock_host("local", containers)]);
+    server
+}
+
+/// Builds a server letting any login in with the `none` method, running its sessions in the
+/// container `app` behind the fake Docker daemon.
+///
+/// # Arguments
+///
+/// * `config` - The server configuration, its authentication methods are replaced by `none`.
+/// * `daemon` - The Docker daemon the container runs on.
+pub fn docker_server(config: ServerConfig, daemon: &MockDocker) -> Server {
+    let mut server = open_server(config, Vec::new());
+    server.docker_hosts = Arc::new(vec![daemon.host("local", vec![loopback_container("app")])]);
+    server
+}
+
+/// What a session channel received until the server closed it.
+///
+/// # Fields
+///
+/// * `stdout`: The data sent on the channel.
+/// * `stderr`: The extended data sent on the channel.
+/// * `exit_status`: The exit status, if one was sent.
+#[derive(Default)]
+pub struct SessionOutput {
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    pub exit_status: Option<u32>,
+}
+
+/// Reads a session channel until the server closes it.
+pub async fn read_session(channel: &mut Channel<Msg>) -> SessionOutput {
+    let read = async {
+        let mut output = SessionOutput::default();
+        while let Some(message) = channel.wait().await {
+            match message {
+                ChannelMsg::Data { data } => output.stdout.extend_from_slice(&data),
+                ChannelMsg::ExtendedData { data, .. } => output.stderr.extend_from_slice(&data),
+                ChannelMsg::ExitStatus { exit_status } => output.exit_status = Some(exit_status),
+                ChannelMsg::Close => break,
+                _ => {}
+            }
+        }
+        output
+    };
+    tokio::time::timeout(Duration::from_secs(10), read)
+        .await
+        .expect("the session didn't end within 10s")
+}
+
+/// Waits until a condition holds, failing the test if it doesn't within 5 seconds.
+///
+/// # Arguments
+///
+/// * `condition` - Checks the condition, called again every 10 milliseconds.
+pub async fn wait_until<F, Fut>(mut condition: F)
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = bool>,
+{
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(5);
+    while !condition().await {
+        assert!(
+            tokio::time::Instant::now() < deadline,
+            "condition not met within 5s"
+        );
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+}
+
+/// Builds a SSH configuration with a fresh host key and no delay on rejected logins.
+///
+/// # Arguments
+///
+/// * `methods` - The authentication methods offered to clients.
+pub fn ssh_config(methods: MethodSet) -> russh::server::Config {
+    russh::server::Config {
+        keys: vec![key::KeyPair::generate_ed25519().unwrap()],
+        methods,
+        auth_rejection_time: Duration::ZERO,
+        auth_rejection_time_initial: Some(Duration::ZERO),
+        ..Default::default()
+    }
+}
+
+/// The handler of the test client, accepting any host key.
+///
+/// # Fields
+///
+/// * `banner`: The pre-authentication banner the server sent.
+/// * `forwarded`: Receives the `forwarded-tcpip` channels the server opens.
+pub struct TestClient {
+    banner: Arc<std::sync::Mutex<Option<String>>>,
+    forwarded: mpsc::UnboundedSender<Channel<Msg>>,
+}
+
+#[async_trait]
+impl client::Handler for TestClient {
+    type Error = russh::Error;
+
+    async fn check_server_key(
+        self,
+        _server_public_key: &key::PublicKey,
+    ) -> Result<(Self, bool), Self::Error> {
+        Ok((self, true))
+    }
+
+    async fn auth_banner(
+        self,
+        banner: &str,
+        session: client::Session,
+    ) -> Result<(Self, client::Session), Self::Error> {
+        *self.banner.lock().unwrap() = Some(banner.to_string());
+        Ok((self, session))
+    }
+
+    async fn server_channel_open_forwarded_tcpip(
+        self,
+        channel: Channel<Msg>,
+        _connected_address: &str,
+        _connected_port: u32,
+        _originator_address: &str,
+        _originator_port: u32,
+        session: client::Session,
+    ) -> Result<(Self, client::Session), Self::Error> {
+        let _ = self.forwarded.send(channel);
+        Ok((self, session))
+    }
+}
+
+/// A SSH connection to a server running in the test.
+///
+/// # Fields
+///
+/// * `handle`: The client side of the connection.
+/// * `banner`: The pre-authentication banner the server sent, once received.
+/// * `forwarded`: The `forwarded-tcpip` channels the server opened.
+pub struct TestConnection {
+    pub handle: client::Handle<TestClient>,
+    pub banner: Arc<std::sync::Mutex<Option<String>>>,
+    pub forwarded: mpsc::UnboundedReceiver<Channel<Msg>>,
+}
+
+/// Connects a SSH client to a new connection of the server over an in-memory stream.
+///
+/// # Arguments
+///
+/// * `server` - The server template the connection handler is cloned from.
+/// * `peer_addr` - The address the connection appears to come from.
+/// * `config` - The SSH configuration of the server.
+pub async fn connect(
+    server: &mut Server,
+    peer_addr: Option<SocketAddr>,
+    config: russh::server::Config,
+) -> TestConnection {
+    let (client_stream, server_stream) = tokio::io::duplex(64 * 1024);
+    serve_stream(server, peer_addr, config, server_stream);
+    connect_stream(client_stream).await
+}
+
+/// Runs a new connection of the server over a stream.
+///
+/// # Arguments
+///
+/// * `server` - The server template the connection handler is cloned from.
+/// * `peer_addr` - The address the connection appears to come from.
+/// * `config` - The SSH configuration of the server.
+/// * `stream` - The server side of the stream.
+pub fn serve_stream<S>(
+    server: &mut Server,
+    peer_addr: Option<SocketAddr>,
+    config: russh::server::Config,
+    stream: S,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let handler = server.new_client(peer_addr);
+    tokio::spawn(async move {
+        if let Ok(session) = russh::server::run_stream(Arc::new(config), stream, handler).await {
+            let _ = session.await;
+        }
+    });
+}
+
+/// Connects a SSH client over a stream to a server.
+///
+/// # Arguments
+///
+/// * `stream` - The client side of the stream.
+pub async fn connect_stream<S>(stream: S) -> TestConnection
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let banner = Arc::new(std::sync::Mutex::new(None));
+    let (sender, forwarded) = mpsc::unbounded_channel();
+    let client = TestClient {
+        banner: Arc::clone(&banner),
+        forwarded: sender,
+    };
+    let handle = client::connect_stream(Arc::new(client::Config::default()), stream, client)
+        .await
+        .unwrap();
+    TestConnection {
+        handle,
+        banner,
+        forwarded,
+    }
+}
@@ -0,0 +1,286 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use bollard::container::{MemoryStatsStats, Stats, StatsOptions};
+use bollard::errors::Error;
+use bollard::models::ContainerSummary;
+use futures::StreamExt;
+use log::warn;
+use tokio::sync::Mutex;
+
+use crate::docker::DockerClient;
+
+/// How long the usage of a container is served from the cache before Docker is asked again.
+pub const USAGE_CACHE_TTL: Duration = Duration::from_secs(5);
+/// How long a login waits for the usage of its container before starting without it.
+const USAGE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// The resource usage of a container, shown to users when they log in.
+///
+/// # Fields
+///
+/// * `cpu_percent`: The CPU usage since the previous sample, `100` being one full core.
+/// * `memory_used`: The memory used by the container, without the reclaimable page cache.
+/// * `memory_limit`: The memory the container may use, the host's memory if it isn't limited.
+/// * `uptime`: How long the container has been running, as reported by Docker, e.g. `3 hours`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ContainerUsage {
+    pub cpu_percent: Option<f64>,
+    pub memory_used: Option<u64>,
+    pub memory_limit: Option<u64>,
+    pub uptime: Option<String>,
+}
+
+impl ContainerUsage {
+    /// Reads the usage of a container from a stats sample.
+    ///
+    /// # Arguments
+    ///
+    /// * `stats` - The stats sample, with the previous CPU sample filled in.
+    /// * `container` - The container summary, its status holds the uptime.
+    pub fn from_stats(stats: &Stats, container: &ContainerSummary) -> ContainerUsage {
+        let cpu = &stats.cpu_stats;
+        let precpu = &stats.precpu_stats;
+        let online_cpus = cpu.online_cpus.unwrap_or_else(|| {
+            cpu.cpu_usage
+                .percpu_usage
+                .as_ref()
+                .map_or(0, |usage| usage.len() as u64)
+        });
+        let memory = &stats.memory_stats;
+        // The page cache can be reclaimed, Docker leaves it out of the usage it shows as well
+        let cache = match &memory.stats {
+            Some(MemoryStatsStats::V1(stats)) => stats.total_inactive_file,
+            Some(MemoryStatsStats::V2(stats)) => stats.inactive_file,
+            None => 0,
+        };
+        ContainerUsage {
+            cpu_percent: cpu_percent(
+                cpu.cpu_usage.total_usage,
+                precpu.cpu_usage.total_usage,
+                cpu.system_cpu_usage.zip(precpu.system_cpu_usage),
+                online_cpus,
+            ),
+            memory_used: memory.usage.map(|usage| usage.saturating_sub(cache)),
+            memory_limit: memory.limit.filter(|limit| *limit > 0),
+            uptime: container
+                .status
+                .as_deref()
+                .and_then(|status| status.strip_prefix("Up "))
+                .map(str::to_string),
+        }
+    }
+
+    /// Renders the usage as a line for the MOTD.
+    ///
+    /// # Returns
+    ///
+    /// The values Docker reported, separated by `|` and terminated by CRLF, or `None` if it
+    /// reported none of them.
+    pub fn summary(&self) -> Option<String> {
+        let mut parts = Vec::new();
+        if let Some(percent) = self.cpu_percent {
+            parts.push(format!("CPU {:.1}%", percent));
+        }
+        match (self.memory_used, self.memory_limit) {
+            (Some(used), Some(limit)) => parts.push(format!(
+                "Memory {} / {} ({:.0}%)",
+                format_bytes(used),
+                format_bytes(limit),
+                used as f64 / limit as f64 * 100.0
+            )),
+            (Some(used), None) => parts.push(format!("Memory {}", format_bytes(used))),
+            _ => {}
+        }
+        if let Some(uptime) = &self.uptime {
+            parts.push(format!("Up {}", uptime));
+        }
+        if parts.is_empty() {
+            return None;
+        }
+        Some(format!("{}\r\n", parts.join(" | ")))
+    }
+}
+
+/// Computes the CPU usage between two samples the way `docker stats` does.
+///
+/// # Arguments
+///
+/// * `total` - The CPU time used by the container at the current sample, in nanoseconds.
+/// * `previous_total` - The CPU time used by the container at the previous sample.
+/// * `system` - The CPU time of the host at the current and the previous sample.
+/// * `online_cpus` - The number of CPUs of the host.
+///
+/// # Returns
+///
+/// The usage in percent of one core, or `None` if there is no previous sample.
+fn cpu_percent(
+    total: u64,
+    previous_total: u64,
+    system: Option<(u64, u64)>,
+    online_cpus: u64,
+) -> Option<f64> {
+    let (system, previous_system) = system?;
+    let system_delta = system.checked_sub(previous_system).filter(|d| *d > 0)?;
+    let delta = total.saturating_sub(previous_total);
+    Some(delta as f64 / system_delta as f64 * online_cpus.max(1) as f64 * 100.0)
+}
+
+/// Formats a size in bytes with a binary unit, e.g. `1.5 GiB`.
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+/// Caches the usage of containers for a short time so logins in quick succession share a sample.
+///
+/// # Fields
+///
+/// * `ttl`: How long a sample is served before it is fetched again.
+/// * `entries`: The samples by container ID and the instant they were fetched at.
+pub struct UsageCache {
+    ttl: Duration,
+    entries: HashMap<String, (Instant, ContainerUsage)>,
+}
+
+impl UsageCache {
+    /// Creates an empty cache.
+    ///
+    /// # Arguments
+    ///
+    /// * `ttl` - How long a sample is served before it is fetched again.
+    pub fn new(ttl: Duration) -> UsageCache {
+        UsageCache {
+            ttl,
+            entries: HashMap::new(),
+        }
+    }
+
+    /// Returns the cached usage of a container if it hasn't expired yet.
+    fn cached(&self, container_id: &str) -> Option<&ContainerUsage> {
+        match self.entries.get(container_id) {
+            Some((fetched_at, usage)) if fetched_at.elapsed() < self.ttl => Some(usage),
+            _ => None,
+        }
+    }
+
+    /// Stores the usage of a container, dropping expired samples of other containers.
+    fn insert(&mut self, container_id: &str, usage: ContainerUsage) {
+        let ttl = self.ttl;
+        self.entries
+            .retain(|_, (fetched_at, _)| fetched_at.elapsed() < ttl);
+        self.entries
+            .insert(container_id.to_string(), (Instant::now(), usage));
+    }
+}
+
+/// Returns the resource usage of a container.
+///
+/// # Arguments
+///
+/// * `docker` - The client of the Docker host the container runs on.
+/// * `container` - The container.
+/// * `cache` - The usage cache shared by every connection.
+///
+/// # Returns
+///
+/// The usage, or `None` if the container has no ID or Docker didn't report it in time.
+///
+/// # Remarks
+///
+/// The stats are requested without `one-shot`, Docker then waits for a second sample so the CPU
+/// usage can be computed. Logins wait for that at most `USAGE_TIMEOUT`.
+pub async fn container_usage(
+    docker: &DockerClient,
+    container: &ContainerSummary,
+    cache: &Mutex<UsageCache>,
+) -> Option<ContainerUsage> {
+    let id = container.id.as_deref()?;
+    if let Some(usage) = cache.lock().await.cached(id) {
+        return Some(usage.clone());
+    }
+    let fetch = docker.with_retry(|docker| async move {
+        let options = StatsOptions {
+            stream: false,
+            one_shot: false,
+        };
+        docker.stats(id, Some(options)).next().await.transpose()
+    });
+    let stats: Result<Option<Stats>, Error> = match tokio::time::timeout(USAGE_TIMEOUT, fetch).await
+    {
+        Ok(stats) => stats,
+        Err(_) => {
+            warn!("Timed out reading the usage of container {}", id);
+            return None;
+        }
+    };
+    match stats {
+        Ok(Some(stats)) => {
+            let usage = ContainerUsage::from_stats(&stats, container);
+            cache.lock().await.insert(id, usage.clone());
+            Some(usage)
+        }
+        Ok(None) => None,
+        Err(e) => {
+            warn!("Failed to read the usage of container {}: {}", id, e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn computes_cpu_usage_like_docker_stats() {
+        let percent = cpu_percent(3_000_000, 1_000_000, Some((20_000_000, 10_000_000)), 4);
+        assert_eq!(percent, Some(80.0));
+        assert_eq!(cpu_percent(3_000_000, 0, None, 4), None);
+        assert_eq!(cpu_percent(3_000_000, 0, Some((5, 5)), 4), None);
+    }
+
+    #[test]
+    fn renders_the_fetched_usage() {
+        let usage = ContainerUsage {
+            cpu_percent: Some(12.345),
+            memory_used: Some(256 * 1024 * 1024),
+            memory_limit: Some(1024 * 1024 * 1024),
+            uptime: Some(String::from("3 hours (healthy)")),
+        };
+        assert_eq!(
+            usage.summary().as_deref(),
+            Some("CPU 12.3% | Memory 256.0 MiB / 1.0 GiB (25%) | Up 3 hours (healthy)\r\n")
+        );
+        let memory_only = ContainerUsage {
+            memory_used: Some(512),
+            ..Default::default()
+        };
+        assert_eq!(memory_only.summary().as_deref(), Some("Memory 512 B\r\n"));
+        assert_eq!(ContainerUsage::default().summary(), None);
+    }
+
+    #[test]
+    fn serves_usage_until_it_expires() {
+        let mut cache = UsageCache::new(Duration::from_secs(60));
+        let usage = ContainerUsage {
+            cpu_percent: Some(1.0),
+            ..Default::default()
+        };
+        cache.insert("abc", usage.clone());
+        assert_eq!(cache.cached("abc"), Some(&usage));
+        assert_eq!(cache.cached("def"), None);
+        let mut expired = UsageCache::new(Duration::ZERO);
+        expired.insert("abc", usage);
+        assert_eq!(expired.cached("abc"), None);
+    }
+}
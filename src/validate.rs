@@ -0,0 +1,171 @@
+use crate::acl::{CommandFilter, IpFilter, IpPolicy};
+use crate::algorithms::preferred;
+use crate::audit::AuditLog;
+use crate::auth::AUTHORIZED_KEYS_USER_TOKEN;
+use crate::cli::ServeArgs;
+use crate::config::ServerConfig;
+use crate::docker::{connect_to_docker, DockerSettings};
+use crate::hook::parse_webhook_url;
+use crate::listen_endpoints;
+
+/// Checks a configuration the way `serve` uses it, without starting the server.
+///
+/// # Arguments
+///
+/// * `args` - The arguments of the `serve` subcommand.
+/// * `config` - The loaded configuration.
+///
+/// # Returns
+///
+/// A message for every problem found, empty if the server would start with this configuration.
+///
+/// # Remarks
+///
+/// The audit log is opened, and created if it doesn't exist yet, to check it is writable. Host
+/// keys are generated at startup, so there are none to check.
+pub fn config_problems(args: &ServeArgs, config: &ServerConfig) -> Vec<String> {
+    let mut problems = Vec::new();
+    if let Err(e) = listen_endpoints(args, config) {
+        problems.push(format!("Invalid listen endpoint: {}", e));
+    }
+    if let Err(e) = AuditLog::open(&config.audit_log) {
+        problems.push(format!("Can't open audit log {}: {}", config.audit_log, e));
+    }
+    if let Err(e) = IpFilter::new(
+        &config.allow_ips,
+        &config.deny_ips,
+        config.default_ip_policy,
+    ) {
+        problems.push(format!("Invalid IP allow/deny list: {}", e));
+    }
+    if let Err(e) = IpFilter::new(&config.trusted_proxies, &[], IpPolicy::Deny) {
+        problems.push(format!("Invalid trusted_proxies: {}", e));
+    }
+    if let Err(e) = CommandFilter::new(&config.allowed_commands, config.allow_interactive_shell) {
+        problems.push(format!("Invalid command allowlist: {}", e));
+    }
+    if let Err(e) = preferred(config) {
+        problems.push(format!("Invalid algorithm preferences: {}", e));
+    }
+    if let Err(e) = docker_settings(args, config).client_version() {
+        problems.push(e);
+    }
+    if let Some(path) = &config.banner_file {
+        if let Err(e) = std::fs::metadata(path) {
+            problems.push(format!("Can't read banner_file {}: {}", path, e));
+        }
+    }
+    if let Some(url) = &config.connect_hook_url {
+        if let Err(e) = parse_webhook_url(url) {
+            problems.push(e);
+        }
+    }
+    if config
+        .connect_hook_command
+        .as_ref()
+        .is_some_and(|command| command.is_empty())
+    {
+        problems.push(String::from("connect_hook_command is empty"));
+    }
+    // Per-user files only exist for the users that have keys
+    if let Some(path) = config
+        .authorized_keys_file
+        .as_ref()
+        .filter(|path| !path.contains(AUTHORIZED_KEYS_USER_TOKEN))
+    {
+        if let Err(e) = std::fs::metadata(path) {
+            problems.push(format!("Can't read authorized_keys_file {}: {}", path, e));
+        }
+    }
+    problems
+}
+
+/// Builds the settings of the Docker connection `serve` would use.
+fn docker_settings(args: &ServeArgs, config: &ServerConfig) -> DockerSettings {
+    DockerSettings::new(
+        args.docker_context
+            .clone()
+            .or_else(|| config.docker_context.clone()),
+        config.docker_api_version.clone(),
+    )
+}
+
+/// Loads and checks the configuration of `serve --test-config`, printing every problem found.
+///
+/// # Arguments
+///
+/// * `args` - The arguments of the `serve` subcommand, `--check-docker` also connects to Docker.
+///
+/// # Returns
+///
+/// `true` if the configuration is valid and, if checked, Docker is reachable.
+pub async fn test_config(args: &ServeArgs) -> bool {
+    let config = match ServerConfig::load(args.config.as_deref()) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Invalid configuration: {}", e);
+            return false;
+        }
+    };
+    let mut problems = config_problems(args, &config);
+    if args.check_docker && problems.is_empty() {
+        if let Err(e) = connect_to_docker(&docker_settings(args, &config)).await {
+            problems.push(format!("Docker is not reachable: {}", e));
+        }
+    }
+    for problem in &problems {
+        eprintln!("{}", problem);
+    }
+    if problems.is_empty() {
+        println!("Configuration OK");
+    }
+    problems.is_empty()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    fn serve_args(config: &tempfile::NamedTempFile) -> ServeArgs {
+        ServeArgs {
+            bind: String::from("0.0.0.0"),
+            port: 2222,
+            listen: Vec::new(),
+            unix_socket: None,
+            docker_context: None,
+            config: Some(config.path().display().to_string()),
+            test_config: true,
+            check_docker: false,
+            insecure_allow_all: false,
+        }
+    }
+
+    fn config_file(contents: &str) -> tempfile::NamedTempFile {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(contents.as_bytes()).unwrap();
+        file
+    }
+
+    #[tokio::test]
+    async fn accepts_a_valid_configuration() {
+        let file = config_file("max_sessions = 4\nallow_ips = [\"10.0.0.0/8\"]\n");
+        assert!(test_config(&serve_args(&file)).await);
+    }
+
+    #[tokio::test]
+    async fn rejects_an_invalid_configuration() {
+        let file = config_file("max_sessions = \"four\"\n");
+        assert!(!test_config(&serve_args(&file)).await);
+
+        let file = config_file("deny_ips = [\"not-an-address\"]\nciphers = [\"3des-cbc\"]\n");
+        let args = serve_args(&file);
+        let config = ServerConfig::load(args.config.as_deref()).unwrap();
+        let problems = config_problems(&args, &config);
+        assert_eq!(problems.len(), 2);
+        assert!(problems[0].starts_with("Invalid IP allow/deny list: "));
+        assert!(problems[1].starts_with("Invalid algorithm preferences: Unknown cipher '3des-cbc'"));
+        assert!(!test_config(&args).await);
+    }
+}